@@ -0,0 +1,231 @@
+//! Benchmarks of a mounted filesystem, for measuring how changes to the read
+//! and write paths affect performance. They need FUSE and a database to run
+//! against, given by:
+//!
+//!     COCKROACHFS_URL   database to benchmark; not run if unset
+//!
+//! The database is initialized if it is not already and mounted at a
+//! temporary directory, and the benchmarks work in a directory of their own
+//! that is removed afterwards. Files are read and written with O_DIRECT, so
+//! that reads are served by the database rather than the page cache.
+//!
+//! Criterion's arguments follow `--`, so `cargo bench -- readdir` runs only
+//! the readdir benchmarks, though the files of all of them are still set up.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{run, Mount};
+use criterion::{BenchmarkId, Criterion, Throughput};
+use libc::O_DIRECT;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+/// Sizes of the files written and read whole.
+const SIZES: &[usize] = &[4 << 10, 64 << 10, 1 << 20, 16 << 20];
+
+/// Size of the reads and writes at random offsets, and of the file they are
+/// made within.
+const RANDOM_IO: usize = 4 << 10;
+const RANDOM_FILE: usize = 16 << 20;
+
+/// Small files created, stat'd and deleted by each metadata iteration.
+const SMALL_FILES: usize = 100;
+
+/// Entries in the directories listed by the readdir benchmarks.
+const DIR_ENTRIES: &[usize] = &[100, 1000, 10000];
+
+/// Alignment of the buffers and offsets of direct I/O.
+const ALIGN: usize = 4096;
+
+fn main() {
+    let url = match env::var("COCKROACHFS_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            println!("benchmarks not run, COCKROACHFS_URL is unset");
+            return;
+        }
+    };
+    let crfs = Path::new(env!("CARGO_BIN_EXE_cockroach_fuse"));
+    run(Command::new(crfs).args(["--url", &url, "init"]));
+    let dir = env::temp_dir().join(format!("crfs-bench-{}", process::id()));
+    let mountpoint = dir.join("mnt");
+    let mount = Mount::start(crfs, &url, &mountpoint, &dir.join("mount.log"), &[]);
+    let work = mountpoint.join(format!("bench-{}", process::id()));
+    fs::create_dir(&work).unwrap();
+
+    let mut c = Criterion::default().configure_from_args();
+    sequential(&mut c, &work);
+    random(&mut c, &work);
+    metadata(&mut c, &work);
+    readdir(&mut c, &work);
+    c.final_summary();
+
+    fs::remove_dir_all(&work).unwrap();
+    drop(mount);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Write files of each size whole, replacing their contents, and read them
+/// back whole.
+fn sequential(c: &mut Criterion, work: &Path) {
+    let mut group = c.benchmark_group("sequential");
+    group.sample_size(10);
+    for &size in SIZES {
+        let data = Buffer::pattern(size);
+        let path = work.join(format!("sequential-{}", size));
+        fs::write(&path, data.bytes()).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("write", size), &data, |b, data| {
+            b.iter(|| {
+                let mut file = direct().write(true).truncate(true).open(&path).unwrap();
+                file.write_all(data.bytes()).unwrap();
+                file.sync_all().unwrap();
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("read", size), &size, |b, &size| {
+            let mut buf = Buffer::new(size);
+            b.iter(|| {
+                let mut file = direct().read(true).open(&path).unwrap();
+                file.read_exact(buf.bytes_mut()).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Write and read RANDOM_IO bytes at a time at random aligned offsets.
+fn random(c: &mut Criterion, work: &Path) {
+    let path = work.join("random");
+    fs::write(&path, Buffer::pattern(RANDOM_FILE).bytes()).unwrap();
+    let file = direct().read(true).write(true).open(&path).unwrap();
+    let data = Buffer::pattern(RANDOM_IO);
+    let mut buf = Buffer::new(RANDOM_IO);
+    let mut offsets = Offsets(0x9e37_79b9_7f4a_7c15);
+    let mut group = c.benchmark_group("random");
+    group.throughput(Throughput::Bytes(RANDOM_IO as u64));
+    group.bench_function("write", |b| {
+        b.iter(|| file.write_all_at(data.bytes(), offsets.offset()).unwrap())
+    });
+    group.bench_function("read", |b| {
+        b.iter(|| {
+            file.read_exact_at(buf.bytes_mut(), offsets.offset())
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+/// Create, stat and delete many small files in one directory, and stat
+/// files that already exist.
+fn metadata(c: &mut Criterion, work: &Path) {
+    let dir = work.join("small");
+    fs::create_dir(&dir).unwrap();
+    let data = Buffer::pattern(100);
+    let paths: Vec<PathBuf> = (0..SMALL_FILES)
+        .map(|i| dir.join(format!("file-{}", i)))
+        .collect();
+    let mut group = c.benchmark_group("metadata");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SMALL_FILES as u64));
+    group.bench_function("create-stat-delete", |b| {
+        b.iter(|| {
+            for path in &paths {
+                fs::write(path, data.bytes()).unwrap();
+            }
+            for path in &paths {
+                fs::metadata(path).unwrap();
+            }
+            for path in &paths {
+                fs::remove_file(path).unwrap();
+            }
+        })
+    });
+    for path in &paths {
+        fs::write(path, data.bytes()).unwrap();
+    }
+    group.bench_function("stat", |b| {
+        b.iter(|| {
+            for path in &paths {
+                fs::metadata(path).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+/// List directories of each size.
+fn readdir(c: &mut Criterion, work: &Path) {
+    let mut group = c.benchmark_group("readdir");
+    group.sample_size(10);
+    for &entries in DIR_ENTRIES {
+        let dir = work.join(format!("dir-{}", entries));
+        fs::create_dir(&dir).unwrap();
+        for i in 0..entries {
+            File::create(dir.join(format!("entry-{}", i))).unwrap();
+        }
+        group.throughput(Throughput::Elements(entries as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(entries), &dir, |b, dir| {
+            b.iter(|| assert_eq!(fs::read_dir(dir).unwrap().count(), entries))
+        });
+    }
+    group.finish();
+}
+
+/// Offsets of RANDOM_IO byte extents of a RANDOM_FILE byte file, in a fixed
+/// pseudorandom order so that runs are comparable.
+struct Offsets(u64);
+
+impl Offsets {
+    fn offset(&mut self) -> u64 {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % (RANDOM_FILE / RANDOM_IO) as u64 * RANDOM_IO as u64
+    }
+}
+
+/// Options to open a file with for direct I/O.
+fn direct() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.custom_flags(O_DIRECT);
+    options
+}
+
+/// A buffer aligned for direct I/O, carved out of a vector with room to
+/// spare, whose contents do not move once allocated.
+struct Buffer {
+    data: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl Buffer {
+    fn new(len: usize) -> Buffer {
+        let data = vec![0; len + ALIGN];
+        let start = data.as_ptr().align_offset(ALIGN);
+        Buffer { data, start, len }
+    }
+
+    /// len bytes of data that do not repeat at block boundaries.
+    fn pattern(len: usize) -> Buffer {
+        let mut buf = Buffer::new(len);
+        for (i, byte) in buf.bytes_mut().iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        buf
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.data[self.start..self.start + self.len]
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data[self.start..self.start + self.len]
+    }
+}
@@ -0,0 +1,208 @@
+//! In-memory caches sharing a single memory budget.
+//!
+//! Every cached item lives in one LRU list regardless of its kind, so pressure
+//! from one kind of cache evicts the least recently used items of any kind and
+//! the total memory held stays within the configured budget.
+
+use super::metrics::Metrics;
+use fuse::FileAttr;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::mem;
+use std::time::Instant;
+
+/// Approximate bookkeeping overhead of a cached item, in bytes.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// The kinds of items held in the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Kind {
+    Dentry,
+    Attr,
+    Block,
+}
+
+const KINDS: &[Kind] = &[Kind::Dentry, Kind::Attr, Kind::Block];
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Dentry => write!(f, "dentry"),
+            Kind::Attr => write!(f, "attr"),
+            Kind::Block => write!(f, "block"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    /// A directory entry, by parent inode and name.
+    Dentry(u64, Vec<u8>),
+    /// The attributes of an inode.
+    Attr(u64),
+    /// A block of file data, by inode and block index.
+    Block(u64, i64),
+}
+
+impl Key {
+    fn kind(&self) -> Kind {
+        match self {
+            Key::Dentry(..) => Kind::Dentry,
+            Key::Attr(_) => Kind::Attr,
+            Key::Block(..) => Kind::Block,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Key::Dentry(_, name) => mem::size_of::<Key>() + name.len(),
+            Key::Attr(_) | Key::Block(..) => mem::size_of::<Key>(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    /// The inode a directory entry refers to and its generation, or None if
+    /// there is no such entry, and when it was read from the database.
+    Dentry(Option<(u64, u64)>, Instant),
+    /// The attributes of an inode, and when they were read from the database.
+    Attr(FileAttr, Instant),
+    /// The contents of a block, and when they were read from the database. A
+    /// block shorter than the block size is the last block of its file.
+    Block(Vec<u8>, Instant),
+}
+
+impl Value {
+    fn size(&self) -> usize {
+        match self {
+            Value::Block(data, _) => mem::size_of::<Value>() + data.len(),
+            _ => mem::size_of::<Value>(),
+        }
+    }
+}
+
+/// Usage counters for one kind of cached item. Hit rates are recorded in
+/// Metrics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub evictions: u64,
+}
+
+pub struct Cache {
+    /// Maximum number of bytes held across all kinds.
+    budget: usize,
+    /// Number of bytes currently held.
+    used: usize,
+    /// Logical clock used to order items by recency.
+    tick: u64,
+    items: HashMap<Key, (Value, u64)>,
+    lru: BTreeMap<u64, Key>,
+    stats: HashMap<Kind, Stats>,
+    metrics: Metrics,
+}
+
+impl Cache {
+    pub fn new(budget: usize, metrics: Metrics) -> Cache {
+        Cache {
+            budget,
+            used: 0,
+            tick: 0,
+            items: HashMap::new(),
+            lru: BTreeMap::new(),
+            stats: KINDS.iter().map(|&k| (k, Stats::default())).collect(),
+            metrics,
+        }
+    }
+
+    /// Look up an item, marking it as most recently used.
+    pub fn get(&mut self, key: &Key) -> Option<&Value> {
+        self.tick += 1;
+        let tick = self.tick;
+        let item = self.items.get_mut(key);
+        self.metrics.cache_lookup(key.kind(), item.is_some());
+        match item {
+            None => None,
+            Some(item) => {
+                let key = self.lru.remove(&item.1).unwrap();
+                self.lru.insert(tick, key);
+                item.1 = tick;
+                Some(&item.0)
+            }
+        }
+    }
+
+    /// Add or replace an item, evicting the least recently used items as
+    /// needed to stay within the budget.
+    pub fn insert(&mut self, key: Key, value: Value) {
+        self.remove(&key);
+        let size = key.size() + value.size() + ENTRY_OVERHEAD;
+        if size > self.budget {
+            return;
+        }
+        while self.used + size > self.budget {
+            self.evict();
+        }
+        self.tick += 1;
+        self.used += size;
+        let stats = self.stats.get_mut(&key.kind()).unwrap();
+        stats.entries += 1;
+        stats.bytes += size;
+        self.lru.insert(self.tick, key.clone());
+        self.items.insert(key, (value, self.tick));
+    }
+
+    /// Drop an item, if present.
+    pub fn remove(&mut self, key: &Key) {
+        if let Some((value, tick)) = self.items.remove(key) {
+            self.lru.remove(&tick);
+            self.release(key, &value);
+        }
+    }
+
+    /// Drop every item for which pred holds.
+    pub fn remove_matching<F: Fn(&Key, &Value) -> bool>(&mut self, pred: F) {
+        let keys: Vec<Key> = self
+            .items
+            .iter()
+            .filter(|(k, (v, _))| pred(k, v))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    /// Number of bytes currently held.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Maximum number of bytes held.
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Counters for each kind of cached item.
+    pub fn stats(&self) -> Vec<(Kind, Stats)> {
+        KINDS.iter().map(|k| (*k, self.stats[k])).collect()
+    }
+
+    fn evict(&mut self) {
+        let tick = *self.lru.keys().next().unwrap();
+        let key = self.lru.remove(&tick).unwrap();
+        let (value, _) = self.items.remove(&key).unwrap();
+        self.release(&key, &value);
+        self.stats.get_mut(&key.kind()).unwrap().evictions += 1;
+    }
+
+    fn release(&mut self, key: &Key, value: &Value) {
+        let size = key.size() + value.size() + ENTRY_OVERHEAD;
+        self.used -= size;
+        let stats = self.stats.get_mut(&key.kind()).unwrap();
+        stats.entries -= 1;
+        stats.bytes -= size;
+    }
+}
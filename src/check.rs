@@ -0,0 +1,206 @@
+//! Consistency checks run against the database before it is mounted, and
+//! repairs for the problems they find.
+
+use super::gc;
+use super::sql;
+use fuse::{FileType, FUSE_ROOT_ID};
+use postgres::{GenericConnection, Result};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How thorough a consistency check should be.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Level {
+    /// Only checks that are cheap regardless of the size of the filesystem.
+    Quick,
+    /// Every check, including ones that scan all inodes and blocks.
+    Full,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Level, String> {
+        match s {
+            "quick" => Ok(Level::Quick),
+            "full" => Ok(Level::Full),
+            _ => Err(format!("unknown check level {:?}", s)),
+        }
+    }
+}
+
+/// Check the filesystem stored in the database for inconsistencies, returning
+/// a description of each problem found.
+pub fn run<C: GenericConnection>(conn: &C, level: Level) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+
+    let missing = sql::missing_tables(conn)?;
+    if missing.len() == sql::TABLES.len() {
+        // A fresh database; the schema is created by init.
+        return Ok(problems);
+    }
+    if !missing.is_empty() {
+        problems.push(format!("missing tables: {}", missing.join(", ")));
+        return Ok(problems);
+    }
+
+    match sql::lookup_inode_kind(conn, sql::root())? {
+        None => problems.push("root directory does not exist".to_string()),
+        Some(FileType::Directory) => {}
+        Some(_) => problems.push("root inode is not a directory".to_string()),
+    }
+
+    let (max_ino, last_alloc) = sql::inode_alloc_bounds(conn)?;
+    if max_ino > last_alloc {
+        problems.push(format!(
+            "inode {} exists but the allocator has only handed out up to {}",
+            max_ino, last_alloc
+        ));
+    }
+
+    if level == Level::Full {
+        let roots = sql::roots(conn)?;
+        let checks = [
+            (
+                sql::count_dangling_dir_ents(conn)?,
+                "directory entries refer to missing inodes",
+            ),
+            (
+                sql::count_mismatched_dir_ent_kinds(conn)?,
+                "directory entries disagree with the kind of their inode",
+            ),
+            (
+                sql::count_mismatched_nlinks(conn, &roots)?,
+                "inodes have a link count that disagrees with their directory entries",
+            ),
+            (
+                sql::count_mismatched_blocks(conn)?,
+                "inodes have a block count that disagrees with their stored blocks",
+            ),
+            (
+                sql::count_blocks_past_eof(conn)?,
+                "blocks lie beyond the end of their file",
+            ),
+            (
+                sql::count_orphaned_inodes(conn, &roots)?,
+                "inodes are not linked into any directory",
+            ),
+            (
+                sql::count_extra_roots(conn, &roots)?,
+                "of those are directories left behind as extra roots",
+            ),
+        ];
+        for &(n, what) in checks.iter() {
+            if n > 0 {
+                problems.push(format!("{} {}", n, what));
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Name of the directory under the root that inodes of the selected
+/// filesystem found unlinked are linked into by repair.
+const LOST_FOUND: &[u8] = b"lost+found";
+
+/// Repair the problems found by a full check that can be fixed without
+/// losing data, returning a description of each repair made. Unlinked inodes
+/// are linked into lost+found rather than deleted, except for empty
+/// directories, and only data beyond the end of a file is discarded. The
+/// filesystem should not be mounted while it is repaired.
+pub fn repair<C: GenericConnection>(conn: &C) -> Result<Vec<String>> {
+    let mut repairs = Vec::new();
+
+    let root = sql::root();
+    match sql::lookup_inode_kind(conn, root)? {
+        None if root == FUSE_ROOT_ID => record(
+            &mut repairs,
+            sql::create_root(conn, 0, 0)? as u64,
+            "root directory created",
+        ),
+        // The registry names the root of any other filesystem, so one
+        // created in its place would not be found.
+        None => {
+            return Ok(vec![
+                "root directory does not exist, not repaired".to_string()
+            ])
+        }
+        Some(FileType::Directory) => {}
+        // Nothing can safely replace a root that holds data.
+        Some(_) => {
+            return Ok(vec![
+                "root inode is not a directory, not repaired".to_string()
+            ])
+        }
+    }
+
+    let roots = sql::roots(conn)?;
+    record(
+        &mut repairs,
+        sql::delete_dangling_dir_ents(conn)?,
+        "directory entries referring to missing inodes deleted",
+    );
+    record(
+        &mut repairs,
+        sql::fix_dir_ent_kinds(conn)?,
+        "directory entry kinds corrected",
+    );
+    record(
+        &mut repairs,
+        sql::delete_extra_roots(conn, &roots)?,
+        "empty unlinked directories deleted",
+    );
+    record(
+        &mut repairs,
+        gc::collect_blocks(conn, gc::BATCH, Duration::from_secs(0))?,
+        "blocks beyond the end of their file deleted",
+    );
+
+    if sql::count_orphaned_inodes(conn, &roots)? > 0 {
+        let lost_found = match sql::lookup_dir_ent(conn, root, LOST_FOUND)? {
+            Some((ref attr, _)) if attr.kind == FileType::Directory => Some(attr.ino),
+            Some(_) => None,
+            None => {
+                let (attr, _) = sql::create_inode(
+                    conn,
+                    root,
+                    LOST_FOUND,
+                    FileType::Directory,
+                    0o700,
+                    0,
+                    (0, 0),
+                )?;
+                Some(attr.ino)
+            }
+        };
+        match lost_found {
+            Some(dir) => record(
+                &mut repairs,
+                sql::link_orphans(conn, &roots, dir)?,
+                "unlinked inodes linked into /lost+found",
+            ),
+            None => repairs
+                .push("/lost+found is not a directory, unlinked inodes not linked".to_string()),
+        }
+    }
+
+    record(
+        &mut repairs,
+        sql::fix_nlinks(conn, &roots)?,
+        "link counts corrected",
+    );
+    record(
+        &mut repairs,
+        sql::fix_block_counts(conn)?,
+        "block counts corrected",
+    );
+    Ok(repairs)
+}
+
+/// Describe a repair made to n items, if any.
+fn record(repairs: &mut Vec<String>, n: u64, what: &str) {
+    if n > 0 {
+        repairs.push(format!("{} {}", n, what));
+    }
+}
@@ -0,0 +1,368 @@
+//! Configuration files.
+//!
+//! A configuration file is written in a subset of TOML: each key is the long
+//! name of a command-line flag, set to a string, an integer, a boolean for
+//! flags that take no value, or an array for flags that may be repeated.
+//! Settings in a table named after a subcommand only apply to it, while those
+//! outside any table or in the connection table apply to every subcommand.
+//!
+//! ```toml
+//! [connection]
+//! url = "postgres://fs@db.example.com:26257/cockroachfs"
+//! certs-dir = "/etc/cockroachfs/certs"
+//!
+//! [mount]
+//! mountpoint = "/mnt/cockroachfs"
+//! read-endpoint = ["db-east.example.com:26257", "db-west.example.com:26257"]
+//! allow-other = true
+//! cache-size = 256
+//! staleness = ["getattr=5", "readdir=5"]
+//! ```
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+pub struct Config {
+    /// Settings in the order they appear, by the table they appear in and
+    /// flag name.
+    settings: Vec<(Option<String>, String, Value)>,
+}
+
+impl Config {
+    /// Read and parse a configuration file.
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let contents = fs::read_to_string(path)?;
+        Config::parse(&contents).map_err(|(line, msg)| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}:{}: {}", path.display(), line, msg),
+            )
+        })
+    }
+
+    /// Parse a configuration, returning the line number of any error.
+    fn parse(contents: &str) -> Result<Config, (usize, String)> {
+        let mut settings: Vec<(Option<String>, String, Value)> = Vec::new();
+        let mut table = None;
+        for (i, line) in contents.lines().enumerate() {
+            let err = |msg: String| (i + 1, msg);
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                table = Some(line[1..line.len() - 1].trim().to_string());
+                continue;
+            }
+            let eq = line
+                .find('=')
+                .ok_or_else(|| err(format!("expected key = value, found {:?}", line)))?;
+            let key = line[..eq].trim().trim_matches('"');
+            if key.is_empty() {
+                return Err(err("missing key".to_string()));
+            }
+            // A key set in two tables that apply to the same subcommand
+            // would give its flag twice.
+            if let Some((t, _, _)) = settings
+                .iter()
+                .find(|(t, k, _)| k == key && overlap(t, &table))
+            {
+                return Err(err(match t {
+                    _ if *t == table => format!("{} is set more than once", key),
+                    Some(t) => format!("{} is already set in [{}]", key, t),
+                    None => format!("{} is already set outside any table", key),
+                }));
+            }
+            let (value, rest) = parse_value(line[eq + 1..].trim()).map_err(err)?;
+            if !rest.trim().is_empty() {
+                return Err(err(format!("unexpected {:?} after value", rest.trim())));
+            }
+            settings.push((table.clone(), key.to_string(), value));
+        }
+        Ok(Config { settings })
+    }
+
+    /// The command-line arguments of subcommand command equivalent to the
+    /// configuration, leaving out flags for which given returns true so that
+    /// flags given on the command line take precedence.
+    pub fn args<F: Fn(&str) -> bool>(&self, command: &str, given: F) -> io::Result<Vec<String>> {
+        let mut args = Vec::new();
+        for (table, key, value) in &self.settings {
+            let applies = match table {
+                None => true,
+                Some(table) => table == "connection" || table == command,
+            };
+            if !applies || given(key) {
+                continue;
+            }
+            match value {
+                Value::Boolean(true) => args.push(format!("--{}", key)),
+                Value::Boolean(false) => {}
+                Value::Array(values) => {
+                    for value in values {
+                        args.push(format!("--{}={}", key, scalar(key, value)?));
+                    }
+                }
+                value => args.push(format!("--{}={}", key, scalar(key, value)?)),
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Whether settings in tables a and b apply to some subcommand alike.
+fn overlap(a: &Option<String>, b: &Option<String>) -> bool {
+    let shared = |t: &Option<String>| matches!(t.as_deref(), None | Some("connection"));
+    a == b || shared(a) || shared(b)
+}
+
+/// The text of a string or integer value.
+fn scalar(key: &str, value: &Value) -> io::Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(n) => Ok(n.to_string()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} must be a string or integer", key),
+        )),
+    }
+}
+
+/// Drop a trailing comment from a line, leaving any # inside strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// Parse the value at the start of s, returning it and the rest of s.
+fn parse_value(s: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, c @ '"')) | Some((_, c @ '\\')) => value.push(c),
+                    _ => return Err("unsupported escape in string".to_string()),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+    if let Some(rest) = s.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("unterminated string")?;
+        return Ok((Value::String(rest[..end].to_string()), &rest[end + 1..]));
+    }
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("expected , or ] in array".to_string());
+            }
+        }
+    }
+    let end = s
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    let value = match word {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => match word.replace('_', "").parse() {
+            Ok(n) => Value::Integer(n),
+            Err(_) => return Err(format!("unsupported value {:?}", word)),
+        },
+    };
+    Ok((value, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(contents: &str, command: &str) -> Vec<String> {
+        Config::parse(contents)
+            .unwrap()
+            .args(command, |_| false)
+            .unwrap()
+    }
+
+    fn parse_error(contents: &str) -> (usize, String) {
+        match Config::parse(contents) {
+            Ok(_) => panic!("{:?} parsed", contents),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn quoting() {
+        let contents = r#"
+            basic = "a \"b\" \\ # c\td"
+            literal = 'C:\dir # not a comment' # a comment
+            "quoted-key" = "x"
+        "#;
+        assert_eq!(
+            args(contents, "mount"),
+            vec![
+                "--basic=a \"b\" \\ # c\td",
+                "--literal=C:\\dir # not a comment",
+                "--quoted-key=x",
+            ]
+        );
+        assert_eq!(
+            parse_error("a = \"b"),
+            (1, "unterminated string".to_string())
+        );
+        assert_eq!(
+            parse_error("a = 'b"),
+            (1, "unterminated string".to_string())
+        );
+        assert_eq!(
+            parse_error("a = \"\\q\""),
+            (1, "unsupported escape in string".to_string())
+        );
+    }
+
+    #[test]
+    fn values() {
+        let contents = "
+            cache-size = 1_024
+            allow-other = true
+            read-only = false
+        ";
+        assert_eq!(
+            args(contents, "mount"),
+            vec!["--cache-size=1024", "--allow-other"]
+        );
+        assert_eq!(
+            parse_error("\n a = yes"),
+            (2, "unsupported value \"yes\"".to_string())
+        );
+        assert_eq!(
+            parse_error("a = 1 2"),
+            (1, "unexpected \"2\" after value".to_string())
+        );
+        assert_eq!(
+            parse_error("just a key"),
+            (1, "expected key = value, found \"just a key\"".to_string())
+        );
+    }
+
+    #[test]
+    fn tables() {
+        let contents = "
+            verbose = true
+
+            [connection]
+            url = 'postgres://db'
+
+            [ mount ]
+            cache-size = 64
+
+            [gc]
+            cache-size = 128
+        ";
+        assert_eq!(
+            args(contents, "mount"),
+            vec!["--verbose", "--url=postgres://db", "--cache-size=64"]
+        );
+        assert_eq!(
+            args(contents, "gc"),
+            vec!["--verbose", "--url=postgres://db", "--cache-size=128"]
+        );
+        assert_eq!(
+            args(contents, "fsck"),
+            vec!["--verbose", "--url=postgres://db"]
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        let contents = "read-endpoint = [ 'a:1', \"b:2\", ]\nstaleness = []";
+        assert_eq!(
+            args(contents, "mount"),
+            vec!["--read-endpoint=a:1", "--read-endpoint=b:2"]
+        );
+        assert_eq!(
+            parse_error("a = ['x' 'y']"),
+            (1, "expected , or ] in array".to_string())
+        );
+        let config = Config::parse("a = [true]").unwrap();
+        let err = config.args("mount", |_| false).unwrap_err();
+        assert_eq!(err.to_string(), "a must be a string or integer");
+    }
+
+    #[test]
+    fn given_flags_take_precedence() {
+        let config = Config::parse("cache-size = 64\nallow-other = true").unwrap();
+        let args = config.args("mount", |key| key == "cache-size").unwrap();
+        assert_eq!(args, vec!["--allow-other"]);
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        assert_eq!(
+            parse_error("[mount]\na = 1\na = 2"),
+            (3, "a is set more than once".to_string())
+        );
+        assert_eq!(
+            parse_error("a = 1\n[mount]\na = 2"),
+            (3, "a is already set outside any table".to_string())
+        );
+        assert_eq!(
+            parse_error("[connection]\na = 1\n[mount]\na = 2"),
+            (4, "a is already set in [connection]".to_string())
+        );
+        assert_eq!(
+            parse_error("[mount]\na = 1\n[connection]\na = 2"),
+            (4, "a is already set in [mount]".to_string())
+        );
+        // Tables of different subcommands never apply together.
+        assert_eq!(args("[mount]\na = 1\n[gc]\na = 2", "gc"), vec!["--a=2"]);
+    }
+
+    #[test]
+    fn unknown_keys_are_left_to_the_argument_parser() {
+        assert_eq!(args("no-such-flag = 1", "mount"), vec!["--no-such-flag=1"]);
+        assert_eq!(
+            args("[no-such-command]\na = 1", "mount"),
+            Vec::<String>::new()
+        );
+    }
+}
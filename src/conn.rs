@@ -0,0 +1,483 @@
+//! Configuration of the connection to CockroachDB.
+
+use super::sql;
+use super::token::Token;
+use libc::{c_int, c_void, socklen_t, ECHO, STDIN_FILENO, TCSANOW};
+use log::warn;
+use openssl::ssl::{SslConnectorBuilder, SslMethod, SSL_VERIFY_NONE};
+use openssl::x509::X509_FILETYPE_PEM;
+use postgres::params::{ConnectParams, Host};
+use postgres::tls::openssl::OpenSsl;
+use postgres::tls::{Stream, TlsHandshake, TlsStream};
+use postgres::{Connection, TlsMode};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Application name reported to the server, visible in SHOW SESSIONS.
+const APPLICATION_NAME: &str = "cockroachfs";
+
+/// Idle time after which TCP keepalive probes are sent in cloud mode, chosen
+/// to stay well under the idle timeouts of common cloud load balancers.
+const CLOUD_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// How to negotiate TLS with the server, mirroring libpq's sslmode.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum SslMode {
+    Disable,
+    Allow,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl FromStr for SslMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SslMode, String> {
+        match s {
+            "disable" => Ok(SslMode::Disable),
+            "allow" => Ok(SslMode::Allow),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-ca" => Ok(SslMode::VerifyCa),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            _ => Err(format!("unknown sslmode {:?}", s)),
+        }
+    }
+}
+
+pub struct ConnOptions {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub database: String,
+    /// TLS mode, defaulting to verify-full if any certificates are configured
+    /// and to disable otherwise.
+    pub ssl_mode: Option<SslMode>,
+    /// Client certificate to authenticate with.
+    pub ssl_cert: Option<PathBuf>,
+    /// Private key of the client certificate.
+    pub ssl_key: Option<PathBuf>,
+    /// Certificate authority to verify the server against.
+    pub ssl_root_cert: Option<PathBuf>,
+    /// Password given in the connection URL.
+    pub password: Option<String>,
+    /// File whose first line is the password.
+    pub password_file: Option<PathBuf>,
+    /// Prompt for the password if no other source provides one.
+    pub prompt_password: bool,
+    /// Authenticate with a token, such as a JWT, instead of a password.
+    pub token: Option<Token>,
+    /// Routing id of a CockroachDB Cloud cluster. Setting this requires TLS
+    /// and enables TCP keepalives.
+    pub cloud_cluster: Option<String>,
+    /// Name of the filesystem within the database to use.
+    pub filesystem: String,
+}
+
+impl Default for ConnOptions {
+    fn default() -> ConnOptions {
+        ConnOptions {
+            host: "localhost".to_string(),
+            port: 26257,
+            user: "root".to_string(),
+            database: "cockroachfs".to_string(),
+            ssl_mode: None,
+            ssl_cert: None,
+            ssl_key: None,
+            ssl_root_cert: None,
+            password: None,
+            password_file: None,
+            prompt_password: false,
+            token: None,
+            cloud_cluster: None,
+            filesystem: sql::DEFAULT_FS.to_string(),
+        }
+    }
+}
+
+impl ConnOptions {
+    /// The default options, overridden by any of the libpq environment
+    /// variables PGHOST, PGPORT, PGUSER, PGDATABASE, PGSSLMODE, PGSSLCERT,
+    /// PGSSLKEY and PGSSLROOTCERT.
+    pub fn from_env() -> io::Result<ConnOptions> {
+        let mut opts = ConnOptions::default();
+        if let Ok(host) = env::var("PGHOST") {
+            opts.host = host;
+        }
+        if let Ok(port) = env::var("PGPORT") {
+            opts.port = port
+                .parse()
+                .map_err(|e| invalid(format!("invalid PGPORT: {}", e)))?;
+        }
+        if let Ok(user) = env::var("PGUSER") {
+            opts.user = user;
+        }
+        if let Ok(database) = env::var("PGDATABASE") {
+            opts.database = database;
+        }
+        if let Ok(ssl_mode) = env::var("PGSSLMODE") {
+            opts.ssl_mode = Some(ssl_mode.parse().map_err(invalid)?);
+        }
+        opts.ssl_cert = env::var_os("PGSSLCERT").map(PathBuf::from);
+        opts.ssl_key = env::var_os("PGSSLKEY").map(PathBuf::from);
+        opts.ssl_root_cert = env::var_os("PGSSLROOTCERT").map(PathBuf::from);
+        Ok(opts)
+    }
+
+    /// Override the options given in a connection URL of the form
+    /// postgres://[user[:password]@][host][:port][/database][?param=value&...],
+    /// where the parameters may be sslmode, sslcert, sslkey and sslrootcert.
+    pub fn apply_url(&mut self, url: &str) -> io::Result<()> {
+        let rest = url
+            .strip_prefix("postgres://")
+            .or_else(|| url.strip_prefix("postgresql://"))
+            .ok_or_else(|| invalid(format!("invalid URL {:?}: expected postgres://", url)))?;
+        let (rest, query) = match rest.find('?') {
+            Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+            None => (rest, None),
+        };
+        let (authority, database) = match rest.find('/') {
+            Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+            None => (rest, None),
+        };
+        let (userinfo, hostport) = match authority.rfind('@') {
+            Some(i) => (Some(&authority[..i]), &authority[i + 1..]),
+            None => (None, authority),
+        };
+        if let Some(userinfo) = userinfo {
+            let (user, password) = match userinfo.find(':') {
+                Some(i) => (&userinfo[..i], Some(&userinfo[i + 1..])),
+                None => (userinfo, None),
+            };
+            if !user.is_empty() {
+                self.user = percent_decode(user)?;
+            }
+            if let Some(password) = password {
+                self.password = Some(percent_decode(password)?);
+            }
+        }
+        // The port follows the last colon unless that colon is inside a
+        // bracketed IPv6 address.
+        let (host, port) = match hostport.rfind(':') {
+            Some(i) if !hostport[i..].contains(']') => (&hostport[..i], Some(&hostport[i + 1..])),
+            _ => (hostport, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if !host.is_empty() {
+            self.host = percent_decode(host)?;
+        }
+        if let Some(port) = port {
+            self.port = port
+                .parse()
+                .map_err(|e| invalid(format!("invalid port in URL {:?}: {}", port, e)))?;
+        }
+        if let Some(database) = database.filter(|d| !d.is_empty()) {
+            self.database = percent_decode(database)?;
+        }
+        for param in query.unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = match param.find('=') {
+                Some(i) => (&param[..i], percent_decode(&param[i + 1..])?),
+                None => (param, String::new()),
+            };
+            match name {
+                "sslmode" => self.ssl_mode = Some(value.parse().map_err(invalid)?),
+                "sslcert" => self.ssl_cert = Some(PathBuf::from(value)),
+                "sslkey" => self.ssl_key = Some(PathBuf::from(value)),
+                "sslrootcert" => self.ssl_root_cert = Some(PathBuf::from(value)),
+                _ => return Err(invalid(format!("unsupported URL parameter {:?}", name))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Use the certificates in a directory laid out the way CockroachDB
+    /// expects: ca.crt, client.<user>.crt and client.<user>.key.
+    pub fn use_certs_dir(&mut self, dir: &Path) {
+        self.ssl_root_cert = Some(dir.join("ca.crt"));
+        self.ssl_cert = Some(dir.join(format!("client.{}.crt", self.user)));
+        self.ssl_key = Some(dir.join(format!("client.{}.key", self.user)));
+    }
+
+    fn effective_ssl_mode(&self) -> io::Result<SslMode> {
+        let has_certs =
+            self.ssl_cert.is_some() || self.ssl_key.is_some() || self.ssl_root_cert.is_some();
+        match self.ssl_mode {
+            Some(mode) if self.cloud_cluster.is_some() && mode < SslMode::Require => Err(invalid(
+                format!("CockroachDB Cloud requires TLS, but sslmode is {:?}", mode),
+            )),
+            Some(mode) => Ok(mode),
+            None if has_certs || self.cloud_cluster.is_some() => Ok(SslMode::VerifyFull),
+            None => Ok(SslMode::Disable),
+        }
+    }
+
+    fn tls(&self, mode: SslMode) -> io::Result<Keepalive<OpenSsl>> {
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls()).map_err(io::Error::other)?;
+        if let Some(ref ca) = self.ssl_root_cert {
+            builder.set_ca_file(ca).map_err(io::Error::other)?;
+        }
+        if let Some(ref cert) = self.ssl_cert {
+            builder
+                .set_certificate_chain_file(cert)
+                .map_err(io::Error::other)?;
+        }
+        if let Some(ref key) = self.ssl_key {
+            builder
+                .set_private_key_file(key, X509_FILETYPE_PEM)
+                .map_err(io::Error::other)?;
+        }
+        if mode < SslMode::VerifyCa {
+            builder.set_verify(SSL_VERIFY_NONE);
+        }
+        let mut tls = OpenSsl::from(builder.build());
+        tls.danger_disable_hostname_verification(mode != SslMode::VerifyFull);
+        let idle = self.cloud_cluster.as_ref().map(|_| CLOUD_KEEPALIVE);
+        Ok(Keepalive { tls, idle })
+    }
+
+    /// Open a new connection. Tokens are refreshed as needed on each call, so
+    /// new connections keep working after earlier tokens have expired.
+    pub fn connect(&self) -> io::Result<Connection> {
+        self.connect_to(&self.host, self.port)
+    }
+
+    /// Open a new connection to a specific gateway.
+    pub fn connect_to(&self, host: &str, port: u16) -> io::Result<Connection> {
+        let mut builder = ConnectParams::builder();
+        builder
+            .database(&self.database)
+            .option("application_name", APPLICATION_NAME);
+        let mut options = Vec::new();
+        if let Some(ref cluster) = self.cloud_cluster {
+            options.push(format!("--cluster={}", cluster));
+        }
+        match self.token {
+            Some(ref token) => {
+                builder.user(&self.user, Some(&token.get()?));
+                options.push("--crdb:jwt_auth_enabled=true".to_string());
+            }
+            None => {
+                builder.user(&self.user, self.password()?.as_deref());
+            }
+        }
+        if !options.is_empty() {
+            builder.option("options", &options.join(" "));
+        }
+        builder.port(port);
+        let params = builder.build(Host::Tcp(host.to_string()));
+        let mode = self.effective_ssl_mode()?;
+        if mode == SslMode::Disable {
+            return Ok(Connection::connect(params, TlsMode::None)?);
+        }
+        let tls = self.tls(mode)?;
+        let tls_mode = match mode {
+            SslMode::Allow | SslMode::Prefer => TlsMode::Prefer(&tls),
+            _ => TlsMode::Require(&tls),
+        };
+        Ok(Connection::connect(params, tls_mode)?)
+    }
+
+    /// The password to authenticate with, taken from the password file, the
+    /// connection URL, PGPASSWORD, the pgpass file, or an interactive prompt,
+    /// in that order.
+    fn password(&self) -> io::Result<Option<String>> {
+        if let Some(ref path) = self.password_file {
+            if fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+                warn!(
+                    "password file {} is accessible by other users",
+                    path.display()
+                );
+            }
+            let contents = fs::read_to_string(path)?;
+            return Ok(Some(contents.lines().next().unwrap_or("").to_string()));
+        }
+        if let Some(ref password) = self.password {
+            return Ok(Some(password.clone()));
+        }
+        if let Ok(password) = env::var("PGPASSWORD") {
+            return Ok(Some(password));
+        }
+        if let Some(password) = self.pgpass_password()? {
+            return Ok(Some(password));
+        }
+        if self.prompt_password {
+            return prompt_password(&format!("Password for {}: ", self.user)).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Look up the password in PGPASSFILE or ~/.pgpass. As with libpq, the
+    /// file is ignored if it is accessible by other users.
+    fn pgpass_password(&self) -> io::Result<Option<String>> {
+        let path = match env::var_os("PGPASSFILE") {
+            Some(path) => PathBuf::from(path),
+            None => match env::var_os("HOME") {
+                Some(home) => Path::new(&home).join(".pgpass"),
+                None => return Ok(None),
+            },
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if fs::metadata(&path)?.permissions().mode() & 0o077 != 0 {
+            warn!(
+                "ignoring password file {} because it is accessible by other users",
+                path.display()
+            );
+            return Ok(None);
+        }
+        let port = self.port.to_string();
+        let want = [&self.host[..], &port, &self.database, &self.user];
+        for line in contents.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            let fields = split_pgpass_line(line);
+            if fields.len() != 5 {
+                continue;
+            }
+            let matches = fields[..4]
+                .iter()
+                .zip(want.iter())
+                .all(|(field, want)| field == "*" || field == want);
+            if matches {
+                return Ok(Some(fields[4].clone()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Wraps a TLS implementation to enable TCP keepalives on the underlying
+/// socket, so that idle connections are not silently dropped by middleboxes.
+#[derive(Debug)]
+pub struct Keepalive<T> {
+    tls: T,
+    /// Idle time before sending keepalive probes, or None to leave them off.
+    idle: Option<Duration>,
+}
+
+impl<T: TlsHandshake> TlsHandshake for Keepalive<T> {
+    fn tls_handshake(
+        &self,
+        host: &str,
+        stream: Stream,
+    ) -> Result<Box<dyn TlsStream>, Box<dyn Error + Sync + Send>> {
+        if let Some(idle) = self.idle {
+            set_keepalive(stream.as_raw_fd(), idle)?;
+        }
+        self.tls.tls_handshake(host, stream)
+    }
+}
+
+fn set_keepalive(fd: RawFd, idle: Duration) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    #[cfg(target_os = "linux")]
+    setsockopt(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPIDLE,
+        idle.as_secs() as c_int,
+    )?;
+    #[cfg(target_os = "macos")]
+    setsockopt(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPALIVE,
+        idle.as_secs() as c_int,
+    )?;
+    Ok(())
+}
+
+fn setsockopt(fd: RawFd, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const c_int as *const c_void,
+            mem::size_of::<c_int>() as socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Split a pgpass line into its colon-separated fields, unescaping backslashes.
+fn split_pgpass_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    fields.last_mut().unwrap().push(next);
+                }
+            }
+            ':' if fields.len() < 5 => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Decode the %XX escapes of a URL component.
+fn percent_decode(s: &str) -> io::Result<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = s
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| invalid(format!("invalid escape in URL component {:?}", s)))?;
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).map_err(|_| invalid(format!("URL component {:?} is not UTF-8", s)))
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+/// Read a line from stdin without echoing it.
+fn prompt_password(prompt: &str) -> io::Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+
+    let mut term: libc::termios = unsafe { mem::zeroed() };
+    let is_tty = unsafe { libc::tcgetattr(STDIN_FILENO, &mut term) } == 0;
+    if is_tty {
+        let mut silent = term;
+        silent.c_lflag &= !ECHO;
+        unsafe { libc::tcsetattr(STDIN_FILENO, TCSANOW, &silent) };
+    }
+    let mut line = String::new();
+    let res = io::stdin().lock().read_line(&mut line);
+    if is_tty {
+        unsafe { libc::tcsetattr(STDIN_FILENO, TCSANOW, &term) };
+        eprintln!();
+    }
+    res?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
@@ -0,0 +1,204 @@
+//! A Unix socket through which a running mount is inspected and adjusted
+//! without restarting it, and the client that the `ctl` subcommand, or the
+//! binary run as `crfsctl`, uses to talk to it.
+//!
+//! A client sends one command per connection, as a line of words, and reads
+//! the reply until the socket is closed. The first line of a reply is `ok`,
+//! or `error` followed by what went wrong, and the rest is the command's
+//! output. The commands are:
+//!
+//!     status              the mount, its caches, log levels and metrics
+//!     log-level LEVELS    log at LEVELS, given as --log-level takes them
+//!     drop-caches         empty the in-process caches
+//!     fsck [quick|full]   check the filesystem for inconsistencies
+//!     gc                  delete inodes and blocks that are unreachable
+//!
+//! Only the in-process caches are dropped; the kernel keeps its own until
+//! their TTLs expire. Repairs are left to `fsck --repair` on an unmounted
+//! filesystem, since the mount would go on serving what it cached before.
+
+use super::cache::Cache;
+use super::check;
+use super::gc;
+use super::logging;
+use super::metrics::Metrics;
+use super::pool::Connector;
+use log::{info, warn};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest command accepted, in bytes.
+const MAX_COMMAND: u64 = 4096;
+
+/// What a mount shares with its control socket.
+pub struct Control {
+    pub mountpoint: PathBuf,
+    pub cache: Arc<Mutex<Cache>>,
+    pub metrics: Metrics,
+    /// Opens the connections that checks and collections run on, so as not
+    /// to hold up the mount's own.
+    pub connector: Connector,
+}
+
+/// Listen on a control socket at path, serving its commands from a
+/// background thread. The socket is only accessible to the mounting user.
+pub fn serve(path: &Path, control: Control) -> io::Result<()> {
+    if path.exists() {
+        // A socket left behind by a mount that did not exit cleanly is
+        // replaced, but not one that is still being served.
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already serving a mount", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!("serving control commands on {}", path.display());
+    let control = Arc::new(control);
+    let started = Instant::now();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("control: accept {}", err);
+                    continue;
+                }
+            };
+            let control = control.clone();
+            thread::spawn(move || {
+                if let Err(err) = serve_connection(&control, started, stream) {
+                    warn!("control: {}", err);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn serve_connection(control: &Control, started: Instant, mut stream: UnixStream) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new((&stream).take(MAX_COMMAND)).read_line(&mut line)?;
+    let words: Vec<&str> = line.split_whitespace().collect();
+    info!("control: {}", line.trim());
+    let mut out = String::new();
+    let status = match run(control, started, &words, &mut out) {
+        Ok(()) => "ok".to_string(),
+        Err(err) => format!("error {}", err.to_string().replace('\n', " ")),
+    };
+    stream.write_all(format!("{}\n{}", status, out).as_bytes())
+}
+
+/// Run the command given by words, writing its output to out.
+fn run(control: &Control, started: Instant, words: &[&str], out: &mut String) -> io::Result<()> {
+    match words {
+        ["status"] => {
+            let _ = writeln!(out, "mountpoint  {}", control.mountpoint.display());
+            let _ = writeln!(out, "pid         {}", process::id());
+            let _ = writeln!(
+                out,
+                "uptime      {:?}",
+                Duration::from_secs(started.elapsed().as_secs())
+            );
+            let _ = writeln!(out, "log levels  {}", logging::levels());
+            let cache = control.cache.lock().unwrap();
+            let _ = writeln!(
+                out,
+                "cache       {} of {} bytes used",
+                cache.used(),
+                cache.budget()
+            );
+            for (kind, stats) in cache.stats() {
+                let _ = writeln!(
+                    out,
+                    "  {:<9} {} entries, {} bytes, {} evictions",
+                    kind, stats.entries, stats.bytes, stats.evictions
+                );
+            }
+            drop(cache);
+            let _ = writeln!(out, "{}", control.metrics.summary());
+        }
+        ["log-level", levels] => {
+            logging::set_levels(levels)?;
+            info!("logging at {}", levels);
+        }
+        ["drop-caches"] => {
+            let mut cache = control.cache.lock().unwrap();
+            let used = cache.used();
+            cache.remove_matching(|_, _| true);
+            let _ = writeln!(out, "dropped {} bytes", used);
+        }
+        ["fsck"] | ["fsck", _] => {
+            let level = match words.get(1) {
+                Some(level) => level.parse().map_err(invalid)?,
+                None => check::Level::Quick,
+            };
+            let conn = (control.connector)()?;
+            let problems = check::run(&conn, level)?;
+            for problem in &problems {
+                let _ = writeln!(out, "{}", problem);
+            }
+            if !problems.is_empty() {
+                return Err(io::Error::other(format!(
+                    "found {} problems",
+                    problems.len()
+                )));
+            }
+            let _ = writeln!(out, "no problems found");
+        }
+        ["gc"] => {
+            let conn = (control.connector)()?;
+            let (inodes, blocks) = gc::collect(&conn, gc::BATCH, Duration::from_secs(0))?;
+            let _ = writeln!(
+                out,
+                "deleted {} orphaned inodes and {} blocks past the end of their file",
+                inodes, blocks
+            );
+        }
+        _ => return Err(invalid(format!("unknown command {:?}", words.join(" ")))),
+    }
+    Ok(())
+}
+
+/// Send the command given by words to the control socket at path, writing
+/// its output to out, and fail if the command did.
+pub fn request(path: &Path, words: &[&str], out: &mut dyn Write) -> io::Result<()> {
+    let mut stream = UnixStream::connect(path).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("cannot connect to {}: {}", path.display(), err),
+        )
+    })?;
+    stream.write_all(format!("{}\n", words.join(" ")).as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    let (status, output) = reply.split_at(reply.find('\n').map_or(reply.len(), |i| i + 1));
+    out.write_all(output.as_bytes())?;
+    match status.trim_end() {
+        "ok" => Ok(()),
+        status => match status.strip_prefix("error ") {
+            Some(err) => Err(io::Error::other(err.to_string())),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed reply from the control socket",
+            )),
+        },
+    }
+}
+
+fn invalid<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
@@ -0,0 +1,148 @@
+//! The errors of filesystem operations, told apart by what went wrong rather
+//! than by how the database reported it, and the errnos they are reported as.
+
+use super::sql;
+use libc::{
+    c_int, EAGAIN, ECONNREFUSED, EDQUOT, EEXIST, EINVAL, EIO, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY,
+    EPERM, EROFS, ESTALE, EXDEV,
+};
+use postgres::error;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum CrfsError {
+    /// The entry operated on does not exist.
+    NotFound,
+    /// The inode operated on, which the caller already knew of, no longer
+    /// exists.
+    Stale,
+    /// The entry being created already exists.
+    Exists,
+    /// A file was to be removed or to replace an entry, but the entry is a
+    /// directory.
+    IsDir,
+    /// A directory was to be removed or to replace an entry, but the entry
+    /// is not one.
+    NotDir,
+    /// The directory being removed or replaced has entries.
+    NotEmpty,
+    /// The inode is not of a kind the operation applies to, as when linking
+    /// anything but a regular file.
+    NotPermitted,
+    /// A directory was to move out from under the quota it is charged to.
+    CrossQuota,
+    /// A directory was to move beneath itself.
+    IntoItself,
+    /// The filesystem only serves reads for now, as while serving offline.
+    ReadOnly,
+    /// A quota does not allow what is being stored.
+    QuotaExceeded(postgres::Error),
+    /// The transaction kept conflicting with others until it gave up.
+    Retryable(postgres::Error),
+    /// The connection to the database broke and could not be replaced in
+    /// time.
+    Unavailable(postgres::Error),
+    /// Any other failure of the database, or of the connection to it.
+    Backend(postgres::Error),
+}
+
+impl CrfsError {
+    /// The errno the error is reported as, by every frontend.
+    pub fn errno(&self) -> c_int {
+        match *self {
+            CrfsError::NotFound => ENOENT,
+            CrfsError::Stale => ESTALE,
+            CrfsError::Exists => EEXIST,
+            CrfsError::IsDir => EISDIR,
+            CrfsError::NotDir => ENOTDIR,
+            CrfsError::NotEmpty => ENOTEMPTY,
+            CrfsError::NotPermitted => EPERM,
+            CrfsError::CrossQuota => EXDEV,
+            CrfsError::IntoItself => EINVAL,
+            CrfsError::ReadOnly => EROFS,
+            CrfsError::QuotaExceeded(_) => EDQUOT,
+            CrfsError::Retryable(_) => EAGAIN,
+            CrfsError::Unavailable(_) => EIO,
+            CrfsError::Backend(_) => ECONNREFUSED,
+        }
+    }
+
+    /// The database error behind the error, if there is one.
+    pub fn backend(&self) -> Option<&postgres::Error> {
+        match *self {
+            CrfsError::Retryable(ref err)
+            | CrfsError::Unavailable(ref err)
+            | CrfsError::Backend(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<postgres::Error> for CrfsError {
+    fn from(err: postgres::Error) -> CrfsError {
+        if err.code() == Some(&error::UNIQUE_VIOLATION) {
+            CrfsError::Exists
+        } else if sql::quota_exceeded(&err) {
+            CrfsError::QuotaExceeded(err)
+        } else if sql::retryable(&err) {
+            CrfsError::Retryable(err)
+        } else {
+            CrfsError::Backend(err)
+        }
+    }
+}
+
+impl From<CrfsError> for io::Error {
+    fn from(err: CrfsError) -> io::Error {
+        let kind = match err {
+            CrfsError::NotFound | CrfsError::Stale => io::ErrorKind::NotFound,
+            CrfsError::Exists => io::ErrorKind::AlreadyExists,
+            CrfsError::NotPermitted | CrfsError::ReadOnly => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err.to_string())
+    }
+}
+
+/// For code that still deals in database errors, the error the database
+/// reported, if there was one.
+impl From<CrfsError> for postgres::Error {
+    fn from(err: CrfsError) -> postgres::Error {
+        match err {
+            CrfsError::QuotaExceeded(err)
+            | CrfsError::Retryable(err)
+            | CrfsError::Unavailable(err)
+            | CrfsError::Backend(err) => err,
+            err => io::Error::from(err).into(),
+        }
+    }
+}
+
+impl fmt::Display for CrfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CrfsError::NotFound => f.write_str("no such file or directory"),
+            CrfsError::Stale => f.write_str("stale file handle"),
+            CrfsError::Exists => f.write_str("file exists"),
+            CrfsError::IsDir => f.write_str("is a directory"),
+            CrfsError::NotDir => f.write_str("not a directory"),
+            CrfsError::NotEmpty => f.write_str("directory not empty"),
+            CrfsError::NotPermitted => f.write_str("operation not permitted"),
+            CrfsError::CrossQuota => f.write_str("directory would leave its quota"),
+            CrfsError::IntoItself => f.write_str("directory would move beneath itself"),
+            CrfsError::ReadOnly => f.write_str("read-only file system"),
+            CrfsError::QuotaExceeded(_) => f.write_str("quota exceeded"),
+            CrfsError::Retryable(ref err) => write!(f, "gave up retrying: {}", err),
+            CrfsError::Unavailable(ref err) => write!(f, "database unavailable: {}", err),
+            CrfsError::Backend(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for CrfsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.backend().map(|err| err as &(dyn Error + 'static))
+    }
+}
@@ -0,0 +1,138 @@
+//! Export of a subtree of the filesystem to a tar archive, read directly from
+//! the database rather than through a mount.
+
+use super::sql;
+use super::tar::{self, Header, Kind};
+use fuse::{FileAttr, FileType};
+use log::warn;
+use postgres::GenericConnection;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Directory entries read per query.
+const DIR_BATCH: i64 = 1000;
+
+/// Blocks of file data read per query.
+const READ_BLOCKS: i64 = 256;
+
+/// What an export wrote.
+#[derive(Default)]
+pub struct Summary {
+    pub entries: u64,
+    pub bytes: u64,
+    /// Sockets, which tar cannot hold.
+    pub skipped: u64,
+}
+
+/// Write the subtree rooted at root to out as a tar archive whose entries are
+/// named under name. Files linked more than once in the subtree are archived
+/// once and then as hard links to the first path they were archived under.
+pub fn export<C: GenericConnection, W: Write>(
+    conn: &C,
+    root: &FileAttr,
+    name: &[u8],
+    out: W,
+) -> io::Result<(Summary, W)> {
+    let mut tar = tar::Writer::new(out);
+    let mut summary = Summary::default();
+    let mut linked: HashMap<u64, Vec<u8>> = HashMap::new();
+    let mut pending = vec![(*root, name.to_vec())];
+    while let Some((attr, path)) = pending.pop() {
+        if attr.kind == FileType::Directory {
+            // Children are pushed in reverse so that they are archived in
+            // order of name.
+            let mut children = Vec::new();
+            let mut after = Vec::new();
+            loop {
+                let ents = sql::read_dir_plus(conn, attr.ino, &after, DIR_BATCH)?;
+                for (ent, child) in &ents {
+                    let mut child_path = path.clone();
+                    child_path.push(b'/');
+                    child_path.extend_from_slice(&ent.child_name);
+                    children.push((*child, child_path));
+                }
+                match ents.last() {
+                    Some((last, _)) if ents.len() as i64 == DIR_BATCH => {
+                        after = last.child_name.clone()
+                    }
+                    _ => break,
+                }
+            }
+            pending.extend(children.into_iter().rev());
+        }
+        if let Some(first) = linked.get(&attr.ino) {
+            tar.start_entry(&header(&attr, &path, Kind::HardLink, first))?;
+            tar.end_entry()?;
+            summary.entries += 1;
+            continue;
+        }
+        let target = match attr.kind {
+            FileType::Symlink => sql::read_symlink(conn, attr.ino)?.unwrap_or_default(),
+            _ => String::new(),
+        };
+        let kind = match attr.kind {
+            FileType::RegularFile => Kind::File,
+            FileType::Symlink => Kind::Symlink,
+            FileType::CharDevice => Kind::CharDevice,
+            FileType::BlockDevice => Kind::BlockDevice,
+            FileType::Directory => Kind::Directory,
+            FileType::NamedPipe => Kind::Fifo,
+            FileType::Socket => {
+                warn!("export: skipping socket {}", String::from_utf8_lossy(&path));
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        let mut entry_path = path.clone();
+        if kind == Kind::Directory {
+            entry_path.push(b'/');
+        }
+        tar.start_entry(&header(&attr, &entry_path, kind, target.as_bytes()))?;
+        if kind == Kind::File {
+            summary.bytes += copy_data(conn, &attr, &mut tar)?;
+        }
+        tar.end_entry()?;
+        summary.entries += 1;
+        if attr.kind != FileType::Directory && attr.nlink > 1 {
+            linked.insert(attr.ino, path);
+        }
+    }
+    Ok((summary, tar.finish()?))
+}
+
+fn header<'a>(attr: &FileAttr, path: &'a [u8], kind: Kind, link: &'a [u8]) -> Header<'a> {
+    Header {
+        path,
+        kind,
+        mode: u32::from(attr.perm),
+        uid: attr.uid,
+        gid: attr.gid,
+        size: attr.size,
+        mtime: attr.mtime.sec,
+        link,
+        rdev: attr.rdev,
+    }
+}
+
+/// Copy the data of the file attr describes into the current entry of tar.
+/// A file that shrinks meanwhile is padded with zeroes by end_entry, and one
+/// that grows is cut off at the size it had when its entry was started.
+fn copy_data<C: GenericConnection, W: Write>(
+    conn: &C,
+    attr: &FileAttr,
+    tar: &mut tar::Writer<W>,
+) -> io::Result<u64> {
+    let chunk = (sql::block_size() * READ_BLOCKS) as usize;
+    let mut offset = 0;
+    while offset < attr.size {
+        let data = match sql::read_data(conn, attr.ino, offset as i64, chunk)? {
+            Some(data) => data,
+            None => break,
+        };
+        offset += tar.write_data(&data)? as u64;
+        if data.len() < chunk {
+            break;
+        }
+    }
+    Ok(offset)
+}
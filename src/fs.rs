@@ -1,80 +1,1605 @@
-use super::sql;
+use super::cache::{Cache, Key, Value};
+use super::error::CrfsError;
+use super::idmap::IdMap;
+use super::journal::{Entry, Journal};
+use super::logging;
+use super::metrics::Metrics;
+use super::migrate;
+use super::pool::{self, Pool};
+use super::route::Router;
+use super::sql::{self, DirEntry, ReadClass};
+use super::store::Store;
+use fuse::consts::FOPEN_DIRECT_IO;
 use fuse::{
-    FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyWrite,
-    Request,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, FUSE_ROOT_ID,
 };
-use libc::{c_int, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK};
-use libc::{ECONNREFUSED, EEXIST, ENOENT, ENOTDIR};
+use libc::{
+    c_int, O_ACCMODE, O_APPEND, O_EXCL, O_RDONLY, O_TRUNC, O_WRONLY, S_IFBLK, S_IFCHR, S_IFDIR,
+    S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK,
+};
+use libc::{
+    EACCES, EAGAIN, EBADF, EDEADLK, EILSEQ, EINTR, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT,
+    ENOTDIR, EPERM, ERANGE, EROFS,
+};
+use libc::{F_UNLCK, R_OK, S_ISGID, S_ISUID, W_OK, XATTR_CREATE, XATTR_REPLACE, X_OK};
+use log::{debug, error, info, warn};
 use postgres::error;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 use time::Timespec;
 
 /// Cache timeout for name and attribute replies.
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
 
-pub struct CockroachFS {
-    /// Database connection
-    conn: postgres::Connection,
+/// Cache timeout for replies served from the local cache while the database
+/// is unreachable, which may be stale.
+const STALE_TTL: Timespec = Timespec { sec: 0, nsec: 0 };
+
+/// Permission bits of a mode, including the setid and sticky bits.
+const PERM_BITS: u16 = 0o7777;
+
+/// Permission bits hidden from callers when mounted with nosuid.
+const SETID_BITS: u16 = (S_ISUID | S_ISGID) as u16;
+
+/// Open flag set by the kernel when a file is opened for execution (Linux only).
+#[cfg(target_os = "linux")]
+const FMODE_EXEC: u32 = 0x20;
+
+/// Error for a missing extended attribute, which Linux calls ENODATA.
+#[cfg(target_os = "linux")]
+const ENOATTR: c_int = libc::ENODATA;
+#[cfg(not(target_os = "linux"))]
+const ENOATTR: c_int = libc::ENOATTR;
+
+/// Longest symlink target accepted, in bytes, including the terminating
+/// null byte of the path it is read into.
+const PATH_MAX: usize = libc::PATH_MAX as usize;
+
+/// Free blocks and inodes reported when the cluster's capacity is unknown.
+const NOMINAL_FREE: u64 = 1 << 40;
+
+/// How long a blocking lock request waits for a conflicting lock to be
+/// released, and how often it checks.
+const LOCK_WAIT: Duration = Duration::from_secs(30);
+const LOCK_POLL: Duration = Duration::from_millis(100);
+
+/// How long byte-range locks outlive their mount. Locks are renewed a few
+/// times per lease while the mount runs.
+const LOCK_LEASE: Duration = Duration::from_secs(60);
+
+/// Directory entries fetched per readdir call. The kernel's buffer rarely
+/// holds more.
+const READDIR_BATCH: i64 = 128;
+
+/// Bytes of writes buffered per open file in write-back mode before they are
+/// flushed regardless.
+const WRITE_BACK_LIMIT: usize = 4 << 20;
+
+/// Minimum time between attempts to reconnect while writes are journaled.
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Pause before the first attempt to replace a broken connection, and the
+/// longest pause between attempts.
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Reads whose atime is held back before it is written, and the longest it
+/// is held back for.
+const ATIME_BATCH: usize = 256;
+const ATIME_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Name of the read-only directory of virtual files at the root. It is not
+/// listed, but can be looked up, as in `cat .crfs/stats`.
+const CTL_DIR: &str = ".crfs";
+
+/// Inode number of the control directory, which its files follow, at the top
+/// of the range so as never to meet the inodes the database allocates.
+const CTL_DIR_INO: u64 = u64::MAX - 0xff;
+
+/// Files of the control directory, generated by the mount when opened.
+#[derive(Clone, Copy)]
+enum CtlFile {
+    Stats,
+    Connections,
+    Cache,
+    Version,
+}
+
+const CTL_FILES: &[CtlFile] = &[
+    CtlFile::Stats,
+    CtlFile::Connections,
+    CtlFile::Cache,
+    CtlFile::Version,
+];
+
+impl CtlFile {
+    fn name(self) -> &'static str {
+        match self {
+            CtlFile::Stats => "stats",
+            CtlFile::Connections => "connections",
+            CtlFile::Cache => "cache",
+            CtlFile::Version => "version",
+        }
+    }
+
+    fn ino(self) -> u64 {
+        CTL_DIR_INO + 1 + self as u64
+    }
+}
+
+/// The control file with inode number ino, if it is one.
+fn ctl_file(ino: u64) -> Option<CtlFile> {
+    CTL_FILES.iter().copied().find(|file| file.ino() == ino)
+}
+
+/// Whether ino is the control directory or one of its files.
+fn is_ctl(ino: u64) -> bool {
+    ino >= CTL_DIR_INO
+}
+
+/// Opens a new connection to the database.
+pub type Connector<S = postgres::Connection> = Box<dyn Fn() -> io::Result<S>>;
+
+/// Journaling of writes while the database is unreachable.
+struct Offline<S> {
+    journal: Journal,
+    connector: Connector<S>,
+    /// Version of each inode as of the last write made through this mount.
+    versions: HashMap<u64, String>,
+    /// When reconnecting was last attempted.
+    last_attempt: Option<Instant>,
+}
+
+/// An open file.
+struct Handle {
+    /// Flags the file was opened with.
+    flags: u32,
+    /// Attributes of the file as of when it was opened, updated by writes
+    /// through this handle.
+    attr: FileAttr,
+    /// An error from a write through this handle that was reported as
+    /// successful at the time, to be reported when the file is closed.
+    error: Option<c_int>,
+    /// Writes buffered in write-back mode, as runs of contiguous data by
+    /// offset, in the order they were made.
+    pending: Vec<(i64, Vec<u8>)>,
+    /// Offset following the last read through this handle.
+    next_read: i64,
+    /// Offset up to which data has been read ahead of sequential reads.
+    read_ahead: i64,
+    /// Whether writes through this handle are buffered in write-back mode,
+    /// which they only are while this mount holds the file's write lease.
+    buffered: bool,
+    /// The contents of a control file, generated when it was opened.
+    contents: Option<Vec<u8>>,
+}
+
+/// Open files, by file handle, shared with operations running on the pool.
+type Handles = Arc<Mutex<HashMap<u64, Handle>>>;
+
+/// Inodes and lock owners that may hold byte-range locks through the mount.
+type LockOwners = Arc<Mutex<HashSet<(u64, u64)>>>;
+
+impl Handle {
+    fn readable(&self) -> bool {
+        self.flags & O_ACCMODE as u32 != O_WRONLY as u32
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & O_ACCMODE as u32 != O_RDONLY as u32
+    }
+}
+
+/// Which callers have their identity replaced by the anonymous user.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Squash {
+    /// Callers keep their own identity.
+    #[default]
+    None,
+    /// Root is treated as the anonymous user.
+    Root,
+    /// Every caller is treated as the anonymous user.
+    All,
+}
+
+/// When reading a file or listing a directory updates its atime.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Atime {
+    /// Never.
+    #[default]
+    Never,
+    /// Only when the atime is no newer than the mtime or ctime, or is more
+    /// than a day old.
+    Relative,
+    /// On every read.
+    Strict,
+}
+
+impl Atime {
+    /// Whether a read at time moves the atime of attr.
+    fn updates(self, attr: &FileAttr, time: Timespec) -> bool {
+        match self {
+            Atime::Never => false,
+            _ if attr.atime >= time => false,
+            Atime::Strict => true,
+            Atime::Relative => {
+                attr.atime <= attr.mtime
+                    || attr.atime <= attr.ctime
+                    || attr.atime.sec < time.sec - 24 * 60 * 60
+            }
+        }
+    }
+}
+
+/// What a mount does when it opens a file for writing in write-back mode
+/// while another mount holds the file's write lease.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LeaseConflict {
+    /// Write through to the database instead of buffering.
+    #[default]
+    WriteThrough,
+    /// Wait up to the length of a lease for it to be released or to expire,
+    /// then write through.
+    Wait,
+    /// Take the lease over. Writes the other mount has buffered may then
+    /// overwrite this mount's.
+    Break,
+}
+
+/// Options controlling how the filesystem presents itself once mounted.
+#[derive(Debug, Default)]
+pub struct MountOptions {
+    /// Ignore setuid and setgid bits on all files.
+    pub nosuid: bool,
+    /// When reads update atimes.
+    pub atime: Atime,
+    /// Refuse to execute any file.
+    pub noexec: bool,
+    /// Allow users other than the mounting user to access the filesystem.
+    pub allow_other: bool,
+    /// Allow root, in addition to the mounting user, to access the filesystem.
+    pub allow_root: bool,
+    /// Unmount the filesystem automatically when the process exits.
+    pub auto_unmount: bool,
+    /// Mapping between stored and local user ids.
+    pub uid_map: IdMap,
+    /// Mapping between stored and local group ids.
+    pub gid_map: IdMap,
+    /// Which callers are squashed to the anonymous user.
+    pub squash: Squash,
+    /// User id given to squashed callers.
+    pub anon_uid: u32,
+    /// Group id given to squashed callers.
+    pub anon_gid: u32,
+    /// Present all files as owned by this user.
+    pub uid: Option<u32>,
+    /// Present all files as owned by this group.
+    pub gid: Option<u32>,
+    /// Present all files with these permission bits cleared.
+    pub umask: Option<u16>,
+    /// Longest file name accepted, in bytes.
+    pub name_max: u32,
+    /// Memory budget, in bytes, shared by all in-process caches.
+    pub cache_size: usize,
+    /// How often to log a summary of metrics, if at all.
+    pub stats_interval: Option<Duration>,
+    /// How long attributes read from the database are served from the
+    /// in-process cache, if at all.
+    pub attr_ttl: Option<Duration>,
+    /// How long file data read from the database is served from the
+    /// in-process cache, if at all.
+    pub block_ttl: Option<Duration>,
+    /// Buffer writes through open files until they are flushed, synced or
+    /// closed.
+    pub write_back: bool,
+    /// How long a write lease lasts unless renewed.
+    pub write_lease: Duration,
+    /// What to do when another mount holds a write lease.
+    pub lease_conflict: LeaseConflict,
+    /// Number of blocks to read ahead of sequential reads, into the block
+    /// cache.
+    pub readahead: u32,
+    /// Serve reads from the local cache, and refuse writes, while the
+    /// database is unreachable.
+    pub offline_reads: bool,
+    /// How long to keep trying to replace a broken connection before
+    /// failing the operation that found it broken, if at all.
+    pub reconnect_timeout: Option<Duration>,
 }
 
-impl CockroachFS {
-    pub fn new(conn: postgres::Connection) -> CockroachFS {
-        CockroachFS { conn: conn }
+impl MountOptions {
+    /// Whether the kernel checks permission bits itself. It does whenever
+    /// other users may access the mount, so that exposing the mount to them
+    /// does not also hand them write access to everything, unless callers are
+    /// squashed: the kernel would check their real identity, so the checks
+    /// are left to the filesystem.
+    pub fn default_permissions(&self) -> bool {
+        (self.allow_other || self.allow_root) && self.squash == Squash::None
+    }
+
+    /// Kernel mount options corresponding to these options.
+    pub fn kernel_options(&self) -> Vec<&'static str> {
+        let mut opts = Vec::new();
+        if self.nosuid {
+            opts.push("nosuid");
+        }
+        if self.noexec {
+            opts.push("noexec");
+        }
+        if self.allow_other {
+            opts.push("allow_other");
+        }
+        if self.allow_root {
+            opts.push("allow_root");
+        }
+        if self.default_permissions() {
+            opts.push("default_permissions");
+        }
+        if self.auto_unmount && cfg!(target_os = "linux") {
+            // Elsewhere this is emulated by the unmount module.
+            opts.push("auto_unmount");
+        }
+        opts
+    }
+}
+
+pub struct CockroachFS<S = postgres::Connection> {
+    /// Connection to the store
+    conn: S,
+    /// Mount options
+    opts: MountOptions,
+    /// In-process caches, shared with the control socket
+    cache: Arc<Mutex<Cache>>,
+    /// Operation metrics
+    metrics: Metrics,
+    /// Router for read-only statements, if reads may use other gateways
+    router: Option<Router<S>>,
+    /// Write journal, if writes should be journaled while disconnected
+    offline: Option<Offline<S>>,
+    /// Connections serving operations concurrently, if any
+    pool: Option<Pool<S>>,
+    /// Open files, by file handle
+    handles: Handles,
+    /// File handle to assign to the next opened file
+    next_fh: u64,
+    /// Identifies this mount's lock owners to other mounts
+    session: String,
+    /// Lock owners that may hold locks, so that closing a file only
+    /// releases locks when some may be held
+    lock_owners: LockOwners,
+    /// Opens the connection locks are renewed on
+    lock_renewal: Option<pool::Connector<S>>,
+    /// Stops lock renewal when dropped
+    renewing: Option<Sender<()>>,
+    /// Set once shutdown has run
+    shut_down: bool,
+    /// Opens a connection to replace the primary one if it breaks
+    connector: Option<Connector<S>>,
+    /// Blocks read ahead on the pool, to be added to the block cache
+    prefetched: Receiver<Prefetch>,
+    prefetcher: Sender<Prefetch>,
+    /// Incremented whenever cached blocks are invalidated, so that blocks
+    /// read ahead before then are not cached
+    block_epoch: u64,
+    /// Reads whose atime has not yet been written, by inode
+    atimes: HashMap<u64, Timespec>,
+    /// When pending atimes were last written
+    atimes_flushed: Instant,
+    /// Write leases held, by inode, with when each was last taken or renewed
+    leases: HashMap<u64, Instant>,
+    /// When the filesystem was mounted
+    mounted: Timespec,
+    /// The directory presented as the root of the mount, which the kernel
+    /// knows as FUSE_ROOT_ID
+    root: u64,
+}
+
+/// Data read ahead of a sequential reader.
+struct Prefetch {
+    ino: u64,
+    offset: i64,
+    size: usize,
+    data: Vec<u8>,
+    /// The block epoch when the data was read.
+    epoch: u64,
+}
+
+impl<S: Store> CockroachFS<S> {
+    pub fn new(conn: S, opts: MountOptions) -> CockroachFS<S> {
+        let metrics = Metrics::new();
+        let cache = Arc::new(Mutex::new(Cache::new(opts.cache_size, metrics.clone())));
+        let (prefetcher, prefetched) = mpsc::channel();
+        CockroachFS {
+            conn,
+            opts,
+            cache,
+            metrics,
+            router: None,
+            offline: None,
+            pool: None,
+            handles: Arc::new(Mutex::new(HashMap::new())),
+            next_fh: 1,
+            session: String::new(),
+            lock_owners: Arc::new(Mutex::new(HashSet::new())),
+            lock_renewal: None,
+            renewing: None,
+            shut_down: false,
+            connector: None,
+            prefetched,
+            prefetcher,
+            block_epoch: 0,
+            atimes: HashMap::new(),
+            atimes_flushed: Instant::now(),
+            leases: HashMap::new(),
+            mounted: time::get_time(),
+            root: sql::root(),
+        }
+    }
+
+    /// Journal writes that fail because the database is unreachable, using
+    /// connector to reconnect and replay them.
+    pub fn with_journal(mut self, journal: Journal, connector: Connector<S>) -> CockroachFS<S> {
+        self.offline = Some(Offline {
+            journal,
+            connector,
+            versions: HashMap::new(),
+            last_attempt: None,
+        });
+        self
+    }
+
+    /// Replay journaled writes if there are any and the database is reachable
+    /// again. Returns whether the journal is now empty.
+    fn replay_journal(&mut self) -> bool {
+        let offline = match self.offline {
+            Some(ref mut offline) => offline,
+            None => return true,
+        };
+        if offline.journal.is_empty() {
+            return true;
+        }
+        if let Some(last) = offline.last_attempt {
+            if last.elapsed() < RECONNECT_BACKOFF {
+                return false;
+            }
+        }
+        offline.last_attempt = Some(Instant::now());
+        if self.conn.broken() || self.conn.inode_version(0).is_err() {
+            match (offline.connector)() {
+                Ok(conn) => self.conn = conn,
+                Err(_) => return false,
+            }
+        }
+
+        let mut replayed = 0;
+        for entry in offline.journal.entries() {
+            let current = match self.conn.inode_version(entry.ino) {
+                Ok(current) => current,
+                Err(_) => break,
+            };
+            let conflict = match (&entry.version, &current) {
+                (_, None) => true,
+                (Some(based_on), Some(current)) => based_on != current,
+                (None, Some(_)) => false,
+            };
+            if conflict {
+                warn!(
+                    "journal: write of {} bytes to inode {} at {} conflicts, set aside",
+                    entry.data.len(),
+                    entry.ino,
+                    entry.offset
+                );
+                if let Err(err) = offline.journal.record_conflict(entry) {
+                    error!("journal: {}", err);
+                    break;
+                }
+                for handle in self.handles.lock().unwrap().values_mut() {
+                    if handle.attr.ino == entry.ino {
+                        handle.error = Some(EIO);
+                    }
+                }
+            } else {
+                let offset = Some(entry.offset);
+                if self
+                    .conn
+                    .write_data(entry.ino, offset, &entry.data)
+                    .is_err()
+                {
+                    break;
+                }
+                if let Ok(Some(version)) = self.conn.inode_version(entry.ino) {
+                    offline.versions.insert(entry.ino, version);
+                }
+            }
+            replayed += 1;
+        }
+        if replayed > 0 {
+            info!("journal: replayed {} writes", replayed);
+            if let Err(err) = offline.journal.truncate_front(replayed) {
+                error!("journal: {}", err);
+            }
+        }
+        offline.journal.is_empty()
+    }
+
+    /// Durably journal a write to be replayed later, returning whether it
+    /// was journaled.
+    fn journal_write(&mut self, ino: u64, offset: i64, data: &[u8]) -> bool {
+        let offline = match self.offline {
+            Some(ref mut offline) => offline,
+            None => return false,
+        };
+        let entry = Entry {
+            ino,
+            offset,
+            version: offline.versions.get(&ino).cloned(),
+            data: data.to_vec(),
+        };
+        match offline.journal.append(entry) {
+            Ok(()) => true,
+            Err(err) => {
+                error!("journal: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Replace the primary connection using connector if it breaks.
+    pub fn with_connector(mut self, connector: Connector<S>) -> CockroachFS<S> {
+        self.connector = Some(connector);
+        self
+    }
+
+    /// Renew the mount's byte-range locks on a connection opened with
+    /// connector, so that they do not expire while the mount runs.
+    pub fn with_lock_renewal(mut self, connector: pool::Connector<S>) -> CockroachFS<S> {
+        self.lock_renewal = Some(connector);
+        self
+    }
+
+    /// Replace the primary connection if it has broken, trying again with
+    /// exponential backoff until deadline. Returns whether it is usable.
+    fn reconnect(&mut self, deadline: Instant) -> bool {
+        if !self.conn.broken() {
+            return true;
+        }
+        let mut backoff = RECONNECT_INITIAL_BACKOFF;
+        loop {
+            let res = match self.connector {
+                Some(ref connector) => connector(),
+                None => return false,
+            };
+            match res {
+                Ok(conn) => {
+                    info!("reconnected to the database");
+                    self.conn = conn;
+                    return true;
+                }
+                Err(err) => warn!("reconnect {}", err),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            thread::sleep(cmp::min(backoff, deadline - now));
+            backoff = cmp::min(backoff * 2, RECONNECT_MAX_BACKOFF);
+        }
+    }
+
+    /// The deadline for replacing a broken connection found now, if broken
+    /// connections are replaced at all.
+    fn reconnect_deadline(&self) -> Option<Instant> {
+        match (&self.connector, self.opts.reconnect_timeout) {
+            (Some(_), Some(timeout)) => Some(Instant::now() + timeout),
+            _ => None,
+        }
+    }
+
+    /// Run read, which must be idempotent, on a reader. Reads on the primary
+    /// connection run again on a new connection if it breaks, until the
+    /// reconnect timeout is spent.
+    fn read_retrying<T, E, F>(&mut self, mut read: F) -> Result<T, CrfsError>
+    where
+        E: Into<CrfsError>,
+        F: FnMut(&S) -> Result<T, E>,
+    {
+        if let Some(conn) = self.router.as_ref().and_then(|router| router.reader()) {
+            return read(conn).map_err(Into::into);
+        }
+        let deadline = match self.reconnect_deadline() {
+            Some(deadline) => deadline,
+            None => return read(&self.conn).map_err(Into::into),
+        };
+        loop {
+            self.reconnect(deadline);
+            match read(&self.conn).map_err(Into::into) {
+                Err(ref err) if lost(err) && Instant::now() < deadline => {
+                    warn!("{}, retrying on a new connection", err)
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// What an operation that failed on the database is reported as, which
+    /// is Unavailable if a broken connection could not be replaced in time.
+    fn db_error(&self, err: CrfsError) -> CrfsError {
+        match err {
+            CrfsError::Backend(err) if unreachable(&err) && self.reconnect_deadline().is_some() => {
+                CrfsError::Unavailable(err)
+            }
+            err => err,
+        }
+    }
+
+    /// Send read-only statements through router instead of the primary
+    /// connection.
+    pub fn with_router(mut self, router: Router<S>) -> CockroachFS<S> {
+        self.router = Some(router);
+        self
+    }
+
+    /// Present directory root, rather than the filesystem's own root, at the
+    /// mountpoint, leaving whatever lies outside it unreachable.
+    pub fn with_root(mut self, root: u64) -> CockroachFS<S> {
+        self.root = root;
+        self
+    }
+
+    /// Serve file reads and writes and directory listings from pool instead
+    /// of the FUSE session thread, so that they run concurrently rather than
+    /// queueing behind each other.
+    pub fn with_pool(mut self, pool: Pool<S>) -> CockroachFS<S> {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// The caches and metrics of the mount, for the control socket to report
+    /// on and drop caches through while the session runs.
+    pub fn control_handles(&self) -> (Arc<Mutex<Cache>>, Metrics) {
+        (self.cache.clone(), self.metrics.clone())
+    }
+
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap()
+    }
+
+    /// The pool to hand operations to, if they may run off the session
+    /// thread. Offline reads and the write journal depend on state that only
+    /// the session thread may touch, so they keep every operation on it.
+    fn pool(&self) -> Option<&Pool<S>> {
+        match self.pool {
+            Some(ref pool) if self.offline.is_none() && !self.opts.offline_reads => Some(pool),
+            _ => None,
+        }
+    }
+
+    /// The connection to use for read-only statements.
+    fn reader(&self) -> &S {
+        match self.router {
+            Some(ref router) => router.reader().unwrap_or(&self.conn),
+            None => &self.conn,
+        }
+    }
+
+    /// Forget any cached knowledge of the directory entry name in parent,
+    /// and of the attributes of parent and of the inode the entry refers to.
+    fn invalidate_dentry(&mut self, parent: u64, name: &OsStr) {
+        let key = Key::Dentry(parent, name.as_bytes().to_vec());
+        let cached = self.cache().get(&key).cloned();
+        if let Some(Value::Dentry(Some((ino, _)), _)) = cached {
+            self.cache().remove(&Key::Attr(ino));
+        }
+        self.cache().remove(&key);
+        self.cache().remove(&Key::Attr(parent));
+    }
+
+    /// Remember the attributes of an inode for offline reads and for the
+    /// attribute cache.
+    fn cache_attr(&mut self, attr: &FileAttr) {
+        if self.opts.offline_reads || self.opts.attr_ttl.is_some() {
+            let value = Value::Attr(*attr, Instant::now());
+            self.cache().insert(Key::Attr(attr.ino), value);
+        }
+    }
+
+    /// Remember what the directory entry name in parent refers to, if
+    /// anything, for the attribute cache. Entries that exist are remembered
+    /// for offline reads as well.
+    fn cache_dentry(&mut self, parent: u64, name: &[u8], entry: Option<(u64, u64)>) {
+        let key = Key::Dentry(parent, name.to_vec());
+        if self.opts.attr_ttl.is_some() || (entry.is_some() && self.opts.offline_reads) {
+            self.cache()
+                .insert(key, Value::Dentry(entry, Instant::now()));
+        } else {
+            self.cache().remove(&key);
+        }
+    }
+
+    /// What the directory entry name in parent refers to, if it was read
+    /// from the database within the attribute cache TTL.
+    fn fresh_dentry(&mut self, parent: u64, name: &[u8]) -> Option<Option<(u64, u64)>> {
+        let ttl = self.opts.attr_ttl?;
+        match self.cache().get(&Key::Dentry(parent, name.to_vec())) {
+            Some(&Value::Dentry(entry, read)) if read.elapsed() < ttl => Some(entry),
+            _ => None,
+        }
+    }
+
+    /// The cached attributes of an inode, if they were read from the database
+    /// within the attribute cache TTL.
+    fn fresh_attr(&mut self, ino: u64) -> Option<FileAttr> {
+        let ttl = self.opts.attr_ttl?;
+        match self.cache().get(&Key::Attr(ino)) {
+            Some(&Value::Attr(attr, read)) if read.elapsed() < ttl => Some(attr),
+            _ => None,
+        }
+    }
+
+    /// Remember the whole blocks covered by a read of size bytes at offset
+    /// for offline reads and for the block cache. A short read reached the
+    /// end of the file, so its final partial block is remembered too.
+    fn cache_blocks(&mut self, ino: u64, offset: i64, size: usize, data: &[u8]) {
+        if !self.opts.offline_reads && self.opts.block_ttl.is_none() {
+            return;
+        }
+        let now = Instant::now();
+        let block_size = sql::block_size();
+        let eof = data.len() < size;
+        let mut block = (offset + block_size - 1) / block_size;
+        loop {
+            let start = (block * block_size - offset) as usize;
+            let end = start + block_size as usize;
+            if end <= data.len() {
+                let value = Value::Block(data[start..end].to_vec(), now);
+                self.cache().insert(Key::Block(ino, block), value);
+            } else {
+                if eof && start <= data.len() {
+                    let value = Value::Block(data[start..].to_vec(), now);
+                    self.cache().insert(Key::Block(ino, block), value);
+                }
+                break;
+            }
+            block += 1;
+        }
+    }
+
+    /// Reply with the entries of directory ino after the entry given cookie,
+    /// caching their attributes along the way so that the lookups that
+    /// typically follow a listing, one per entry, are served from the cache.
+    fn list_dir_plus(&mut self, ino: u64, cookie: i64, mut reply: ReplyDirectory) {
+        let errno = dir_errno(self.reader(), ino);
+        if errno != 0 {
+            reply.error(errno);
+            return;
+        }
+        match self.reader().read_dir_plus_from(ino, cookie, READDIR_BATCH) {
+            Err(err) => {
+                warn!("readdir {}", err);
+                reply.error(self.db_error(err.into()).errno())
+            }
+            Ok(ents) => {
+                // Entries whose generations could not be read are left for
+                // their lookups to read.
+                let inos: Vec<u64> = ents.iter().map(|((_, attr), _)| attr.ino).collect();
+                let generations = self.reader().generations(&inos).unwrap_or_default();
+                let mut listed = Vec::with_capacity(ents.len());
+                for ((ent, attr), cookie) in ents {
+                    if let Some(&generation) = generations.get(&attr.ino) {
+                        self.cache_dentry(ino, &ent.child_name, Some((attr.ino, generation)));
+                    }
+                    self.cache_attr(&attr);
+                    listed.push((ent, cookie));
+                }
+                add_entries(&listed, &mut reply);
+                reply.ok();
+            }
+        };
+    }
+
+    /// Read ahead of a read of size bytes at offset through handle fh if it
+    /// continues the previous one, prefetching the blocks that follow it on
+    /// the pool so that they are cached by the time they are read.
+    fn readahead(&self, fh: u64, ino: u64, offset: i64, size: usize) {
+        let pool = match self.pool {
+            Some(ref pool) if self.opts.readahead > 0 => pool,
+            _ => return,
+        };
+        let block_size = sql::block_size();
+        let end = offset + size as i64;
+        let (start, limit) = {
+            let mut handles = self.handles.lock().unwrap();
+            let handle = match handles.get_mut(&fh) {
+                Some(handle) => handle,
+                None => return,
+            };
+            let sequential = handle.next_read == offset;
+            handle.next_read = end;
+            if !sequential {
+                handle.read_ahead = 0;
+                return;
+            }
+            // Blocks already prefetched, or partly covered by this read, are
+            // not prefetched again.
+            let start = cmp::max(
+                handle.read_ahead,
+                (end + block_size - 1) / block_size * block_size,
+            );
+            let limit = (end / block_size + self.opts.readahead as i64) * block_size;
+            if start >= limit {
+                return;
+            }
+            handle.read_ahead = limit;
+            (start, limit)
+        };
+        let prefetcher = self.prefetcher.clone();
+        let epoch = self.block_epoch;
+        pool.execute(move |conn| {
+            let _class = sql::read_class(ReadClass::Read);
+            let size = (limit - start) as usize;
+            if let Ok(Some(data)) = conn.read_data(ino, start, size) {
+                let prefetch = Prefetch {
+                    ino,
+                    offset: start,
+                    size,
+                    data,
+                    epoch,
+                };
+                // The filesystem may have been unmounted meanwhile.
+                let _ = prefetcher.send(prefetch);
+            }
+        });
+    }
+
+    /// Add the blocks read ahead so far to the block cache, unless cached
+    /// blocks have been invalidated since they were read.
+    fn cache_prefetched(&mut self) {
+        while let Ok(prefetch) = self.prefetched.try_recv() {
+            if prefetch.epoch == self.block_epoch {
+                let (ino, offset, size) = (prefetch.ino, prefetch.offset, prefetch.size);
+                self.cache_blocks(ino, offset, size, &prefetch.data);
+            }
+        }
+    }
+
+    /// Read size bytes at offset from cached blocks, if all of them are
+    /// cached and, given max_age, were read from the database within it.
+    fn cached_read(
+        &mut self,
+        ino: u64,
+        offset: i64,
+        size: usize,
+        max_age: Option<Duration>,
+    ) -> Option<Vec<u8>> {
+        let end = offset + size as i64;
+        let mut data = Vec::with_capacity(size);
+        let block_size = sql::block_size();
+        let mut block = offset / block_size;
+        while block * block_size < end {
+            let block_start = block * block_size;
+            let bytes = match self.cache().get(&Key::Block(ino, block)).cloned() {
+                Some(Value::Block(bytes, read))
+                    if max_age.is_none_or(|age| read.elapsed() < age) =>
+                {
+                    bytes
+                }
+                _ => return None,
+            };
+            let lo = (offset - block_start).max(0) as usize;
+            let hi = (bytes.len() as i64).min(end - block_start) as usize;
+            if lo < hi {
+                data.extend_from_slice(&bytes[lo..hi]);
+            }
+            if bytes.len() < block_size as usize {
+                break;
+            }
+            block += 1;
+        }
+        Some(data)
+    }
+
+    /// Assign a file handle to a newly opened file.
+    fn open_handle(&mut self, attr: FileAttr, flags: u32) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        let mut handle = Handle {
+            flags,
+            attr,
+            error: None,
+            pending: Vec::new(),
+            next_read: 0,
+            read_ahead: 0,
+            buffered: false,
+            contents: None,
+        };
+        if self.opts.write_back && attr.kind == FileType::RegularFile && handle.writable() {
+            handle.buffered = self.take_write_lease(attr.ino, self.opts.lease_conflict);
+        }
+        self.handles.lock().unwrap().insert(fh, handle);
+        fh
+    }
+
+    /// Take this mount's write lease on ino, or renew it if it is due,
+    /// returning whether the mount holds it. Another mount's lease is handled
+    /// as conflict says.
+    fn take_write_lease(&mut self, ino: u64, conflict: LeaseConflict) -> bool {
+        let lease = self.opts.write_lease;
+        if let Some(taken) = self.leases.get(&ino) {
+            if taken.elapsed() < lease / 2 {
+                return true;
+            }
+        }
+        let deadline = Instant::now() + lease;
+        loop {
+            let force = conflict == LeaseConflict::Break;
+            match self.conn.take_write_lease(ino, &self.session, lease, force) {
+                Ok(true) => {
+                    self.leases.insert(ino, Instant::now());
+                    return true;
+                }
+                Ok(false) if conflict == LeaseConflict::Wait && Instant::now() < deadline => {
+                    thread::sleep(LOCK_POLL);
+                }
+                Ok(false) => break,
+                Err(err) => {
+                    warn!("write lease {}", err);
+                    break;
+                }
+            }
+        }
+        self.leases.remove(&ino);
+        false
+    }
+
+    /// Stop buffering writes to ino after losing its write lease, writing out
+    /// what has been buffered so far.
+    fn lose_write_lease(&mut self, ino: u64) -> Result<(), c_int> {
+        warn!("lost the write lease on {}, writing through", ino);
+        for handle in self.handles.lock().unwrap().values_mut() {
+            if handle.attr.ino == ino {
+                handle.buffered = false;
+            }
+        }
+        self.flush_ino(ino)
+    }
+
+    /// Give up the write lease on ino once no open handle buffers writes to
+    /// it.
+    fn release_write_lease(&mut self, ino: u64) {
+        let buffered = self
+            .handles
+            .lock()
+            .unwrap()
+            .values()
+            .any(|handle| handle.attr.ino == ino && handle.buffered);
+        if buffered || self.leases.remove(&ino).is_none() {
+            return;
+        }
+        if let Err(err) = self.conn.release_write_lease(ino, &self.session) {
+            warn!("release write lease {}", err);
+        }
+    }
+
+    /// Write data at offset to ino in the database, on behalf of handle fh,
+    /// or at the end of the file if append is set. Offset is then only the
+    /// end of the file as far as the kernel knows, used if the write has to
+    /// be journaled.
+    fn write_through(
+        &mut self,
+        fh: u64,
+        ino: u64,
+        offset: i64,
+        append: bool,
+        data: &[u8],
+    ) -> Result<usize, c_int> {
+        // Writes must be applied in order, so once one has been journaled so
+        // are all that follow until the journal has been replayed.
+        if !self.replay_journal() {
+            if self.journal_write(ino, offset, data) {
+                return Ok(data.len());
+            }
+            let err = io::Error::new(io::ErrorKind::NotConnected, "journal not replayed");
+            return Err(CrfsError::Unavailable(err.into()).errno());
+        }
+        let at = if append { None } else { Some(offset) };
+        match self.conn.write_data(ino, at, data) {
+            Err(CrfsError::Backend(ref err))
+                if unreachable(err) && self.journal_write(ino, offset, data) =>
+            {
+                warn!("write {}, journaled", err);
+                Ok(data.len())
+            }
+            Err(err) => Err(self.mutation_error("write", err).errno()),
+            Ok(offset) => {
+                let size = data.len();
+                extend_handle(&self.handles, fh, (offset + size as i64) as u64);
+                if let Some(ref mut offline) = self.offline {
+                    if let Ok(Some(version)) = self.conn.inode_version(ino) {
+                        offline.versions.insert(ino, version);
+                    }
+                }
+                Ok(size)
+            }
+        }
+    }
+
+    /// Write out the writes buffered by handle fh.
+    fn flush_handle(&mut self, fh: u64) -> Result<(), c_int> {
+        let (ino, pending) = match self.handles.lock().unwrap().get_mut(&fh) {
+            Some(handle) => (handle.attr.ino, mem::take(&mut handle.pending)),
+            None => return Ok(()),
+        };
+        for (offset, data) in pending {
+            self.write_through(fh, ino, offset, false, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Write out the writes buffered for ino by any handle, so that the
+    /// database reflects them before ino is read or changed otherwise.
+    fn flush_ino(&mut self, ino: u64) -> Result<(), c_int> {
+        let fhs: Vec<u64> = self
+            .handles
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, handle)| handle.attr.ino == ino && !handle.pending.is_empty())
+            .map(|(fh, _)| *fh)
+            .collect();
+        for fh in fhs {
+            self.flush_handle(fh)?;
+        }
+        Ok(())
+    }
+
+    /// Get the attributes of an inode along with how long they may be cached,
+    /// falling back to cached attributes while serving offline reads.
+    fn attr(&mut self, op: &str, ino: u64) -> Result<(FileAttr, Timespec), c_int> {
+        if let Some(attr) = self.ctl_attr(ino) {
+            return Ok((attr, TTL));
+        }
+        self.flush_ino(ino)?;
+        if let Some(attr) = self.fresh_attr(ino) {
+            return Ok((attr, TTL));
+        }
+        match self.read_retrying(|conn| conn.lookup_inode(ino)) {
+            Err(ref err) if self.serve_offline(err) => match self.cache().get(&Key::Attr(ino)) {
+                Some(&Value::Attr(attr, _)) => {
+                    warn!("{} {}, serving stale attributes", op, err);
+                    Ok((attr, STALE_TTL))
+                }
+                _ => {
+                    warn!("{} {}", op, err);
+                    Err(err.errno())
+                }
+            },
+            Err(err) => {
+                warn!("{} {}", op, err);
+                Err(self.db_error(err).errno())
+            }
+            Ok(None) => Err(ENOENT),
+            Ok(Some(attr)) => {
+                self.cache_attr(&attr);
+                Ok((attr, TTL))
+            }
+        }
+    }
+
+    /// Whether a failed read may be served from the local cache instead.
+    fn serve_offline(&self, err: &CrfsError) -> bool {
+        self.opts.offline_reads && lost(err)
+    }
+
+    /// What a failed mutating operation is reported as. Mutations are
+    /// refused as on a read-only filesystem while serving offline reads.
+    /// Writes are not retried, but a broken connection is replaced for the
+    /// operations that follow.
+    fn mutation_error(&mut self, op: &str, err: CrfsError) -> CrfsError {
+        if let Some(db) = err.backend() {
+            warn!("{} {}", op, db);
+        }
+        if self.serve_offline(&err) {
+            return CrfsError::ReadOnly;
+        }
+        if let Some(deadline) = self.reconnect_deadline() {
+            self.reconnect(deadline);
+        }
+        self.db_error(err)
+    }
+
+    /// The local identity a request is performed as, after squashing.
+    fn caller(&self, req: &Request) -> (u32, u32) {
+        let squashed = match self.opts.squash {
+            Squash::None => false,
+            Squash::Root => req.uid() == 0,
+            Squash::All => true,
+        };
+        if squashed {
+            (self.opts.anon_uid, self.opts.anon_gid)
+        } else {
+            (req.uid(), req.gid())
+        }
+    }
+
+    /// Whether the caller of a request may access a file as mask, a
+    /// combination of R_OK, W_OK and X_OK, going by the file's presented
+    /// owner and permission bits. Only the caller's primary group is known.
+    fn permitted(&self, req: &Request, attr: &FileAttr, mask: c_int) -> bool {
+        if self.opts.default_permissions() {
+            return true;
+        }
+        let attr = self.present_attr(*attr);
+        let (uid, gid) = self.caller(req);
+        let perm = c_int::from(attr.perm);
+        if uid == 0 {
+            // Root may do anything but execute a file nobody may execute.
+            return mask & X_OK == 0 || attr.kind == FileType::Directory || perm & 0o111 != 0;
+        }
+        let bits = if uid == attr.uid {
+            perm >> 6
+        } else if gid == attr.gid {
+            perm >> 3
+        } else {
+            perm
+        };
+        bits & mask == mask
+    }
+
+    /// Check that the caller of a request may access ino as mask.
+    fn check_access(
+        &mut self,
+        req: &Request,
+        op: &str,
+        ino: u64,
+        mask: c_int,
+    ) -> Result<(), c_int> {
+        if self.opts.default_permissions() {
+            return Ok(());
+        }
+        let (attr, _) = self.attr(op, ino)?;
+        if self.permitted(req, &attr, mask) {
+            Ok(())
+        } else {
+            Err(EACCES)
+        }
+    }
+
+    /// Open the file another creator created as name in parent first, as
+    /// open would: checking the caller's access, and truncating it for
+    /// O_TRUNC.
+    fn open_created(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &[u8],
+        flags: u32,
+    ) -> Result<(FileAttr, u64), c_int> {
+        let (attr, generation) = self
+            .conn
+            .lookup_dir_ent(parent, name)
+            .and_then(|found| found.ok_or(CrfsError::NotFound))
+            .map_err(|err| self.mutation_error("create", err).errno())?;
+        if attr.kind == FileType::Directory {
+            return Err(EISDIR);
+        }
+        if !self.permitted(req, &attr, access_mask(flags)) {
+            return Err(EACCES);
+        }
+        if flags & O_TRUNC as u32 == 0 || attr.size == 0 {
+            return Ok((attr, generation));
+        }
+        let ino = attr.ino;
+        self.flush_ino(ino)?;
+        self.cache().remove(&Key::Attr(ino));
+        self.cache()
+            .remove_matching(|key, _| matches!(*key, Key::Block(i, _) if i == ino));
+        self.block_epoch += 1;
+        let now = time::get_time();
+        let attr = self
+            .conn
+            .update_inode(
+                ino,
+                Some(0),
+                None,
+                Some(now),
+                Some(now),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .map_err(|err| self.mutation_error("create", err).errno())?;
+        self.cache_attr(&attr);
+        Ok((attr, generation))
+    }
+
+    /// Check that name is short enough to be linked into a directory.
+    fn check_name(&self, name: &OsStr) -> Result<(), c_int> {
+        if name.len() > self.opts.name_max as usize {
+            Err(ENAMETOOLONG)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that the entry name in parent may be created, removed or
+    /// renamed, which it may not be within the control directory or as the
+    /// control directory itself.
+    fn check_entry(&self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        if is_ctl(parent) || (parent == self.root && name == CTL_DIR) {
+            return Err(EROFS);
+        }
+        self.check_name(name)
+    }
+
+    /// The attributes of the control directory or one of its files, owned
+    /// by the mounting user and dated to when the filesystem was mounted.
+    fn ctl_attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, perm, nlink) = match ctl_file(ino) {
+            _ if ino == CTL_DIR_INO => (FileType::Directory, 0o555, 2),
+            Some(_) => (FileType::RegularFile, 0o444, 1),
+            None => return None,
+        };
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Some(FileAttr {
+            ino,
+            // The size of a file is only known once it is generated.
+            size: 0,
+            blocks: 0,
+            atime: self.mounted,
+            mtime: self.mounted,
+            ctime: self.mounted,
+            crtime: self.mounted,
+            kind,
+            perm,
+            nlink,
+            uid: self.opts.uid_map.stored(uid),
+            gid: self.opts.gid_map.stored(gid),
+            rdev: 0,
+            flags: 0,
+        })
+    }
+
+    /// The attributes of the entry name in parent if it is the control
+    /// directory or would be one of its files.
+    fn ctl_lookup(&self, parent: u64, name: &OsStr) -> Option<Result<FileAttr, c_int>> {
+        if parent == self.root && name == CTL_DIR {
+            return self.ctl_attr(CTL_DIR_INO).map(Ok);
+        }
+        if parent != CTL_DIR_INO {
+            return None;
+        }
+        let file = CTL_FILES.iter().find(|file| name == file.name());
+        Some(
+            file.and_then(|file| self.ctl_attr(file.ino()))
+                .ok_or(ENOENT),
+        )
+    }
+
+    /// Generate the contents of a control file from the mount's state.
+    fn ctl_contents(&self, file: CtlFile) -> String {
+        let mut out = String::new();
+        match file {
+            CtlFile::Stats => {
+                let uptime = (time::get_time() - self.mounted).num_seconds();
+                out += &format!("uptime {}s\n", uptime);
+                out += &format!("{}\n", self.metrics.summary());
+                out += &format!("open handles {}\n", self.handles.lock().unwrap().len());
+                out += &format!("write leases {}\n", self.leases.len());
+                out += &format!("pending atimes {}\n", self.atimes.len());
+            }
+            CtlFile::Connections => {
+                let primary = if self.conn.broken() { "broken" } else { "open" };
+                out += &format!("primary {}\n", primary);
+                match self.pool {
+                    Some(ref pool) => out += &format!("pool {} connections\n", pool.size()),
+                    None => out += "pool none\n",
+                }
+                if let Some(ref router) = self.router {
+                    for (addr, latency) in router.gateways() {
+                        match latency {
+                            Some(latency) => out += &format!("gateway {} {:?}\n", addr, latency),
+                            None => out += &format!("gateway {} unreachable\n", addr),
+                        }
+                    }
+                }
+                if let Some(ref offline) = self.offline {
+                    out += &format!("journal {} writes\n", offline.journal.entries().len());
+                }
+            }
+            CtlFile::Cache => {
+                let cache = self.cache();
+                out += &format!("used {} of {} bytes\n", cache.used(), cache.budget());
+                for (kind, stats) in cache.stats() {
+                    out += &format!(
+                        "{} {} entries, {} bytes, {} evictions\n",
+                        kind, stats.entries, stats.bytes, stats.evictions
+                    );
+                }
+            }
+            CtlFile::Version => {
+                out += &format!("cockroach_fuse {}\n", env!("CARGO_PKG_VERSION"));
+                out += &format!("schema version {}\n", migrate::latest());
+                out += &format!("block size {}\n", sql::block_size());
+                out += &format!("name max {}\n", self.opts.name_max);
+            }
+        }
+        out
+    }
+
+    /// The stored owner of files created by a request.
+    fn owner(&self, req: &Request) -> (u32, u32) {
+        let (uid, gid) = self.caller(req);
+        (self.opts.uid_map.stored(uid), self.opts.gid_map.stored(gid))
+    }
+
+    /// Note that ino was read. Its atime is written later, together with
+    /// those of other inodes read in the meantime.
+    fn record_access(&mut self, ino: u64) {
+        if self.opts.atime == Atime::Never {
+            return;
+        }
+        self.atimes.insert(ino, time::get_time());
+        if self.atimes.len() >= ATIME_BATCH || self.atimes_flushed.elapsed() >= ATIME_FLUSH_INTERVAL
+        {
+            self.flush_atimes();
+        }
+    }
+
+    /// Write out buffered writes, wait for operations running on the pool,
+    /// and give up the session's locks and leases. Called once the session
+    /// ends, whether the kernel destroyed the filesystem or it was unmounted.
+    pub fn shutdown(&mut self) {
+        if self.shut_down {
+            return;
+        }
+        self.shut_down = true;
+        self.flush_atimes();
+        let fhs: Vec<u64> = self
+            .handles
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, handle)| !handle.pending.is_empty())
+            .map(|(fh, _)| *fh)
+            .collect();
+        for fh in fhs {
+            if let Err(errno) = self.flush_handle(fh) {
+                error!(
+                    "shutdown: dropped buffered writes to handle {}: errno {}",
+                    fh, errno
+                );
+            }
+        }
+        if let Some(pool) = self.pool.take() {
+            pool.join();
+        }
+        self.renewing.take();
+        self.lock_owners.lock().unwrap().clear();
+        if let Err(err) = self.conn.release_session_locks(&self.session) {
+            warn!("shutdown {}", err);
+        }
+        if let Err(err) = self.conn.release_session_leases(&self.session) {
+            warn!("shutdown {}", err);
+        }
+        info!("{}", self.metrics.take_summary());
+        info!(
+            "cache used {} of {} bytes",
+            self.cache().used(),
+            self.cache().budget()
+        );
+        for (kind, stats) in self.cache().stats() {
+            info!(
+                "{} cache: {} entries, {} bytes, {} evictions",
+                kind, stats.entries, stats.bytes, stats.evictions
+            );
+        }
+    }
+
+    /// Write the atimes of inodes read since the last flush. An atime that
+    /// cannot be written is dropped rather than failing the read.
+    fn flush_atimes(&mut self) {
+        self.atimes_flushed = Instant::now();
+        if self.atimes.is_empty() {
+            return;
+        }
+        let atimes: Vec<(u64, Timespec)> = self.atimes.drain().collect();
+        for &(ino, _) in &atimes {
+            self.cache().remove(&Key::Attr(ino));
+        }
+        let strict = self.opts.atime == Atime::Strict;
+        if let Some(pool) = self.pool() {
+            pool.execute(move |conn| {
+                if let Err(err) = conn.update_atimes(&atimes, strict) {
+                    warn!("atime {}", err);
+                }
+            });
+            return;
+        }
+        if let Err(err) = self.conn.update_atimes(&atimes, strict) {
+            warn!("atime {}", err);
+        }
+    }
+
+    /// The stored inode the kernel's ino stands for.
+    fn db_ino(&self, ino: u64) -> u64 {
+        if ino == FUSE_ROOT_ID {
+            self.root
+        } else {
+            ino
+        }
+    }
+
+    /// Adjust the attributes of an inode before handing them to the kernel.
+    fn present_attr(&self, mut attr: FileAttr) -> FileAttr {
+        if attr.ino == self.root {
+            attr.ino = FUSE_ROOT_ID;
+        }
+        if let Some(&time) = self.atimes.get(&attr.ino) {
+            if self.opts.atime.updates(&attr, time) {
+                attr.atime = time;
+            }
+        }
+        if self.opts.nosuid {
+            attr.perm &= !SETID_BITS;
+        }
+        attr.uid = self.opts.uid.unwrap_or(self.opts.uid_map.local(attr.uid));
+        attr.gid = self.opts.gid.unwrap_or(self.opts.gid_map.local(attr.gid));
+        if let Some(umask) = self.opts.umask {
+            attr.perm &= !umask;
+        }
+        attr
     }
 }
 
-impl Filesystem for CockroachFS {
+impl<S: Store> Filesystem for CockroachFS<S> {
     /// Initialize filesystem.
     /// Called before any other filesystem method.
     fn init(&mut self, _req: &Request) -> Result<(), c_int> {
-        // Initialize the databse schema.
-        sql::create_schema(&self.conn).map_err(|e| {
-            eprintln!("{}", e);
-            ECONNREFUSED
-        })?;
+        if let Some(interval) = self.opts.stats_interval {
+            self.metrics.report_every(interval);
+        }
+
+        self.replay_journal();
 
-        // Create the root directory.
-        sql::create_inode(&self.conn, 0, &"", FileType::Directory, 0).map_err(|e| {
-            eprintln!("{}", e);
-            ECONNREFUSED
+        self.session = self.conn.new_session().map_err(|e| {
+            error!("{}", e);
+            CrfsError::from(e).errno()
         })?;
+        if let Some(connector) = self.lock_renewal.take() {
+            let (renewing, stop) = mpsc::channel();
+            let (session, owners) = (self.session.clone(), self.lock_owners.clone());
+            thread::spawn(move || renew_locks(connector, session, owners, stop));
+            self.renewing = Some(renewing);
+        }
+
+        // Create the root directory, owned by the mounting user, when the
+        // default filesystem is first mounted. Others are created with their
+        // root by `fs create`.
+        if self.root == FUSE_ROOT_ID {
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+            let (uid, gid) = (self.opts.uid_map.stored(uid), self.opts.gid_map.stored(gid));
+            self.conn.create_root(uid, gid).map_err(|e| {
+                error!("{}", e);
+                CrfsError::from(e).errno()
+            })?;
+        }
 
         Ok(())
     }
 
+    /// Clean up filesystem.
+    /// Called on filesystem exit.
+    fn destroy(&mut self, _req: &Request) {
+        self.shutdown();
+    }
+
     /// Look up a directory entry by name and get its attributes.
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        println!("lookup {} {}", parent, name.to_str().unwrap());
-        match sql::lookup_dir_ent(&self.conn, parent, name.to_str().unwrap()) {
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let parent = self.db_ino(parent);
+        let _op = self.metrics.start("lookup");
+        let _span = logging::span("lookup", parent, Some(name));
+        let _class = sql::read_class(ReadClass::Lookup);
+        if let Err(errno) = self
+            .check_name(name)
+            .and_then(|_| self.check_access(req, "lookup", parent, X_OK))
+        {
+            reply.error(errno);
+            return;
+        }
+        if let Some(res) = self.ctl_lookup(parent, name) {
+            match res {
+                Err(errno) => reply.error(errno),
+                Ok(attr) => reply.entry(&TTL, &self.present_attr(attr), 0),
+            }
+            return;
+        }
+        debug!("lookup {} {}", parent, name.to_string_lossy());
+        let key = Key::Dentry(parent, name.as_bytes().to_vec());
+        let res = match self.fresh_dentry(parent, name.as_bytes()) {
+            Some(None) => Ok(None),
+            Some(Some((ino, generation))) => match self.fresh_attr(ino) {
+                Some(attr) => Ok(Some((attr, generation))),
+                None => self.read_retrying(|conn| conn.lookup_inode_generation(ino)),
+            },
+            None => self.read_retrying(|conn| conn.lookup_dir_ent(parent, name.as_bytes())),
+        };
+        match res {
+            Ok(None) => self.cache_dentry(parent, name.as_bytes(), None),
+            Ok(Some((ref attr, generation))) => {
+                self.cache_dentry(parent, name.as_bytes(), Some((attr.ino, generation)));
+                self.cache_attr(attr);
+            }
+            Err(ref err) if self.serve_offline(err) => {}
+            Err(_) => self.cache().remove(&key),
+        };
+        match res {
+            Err(ref err) if self.serve_offline(err) => {
+                // Some(None) if the entry is known not to exist.
+                let cached = self.cache().get(&key).cloned();
+                let cached = match cached {
+                    Some(Value::Dentry(None, _)) => Some(None),
+                    Some(Value::Dentry(Some((ino, generation)), _)) => {
+                        match self.cache().get(&Key::Attr(ino)).cloned() {
+                            Some(Value::Attr(attr, _)) => Some(Some((attr, generation))),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                match cached {
+                    Some(None) => reply.error(ENOENT),
+                    Some(Some((attr, generation))) => {
+                        warn!("lookup {}, serving stale entry", err);
+                        reply.entry(&STALE_TTL, &self.present_attr(attr), generation)
+                    }
+                    None => {
+                        warn!("lookup {}", err);
+                        reply.error(err.errno())
+                    }
+                }
+            }
             Err(err) => {
-                eprintln!("lookup {}", err);
-                reply.error(ECONNREFUSED)
+                warn!("lookup {}", err);
+                reply.error(self.db_error(err).errno())
             }
             Ok(None) => reply.error(ENOENT),
-            Ok(Some(attr)) => {
-                println!("lookup found {}", name.to_str().unwrap());
-                reply.entry(&TTL, &attr, 0)
+            Ok(Some((attr, generation))) => {
+                debug!("lookup found {}", name.to_string_lossy());
+                reply.entry(&TTL, &self.present_attr(attr), generation)
             }
         };
     }
 
     /// Get file attributes.
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        println!("getattr {}", ino);
-        match sql::lookup_inode(&self.conn, ino) {
-            Err(err) => {
-                eprintln!("getattr {}", err);
-                reply.error(ECONNREFUSED)
-            }
-            Ok(None) => reply.error(ENOENT),
-            Ok(Some(attr)) => reply.attr(&TTL, &attr),
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("getattr");
+        let _span = logging::span("getattr", ino, None);
+        let _class = sql::read_class(ReadClass::Getattr);
+        debug!("getattr {}", ino);
+        match self.attr("getattr", ino) {
+            Err(errno) => reply.error(errno),
+            Ok((attr, ttl)) => reply.attr(&ttl, &self.present_attr(attr)),
         };
     }
 
     /// Set file attributes.
     fn setattr(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
@@ -89,17 +1614,66 @@ impl Filesystem for CockroachFS {
         flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        println!("setattr {}", ino);
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("setattr");
+        let _span = logging::span("setattr", ino, None);
+        debug!("setattr {}", ino);
+        if is_ctl(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        if !self.opts.default_permissions() {
+            let attr = match self.attr("setattr", ino) {
+                Err(errno) => {
+                    reply.error(errno);
+                    return;
+                }
+                Ok((attr, _)) => attr,
+            };
+            let (caller, _) = self.caller(req);
+            let local = self.present_attr(attr);
+            let owns = caller == 0 || caller == local.uid;
+            // Only root may give a file away, and only the owner may change
+            // its other metadata.
+            let chown = uid.is_some_and(|uid| uid != local.uid) && caller != 0;
+            let times = atime.is_some() || mtime.is_some();
+            let errno = if chown || (mode.is_some() || gid.is_some() || flags.is_some()) && !owns {
+                EPERM
+            } else if (size.is_some() || times && !owns) && !self.permitted(req, &attr, W_OK) {
+                EACCES
+            } else {
+                0
+            };
+            if errno != 0 {
+                reply.error(errno);
+                return;
+            }
+        }
+        if let Err(errno) = self.flush_ino(ino) {
+            reply.error(errno);
+            return;
+        }
         let (kind, perm) = optional_kind_and_perm_from_mode(mode);
-        match sql::update_inode(
-            &self.conn, ino, size, atime, mtime, chgtime, crtime, kind, perm, uid, gid, flags,
+        let uid = uid.map(|uid| self.opts.uid_map.stored(uid));
+        let gid = gid.map(|gid| self.opts.gid_map.stored(gid));
+        self.cache().remove(&Key::Attr(ino));
+        if atime.is_some() {
+            // An explicit atime overrides reads not yet recorded.
+            self.atimes.remove(&ino);
+        }
+        if size.is_some() {
+            self.cache()
+                .remove_matching(|key, _| matches!(*key, Key::Block(i, _) if i == ino));
+            self.block_epoch += 1;
+        }
+        match self.conn.update_inode(
+            ino, size, atime, mtime, chgtime, crtime, kind, perm, uid, gid, flags,
         ) {
-            Err(err) => {
-                eprintln!("setattr {}", err);
-                reply.error(ECONNREFUSED)
+            Err(err) => reply.error(self.mutation_error("setattr", err).errno()),
+            Ok(attr) => {
+                self.cache_attr(&attr);
+                reply.attr(&TTL, &self.present_attr(attr))
             }
-            Ok(None) => reply.error(ENOENT),
-            Ok(Some(attr)) => reply.attr(&TTL, &attr),
         };
     }
 
@@ -107,113 +1681,356 @@ impl Filesystem for CockroachFS {
     /// Create a regular file, character device, block device, fifo or socket node.
     fn mknod(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
-        _mode: u32, // TODO: what is this supposed to be?
+        mode: u32,
         rdev: u32,
         reply: ReplyEntry,
     ) {
-        match sql::create_inode(
-            &self.conn,
+        let parent = self.db_ino(parent);
+        let _op = self.metrics.start("mknod");
+        let _span = logging::span("mknod", parent, Some(name));
+        if let Err(errno) = self
+            .check_entry(parent, name)
+            .and_then(|_| self.check_access(req, "mknod", parent, W_OK | X_OK))
+        {
+            reply.error(errno);
+            return;
+        }
+        self.invalidate_dentry(parent, name);
+        // The kernel has already applied the caller's umask to mode.
+        let (kind, perm) = kind_and_perm_from_mode(mode);
+        match self.conn.create_inode(
             parent,
-            name.to_str().unwrap(),
-            FileType::RegularFile,
+            name.as_bytes(),
+            kind,
+            perm & PERM_BITS,
             rdev,
+            self.owner(req),
         ) {
-            Err(err) => {
-                eprintln!("mknod {}", err);
-                reply.error(ECONNREFUSED)
+            Err(err) => reply.error(self.mutation_error("mknod", err).errno()),
+            Ok((attr, generation)) => reply.entry(&TTL, &self.present_attr(attr), generation),
+        };
+    }
+
+    /// Create and open a file.
+    /// The inode and its directory entry are created in a single transaction,
+    /// so of several concurrent exclusive creators exactly one succeeds.
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        let parent = self.db_ino(parent);
+        let _op = self.metrics.start("create");
+        let _span = logging::span("create", parent, Some(name));
+        if let Err(errno) = self
+            .check_entry(parent, name)
+            .and_then(|_| self.check_access(req, "create", parent, W_OK | X_OK))
+        {
+            reply.error(errno);
+            return;
+        }
+        debug!("create {} {} {:o}", parent, name.to_string_lossy(), flags);
+        self.invalidate_dentry(parent, name);
+        let owner = self.owner(req);
+        let name = name.as_bytes();
+        let perm = mode as u16 & PERM_BITS;
+        let res = match self
+            .conn
+            .create_inode(parent, name, FileType::RegularFile, perm, 0, owner)
+        {
+            // Lost a race with another creator, so open the file they created.
+            Err(CrfsError::Exists) if flags & O_EXCL as u32 == 0 => {
+                self.open_created(req, parent, name, flags)
+            }
+            res => res.map_err(|err| self.mutation_error("create", err).errno()),
+        };
+        match res {
+            Err(errno) => reply.error(errno),
+            Ok((attr, generation)) => {
+                let fh = self.open_handle(attr, flags);
+                reply.created(&TTL, &self.present_attr(attr), generation, fh, 0)
             }
-            Ok(attr) => reply.entry(&TTL, &attr, 0),
         };
     }
 
     /// Create a directory.
-    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
-        match sql::create_inode(
-            &self.conn,
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        let parent = self.db_ino(parent);
+        let _op = self.metrics.start("mkdir");
+        let _span = logging::span("mkdir", parent, Some(name));
+        if let Err(errno) = self
+            .check_entry(parent, name)
+            .and_then(|_| self.check_access(req, "mkdir", parent, W_OK | X_OK))
+        {
+            reply.error(errno);
+            return;
+        }
+        self.invalidate_dentry(parent, name);
+        // Unlike for mknod, mode carries no file type.
+        match self.conn.create_inode(
             parent,
-            name.to_str().unwrap(),
+            name.as_bytes(),
             FileType::Directory,
+            mode as u16 & PERM_BITS,
             0,
+            self.owner(req),
         ) {
-            Err(err) => {
-                eprintln!("mkdir {}", err);
-                reply.error(ECONNREFUSED)
+            Err(err) => reply.error(self.mutation_error("mkdir", err).errno()),
+            Ok((attr, generation)) => reply.entry(&TTL, &self.present_attr(attr), generation),
+        };
+    }
+
+    /// Create a symbolic link.
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let parent = self.db_ino(parent);
+        let _op = self.metrics.start("symlink");
+        let _span = logging::span("symlink", parent, Some(name));
+        if let Err(errno) = self
+            .check_entry(parent, name)
+            .and_then(|_| self.check_access(req, "symlink", parent, W_OK | X_OK))
+        {
+            reply.error(errno);
+            return;
+        }
+        debug!(
+            "symlink {} {} -> {}",
+            parent,
+            name.to_string_lossy(),
+            link.display()
+        );
+        let target = match link.to_str() {
+            Some(target) => target,
+            None => {
+                // Targets are stored as strings.
+                reply.error(EILSEQ);
+                return;
             }
-            Ok(attr) => reply.entry(&TTL, &attr, 0),
+        };
+        if target.len() >= PATH_MAX {
+            reply.error(ENAMETOOLONG);
+            return;
+        }
+        self.invalidate_dentry(parent, name);
+        let (uid, gid) = self.owner(req);
+        match self
+            .conn
+            .create_symlink(parent, name.as_bytes(), target, uid, gid)
+        {
+            Err(err) => reply.error(self.mutation_error("symlink", err).errno()),
+            Ok((attr, generation)) => reply.entry(&TTL, &self.present_attr(attr), generation),
         };
     }
 
-    /// Remove a file.
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        match sql::unlink(&self.conn, parent, name.to_str().unwrap()) {
+    /// Read a symbolic link.
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("readlink");
+        let _span = logging::span("readlink", ino, None);
+        let _class = sql::read_class(ReadClass::Readlink);
+        debug!("readlink {}", ino);
+        match self.read_retrying(|conn| conn.read_symlink(ino)) {
             Err(err) => {
-                eprintln!("unlink {}", err);
-                reply.error(ECONNREFUSED)
+                warn!("readlink {}", err);
+                reply.error(self.db_error(err).errno())
             }
-            Ok(None) => reply.error(ENOENT),
-            Ok(Some(_)) => reply.ok(),
+            Ok(None) => reply.error(EINVAL),
+            Ok(Some(target)) => reply.data(target.as_bytes()),
+        };
+    }
+
+    /// Remove a file.
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let parent = self.db_ino(parent);
+        let _op = self.metrics.start("unlink");
+        let _span = logging::span("unlink", parent, Some(name));
+        if let Err(errno) = self
+            .check_entry(parent, name)
+            .and_then(|_| self.check_access(req, "unlink", parent, W_OK | X_OK))
+        {
+            reply.error(errno);
+            return;
+        }
+        self.invalidate_dentry(parent, name);
+        match self.conn.unlink(parent, name.as_bytes(), false) {
+            Err(err) => reply.error(self.mutation_error("unlink", err).errno()),
+            Ok(()) => reply.ok(),
         };
     }
 
     /// Remove a directory.
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        match sql::unlink(&self.conn, parent, name.to_str().unwrap()) {
-            Err(err) => {
-                eprintln!("rmdir {}", err);
-                reply.error(ECONNREFUSED)
-            }
-            Ok(None) => reply.error(ENOENT),
-            Ok(Some(_)) => reply.ok(),
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let parent = self.db_ino(parent);
+        let _op = self.metrics.start("rmdir");
+        let _span = logging::span("rmdir", parent, Some(name));
+        if let Err(errno) = self
+            .check_entry(parent, name)
+            .and_then(|_| self.check_access(req, "rmdir", parent, W_OK | X_OK))
+        {
+            reply.error(errno);
+            return;
+        }
+        self.invalidate_dentry(parent, name);
+        match self.conn.unlink(parent, name.as_bytes(), true) {
+            Err(err) => reply.error(self.mutation_error("rmdir", err).errno()),
+            Ok(()) => reply.ok(),
         };
     }
 
     /// Rename a file.
     fn rename(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
         reply: ReplyEmpty,
     ) {
-        match sql::rename_dir_ent(
-            &self.conn,
-            parent,
-            name.to_str().unwrap(),
-            newparent,
-            newname.to_str().unwrap(),
-        ) {
-            Err(ref err) if err.code() == Some(&error::UNIQUE_VIOLATION) => reply.error(EEXIST),
-            Err(err) => {
-                eprintln!("rename {}", err);
-                reply.error(ECONNREFUSED)
-            }
-            Ok(false) => reply.error(ENOENT),
-            Ok(true) => reply.ok(),
+        let parent = self.db_ino(parent);
+        let newparent = self.db_ino(newparent);
+        let _op = self.metrics.start("rename");
+        let _span = logging::span("rename", parent, Some(name));
+        if let Err(errno) = self
+            .check_entry(parent, name)
+            .and_then(|_| self.check_entry(newparent, newname))
+            .and_then(|_| self.check_access(req, "rename", parent, W_OK | X_OK))
+            .and_then(|_| self.check_access(req, "rename", newparent, W_OK | X_OK))
+        {
+            reply.error(errno);
+            return;
+        }
+        self.invalidate_dentry(parent, name);
+        self.invalidate_dentry(newparent, newname);
+        match self
+            .conn
+            .rename_dir_ent(parent, name.as_bytes(), newparent, newname.as_bytes())
+        {
+            Err(err) => reply.error(self.mutation_error("rename", err).errno()),
+            Ok(()) => reply.ok(),
         };
     }
 
     /// Create a hard link.
     fn link(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         newparent: u64,
         newname: &OsStr,
         reply: ReplyEntry,
     ) {
-        match sql::link(&self.conn, ino, newparent, newname.to_str().unwrap()) {
-            Err(err) => {
-                eprintln!("link {}", err);
-                reply.error(ECONNREFUSED)
+        let ino = self.db_ino(ino);
+        let newparent = self.db_ino(newparent);
+        let _op = self.metrics.start("link");
+        let _span = logging::span("link", ino, None);
+        if is_ctl(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        if let Err(errno) = self
+            .check_entry(newparent, newname)
+            .and_then(|_| self.check_access(req, "link", newparent, W_OK | X_OK))
+        {
+            reply.error(errno);
+            return;
+        }
+        self.invalidate_dentry(newparent, newname);
+        self.cache().remove(&Key::Attr(ino));
+        match self.conn.link(ino, newparent, newname.as_bytes()) {
+            Err(err) => reply.error(self.mutation_error("link", err).errno()),
+            Ok((attr, generation)) => reply.entry(&TTL, &self.present_attr(attr), generation),
+        };
+    }
+
+    /// Open a file.
+    /// Open flags (with the exception of O_CREAT, O_EXCL, O_NOCTTY and O_TRUNC) are
+    /// available in flags. Filesystem may store an arbitrary file handle (pointer, index,
+    /// etc) in fh, and use this in other all other file operations (read, write, flush,
+    /// release, fsync).
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("open");
+        let _span = logging::span("open", ino, None);
+        debug!("open {} {:o}", ino, flags);
+        #[cfg(target_os = "linux")]
+        {
+            if self.opts.noexec && flags & FMODE_EXEC != 0 {
+                reply.error(EACCES);
+                return;
             }
-            Ok(None) => reply.error(ENOENT),
-            Ok(Some(attr)) => reply.entry(&TTL, &attr, 0),
+        }
+        let attr = match self.attr("open", ino) {
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+            Ok((attr, _)) => attr,
         };
+        let mask = access_mask(flags);
+        if !self.permitted(req, &attr, mask) {
+            reply.error(EACCES);
+            return;
+        }
+        if let Some(file) = ctl_file(ino) {
+            if mask & W_OK != 0 {
+                reply.error(EROFS);
+                return;
+            }
+            let contents = self.ctl_contents(file).into_bytes();
+            let fh = self.open_handle(attr, flags);
+            if let Some(handle) = self.handles.lock().unwrap().get_mut(&fh) {
+                handle.contents = Some(contents);
+            }
+            // Reads bypass the page cache, which would otherwise stop at the
+            // size of 0 that the file's attributes give.
+            reply.opened(fh, FOPEN_DIRECT_IO);
+            return;
+        }
+        reply.opened(self.open_handle(attr, flags), 0);
+    }
+
+    /// Release an open file.
+    /// Called when there are no more references to an open file: all file descriptors
+    /// are closed and all memory mappings are unmapped.
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("release");
+        let _span = logging::span("release", ino, None);
+        // Writes are normally flushed when the file is closed, but release
+        // also follows the last close of a memory mapping.
+        if let Err(errno) = self.flush_handle(fh) {
+            error!("release: dropped buffered writes ({})", errno);
+        }
+        let handle = self.handles.lock().unwrap().remove(&fh);
+        if let Some(handle) = handle {
+            if handle.buffered {
+                self.release_write_lease(handle.attr.ino);
+            }
+        }
+        reply.ok();
     }
 
     /// Read data.
@@ -227,19 +2044,83 @@ impl Filesystem for CockroachFS {
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         reply: ReplyData,
     ) {
-        println!("read");
-        match sql::read_data(&self.conn, ino, offset, size as usize) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("read");
+        let _span = logging::span("read", ino, None);
+        let _class = sql::read_class(ReadClass::Read);
+        debug!("read");
+        if let Some(handle) = self.handles.lock().unwrap().get(&fh) {
+            if !handle.readable() {
+                reply.error(EBADF);
+                return;
+            }
+            if handle.attr.kind == FileType::Directory {
+                reply.error(EISDIR);
+                return;
+            }
+            if let Some(ref contents) = handle.contents {
+                let start = (offset as usize).min(contents.len());
+                let end = (start + size as usize).min(contents.len());
+                reply.data(&contents[start..end]);
+                return;
+            }
+        }
+        if let Err(errno) = self.flush_ino(ino) {
+            reply.error(errno);
+            return;
+        }
+        self.record_access(ino);
+        self.readahead(fh, ino, offset, size as usize);
+        self.cache_prefetched();
+        if let Some(ttl) = self.opts.block_ttl {
+            if let Some(data) = self.cached_read(ino, offset, size as usize, Some(ttl)) {
+                reply.data(data.as_slice());
+                return;
+            }
+        } else if let Some(pool) = self.pool() {
+            // Blocks read on the pool are not cached, so the pool only serves
+            // reads while the block cache is disabled.
+            pool.execute(move |conn| {
+                let _class = sql::read_class(ReadClass::Read);
+                match conn.read_data(ino, offset, size as usize) {
+                    Err(err) => {
+                        warn!("read {}", err);
+                        reply.error(CrfsError::from(err).errno())
+                    }
+                    Ok(None) => reply.error(ENOENT),
+                    Ok(Some(data)) => reply.data(data.as_slice()),
+                }
+                drop(_op);
+            });
+            return;
+        }
+        match self.read_retrying(|conn| conn.read_data(ino, offset, size as usize)) {
+            Err(ref err) if self.serve_offline(err) => {
+                match self.cached_read(ino, offset, size as usize, None) {
+                    Some(data) => {
+                        warn!("read {}, serving stale data", err);
+                        reply.data(data.as_slice())
+                    }
+                    None => {
+                        warn!("read {}", err);
+                        reply.error(err.errno())
+                    }
+                }
+            }
             Err(err) => {
-                eprintln!("read {}", err);
-                reply.error(ECONNREFUSED)
+                warn!("read {}", err);
+                reply.error(self.db_error(err).errno())
             }
             Ok(None) => reply.error(ENOENT),
-            Ok(Some(data)) => reply.data(data.as_slice()),
+            Ok(Some(data)) => {
+                self.cache_blocks(ino, offset, size as usize, &data);
+                reply.data(data.as_slice())
+            }
         };
     }
 
@@ -251,30 +2132,431 @@ impl Filesystem for CockroachFS {
     /// will be undefined if the open method didn't set any value.
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _flags: u32,
         reply: ReplyWrite,
     ) {
-        println!("write {} bytes to {}", data.len(), ino);
-        match sql::write_data(&self.conn, ino, offset, data) {
-            Err(err) => {
-                eprintln!("write {}", err);
-                reply.error(ECONNREFUSED)
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("write");
+        let _span = logging::span("write", ino, None);
+        debug!("write {} bytes to {}", data.len(), ino);
+        let handle = self
+            .handles
+            .lock()
+            .unwrap()
+            .get(&fh)
+            .map(|handle| (handle.writable(), handle.flags, handle.buffered));
+        let mut buffered = false;
+        let append = match handle {
+            Some((false, _, _)) => {
+                reply.error(EBADF);
+                return;
             }
-            Ok(None) => reply.error(ENOENT),
-            Ok(Some(size)) => reply.written(size as u32),
+            Some((true, flags, b)) => {
+                buffered = b;
+                flags & O_APPEND as u32 != 0
+            }
+            None => {
+                // Access was not checked when the file was opened.
+                if let Err(errno) = self.check_access(req, "write", ino, W_OK) {
+                    reply.error(errno);
+                    return;
+                }
+                false
+            }
+        };
+        // The kernel's idea of the end of the file may be stale if another
+        // client has written to it since, so appends are placed at the end
+        // of the file as stored, which is only known once written.
+        self.cache().remove(&Key::Attr(ino));
+        self.block_epoch += 1;
+        let block_size = sql::block_size();
+        if append {
+            self.cache()
+                .remove_matching(|key, _| matches!(*key, Key::Block(i, _) if i == ino));
+        } else {
+            let first = offset / block_size;
+            let last = (offset + data.len() as i64) / block_size;
+            for block in first..=last {
+                self.cache().remove(&Key::Block(ino, block));
+            }
+        }
+        if self.opts.offline_reads || self.opts.block_ttl.is_some() {
+            // The write may extend the file past its cached final block,
+            // which would then be mistaken for the end of the file.
+            let block_size = block_size as usize;
+            self.cache()
+                .remove_matching(|key, value| match (key, value) {
+                    (Key::Block(i, _), Value::Block(bytes, _)) => {
+                        *i == ino && bytes.len() < block_size
+                    }
+                    _ => false,
+                });
+        }
+        if buffered && !self.take_write_lease(ino, LeaseConflict::WriteThrough) {
+            if let Err(errno) = self.lose_write_lease(ino) {
+                reply.error(errno);
+                return;
+            }
+            buffered = false;
+        }
+        if buffered && !append {
+            if buffer_write(&self.handles, fh, offset, data) {
+                if let Err(errno) = self.flush_handle(fh) {
+                    reply.error(errno);
+                    return;
+                }
+            }
+            reply.written(data.len() as u32);
+            return;
+        }
+        if let Some(pool) = self.pool() {
+            let handles = self.handles.clone();
+            let data = data.to_vec();
+            let at = if append { None } else { Some(offset) };
+            pool.execute(move |conn| {
+                match conn.write_data(ino, at, &data) {
+                    Err(err) => {
+                        if let Some(db) = err.backend() {
+                            warn!("write {}", db);
+                        }
+                        reply.error(err.errno())
+                    }
+                    Ok(offset) => {
+                        let size = data.len();
+                        extend_handle(&handles, fh, (offset + size as i64) as u64);
+                        reply.written(size as u32)
+                    }
+                }
+                drop(_op);
+            });
+            return;
+        }
+        match self.write_through(fh, ino, offset, append, data) {
+            Err(errno) => reply.error(errno),
+            Ok(size) => reply.written(size as u32),
+        };
+    }
+
+    /// Flush method.
+    /// Called on each close() of an opened file. Journaled writes are given a
+    /// chance to reach the database, and any error from an earlier write
+    /// through the handle is reported, since this is where close() expects to
+    /// see it. Since every descriptor the lock owner holds is being closed,
+    /// its POSIX locks are released, if it may hold any.
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("flush");
+        let _span = logging::span("flush", ino, None);
+        if let Err(errno) = self.flush_handle(fh) {
+            reply.error(errno);
+            return;
+        }
+        self.replay_journal();
+        if self
+            .lock_owners
+            .lock()
+            .unwrap()
+            .contains(&(ino, lock_owner))
+        {
+            if let Err(err) = self.conn.release_locks(ino, &self.session, lock_owner) {
+                reply.error(self.mutation_error("flush", err.into()).errno());
+                return;
+            }
+            self.lock_owners.lock().unwrap().remove(&(ino, lock_owner));
+        }
+        match self
+            .handles
+            .lock()
+            .unwrap()
+            .get_mut(&fh)
+            .and_then(|handle| handle.error.take())
+        {
+            Some(errno) => reply.error(errno),
+            None => reply.ok(),
         };
     }
 
     /// Synchronize file contents.
     /// If the datasync parameter is non-zero, then only the user data should be flushed,
     /// not the meta data.
-    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        reply.ok()
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("fsync");
+        let _span = logging::span("fsync", ino, None);
+        match self.flush_handle(fh) {
+            Err(errno) => reply.error(errno),
+            Ok(()) => reply.ok(),
+        };
+    }
+
+    /// Test for a POSIX file lock.
+    fn getlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("getlk");
+        let _span = logging::span("getlk", ino, None);
+        // Locks are read on the primary connection, since a reader may serve
+        // stale reads that would miss locks just taken.
+        match self
+            .conn
+            .conflicting_lock(ino, &self.session, lock_owner, start, end, typ)
+        {
+            Err(err) => {
+                warn!("getlk {}", err);
+                reply.error(self.db_error(err.into()).errno())
+            }
+            Ok(None) => reply.locked(start, end, F_UNLCK as u32, pid),
+            Ok(Some(lock)) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+        };
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    /// Locks are stored in the database so that they are seen by every mount.
+    /// Blocking requests wait on the pool, if there is one, for up to
+    /// LOCK_WAIT before failing with EINTR. Without a pool they wait on the
+    /// session thread, where a lock held through this mount could never be
+    /// released meanwhile, so such a conflict fails with EDEADLK.
+    fn setlk(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("setlk");
+        let _span = logging::span("setlk", ino, None);
+        let lock = sql::Lock {
+            session: self.session.clone(),
+            start,
+            end,
+            typ,
+            pid,
+        };
+        if typ != F_UNLCK as u32 {
+            self.lock_owners.lock().unwrap().insert((ino, lock_owner));
+        }
+        if sleep {
+            if let Some(pool) = self.pool() {
+                pool.execute(move |conn| {
+                    match take_lock(conn, ino, lock_owner, &lock, true, true) {
+                        Err(err) => {
+                            warn!("setlk {}", err);
+                            reply.error(CrfsError::from(err).errno())
+                        }
+                        Ok(Err(errno)) => reply.error(errno),
+                        Ok(Ok(())) => reply.ok(),
+                    }
+                    drop(_op);
+                });
+                return;
+            }
+        }
+        match take_lock(&self.conn, ino, lock_owner, &lock, sleep, false) {
+            Err(err) => reply.error(self.mutation_error("setlk", err.into()).errno()),
+            Ok(Err(errno)) => reply.error(errno),
+            Ok(Ok(())) => reply.ok(),
+        };
+    }
+
+    /// Get file system statistics.
+    fn statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("statfs");
+        let _span = logging::span("statfs", ino, None);
+        let _class = sql::read_class(ReadClass::Statfs);
+        let (blocks, files) = match self.read_retrying(|conn| conn.usage()) {
+            Err(err) => {
+                warn!("statfs {}", err);
+                reply.error(self.db_error(err).errno());
+                return;
+            }
+            Ok(usage) => usage,
+        };
+        let block_size = sql::block_size() as u64;
+        let free = match self.reader().store_capacity() {
+            Ok((capacity, available)) if capacity > 0 => available / block_size,
+            _ => NOMINAL_FREE,
+        };
+        reply.statfs(
+            blocks + free,
+            free,
+            free,
+            files + NOMINAL_FREE,
+            NOMINAL_FREE,
+            block_size as u32,
+            self.opts.name_max,
+            block_size as u32,
+        );
+    }
+
+    /// Set an extended attribute.
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("setxattr");
+        let _span = logging::span("setxattr", ino, Some(name));
+        if is_ctl(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                // Attribute names are stored as strings.
+                reply.error(EILSEQ);
+                return;
+            }
+        };
+        debug!("setxattr {} {}", ino, name);
+        let create = flags & XATTR_CREATE as u32 != 0;
+        let replace = flags & XATTR_REPLACE as u32 != 0;
+        match self.conn.set_xattr(ino, name, value, create, replace) {
+            Err(err) => reply.error(self.mutation_error("setxattr", err).errno()),
+            Ok(false) => reply.error(ENOATTR),
+            Ok(true) => {
+                // The change moved the inode's ctime.
+                self.cache().remove(&Key::Attr(ino));
+                reply.ok()
+            }
+        };
+    }
+
+    /// Get an extended attribute.
+    /// If size is 0, the size of the value should be sent with reply.size().
+    /// If size is not 0, and the value fits, send it with reply.data(), or
+    /// reply.error(ERANGE) if it doesn't.
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("getxattr");
+        let _span = logging::span("getxattr", ino, Some(name));
+        let _class = sql::read_class(ReadClass::Xattr);
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                // Attribute names are stored as strings.
+                reply.error(EILSEQ);
+                return;
+            }
+        };
+        debug!("getxattr {} {}", ino, name);
+        match self.read_retrying(|conn| conn.get_xattr(ino, name)) {
+            Err(err) => {
+                warn!("getxattr {}", err);
+                reply.error(self.db_error(err).errno())
+            }
+            Ok(None) => reply.error(ENOATTR),
+            Ok(Some(value)) => reply_xattr(reply, size, &value),
+        };
+    }
+
+    /// List extended attribute names.
+    /// If size is 0, the size of the value should be sent with reply.size().
+    /// If size is not 0, and the value fits, send it with reply.data(), or
+    /// reply.error(ERANGE) if it doesn't.
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("listxattr");
+        let _span = logging::span("listxattr", ino, None);
+        let _class = sql::read_class(ReadClass::Xattr);
+        debug!("listxattr {}", ino);
+        match self.read_retrying(|conn| conn.list_xattrs(ino)) {
+            Err(err) => {
+                warn!("listxattr {}", err);
+                reply.error(self.db_error(err).errno())
+            }
+            Ok(names) => {
+                let mut list = Vec::new();
+                for name in names {
+                    list.extend_from_slice(name.as_bytes());
+                    list.push(0);
+                }
+                reply_xattr(reply, size, &list)
+            }
+        };
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("removexattr");
+        let _span = logging::span("removexattr", ino, Some(name));
+        if is_ctl(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                // Attribute names are stored as strings.
+                reply.error(EILSEQ);
+                return;
+            }
+        };
+        debug!("removexattr {} {}", ino, name);
+        match self.conn.remove_xattr(ino, name) {
+            Err(err) => reply.error(self.mutation_error("removexattr", err.into()).errno()),
+            Ok(false) => reply.error(ENOATTR),
+            Ok(true) => {
+                // The change moved the inode's ctime.
+                self.cache().remove(&Key::Attr(ino));
+                reply.ok()
+            }
+        };
+    }
+
+    /// Check file access permissions.
+    /// This will be called for the access() system call. If the 'default_permissions'
+    /// mount option is given, this method is not called.
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("access");
+        let _span = logging::span("access", ino, None);
+        debug!("access {} {:o}", ino, mask);
+        let attr = match self.attr("access", ino) {
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+            Ok((attr, _)) => attr,
+        };
+        let mask = mask as c_int;
+        // Directories still need to be searchable under noexec.
+        let noexec = self.opts.noexec && mask & X_OK != 0 && attr.kind != FileType::Directory;
+        if noexec || !self.permitted(req, &attr, mask) {
+            reply.error(EACCES);
+        } else {
+            reply.ok();
+        }
     }
 
     /// Read directory.
@@ -284,43 +2566,235 @@ impl Filesystem for CockroachFS {
     /// didn't set any value.
     fn readdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        println!("readdir {} {}", ino, offset);
-        let errno = match sql::lookup_inode_kind(&self.conn, ino) {
-            Err(err) => {
-                eprintln!("readdir {}", err);
-                ECONNREFUSED
-            }
-            Ok(None) => ENOENT,
-            Ok(Some(FileType::Directory)) => 0,
-            Ok(Some(_)) => ENOTDIR,
-        };
-        if errno != 0 {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("readdir");
+        let _span = logging::span("readdir", ino, None);
+        let _class = sql::read_class(ReadClass::Readdir);
+        if let Err(errno) = self.check_access(req, "readdir", ino, R_OK) {
             reply.error(errno);
             return;
         }
-        match sql::read_dir(&self.conn, ino, offset) {
-            Err(err) => {
-                eprintln!("readdir {}", err);
-                reply.error(ECONNREFUSED)
-            }
-            Ok(ents) => {
-                for (i, ent) in ents.iter().enumerate() {
-                    reply.add(
-                        ent.child_ino,
-                        offset + 1 + (i as i64),
-                        ent.child_kind,
-                        &ent.child_name,
-                    );
+        debug!("readdir {} {}", ino, offset);
+        if ino == CTL_DIR_INO {
+            for (i, file) in CTL_FILES.iter().enumerate().skip(offset as usize) {
+                if reply.add(file.ino(), i as i64 + 1, FileType::RegularFile, file.name()) {
+                    break;
                 }
-                reply.ok();
             }
+            reply.ok();
+            return;
+        }
+        // The offset is a cookie handed out by an earlier call, naming the
+        // entry to resume the listing after.
+        if offset == 0 {
+            self.record_access(ino);
+        }
+        if self.opts.attr_ttl.is_some() {
+            self.list_dir_plus(ino, offset, reply);
+            return;
+        }
+        if let Some(pool) = self.pool() {
+            pool.execute(move |conn| {
+                let _class = sql::read_class(ReadClass::Readdir);
+                list_dir(conn, ino, offset, reply);
+                drop(_op);
+            });
+            return;
+        }
+        list_dir(self.reader(), ino, offset, reply);
+    }
+
+    /// Open a directory.
+    fn opendir(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("opendir");
+        let _span = logging::span("opendir", ino, None);
+        match self.attr("opendir", ino) {
+            Err(errno) => reply.error(errno),
+            Ok((attr, _)) if attr.kind != FileType::Directory => reply.error(ENOTDIR),
+            Ok((attr, _)) => reply.opened(self.open_handle(attr, flags), 0),
+        };
+    }
+
+    /// Release an open directory.
+    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        let ino = self.db_ino(ino);
+        let _op = self.metrics.start("releasedir");
+        let _span = logging::span("releasedir", ino, None);
+        self.handles.lock().unwrap().remove(&fh);
+        reply.ok();
+    }
+}
+
+/// Record that a write through handle fh extended the file to end.
+fn extend_handle(handles: &Handles, fh: u64, end: u64) {
+    if let Some(handle) = handles.lock().unwrap().get_mut(&fh) {
+        handle.attr.size = cmp::max(handle.attr.size, end);
+    }
+}
+
+/// Buffer a write through handle fh, coalescing it with the buffered write it
+/// continues, if any. Returns whether the handle's buffer has outgrown
+/// WRITE_BACK_LIMIT and should be flushed.
+fn buffer_write(handles: &Handles, fh: u64, offset: i64, data: &[u8]) -> bool {
+    let mut handles = handles.lock().unwrap();
+    let handle = handles.get_mut(&fh).unwrap();
+    match handle.pending.last_mut() {
+        Some((start, buf)) if *start + buf.len() as i64 == offset => buf.extend_from_slice(data),
+        _ => handle.pending.push((offset, data.to_vec())),
+    }
+    let end = (offset + data.len() as i64) as u64;
+    handle.attr.size = cmp::max(handle.attr.size, end);
+    let buffered: usize = handle.pending.iter().map(|(_, buf)| buf.len()).sum();
+    buffered >= WRITE_BACK_LIMIT
+}
+
+/// The error to reply to a listing of ino with if it is not a directory, or 0
+/// if it is one.
+fn dir_errno<S: Store>(conn: &S, ino: u64) -> c_int {
+    match conn.lookup_inode_kind(ino) {
+        Err(err) => {
+            warn!("readdir {}", err);
+            CrfsError::from(err).errno()
+        }
+        Ok(None) => ENOENT,
+        Ok(Some(FileType::Directory)) => 0,
+        Ok(Some(_)) => ENOTDIR,
+    }
+}
+
+/// Reply with the entries of directory ino after the entry given cookie.
+fn list_dir<S: Store>(conn: &S, ino: u64, cookie: i64, mut reply: ReplyDirectory) {
+    let errno = dir_errno(conn, ino);
+    if errno != 0 {
+        reply.error(errno);
+        return;
+    }
+    match conn.read_dir_from(ino, cookie, READDIR_BATCH) {
+        Err(err) => {
+            warn!("readdir {}", err);
+            reply.error(CrfsError::from(err).errno())
+        }
+        Ok(ents) => {
+            add_entries(&ents, &mut reply);
+            reply.ok();
+        }
+    };
+}
+
+/// Add entries, each with the cookie that resumes the listing after it, to
+/// a listing until the reply is full.
+fn add_entries(ents: &[(DirEntry, i64)], reply: &mut ReplyDirectory) {
+    for (ent, cookie) in ents {
+        if reply.add(
+            ent.child_ino,
+            *cookie,
+            ent.child_kind,
+            OsStr::from_bytes(&ent.child_name),
+        ) {
+            break;
+        }
+    }
+}
+
+/// Reply to getxattr or listxattr with value, or with its size if size is 0.
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+/// Whether err means the database could not be reached, rather than that it
+/// rejected the statement.
+fn unreachable(err: &postgres::Error) -> bool {
+    err.as_io().is_some() || err.as_connection().is_some()
+}
+
+/// Whether an operation failed because the database could not be reached.
+fn lost(err: &CrfsError) -> bool {
+    matches!(*err, CrfsError::Backend(ref err) if unreachable(err))
+}
+
+/// Take, change or release owner's lock, waiting up to LOCK_WAIT for a
+/// conflicting lock to be released if wait is set. A lock held through this
+/// mount only ends the wait with EDEADLK unless on_pool is set, since it
+/// could only be released by the session thread.
+fn take_lock<S: Store>(
+    conn: &S,
+    ino: u64,
+    owner: u64,
+    lock: &sql::Lock,
+    wait: bool,
+    on_pool: bool,
+) -> postgres::Result<Result<(), c_int>> {
+    let deadline = Instant::now() + LOCK_WAIT;
+    loop {
+        match conn.set_lock(ino, &lock.session, owner, lock, LOCK_LEASE) {
+            Err(ref err) if err.code() == Some(&error::T_R_SERIALIZATION_FAILURE) => {}
+            Err(err) => return Err(err),
+            Ok(None) => return Ok(Ok(())),
+            Ok(Some(_)) if !wait => return Ok(Err(EAGAIN)),
+            Ok(Some(ref held)) if held.session == lock.session && !on_pool => {
+                return Ok(Err(EDEADLK))
+            }
+            Ok(Some(_)) => {}
+        }
+        if Instant::now() >= deadline {
+            return Ok(Err(EINTR));
+        }
+        thread::sleep(LOCK_POLL);
+    }
+}
+
+/// Renew the session's locks every third of LOCK_LEASE while any may be
+/// held, until stop is dropped.
+fn renew_locks<S: Store>(
+    connector: pool::Connector<S>,
+    session: String,
+    owners: LockOwners,
+    stop: Receiver<()>,
+) {
+    let mut conn: Option<S> = None;
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(LOCK_LEASE / 3) {
+        if owners.lock().unwrap().is_empty() {
+            continue;
+        }
+        let broken = match conn {
+            Some(ref conn) => conn.broken(),
+            None => true,
         };
+        if broken {
+            conn = match connector() {
+                Ok(conn) => Some(conn),
+                Err(err) => {
+                    warn!("lock renewal {}", err);
+                    continue;
+                }
+            };
+        }
+        if let Err(err) = conn.as_ref().unwrap().renew_locks(&session, LOCK_LEASE) {
+            warn!("lock renewal {}", err);
+        }
+    }
+}
+
+/// The access, as a combination of R_OK and W_OK, that opening a file with
+/// flags requires.
+fn access_mask(flags: u32) -> c_int {
+    match flags & O_ACCMODE as u32 {
+        f if f == O_RDONLY as u32 => R_OK,
+        f if f == O_WRONLY as u32 => W_OK,
+        _ => R_OK | W_OK,
     }
 }
 
@@ -349,3 +2823,250 @@ fn optional_kind_and_perm_from_mode(mode: Option<u32>) -> (Option<FileType>, Opt
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::store::{BlockStore, MetadataStore};
+    use libc::{ECONNREFUSED, ESTALE, F_RDLCK, F_WRLCK, O_RDWR};
+
+    /// A mount of the filesystem kept in store, which is given a root if it
+    /// has none yet.
+    fn mount(store: &MemoryStore, opts: MountOptions) -> CockroachFS<MemoryStore> {
+        let opts = MountOptions {
+            cache_size: 1 << 20,
+            ..opts
+        };
+        store.create_root(0, 0).unwrap();
+        let mut fs = CockroachFS::new(store.clone(), opts);
+        fs.session = store.new_session().unwrap();
+        fs
+    }
+
+    fn create(store: &MemoryStore, name: &str) -> FileAttr {
+        let kind = FileType::RegularFile;
+        let (attr, _) = store
+            .create_inode(FUSE_ROOT_ID, name.as_bytes(), kind, 0o644, 0, (0, 0))
+            .unwrap();
+        attr
+    }
+
+    #[test]
+    fn errnos() {
+        let store = MemoryStore::new();
+        let mut fs = mount(&store, MountOptions::default());
+        let file = create(&store, "file");
+        assert_eq!(fs.attr("getattr", 1000).unwrap_err(), ENOENT);
+        assert_eq!(dir_errno(&store, FUSE_ROOT_ID), 0);
+        assert_eq!(dir_errno(&store, file.ino), ENOTDIR);
+        assert_eq!(dir_errno(&store, 1000), ENOENT);
+
+        // A file removed while open is stale.
+        let fh = fs.open_handle(file, O_WRONLY as u32);
+        store.unlink(FUSE_ROOT_ID, b"file", false).unwrap();
+        assert_eq!(
+            fs.write_through(fh, file.ino, 0, false, b"data"),
+            Err(ESTALE)
+        );
+
+        // An unreachable database is ECONNREFUSED, or EIO once a broken
+        // connection could not be replaced in time.
+        store.set_unreachable(true);
+        assert_eq!(fs.attr("getattr", FUSE_ROOT_ID).unwrap_err(), ECONNREFUSED);
+        assert_eq!(dir_errno(&store, FUSE_ROOT_ID), ECONNREFUSED);
+        let mut fs = fs.with_connector(Box::new(|| {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "unreachable",
+            ))
+        }));
+        fs.opts.reconnect_timeout = Some(Duration::from_millis(0));
+        assert_eq!(fs.attr("getattr", FUSE_ROOT_ID).unwrap_err(), EIO);
+    }
+
+    #[test]
+    fn offline_reads() {
+        let store = MemoryStore::new();
+        let opts = MountOptions {
+            offline_reads: true,
+            ..MountOptions::default()
+        };
+        let mut fs = mount(&store, opts);
+        let file = create(&store, "file");
+        store.write_data(file.ino, Some(0), b"data").unwrap();
+        let (attr, ttl) = fs.attr("getattr", file.ino).unwrap();
+        assert_eq!((attr.size, ttl), (4, TTL));
+
+        // Attributes read before are served, but not cached by the kernel,
+        // and mutations are refused.
+        store.set_unreachable(true);
+        let (attr, ttl) = fs.attr("getattr", file.ino).unwrap();
+        assert_eq!((attr.size, ttl), (4, STALE_TTL));
+        assert_eq!(fs.attr("getattr", FUSE_ROOT_ID).unwrap_err(), ECONNREFUSED);
+        let fh = fs.open_handle(attr, O_WRONLY as u32);
+        assert_eq!(
+            fs.write_through(fh, file.ino, 4, false, b"more"),
+            Err(EROFS)
+        );
+    }
+
+    #[test]
+    fn lock_conflicts() {
+        let store = MemoryStore::new();
+        let fs = mount(&store, MountOptions::default());
+        let ino = create(&store, "file").ino;
+        let lock = |typ: c_int| sql::Lock {
+            session: fs.session.clone(),
+            start: 0,
+            end: 99,
+            typ: typ as u32,
+            pid: 1,
+        };
+        let other = sql::Lock {
+            session: store.new_session().unwrap(),
+            ..lock(F_RDLCK)
+        };
+        let take = |owner, lock: &sql::Lock, wait| take_lock(&store, ino, owner, lock, wait, false);
+        assert_eq!(take(1, &lock(F_WRLCK), false).unwrap(), Ok(()));
+
+        // Waiting on the session thread for a lock held through this mount
+        // would never end, since only that thread could release it.
+        assert_eq!(take(2, &lock(F_RDLCK), false).unwrap(), Err(EAGAIN));
+        assert_eq!(take(2, &lock(F_RDLCK), true).unwrap(), Err(EDEADLK));
+        assert_eq!(take(1, &other, false).unwrap(), Err(EAGAIN));
+
+        // Once the lock is released, read locks are shared.
+        assert_eq!(take(1, &lock(F_UNLCK), false).unwrap(), Ok(()));
+        assert_eq!(take(2, &lock(F_RDLCK), false).unwrap(), Ok(()));
+        assert_eq!(take(1, &other, false).unwrap(), Ok(()));
+        assert_eq!(take(3, &lock(F_WRLCK), false).unwrap(), Err(EAGAIN));
+    }
+
+    #[test]
+    fn buffered_writes() {
+        let store = MemoryStore::new();
+        let opts = || MountOptions {
+            write_back: true,
+            write_lease: Duration::from_secs(60),
+            ..MountOptions::default()
+        };
+        let mut fs = mount(&store, opts());
+        let file = create(&store, "file");
+        let fh = fs.open_handle(file, O_WRONLY as u32);
+        assert!(fs.handles.lock().unwrap()[&fh].buffered);
+
+        // Writes are held back, coalesced with those they continue.
+        assert!(!buffer_write(&fs.handles, fh, 0, b"hello "));
+        assert!(!buffer_write(&fs.handles, fh, 6, b"world"));
+        assert!(!buffer_write(&fs.handles, fh, 20, b"!"));
+        {
+            let handles = fs.handles.lock().unwrap();
+            assert_eq!(handles[&fh].pending.len(), 2);
+            assert_eq!(handles[&fh].attr.size, 21);
+        }
+        assert_eq!(store.lookup_inode(file.ino).unwrap().unwrap().size, 0);
+
+        // Reading the file's attributes writes them out first.
+        assert_eq!(fs.attr("getattr", file.ino).unwrap().0.size, 21);
+        let mut expected = b"hello world".to_vec();
+        expected.resize(20, 0);
+        expected.push(b'!');
+        assert_eq!(store.read_data(file.ino, 0, 100).unwrap(), Some(expected));
+
+        // Another mount writes through while this one holds the file's write
+        // lease, and buffers once it is given up.
+        let mut other = mount(&store, opts());
+        let other_fh = other.open_handle(file, O_RDWR as u32);
+        assert!(!other.handles.lock().unwrap()[&other_fh].buffered);
+        fs.handles.lock().unwrap().remove(&fh);
+        fs.release_write_lease(file.ino);
+        let other_fh = other.open_handle(file, O_RDWR as u32);
+        assert!(other.handles.lock().unwrap()[&other_fh].buffered);
+    }
+
+    #[test]
+    fn attr_cache() {
+        let store = MemoryStore::new();
+        let opts = MountOptions {
+            attr_ttl: Some(Duration::from_secs(3600)),
+            ..MountOptions::default()
+        };
+        let mut fs = mount(&store, opts);
+        let file = create(&store, "file");
+        fs.cache_dentry(FUSE_ROOT_ID, b"file", Some((file.ino, 1)));
+        assert_eq!(fs.attr("getattr", file.ino).unwrap().0.size, 0);
+
+        // Changes made elsewhere are not seen within the TTL, unless what
+        // the mount knows of the entry is dropped.
+        store.write_data(file.ino, Some(0), b"data").unwrap();
+        assert_eq!(fs.attr("getattr", file.ino).unwrap().0.size, 0);
+        fs.invalidate_dentry(FUSE_ROOT_ID, OsStr::new("file"));
+        assert_eq!(fs.fresh_dentry(FUSE_ROOT_ID, b"file"), None);
+        assert_eq!(fs.attr("getattr", file.ino).unwrap().0.size, 4);
+    }
+
+    #[test]
+    fn dentry_cache() {
+        let store = MemoryStore::new();
+        let (file, missing) = (&b"file"[..], &b"missing"[..]);
+        let cached = |fs: &mut CockroachFS<MemoryStore>, name: &[u8]| {
+            let key = Key::Dentry(FUSE_ROOT_ID, name.to_vec());
+            fs.cache().get(&key).is_some()
+        };
+
+        // Without a TTL nothing is served from the cache, and only entries
+        // that exist are kept, for offline reads.
+        let opts = MountOptions {
+            offline_reads: true,
+            ..MountOptions::default()
+        };
+        let mut fs = mount(&store, opts);
+        fs.cache_dentry(FUSE_ROOT_ID, file, Some((2, 1)));
+        fs.cache_dentry(FUSE_ROOT_ID, missing, None);
+        assert!(cached(&mut fs, file));
+        assert!(!cached(&mut fs, missing));
+        assert_eq!(fs.fresh_dentry(FUSE_ROOT_ID, file), None);
+
+        // With one, entries that do not exist are served too, until it runs
+        // out.
+        let opts = MountOptions {
+            attr_ttl: Some(Duration::from_millis(50)),
+            ..MountOptions::default()
+        };
+        let mut fs = mount(&store, opts);
+        fs.cache_dentry(FUSE_ROOT_ID, file, Some((2, 1)));
+        fs.cache_dentry(FUSE_ROOT_ID, missing, None);
+        assert_eq!(fs.fresh_dentry(FUSE_ROOT_ID, file), Some(Some((2, 1))));
+        assert_eq!(fs.fresh_dentry(FUSE_ROOT_ID, missing), Some(None));
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(fs.fresh_dentry(FUSE_ROOT_ID, missing), None);
+    }
+
+    #[test]
+    fn block_cache() {
+        let store = MemoryStore::new();
+        let opts = MountOptions {
+            block_ttl: Some(Duration::from_secs(3600)),
+            ..MountOptions::default()
+        };
+        let mut fs = mount(&store, opts);
+        let block_size = sql::block_size() as usize;
+        let data: Vec<u8> = (0..block_size * 2 + 10).map(|i| i as u8).collect();
+
+        // A read that ends within a block only leaves whole blocks cached,
+        // unless it was short, having reached the end of the file.
+        fs.cache_blocks(7, 0, block_size + 10, &data[..block_size + 10]);
+        assert_eq!(fs.cached_read(7, 5, 10, None), Some(data[5..15].to_vec()));
+        assert_eq!(fs.cached_read(7, 0, block_size + 1, None), None);
+        fs.cache_blocks(7, block_size as i64, block_size * 2, &data[block_size..]);
+        assert_eq!(fs.cached_read(7, 0, data.len() + 100, None), Some(data));
+
+        // Blocks older than the age asked for are not served.
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            fs.cached_read(7, 0, 10, Some(Duration::from_millis(1))),
+            None
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Garbage collection of rows that are no longer reachable from the root of
+//! any filesystem: inodes that no directory entry refers to, and blocks
+//! beyond the end of their file. Both are left behind by bugs and
+//! interrupted operations of earlier versions, and by dropping a filesystem,
+//! rather than by normal operation, so collection can run slowly in the
+//! background without falling behind.
+
+use super::sql;
+use log::{info, warn};
+use postgres::{Connection, GenericConnection, Result};
+use std::thread;
+use std::time::Duration;
+
+/// Rows deleted per transaction, and the pause between transactions, when
+/// collecting in the background. This caps the rate at which background
+/// collection deletes rows at about 1000 per second.
+const BACKGROUND_BATCH: i64 = 100;
+const BACKGROUND_PAUSE: Duration = Duration::from_millis(100);
+
+/// Rows deleted per transaction when collecting in the foreground.
+pub const BATCH: i64 = 1000;
+
+/// Delete unreachable inodes and blocks, batch rows at a time with a pause
+/// between batches. Returns the number of inodes and blocks deleted.
+pub fn collect<C: GenericConnection>(conn: &C, batch: i64, pause: Duration) -> Result<(u64, u64)> {
+    let roots = sql::roots(conn)?;
+    let inodes = repeat(batch, pause, || {
+        sql::delete_orphaned_inodes(conn, &roots, batch)
+    })?;
+    let blocks = collect_blocks(conn, batch, pause)?;
+    Ok((inodes, blocks))
+}
+
+/// Delete the inodes of the dropped filesystem fs_id and their blocks, batch
+/// rows at a time with a pause between batches. Returns the number of inodes
+/// deleted.
+pub fn collect_filesystem<C: GenericConnection>(
+    conn: &C,
+    fs_id: i64,
+    batch: i64,
+    pause: Duration,
+) -> Result<u64> {
+    // Once nothing refers to them, all of its inodes are orphans.
+    repeat(batch, pause, || sql::unlink_filesystem(conn, fs_id, batch))?;
+    let roots = sql::roots(conn)?;
+    repeat(batch, pause, || {
+        sql::delete_orphaned_inodes(conn, &roots, batch)
+    })
+}
+
+/// Delete the blocks beyond the end of their file, batch rows at a time with
+/// a pause between batches. Returns the number deleted.
+pub fn collect_blocks<C: GenericConnection>(conn: &C, batch: i64, pause: Duration) -> Result<u64> {
+    repeat(batch, pause, || sql::delete_blocks_past_eof(conn, batch))
+}
+
+/// Run op, which deletes up to batch rows, until it deletes fewer, pausing
+/// between runs. Returns the total deleted.
+fn repeat<F: FnMut() -> Result<u64>>(batch: i64, pause: Duration, mut op: F) -> Result<u64> {
+    let mut deleted = 0;
+    loop {
+        let n = op()?;
+        deleted += n;
+        if (n as i64) < batch {
+            return Ok(deleted);
+        }
+        thread::sleep(pause);
+    }
+}
+
+/// Collect garbage on conn every interval from a background thread, at a
+/// limited rate so as not to disturb the filesystem's own traffic.
+pub fn run_in_background(conn: Connection, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match collect(&conn, BACKGROUND_BATCH, BACKGROUND_PAUSE) {
+            Err(err) => warn!("gc {}", err),
+            Ok((0, 0)) => {}
+            Ok((inodes, blocks)) => info!(
+                "gc deleted {} orphaned inodes and {} blocks past the end of their file",
+                inodes, blocks
+            ),
+        }
+    });
+}
@@ -0,0 +1,493 @@
+//! A minimal HTTP/1.1 server shared by the frontends that serve the
+//! filesystem over HTTP instead of FUSE. Each connection carries a single
+//! request, served on a pooled database connection, and is then closed.
+
+use super::error::CrfsError;
+use super::import::read_full;
+use super::pool::Pool;
+use super::sql;
+use fuse::FileType;
+use log::{debug, warn};
+use postgres::Connection;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpListener;
+use std::process;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Longest request head accepted.
+const MAX_HEAD: u64 = 64 << 10;
+
+/// Most bytes of a request body left unread by its handler that are read and
+/// thrown away before answering, so that closing the connection does not
+/// reset it.
+const MAX_DISCARD: u64 = 1 << 20;
+
+/// Blocks of file data read or written per query.
+const IO_BLOCKS: i64 = 256;
+
+/// Start of the names of the files uploads are written to before they
+/// replace their target, which frontends leave out of listings.
+pub const UPLOAD_PREFIX: &str = ".upload-";
+
+/// Serves a request, given its body.
+pub type Handler = Arc<dyn Fn(&Connection, &Request, &mut dyn Read) -> Response + Send + Sync>;
+
+pub struct Request {
+    pub method: String,
+    /// Path of the request, decoded.
+    pub path: Vec<u8>,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub enum Body {
+    Empty,
+    /// Text of the given content type.
+    Text(&'static str, String),
+    /// A range of the data of a file.
+    File {
+        ino: u64,
+        start: u64,
+        len: u64,
+    },
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: u16, body: Body) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body,
+        }
+    }
+}
+
+/// Serve requests accepted on listener from the pool's connections with
+/// handler.
+pub fn serve(listener: TcpListener, pool: Pool, handler: Handler) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("http: accept {}", err);
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        pool.execute(move |conn| {
+            let res = stream.try_clone().and_then(|reader| {
+                let mut reader = BufReader::new(reader);
+                let mut out = BufWriter::new(stream);
+                let req = match read_request(&mut reader) {
+                    Ok(req) => req,
+                    Err(err) => {
+                        let res = Response::new(400, Body::Text("text/plain", err.to_string()));
+                        return write_response(conn, &mut out, res, false);
+                    }
+                };
+                debug!(
+                    "http: {} {}",
+                    req.method,
+                    String::from_utf8_lossy(&req.path)
+                );
+                let mut body = match body(&req, &mut reader) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        let res = Response::new(400, Body::Text("text/plain", err.to_string()));
+                        return write_response(conn, &mut out, res, false);
+                    }
+                };
+                if req.header("expect") == Some("100-continue") {
+                    out.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                    out.flush()?;
+                }
+                let res = handler(conn, &req, &mut body);
+                let _ = io::copy(&mut (&mut body).take(MAX_DISCARD), &mut io::sink());
+                write_response(conn, &mut out, res, req.method == "HEAD")
+            });
+            if let Err(err) = res {
+                debug!("http: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Read a request's line and headers.
+fn read_request<R: BufRead>(r: &mut R) -> io::Result<Request> {
+    let mut lines = Vec::new();
+    let mut r = r.take(MAX_HEAD);
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "incomplete or oversized request head",
+            ));
+        }
+        let line = line.trim_end().to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid request line");
+    let mut request_line = lines.first().ok_or_else(invalid)?.split(' ');
+    let method = request_line.next().ok_or_else(invalid)?.to_string();
+    let target = request_line.next().ok_or_else(invalid)?;
+    let (path, query) = match target.find('?') {
+        Some(idx) => (&target[..idx], &target[idx + 1..]),
+        None => (target, ""),
+    };
+    let query = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = match param.find('=') {
+                Some(idx) => (&param[..idx], &param[idx + 1..]),
+                None => (param, ""),
+            };
+            (decode_param(name), decode_param(value))
+        })
+        .collect();
+    let headers = lines[1..]
+        .iter()
+        .filter_map(|line| {
+            let idx = line.find(':')?;
+            Some((
+                line[..idx].trim().to_string(),
+                line[idx + 1..].trim().to_string(),
+            ))
+        })
+        .collect();
+    Ok(Request {
+        method,
+        path: percent_decode(path.as_bytes()),
+        query,
+        headers,
+    })
+}
+
+/// The body of req, read from r.
+fn body<'a, R: BufRead>(req: &Request, r: &'a mut R) -> io::Result<Box<dyn Read + 'a>> {
+    let length =
+        match req.header("content-length") {
+            Some(length) => Some(length.parse::<u64>().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "invalid Content-Length")
+            })?),
+            None => None,
+        };
+    if chunked(req) {
+        Ok(Box::new(Chunked {
+            inner: r.take(length.unwrap_or(u64::MAX)),
+            remaining: 0,
+            done: false,
+        }))
+    } else {
+        // A request with neither a length nor chunks has no body.
+        Ok(Box::new(r.take(length.unwrap_or(0))))
+    }
+}
+
+/// Whether req says how long its body is, by its length or by chunks.
+pub fn framed(req: &Request) -> bool {
+    req.header("content-length").is_some() || chunked(req)
+}
+
+fn chunked(req: &Request) -> bool {
+    // Signed S3 streaming uploads frame the body as chunks, much like HTTP
+    // chunked transfer encoding, with a signature among each chunk's
+    // extensions.
+    req.header("transfer-encoding") == Some("chunked")
+        || req
+            .header("x-amz-content-sha256")
+            .is_some_and(|sha| sha.starts_with("STREAMING-"))
+}
+
+/// Store body as name in directory dir, owned by owner. The body is written
+/// to a temporary file that then replaces name, so that readers never see a
+/// partial file. Fails as replacing name with rename_dir_ent does, or with
+/// Stale if the temporary file was removed meanwhile.
+pub fn put_file(
+    conn: &Connection,
+    dir: u64,
+    name: &[u8],
+    owner: (u32, u32),
+    body: &mut dyn Read,
+) -> Result<(), CrfsError> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let temp = format!("{}{}-{}", UPLOAD_PREFIX, process::id(), nanos);
+    let (attr, _) = sql::create_inode(
+        conn,
+        dir,
+        temp.as_bytes(),
+        FileType::RegularFile,
+        0o644,
+        0,
+        owner,
+    )?;
+    let res = write_file(conn, attr.ino, body)
+        .and_then(|()| sql::rename_dir_ent(conn, dir, temp.as_bytes(), dir, name));
+    if res.is_err() {
+        let _ = sql::unlink(conn, dir, temp.as_bytes(), false);
+    }
+    res
+}
+
+/// Write body to the file ino from its start. Fails with Stale if the file
+/// was removed meanwhile.
+fn write_file(conn: &Connection, ino: u64, body: &mut dyn Read) -> Result<(), CrfsError> {
+    let mut buf = vec![0; (sql::block_size() * IO_BLOCKS) as usize];
+    let mut offset = 0;
+    loop {
+        let len = read_full(body, &mut buf).map_err(postgres::Error::from)?;
+        if len == 0 {
+            return Ok(());
+        }
+        sql::write_data(conn, ino, Some(offset), &buf[..len])?;
+        offset += len as i64;
+        if len < buf.len() {
+            return Ok(());
+        }
+    }
+}
+
+fn write_response<W: Write>(
+    conn: &Connection,
+    out: &mut W,
+    res: Response,
+    head: bool,
+) -> io::Result<()> {
+    let length = match res.body {
+        Body::Empty => 0,
+        Body::Text(_, ref text) => text.len() as u64,
+        Body::File { len, .. } => len,
+    };
+    write!(out, "HTTP/1.1 {} {}\r\n", res.status, reason(res.status))?;
+    for (name, value) in &res.headers {
+        write!(out, "{}: {}\r\n", name, value)?;
+    }
+    if let Body::Text(content_type, _) = res.body {
+        write!(out, "Content-Type: {}\r\n", content_type)?;
+    }
+    write!(
+        out,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        length
+    )?;
+    if !head {
+        match res.body {
+            Body::Empty => {}
+            Body::Text(_, text) => out.write_all(text.as_bytes())?,
+            Body::File { ino, start, len } => {
+                let chunk = (sql::block_size() * IO_BLOCKS) as u64;
+                let mut offset = start;
+                while offset < start + len {
+                    let size = chunk.min(start + len - offset) as usize;
+                    let data = sql::read_data(conn, ino, offset as i64, size)?.unwrap_or_default();
+                    if data.is_empty() {
+                        // The file shrank, so the client sees a short body.
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "file shrank while being sent",
+                        ));
+                    }
+                    out.write_all(&data)?;
+                    offset += data.len() as u64;
+                }
+            }
+        }
+    }
+    out.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        507 => "Insufficient Storage",
+        _ => "Internal Server Error",
+    }
+}
+
+fn decode_param(s: &str) -> String {
+    let s = s.replace('+', " ");
+    String::from_utf8_lossy(&percent_decode(s.as_bytes())).into_owned()
+}
+
+pub fn percent_decode(s: &[u8]) -> Vec<u8> {
+    let hex = |b: u8| (b as char).to_digit(16);
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        let digits = (
+            s.get(i + 1).and_then(|&b| hex(b)),
+            s.get(i + 2).and_then(|&b| hex(b)),
+        );
+        match (s[i], digits) {
+            (b'%', (Some(hi), Some(lo))) => {
+                out.push((hi * 16 + lo) as u8);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Encode a path for use in a URL, leaving its slashes as they are.
+pub fn percent_encode(s: &[u8]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for &b in s {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// The start and length of the single byte range spec asks for of a file of
+/// size bytes, or None if it cannot be satisfied.
+pub fn parse_range(spec: &str, size: u64) -> Option<(u64, u64)> {
+    let spec = spec.strip_prefix("bytes=")?;
+    let idx = spec.find('-')?;
+    let (first, last) = (&spec[..idx], &spec[idx + 1..]);
+    let (start, end) = if first.is_empty() {
+        let suffix: u64 = last.parse().ok()?;
+        (size.saturating_sub(suffix), size)
+    } else {
+        let start = first.parse().ok()?;
+        let end = match last {
+            "" => size,
+            last => last.parse::<u64>().ok()?.saturating_add(1).min(size),
+        };
+        (start, end)
+    };
+    if start < end {
+        Some((start, end - start))
+    } else {
+        None
+    }
+}
+
+/// Reads a body framed as chunks, each preceded by its length in hex.
+struct Chunked<R> {
+    inner: R,
+    /// Bytes left of the current chunk.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let mut line = String::new();
+            self.inner.read_line(&mut line)?;
+            let size = line.trim().split(';').next().unwrap_or("");
+            self.remaining = u64::from_str_radix(size, 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+            if self.remaining == 0 {
+                // Skip any trailers.
+                loop {
+                    line.clear();
+                    if self.inner.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                }
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let n = (&mut self.inner).take(self.remaining).read(buf)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated chunk",
+            ));
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            let mut crlf = String::new();
+            self.inner.read_line(&mut crlf)?;
+        }
+        Ok(n)
+    }
+}
+
+/// An entity tag for the contents of a file, which changes whenever the file
+/// is written.
+pub fn etag(attr: &fuse::FileAttr) -> String {
+    format!(
+        "\"{:x}-{:x}.{:x}-{:x}\"",
+        attr.ino, attr.mtime.sec, attr.mtime.nsec, attr.size
+    )
+}
+
+pub fn iso_time(t: time::Timespec) -> String {
+    time::at_utc(t)
+        .strftime("%Y-%m-%dT%H:%M:%SZ")
+        .map(|t| t.to_string())
+        .unwrap_or_default()
+}
+
+pub fn http_time(t: time::Timespec) -> String {
+    time::at_utc(t).rfc822().to_string()
+}
+
+/// Escape s for use in XML text or attributes.
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,58 @@
+//! Translation between the owner ids stored in the database and the uids and
+//! gids of the local host.
+
+use std::collections::HashMap;
+
+/// A bidirectional mapping of ids. Ids without an entry map to themselves.
+#[derive(Debug, Default)]
+pub struct IdMap {
+    to_local: HashMap<u32, u32>,
+    to_stored: HashMap<u32, u32>,
+}
+
+impl IdMap {
+    /// Build a mapping from "stored:local" pairs.
+    pub fn parse<'a, I: IntoIterator<Item = &'a str>>(pairs: I) -> Result<IdMap, String> {
+        let mut map = IdMap::default();
+        for pair in pairs {
+            let (stored, local) = parse_pair(pair)?;
+            if map.to_local.insert(stored, local).is_some() {
+                return Err(format!("id {} mapped more than once", stored));
+            }
+            if map.to_stored.insert(local, stored).is_some() {
+                return Err(format!("id {} mapped to more than once", local));
+            }
+        }
+        Ok(map)
+    }
+
+    /// Translate an id stored in the database to the local id.
+    pub fn local(&self, id: u32) -> u32 {
+        *self.to_local.get(&id).unwrap_or(&id)
+    }
+
+    /// Translate a local id to the id stored in the database.
+    pub fn stored(&self, id: u32) -> u32 {
+        *self.to_stored.get(&id).unwrap_or(&id)
+    }
+}
+
+fn parse_pair(pair: &str) -> Result<(u32, u32), String> {
+    let mut parts = pair.splitn(2, ':');
+    let stored = parts.next().unwrap();
+    let local = match parts.next() {
+        Some(local) => local,
+        None => {
+            return Err(format!(
+                "invalid id mapping {:?}, expected stored:local",
+                pair
+            ))
+        }
+    };
+    let parse_id = |s: &str| {
+        s.trim()
+            .parse::<u32>()
+            .map_err(|e| format!("invalid id {:?} in mapping {:?}: {}", s, pair, e))
+    };
+    Ok((parse_id(stored)?, parse_id(local)?))
+}
@@ -0,0 +1,152 @@
+//! Bulk loading of a local directory tree into the filesystem, written
+//! straight through SQL in large batches rather than through a mount.
+
+use super::sql::{self, NewInode};
+use fuse::FileType;
+use postgres::GenericConnection;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use time::Timespec;
+
+/// Inodes created per transaction.
+const INODE_BATCH: usize = 1000;
+
+/// Bytes of file data written per transaction.
+const DATA_BATCH: usize = 4 << 20;
+
+/// What an import loaded.
+#[derive(Default)]
+pub struct Summary {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// Load the contents of the local directory local into directory dir,
+/// keeping their owners, permissions and modification times. Files linked
+/// more than once within local are loaded once and then linked.
+pub fn import<C: GenericConnection>(conn: &C, local: &Path, dir: u64) -> io::Result<Summary> {
+    let mut summary = Summary::default();
+    let mut data = Vec::new();
+    let mut data_len = 0;
+    let mut linked: HashMap<(u64, u64), u64> = HashMap::new();
+    let mut pending = vec![(local.to_path_buf(), dir)];
+    while let Some((path, dir)) = pending.pop() {
+        let mut entries = fs::read_dir(&path)?
+            .map(|ent| ent.map(|ent| ent.path()))
+            .collect::<io::Result<Vec<PathBuf>>>()?;
+        entries.sort();
+        for batch in entries.chunks(INODE_BATCH) {
+            let mut inodes = Vec::new();
+            let mut sources = Vec::new();
+            for path in batch {
+                let meta = fs::symlink_metadata(path)?;
+                let name = path.file_name().unwrap().as_bytes().to_vec();
+                if meta.is_file() && meta.nlink() > 1 {
+                    if let Some(&ino) = linked.get(&(meta.dev(), meta.ino())) {
+                        sql::link(conn, ino, dir, &name)?;
+                        summary.entries += 1;
+                        continue;
+                    }
+                }
+                inodes.push(new_inode(path, name, &meta)?);
+                sources.push((path, meta));
+            }
+            let inos = sql::create_inodes(conn, dir, &inodes)?;
+            summary.entries += inos.len() as u64;
+            for ((path, meta), ino) in sources.into_iter().zip(inos) {
+                if meta.is_dir() {
+                    pending.push((path.clone(), ino));
+                } else if meta.is_file() {
+                    if meta.nlink() > 1 {
+                        linked.insert((meta.dev(), meta.ino()), ino);
+                    }
+                    let mut file = File::open(path)?.take(meta.len());
+                    for idx in 0.. {
+                        let mut block = vec![0; sql::block_size() as usize];
+                        let len = read_full(&mut file, &mut block)?;
+                        if len == 0 {
+                            break;
+                        }
+                        data.push((ino, idx, block));
+                        data_len += len;
+                        summary.bytes += len as u64;
+                        if data_len >= DATA_BATCH {
+                            sql::write_new_blocks(conn, &data)?;
+                            data.clear();
+                            data_len = 0;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if !data.is_empty() {
+        sql::write_new_blocks(conn, &data)?;
+    }
+    Ok(summary)
+}
+
+fn new_inode(path: &Path, name: Vec<u8>, meta: &Metadata) -> io::Result<NewInode> {
+    let ft = meta.file_type();
+    let kind = if ft.is_dir() {
+        FileType::Directory
+    } else if ft.is_symlink() {
+        FileType::Symlink
+    } else if ft.is_char_device() {
+        FileType::CharDevice
+    } else if ft.is_block_device() {
+        FileType::BlockDevice
+    } else if ft.is_fifo() {
+        FileType::NamedPipe
+    } else if ft.is_socket() {
+        FileType::Socket
+    } else {
+        FileType::RegularFile
+    };
+    let target = match kind {
+        FileType::Symlink => Some(fs::read_link(path)?.to_string_lossy().into_owned()),
+        _ => None,
+    };
+    let size = match (kind, &target) {
+        (FileType::RegularFile, _) => meta.len(),
+        (_, Some(target)) => target.len() as u64,
+        _ => 0,
+    };
+    Ok(NewInode {
+        name,
+        kind,
+        perm: (meta.mode() & 0o7777) as u16,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        size,
+        rdev: fuse_rdev(meta.rdev()),
+        mtime: Timespec::new(meta.mtime(), meta.mtime_nsec() as i32),
+        target,
+    })
+}
+
+/// Re-encode a device number as glibc gives it in the form the kernel gives
+/// FUSE filesystems.
+fn fuse_rdev(dev: u64) -> u32 {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    ((minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)) as u32
+}
+
+/// Fill buf from r, short only at the end of r. Returns the bytes read.
+pub fn read_full<R: Read + ?Sized>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
@@ -0,0 +1,145 @@
+//! An on-disk journal of writes that could not reach the database, kept so
+//! that they can be replayed once it is reachable again.
+//!
+//! Each entry records the version of the inode that the write was based on.
+//! If the inode has changed in the database by the time the entry is replayed,
+//! the write conflicts and is set aside in a conflicts file instead of being
+//! applied.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub struct Entry {
+    pub ino: u64,
+    pub offset: i64,
+    /// Version of the inode the write was based on, if known.
+    pub version: Option<String>,
+    pub data: Vec<u8>,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    entries: Vec<Entry>,
+}
+
+impl Journal {
+    /// Open the journal at path, loading any entries left over from a
+    /// previous run.
+    pub fn open(path: &Path) -> io::Result<Journal> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut entries = Vec::new();
+        let mut reader = BufReader::new(&file);
+        while let Some(entry) = read_entry(&mut reader)? {
+            entries.push(entry);
+        }
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file,
+            entries,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Durably add an entry to the end of the journal.
+    pub fn append(&mut self, entry: Entry) -> io::Result<()> {
+        write_entry(&mut self.file, &entry)?;
+        self.file.sync_data()?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Drop the first n entries, which have been replayed.
+    pub fn truncate_front(&mut self, n: usize) -> io::Result<()> {
+        self.entries.drain(..n);
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for entry in &self.entries {
+            write_entry(&mut file, entry)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    /// Set aside an entry that conflicts with the current state of the
+    /// database.
+    pub fn record_conflict(&self, entry: &Entry) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.path.with_extension("conflicts"))?;
+        write_entry(&mut file, entry)?;
+        file.sync_data()
+    }
+}
+
+fn write_entry<W: Write>(w: &mut W, entry: &Entry) -> io::Result<()> {
+    let version = entry.version.as_ref().map_or(&[][..], |v| v.as_bytes());
+    let mut buf = Vec::with_capacity(24 + version.len() + entry.data.len());
+    buf.extend_from_slice(&entry.ino.to_le_bytes());
+    buf.extend_from_slice(&entry.offset.to_le_bytes());
+    buf.extend_from_slice(&(version.len() as u32).to_le_bytes());
+    buf.extend_from_slice(version);
+    buf.extend_from_slice(&(entry.data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&entry.data);
+    w.write_all(&buf)
+}
+
+/// Read the next entry, or None at the end of the journal. A partially
+/// written final entry, left by a crash mid-append, is ignored.
+fn read_entry<R: Read>(r: &mut R) -> io::Result<Option<Entry>> {
+    let mut header = [0; 20];
+    match r.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut u64_bytes = [0; 8];
+    let mut u32_bytes = [0; 4];
+    u64_bytes.copy_from_slice(&header[0..8]);
+    let ino = u64::from_le_bytes(u64_bytes);
+    u64_bytes.copy_from_slice(&header[8..16]);
+    let offset = i64::from_le_bytes(u64_bytes);
+    u32_bytes.copy_from_slice(&header[16..20]);
+    let mut version = vec![0; u32::from_le_bytes(u32_bytes) as usize];
+    let rest = r
+        .read_exact(&mut version)
+        .and_then(|_| r.read_exact(&mut u32_bytes))
+        .and_then(|_| {
+            let mut data = vec![0; u32::from_le_bytes(u32_bytes) as usize];
+            r.read_exact(&mut data).map(|_| data)
+        });
+    let data = match rest {
+        Ok(data) => data,
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let version = if version.is_empty() {
+        None
+    } else {
+        Some(
+            String::from_utf8(version)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        )
+    };
+    Ok(Some(Entry {
+        ino,
+        offset,
+        version,
+        data,
+    }))
+}
@@ -0,0 +1,242 @@
+//! Logging through the log facade, which the FUSE bindings log to as well.
+//!
+//! Records are written to stderr, one per line, as text or as JSON objects.
+//! Which are written is set by a default level followed by overrides for
+//! modules and everything beneath them, as in
+//! `info,cockroach_fuse::sql=debug,fuse=warn`. Records logged while a FUSE
+//! operation is being served carry the operation's name, inode and, if it
+//! has one, file name. The levels can be changed while running, which a
+//! mount's control socket does.
+
+use super::trace;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::cell::RefCell;
+use std::cmp;
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::{OnceLock, RwLock};
+
+/// The FUSE operation being served by a thread.
+#[derive(Clone)]
+struct Op {
+    name: &'static str,
+    ino: u64,
+    file: Option<String>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Op>> = const { RefCell::new(None) };
+}
+
+/// Marks the FUSE operation being served by the current thread until it is
+/// dropped, and traces it if tracing is enabled.
+pub struct Span {
+    outer: Option<Op>,
+    _trace: Option<trace::Span>,
+}
+
+/// Mark the current thread as serving operation op on ino, with file name
+/// name if the operation has one.
+pub fn span(op: &'static str, ino: u64, name: Option<&OsStr>) -> Span {
+    let op = Op {
+        name: op,
+        ino,
+        file: name.map(|name| name.to_string_lossy().into_owned()),
+    };
+    let mut trace = trace::start(op.name.to_string(), trace::KIND_SERVER);
+    if let Some(ref mut trace) = trace {
+        trace.set_int("fuse.ino", ino as i64);
+        if let Some(ref file) = op.file {
+            trace.set_str("fuse.name", file.clone());
+        }
+    }
+    Span {
+        outer: CURRENT.with(|current| current.replace(Some(op))),
+        _trace: trace,
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.outer.take());
+    }
+}
+
+/// The installed logger, kept to change its levels.
+static LOGGER: OnceLock<&'static Logger> = OnceLock::new();
+
+struct Levels {
+    /// The spec the levels were parsed from.
+    spec: String,
+    default: LevelFilter,
+    /// Levels of modules, longest path first so that the most specific
+    /// applies.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Levels {
+    fn parse(spec: &str) -> io::Result<Levels> {
+        let mut levels = Levels {
+            spec: spec.to_string(),
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let (module, level) = match directive.find('=') {
+                Some(eq) => (Some(&directive[..eq]), &directive[eq + 1..]),
+                None => (None, directive),
+            };
+            let level: LevelFilter = level.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid log level {:?}", level),
+                )
+            })?;
+            match module {
+                Some(module) => levels.modules.push((module.to_string(), level)),
+                None => levels.default = level,
+            }
+        }
+        levels
+            .modules
+            .sort_by_key(|(module, _)| cmp::Reverse(module.len()));
+        Ok(levels)
+    }
+
+    /// The most verbose of the levels.
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, |a, b| a.max(b))
+    }
+}
+
+struct Logger {
+    levels: RwLock<Levels>,
+    json: bool,
+}
+
+impl Logger {
+    fn level(&self, target: &str) -> LevelFilter {
+        let levels = self.levels.read().unwrap();
+        levels
+            .modules
+            .iter()
+            .find(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .map_or(levels.default, |(_, level)| *level)
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let time = time::now_utc().rfc3339().to_string();
+        let op = CURRENT.with(|current| current.borrow().clone());
+        let mut line = String::new();
+        if self.json {
+            let _ = write!(
+                line,
+                "{{\"time\":{},\"level\":{},\"target\":{},\"message\":{}",
+                json_string(&time),
+                json_string(&record.level().to_string()),
+                json_string(record.target()),
+                json_string(&record.args().to_string())
+            );
+            if let Some(op) = op {
+                let _ = write!(line, ",\"op\":{},\"ino\":{}", json_string(op.name), op.ino);
+                if let Some(file) = op.file {
+                    let _ = write!(line, ",\"name\":{}", json_string(&file));
+                }
+            }
+            line.push('}');
+        } else {
+            let _ = write!(
+                line,
+                "{} {:<5} {}: {}",
+                time,
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            if let Some(op) = op {
+                let _ = write!(line, " op={} ino={}", op.name, op.ino);
+                if let Some(file) = op.file {
+                    let _ = write!(line, " name={:?}", file);
+                }
+            }
+        }
+        line
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = self.format(record);
+            let _ = writeln!(io::stderr().lock(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger, with levels given by spec and records written as JSON
+/// if json is set.
+pub fn init(spec: &str, json: bool) -> io::Result<()> {
+    let levels = Levels::parse(spec)?;
+    let max = levels.max();
+    let logger: &'static Logger = Box::leak(Box::new(Logger {
+        levels: RwLock::new(levels),
+        json,
+    }));
+    log::set_logger(logger).map_err(|e| io::Error::other(e.to_string()))?;
+    log::set_max_level(max);
+    let _ = LOGGER.set(logger);
+    Ok(())
+}
+
+/// Replace the levels records are written at with those given by spec.
+pub fn set_levels(spec: &str) -> io::Result<()> {
+    let levels = Levels::parse(spec)?;
+    let logger = LOGGER
+        .get()
+        .ok_or_else(|| io::Error::other("the logger is not installed"))?;
+    log::set_max_level(levels.max());
+    *logger.levels.write().unwrap() = levels;
+    Ok(())
+}
+
+/// The spec records are currently written at.
+pub fn levels() -> String {
+    LOGGER.get().map_or_else(String::new, |logger| {
+        logger.levels.read().unwrap().spec.clone()
+    })
+}
+
+/// Quote s as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -1,37 +1,2186 @@
 extern crate clap;
 extern crate fuse;
 extern crate libc;
+extern crate log;
+extern crate openssl;
 extern crate postgres;
 extern crate time;
 
+mod cache;
+mod check;
+mod config;
+mod conn;
+mod control;
+mod error;
+mod export;
 mod fs;
+mod gc;
+mod http;
+mod idmap;
+mod import;
+mod journal;
+mod logging;
+#[cfg(test)]
+mod memory;
+mod metrics;
+mod migrate;
+mod nfs;
+mod p9;
+mod pool;
+mod route;
+mod s3;
+mod scrub;
 mod sql;
+mod store;
+mod tar;
+mod token;
+mod trace;
+mod unmount;
+mod webdav;
+mod xdr;
 
-use clap::{App, Arg};
-use fs::CockroachFS;
-use fuse::mount;
-use postgres::{Connection, TlsMode};
-use std::io;
-use std::path::Path;
+use clap::{App, AppSettings, Arg, SubCommand};
+use config::Config;
+use conn::ConnOptions;
+use control::Control;
+use fs::{Atime, CockroachFS, LeaseConflict, MountOptions, Squash};
+use fuse::{FileType, Session};
+use idmap::IdMap;
+use journal::Journal;
+use log::{info, warn};
+use pool::Pool;
+use postgres::{Connection, GenericConnection};
+use route::Router;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use token::Token;
+
+/// Configuration file consulted by fusermount for unprivileged mounts.
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+/// Bounds on the block size a filesystem may be created with. Small blocks
+/// multiply the number of rows per file, while large ones make every partial
+/// block write rewrite a large value.
+const MIN_BLOCK_SIZE: i64 = 512;
+const MAX_BLOCK_SIZE: i64 = 4 << 20;
+
+/// Longest file name limit a filesystem may be created with, which is the
+/// longest name the kernel passes to FUSE filesystems.
+const MAX_NAME_MAX: i64 = 1024;
 
 fn main() -> io::Result<()> {
-    let matches = App::new("CockroachFS")
+    let mut cli_args: Vec<OsString> = env::args_os().collect();
+    // Run as crfsctl, the binary is a client of a mount's control socket.
+    if cli_args
+        .first()
+        .and_then(|arg0| Path::new(arg0).file_name())
+        == Some(OsStr::new("crfsctl"))
+    {
+        cli_args.insert(1, OsString::from("ctl"));
+    }
+    let mut matches = app().get_matches_from(&cli_args);
+    let argv = match matches.subcommand() {
+        (command, Some(sub)) => match sub.value_of("config") {
+            Some(path) => {
+                // Settings from the file are passed as flags after the real
+                // ones, except for flags that were given on the command line.
+                let config = Config::load(Path::new(path))?;
+                let args = config.args(command, |flag| {
+                    flag == "config" || sub.occurrences_of(flag) > 0
+                })?;
+                let mut argv = cli_args.clone();
+                argv.extend(args.into_iter().map(OsString::from));
+                Some(argv)
+            }
+            None => None,
+        },
+        _ => None,
+    };
+    if let Some(argv) = argv {
+        matches = app().get_matches_from(argv);
+    }
+
+    let (command, sub) = matches.subcommand();
+    let sub = sub.unwrap();
+    logging::init(
+        sub.value_of("log-level").unwrap_or("info"),
+        sub.value_of("log-format") == Some("json"),
+    )?;
+    if command == "ctl" {
+        return ctl(sub);
+    }
+    let conn_opts = conn_options(sub)?;
+    match command {
+        "init" => init(&conn_opts, sub),
+        "mount" => mount_fs(conn_opts, sub),
+        "fsck" => fsck(&conn_opts, sub),
+        "gc" => gc(&conn_opts),
+        "scrub" => scrub(&conn_opts, sub),
+        "stats" => stats(&conn_opts),
+        "versions" => versions(&conn_opts, sub),
+        "clone" => clone(&conn_opts, sub),
+        "export" => export(&conn_opts, sub),
+        "import" => import(&conn_opts, sub),
+        "backup" => backup(&conn_opts, sub),
+        "restore" => restore(&conn_opts, sub),
+        "s3-gateway" => s3_gateway(conn_opts, sub),
+        "serve-webdav" => serve_webdav(conn_opts, sub),
+        "serve-nfs" => serve_nfs(conn_opts, sub),
+        "serve-9p" => serve_9p(conn_opts, sub),
+        "quota" => quota(&conn_opts, sub),
+        "fs" => filesystems(&conn_opts, sub),
+        "migrate-block-size" => migrate_block_size(&conn_opts, sub),
+        _ => unreachable!(),
+    }
+}
+
+/// Connection options from the environment, overridden by flags.
+fn conn_options(matches: &clap::ArgMatches) -> io::Result<ConnOptions> {
+    let mut conn_opts = ConnOptions::from_env()?;
+    if let Some(url) = matches.value_of("url") {
+        conn_opts.apply_url(url)?;
+    }
+    if let Some(user) = matches.value_of("user") {
+        conn_opts.user = user.to_string();
+    }
+    conn_opts.cloud_cluster = matches.value_of("cloud-cluster").map(String::from);
+    if let Some(name) = matches.value_of("fs-name") {
+        conn_opts.filesystem = name.to_string();
+    }
+    if let Some(dir) = matches.value_of("certs-dir") {
+        conn_opts.use_certs_dir(Path::new(dir));
+    }
+    if let Some(cert) = matches.value_of("ssl-cert") {
+        conn_opts.ssl_cert = Some(PathBuf::from(cert));
+    }
+    if let Some(key) = matches.value_of("ssl-key") {
+        conn_opts.ssl_key = Some(PathBuf::from(key));
+    }
+    if let Some(ca) = matches.value_of("ssl-root-cert") {
+        conn_opts.ssl_root_cert = Some(PathBuf::from(ca));
+    }
+    if let Some(mode) = matches.value_of("sslmode") {
+        conn_opts.ssl_mode = Some(mode.parse().unwrap());
+    }
+    conn_opts.password_file = matches.value_of("password-file").map(PathBuf::from);
+    conn_opts.prompt_password = matches.is_present("password");
+    if let Some(path) = matches.value_of("token-file") {
+        conn_opts.token = Some(Token::new(token::Source::File(PathBuf::from(path))));
+    }
+    if let Some(cmd) = matches.value_of("token-command") {
+        conn_opts.token = Some(Token::new(token::Source::Command(cmd.to_string())));
+    }
+    Ok(conn_opts)
+}
+
+/// Connect to a filesystem that has already been created with init, or with
+/// `fs create` if it is not the default one, and select it.
+fn connect_existing(conn_opts: &ConnOptions) -> io::Result<Connection> {
+    let conn = conn_opts.connect()?;
+    let version = check_schema_version(&conn)?;
+    if version == 0 {
+        return Err(io::Error::other(
+            "the filesystem does not exist yet, create it with `init` first",
+        ));
+    }
+    if let Some(to) = sql::block_resize_target(&conn)? {
+        return Err(io::Error::other(format!(
+            "the filesystem's blocks are being rewritten to {} bytes, finish with `migrate-block-size --to {}` first",
+            to, to
+        )));
+    }
+    if !sql::use_filesystem(&conn, &conn_opts.filesystem)? {
+        return Err(io::Error::other(format!(
+            "there is no filesystem named {:?}, create it with `fs create` first",
+            conn_opts.filesystem
+        )));
+    }
+    Ok(conn)
+}
+
+/// The schema version of the filesystem, failing if it is newer than this
+/// binary knows how to use, since it may store what this binary would
+/// misread or overwrite.
+fn check_schema_version(conn: &Connection) -> io::Result<i64> {
+    let version = migrate::current_version(conn)?;
+    if version > migrate::latest() {
+        return Err(io::Error::other(format!(
+            "the filesystem's schema version {} is newer than this binary's {}, upgrade cockroachfs to use it",
+            version,
+            migrate::latest()
+        )));
+    }
+    Ok(version)
+}
+
+/// Create the filesystem's schema and root directory, or bring the schema of
+/// an existing filesystem up to date.
+fn init(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = conn_opts.connect()?;
+    check_schema_version(&conn)?;
+    if matches.is_present("dry-run") {
+        return Ok(migrate::dry_run(&conn)?);
+    }
+    migrate::apply(&conn)?;
+
+    let block_size = parse_block_size(matches, "block-size")?;
+    let stored = sql::load_block_size(&conn, block_size)?;
+    check_fixed(matches, "block-size", block_size, stored)?;
+    let name_max = parse_name_max(matches)?;
+    let stored_name_max = sql::load_name_max(&conn, name_max)?;
+    check_fixed(matches, "name-max", name_max, stored_name_max)?;
+
+    // The root directory is owned by the user creating the filesystem.
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    sql::create_root(&conn, uid, gid)?;
+    println!(
+        "filesystem is at schema version {} with {} byte blocks and names of up to {} bytes",
+        migrate::current_version(&conn)?,
+        stored,
+        stored_name_max
+    );
+    Ok(())
+}
+
+/// Mount the filesystem.
+fn mount_fs(conn_opts: ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(&conn_opts)?;
+    let follower_reads = matches.is_present("follower-reads");
+    let primary = format!("{}:{}", conn_opts.host, conn_opts.port);
+    let mut router = match matches.values_of("read-endpoint") {
+        None => None,
+        Some(endpoints) => {
+            let mut readers = Vec::new();
+            for endpoint in endpoints {
+                let (host, port) = parse_endpoint(endpoint)?;
+                let reader = conn_opts.connect_to(&host, port)?;
+                if follower_reads {
+                    sql::use_follower_reads(&reader)?;
+                }
+                readers.push((endpoint.to_string(), reader));
+            }
+            Some(Router::new(primary.clone(), readers))
+        }
+    };
+    if follower_reads {
+        let reader = conn_opts.connect()?;
+        sql::use_follower_reads(&reader)?;
+        let routed = router.unwrap_or_else(|| Router::new(primary, Vec::new()));
+        router = Some(routed.with_primary_reader(reader));
+    }
+
+    migrate::apply(&conn)?;
+    sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    let name_max = sql::load_name_max(&conn, sql::DEFAULT_NAME_MAX)?;
+    // The check needs the schema current and the filesystem's block size,
+    // and connect_existing has already chosen the filesystem it checks.
+    if matches.is_present("check-on-mount") {
+        let level = matches
+            .value_of("check-on-mount")
+            .unwrap_or("quick")
+            .parse()
+            .unwrap();
+        check_on_mount(&conn, level, matches.is_present("check-warn-only"))?;
+    }
+    sql::set_versioning(matches.is_present("versioning"));
+    sql::set_dedup(matches.is_present("dedup"));
+    for (class, staleness) in parse_staleness(matches.values_of("staleness"))? {
+        sql::set_staleness(class, staleness);
+    }
+
+    let path_str = matches.value_of("mountpoint").unwrap_or("./mountpoint");
+    let path = Path::new(path_str);
+
+    let opts = MountOptions {
+        nosuid: matches.is_present("nosuid"),
+        atime: match matches.value_of("atime") {
+            Some("strictatime") => Atime::Strict,
+            Some("relatime") => Atime::Relative,
+            _ => Atime::Never,
+        },
+        noexec: matches.is_present("noexec"),
+        allow_other: matches.is_present("allow-other"),
+        allow_root: matches.is_present("allow-root"),
+        auto_unmount: !matches.is_present("no-auto-unmount"),
+        uid_map: parse_id_map(matches.values_of("uid-map"))?,
+        gid_map: parse_id_map(matches.values_of("gid-map"))?,
+        squash: if matches.is_present("all-squash") {
+            Squash::All
+        } else if matches.is_present("root-squash") {
+            Squash::Root
+        } else {
+            Squash::None
+        },
+        anon_uid: parse_id(matches, "anon-uid")?.unwrap(),
+        anon_gid: parse_id(matches, "anon-gid")?.unwrap(),
+        uid: parse_id(matches, "uid")?,
+        gid: parse_id(matches, "gid")?,
+        umask: parse_umask(matches)?,
+        name_max: name_max as u32,
+        cache_size: parse_cache_size(matches)?,
+        stats_interval: parse_seconds(matches, "stats-interval")?,
+        attr_ttl: parse_seconds(matches, "attr-cache-ttl")?,
+        block_ttl: parse_seconds(matches, "block-cache-ttl")?,
+        write_back: matches.is_present("write-back"),
+        write_lease: parse_seconds(matches, "write-lease")?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--write-lease must be at least 1 second",
+            )
+        })?,
+        lease_conflict: match matches.value_of("lease-conflict") {
+            Some("wait") => LeaseConflict::Wait,
+            Some("break") => LeaseConflict::Break,
+            _ => LeaseConflict::WriteThrough,
+        },
+        readahead: parse_readahead(matches)?,
+        offline_reads: matches.is_present("offline-reads"),
+        reconnect_timeout: parse_seconds(matches, "reconnect-timeout")?,
+    };
+    if opts.allow_other || opts.allow_root {
+        check_user_allow_other()?;
+    }
+    let kernel_opts = opts.kernel_options().join(",");
+    let mut mount_opts: Vec<&OsStr> = Vec::new();
+    if !kernel_opts.is_empty() {
+        mount_opts.push(OsStr::new("-o"));
+        mount_opts.push(OsStr::new(&kernel_opts));
+    }
+
+    let subtree = match matches.value_of_os("subpath") {
+        Some(subpath) => Some(resolve_dir(&conn, subpath)?),
+        None => None,
+    };
+
+    let reconnect = opts.reconnect_timeout.is_some();
+    let mut crfs = CockroachFS::new(conn, opts);
+    if let Some(router) = router {
+        crfs = crfs.with_router(router);
+    }
+    if let Some(root) = subtree {
+        crfs = crfs.with_root(root);
+    }
+    // Connections are opened from the pool's workers as well when broken
+    // ones are replaced.
+    let shared_opts = Arc::new(Mutex::new(conn_opts));
+    let connect = move || shared_opts.lock().unwrap().connect();
+    if reconnect {
+        crfs = crfs.with_connector(Box::new(connect.clone()));
+    }
+    crfs = crfs.with_lock_renewal(Arc::new(connect.clone()));
+    let pool_size = parse_pool_size(matches)?;
+    if pool_size > 0 {
+        let mut conns = Vec::with_capacity(pool_size);
+        for _ in 0..pool_size {
+            conns.push(connect()?);
+        }
+        let connector: Option<pool::Connector> = if reconnect {
+            Some(Arc::new(connect.clone()))
+        } else {
+            None
+        };
+        crfs = crfs.with_pool(Pool::new(conns, connector));
+    }
+    if let Some(endpoint) = matches.value_of("otlp-endpoint") {
+        trace::init(endpoint)?;
+    }
+    if let Some(interval) = parse_seconds(matches, "gc-interval")? {
+        gc::run_in_background(connect()?, interval);
+    }
+    if let Some(interval) = parse_seconds(matches, "scrub-interval")? {
+        scrub::run_in_background(connect()?, interval);
+    }
+    let control_socket = matches.value_of_os("control-socket").map(Path::new);
+    if let Some(socket) = control_socket {
+        let (cache, metrics) = crfs.control_handles();
+        let control = Control {
+            mountpoint: path.to_path_buf(),
+            cache,
+            metrics,
+            connector: Arc::new(connect.clone()),
+        };
+        control::serve(socket, control)?;
+    }
+    if let Some(path) = matches.value_of("journal") {
+        let journal = Journal::open(Path::new(path))?;
+        crfs = crfs.with_journal(journal, Box::new(connect));
+    }
+    let mut session = Session::new(crfs, path, &mount_opts)?;
+    unmount::unmount_on_signal(path)?;
+    let res = session.run();
+    // The kernel does not always destroy the filesystem when it is unmounted,
+    // so shut it down here if it has not been already.
+    session.filesystem.shutdown();
+    if let Some(socket) = control_socket {
+        let _ = std::fs::remove_file(socket);
+    }
+    res
+}
+
+/// Send a command to a mount's control socket and print its output.
+fn ctl(matches: &clap::ArgMatches) -> io::Result<()> {
+    let socket = Path::new(matches.value_of_os("socket").unwrap());
+    let mut words = vec![matches.value_of("command").unwrap()];
+    words.extend(matches.values_of("args").into_iter().flatten());
+    control::request(socket, &words, &mut io::stdout())
+}
+
+/// Check the filesystem for inconsistencies, repairing them if asked to, and
+/// fail if any remain.
+fn fsck(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(conn_opts)?;
+    sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    let repair = matches.is_present("repair");
+    let level = if repair || matches.is_present("full") {
+        check::Level::Full
+    } else {
+        check::Level::Quick
+    };
+    let mut problems = check::run(&conn, level)?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if repair && !problems.is_empty() {
+        for repaired in check::repair(&conn)? {
+            println!("repaired: {}", repaired);
+        }
+        problems = check::run(&conn, level)?;
+        for problem in &problems {
+            println!("remaining: {}", problem);
+        }
+    }
+    if !problems.is_empty() {
+        return Err(io::Error::other(format!(
+            "found {} problems",
+            problems.len()
+        )));
+    }
+    println!("no problems found");
+    Ok(())
+}
+
+/// Delete inodes and blocks that are no longer reachable.
+fn gc(conn_opts: &ConnOptions) -> io::Result<()> {
+    let conn = connect_existing(conn_opts)?;
+    sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    let (inodes, blocks) = gc::collect(&conn, gc::BATCH, Duration::from_secs(0))?;
+    println!(
+        "deleted {} orphaned inodes and {} blocks past the end of their file",
+        inodes, blocks
+    );
+    Ok(())
+}
+
+/// Scrub the filesystem, and fail if it finds problems that remain.
+fn scrub(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(conn_opts)?;
+    sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    sql::use_low_priority(&conn)?;
+    let repair = matches.is_present("repair");
+    let report = scrub::scrub(&conn, scrub::BATCH, Duration::from_secs(0), repair)?;
+    println!(
+        "checked {} blocks and {} inodes",
+        report.blocks, report.inodes
+    );
+    for &(n, what) in &[
+        (report.corrupt, "blocks do not match their checksum"),
+        (report.orphaned, "blocks belong to missing inodes"),
+        (report.past_eof, "blocks lie beyond the end of their file"),
+        (
+            report.miscounted,
+            "inodes have a block count that disagrees with their blocks",
+        ),
+    ] {
+        if n > 0 {
+            println!("{} {}", n, what);
+        }
+    }
+    if report.repaired > 0 {
+        println!("repaired {} problems", report.repaired);
+    }
+    let remaining = report.problems().saturating_sub(report.repaired);
+    if remaining > 0 {
+        return Err(io::Error::other(format!("found {} problems", remaining)));
+    }
+    println!("no problems remain");
+    Ok(())
+}
+
+/// Print how many inodes and blocks the filesystem stores.
+fn stats(conn_opts: &ConnOptions) -> io::Result<()> {
+    let conn = connect_existing(conn_opts)?;
+    let block_size = sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    let (blocks, inodes) = sql::usage(&conn)?;
+    println!("schema version   {}", migrate::current_version(&conn)?);
+    println!("block size       {}", block_size);
+    println!("blocks           {}", blocks);
+    println!("inodes           {}", inodes);
+    for (kind, count, bytes) in sql::usage_by_kind(&conn)? {
+        let kind = match kind {
+            FileType::RegularFile => "files",
+            FileType::Directory => "directories",
+            FileType::Symlink => "symlinks",
+            FileType::NamedPipe => "pipes",
+            FileType::CharDevice => "char devices",
+            FileType::BlockDevice => "block devices",
+            FileType::Socket => "sockets",
+        };
+        println!("  {:<14} {} ({} bytes)", kind, count, bytes);
+    }
+    Ok(())
+}
+
+/// List the versions kept of a file, or restore one of them.
+fn versions(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(conn_opts)?;
+    sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    let path = matches.value_of_os("path").unwrap();
+    let attr = resolve(&conn, Path::new(path))?;
+    if attr.kind != FileType::RegularFile {
+        return Err(io::Error::other(format!(
+            "{} is not a regular file",
+            Path::new(path).display()
+        )));
+    }
+
+    if let Some(version) = matches.value_of("restore") {
+        let version: i64 = version.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid version {:?}", version),
+            )
+        })?;
+        if !sql::restore_version(&conn, attr.ino, version)? {
+            return Err(io::Error::other(format!("no version {}", version)));
+        }
+        println!("restored version {}", version);
+        return Ok(());
+    }
+
+    println!(
+        "{:>8} {:>12}  {:<20}  {:<20}",
+        "VERSION", "SIZE", "MODIFIED", "REPLACED"
+    );
+    for v in sql::list_versions(&conn, attr.ino)? {
+        println!(
+            "{:>8} {:>12}  {:<20}  {:<20}",
+            v.version,
+            v.size,
+            format_time(v.mtime),
+            format_time(v.saved)
+        );
+    }
+    println!(
+        "{:>8} {:>12}  {:<20}",
+        "current",
+        attr.size,
+        format_time(attr.mtime)
+    );
+    Ok(())
+}
+
+/// Clone a file, sharing its blocks with the clone until either is written.
+/// FUSE as this filesystem speaks it passes on neither FICLONE nor
+/// copy_file_range, so clones are made here rather than by cp --reflink.
+fn clone(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(conn_opts)?;
+    let src = Path::new(matches.value_of_os("source").unwrap());
+    let dest = Path::new(matches.value_of_os("dest").unwrap());
+    let attr = resolve(&conn, src)?;
+    if attr.kind != FileType::RegularFile {
+        return Err(io::Error::other(format!(
+            "{} is not a regular file",
+            src.display()
+        )));
+    }
+    let name = dest
+        .file_name()
+        .ok_or_else(|| io::Error::other(format!("{} is not a file name", dest.display())))?;
+    let parent = resolve_dir(&conn, dest.parent().unwrap_or(Path::new("/")).as_os_str())?;
+    if sql::lookup_dir_ent(&conn, parent, name.as_bytes())?.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", dest.display()),
+        ));
+    }
+    match sql::clone_file(&conn, attr.ino, parent, name.as_bytes()) {
+        Err(ref err) if sql::quota_exceeded(err) => {
+            Err(io::Error::other("the clone would go over a quota"))
+        }
+        Err(err) => Err(err.into()),
+        Ok(None) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{}: no such file or directory", src.display()),
+        )),
+        Ok(Some(_)) => Ok(()),
+    }
+}
+
+/// Write a subtree of the filesystem to a tar archive, compressed with gzip
+/// if its name ends in .gz or .tgz, or to standard output if it is "-".
+fn export(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(conn_opts)?;
+    sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    let path = Path::new(matches.value_of_os("path").unwrap());
+    let out = Path::new(matches.value_of_os("out").unwrap());
+
+    // Reading the whole subtree in one transaction as of a time in the past
+    // gives a consistent snapshot without holding up writers.
+    let txn = conn.transaction()?;
+    if let Some(time) = matches.value_of("as-of") {
+        sql::read_as_of(&txn, time)?;
+    }
+    let attr = resolve(&txn, path)?;
+    let name = match path.file_name() {
+        Some(name) => name.as_bytes(),
+        None => b".",
+    };
+
+    if out == Path::new("-") {
+        let stdout = io::stdout();
+        export::export(&txn, &attr, name, io::BufWriter::new(stdout.lock()))?;
+        return Ok(txn.finish()?);
+    }
+    let compress =
+        out.extension() == Some(OsStr::new("gz")) || out.extension() == Some(OsStr::new("tgz"));
+    let (summary, gzip) = if compress {
+        let mut gzip = std::process::Command::new("gzip")
+            .arg("-c")
+            .stdin(std::process::Stdio::piped())
+            .stdout(File::create(out)?)
+            .spawn()?;
+        let stdin = io::BufWriter::new(gzip.stdin.take().unwrap());
+        let (summary, _) = export::export(&txn, &attr, name, stdin)?;
+        (summary, Some(gzip))
+    } else {
+        let file = io::BufWriter::new(File::create(out)?);
+        let (summary, _) = export::export(&txn, &attr, name, file)?;
+        (summary, None)
+    };
+    if let Some(mut gzip) = gzip {
+        let status = gzip.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("gzip {}", status)));
+        }
+    }
+    txn.finish()?;
+    println!(
+        "exported {} entries holding {} bytes to {}",
+        summary.entries,
+        summary.bytes,
+        out.display()
+    );
+    if summary.skipped > 0 {
+        println!("skipped {} sockets", summary.skipped);
+    }
+    Ok(())
+}
+
+/// Load a local directory tree into a directory of the filesystem.
+fn import(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(conn_opts)?;
+    sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    let local = Path::new(matches.value_of_os("local").unwrap());
+    if !local.is_dir() {
+        return Err(io::Error::other(format!(
+            "{} is not a directory",
+            local.display()
+        )));
+    }
+    let dir = resolve_dir(&conn, matches.value_of_os("path").unwrap())?;
+    let summary = match import::import(&conn, local, dir) {
+        Err(err) => match err.get_ref().and_then(|err| err.downcast_ref()) {
+            Some(err) if sql::quota_exceeded(err) => {
+                return Err(io::Error::other("the import would go over a quota"))
+            }
+            _ => return Err(err),
+        },
+        Ok(summary) => summary,
+    };
+    println!(
+        "imported {} entries holding {} bytes",
+        summary.entries, summary.bytes
+    );
+    Ok(())
+}
+
+/// Back up the filesystem with CockroachDB's BACKUP. The backup holds the
+/// whole database, so the block size and schema version go with it.
+fn backup(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(conn_opts)?;
+    let version = migrate::current_version(&conn)?;
+    let block_size = sql::stored_block_size(&conn)?;
+    let uri = matches.value_of("to").unwrap();
+    let (rows, bytes) = sql::backup(&conn, uri, matches.value_of("as-of"))?;
+    println!("backed up {} rows holding {} bytes to {}", rows, bytes, uri);
+    println!("schema version   {}", version);
+    if let Some(block_size) = block_size {
+        println!("block size       {}", block_size);
+    }
+    Ok(())
+}
+
+/// Restore the filesystem from the latest backup made by backup, as the
+/// database named by the connection options, and make it ready to mount.
+fn restore(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = conn_opts.connect()?;
+    let uri = matches.value_of("from").unwrap();
+    let db = sql::restore(&conn, uri)?;
+    let version = migrate::current_version(&conn)?;
+    let latest = migrate::latest();
+    if version == 0 || !sql::missing_tables(&conn)?.is_empty() {
+        return Err(io::Error::other(format!(
+            "restored {} from {}, but it does not hold a filesystem",
+            db, uri
+        )));
+    }
+    if version > latest {
+        return Err(io::Error::other(format!(
+            "restored {} from {}, but its schema version {} is newer than this binary's {}",
+            db, uri, version, latest
+        )));
+    }
+    sql::release_all_sessions(&conn)?;
+    println!("restored {} from {}", db, uri);
+    println!("schema version   {}", version);
+    if let Some(block_size) = sql::stored_block_size(&conn)? {
+        println!("block size       {}", block_size);
+    }
+    if version < latest {
+        println!("run `init` to bring the schema up to version {}", latest);
+    }
+    Ok(())
+}
+
+/// Serve the filesystem over a minimal S3 API instead of mounting it.
+fn s3_gateway(conn_opts: ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(&conn_opts)?;
+    sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    let uid = parse_id(matches, "uid")?.unwrap_or_else(|| unsafe { libc::getuid() });
+    let gid = parse_id(matches, "gid")?.unwrap_or_else(|| unsafe { libc::getgid() });
+    let pool_size = parse_pool_size(matches)?.max(1);
+    let mut conns = vec![conn];
+    for _ in 1..pool_size {
+        conns.push(conn_opts.connect()?);
+    }
+    let shared_opts = Arc::new(Mutex::new(conn_opts));
+    let connector: pool::Connector = Arc::new(move || shared_opts.lock().unwrap().connect());
+    let listen = matches.value_of("listen").unwrap();
+    let listener = std::net::TcpListener::bind(listen)?;
+    info!("serving S3 requests on {}", listen);
+    s3::serve(listener, Pool::new(conns, Some(connector)), (uid, gid))
+}
+
+/// Serve the filesystem over WebDAV until killed.
+fn serve_webdav(conn_opts: ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(&conn_opts)?;
+    sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    let uid = parse_id(matches, "uid")?.unwrap_or_else(|| unsafe { libc::getuid() });
+    let gid = parse_id(matches, "gid")?.unwrap_or_else(|| unsafe { libc::getgid() });
+    let pool_size = parse_pool_size(matches)?.max(1);
+    let mut conns = vec![conn];
+    for _ in 1..pool_size {
+        conns.push(conn_opts.connect()?);
+    }
+    let shared_opts = Arc::new(Mutex::new(conn_opts));
+    let connector: pool::Connector = Arc::new(move || shared_opts.lock().unwrap().connect());
+    let listen = matches.value_of("listen").unwrap();
+    let listener = std::net::TcpListener::bind(listen)?;
+    info!("serving WebDAV requests on {}", listen);
+    webdav::serve(listener, Pool::new(conns, Some(connector)), (uid, gid))
+}
+
+/// Serve the filesystem over NFSv3 until killed.
+fn serve_nfs(conn_opts: ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(&conn_opts)?;
+    sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    let name_max = sql::load_name_max(&conn, sql::DEFAULT_NAME_MAX)?;
+    // Each filesystem in the database needs an id of its own.
+    let fsid = sql::load_fsid(&conn)? ^ sql::fs_id() as u64;
+    let pool_size = parse_pool_size(matches)?.max(1);
+    let mut conns = vec![conn];
+    for _ in 1..pool_size {
+        conns.push(conn_opts.connect()?);
+    }
+    let shared_opts = Arc::new(Mutex::new(conn_opts));
+    let connector: pool::Connector = Arc::new(move || shared_opts.lock().unwrap().connect());
+    let listen = matches.value_of("listen").unwrap();
+    let listener = std::net::TcpListener::bind(listen)?;
+    info!("serving NFS requests on {}", listen);
+    nfs::serve(
+        listener,
+        Pool::new(conns, Some(connector)),
+        fsid,
+        name_max as u32,
+    )
+}
+
+fn serve_9p(conn_opts: ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(&conn_opts)?;
+    sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+    let pool_size = parse_pool_size(matches)?.max(1);
+    let mut conns = vec![conn];
+    for _ in 1..pool_size {
+        conns.push(conn_opts.connect()?);
+    }
+    let shared_opts = Arc::new(Mutex::new(conn_opts));
+    let connector: pool::Connector = Arc::new(move || shared_opts.lock().unwrap().connect());
+    let pool = Pool::new(conns, Some(connector));
+    if let Some(path) = matches.value_of_os("socket") {
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        info!("serving 9P requests on {}", Path::new(path).display());
+        return p9::serve(listener.incoming(), pool);
+    }
+    let listen = matches.value_of("listen").unwrap();
+    let listener = std::net::TcpListener::bind(listen)?;
+    info!("serving 9P requests on {}", listen);
+    p9::serve(listener.incoming(), pool)
+}
+
+/// Set, clear or list directory quotas, or set or report user quotas.
+fn quota(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(conn_opts)?;
+    match matches.subcommand() {
+        ("set", Some(sub)) => {
+            let dir = resolve_dir(&conn, sub.value_of_os("path").unwrap())?;
+            let max_bytes = parse_limit(sub, "max-bytes")?;
+            let max_inodes = parse_limit(sub, "max-inodes")?;
+            match sql::set_quota(&conn, dir, max_bytes, max_inodes) {
+                Err(ref err) if sql::quota_exceeded(err) => Err(io::Error::other(
+                    "the directory's usage is already over the limit",
+                )),
+                Err(err) => Err(err.into()),
+                Ok(()) => Ok(()),
+            }
+        }
+        ("clear", Some(sub)) => {
+            let dir = resolve_dir(&conn, sub.value_of_os("path").unwrap())?;
+            if !sql::clear_quota(&conn, dir)? {
+                return Err(io::Error::other("the directory has no quota"));
+            }
+            Ok(())
+        }
+        ("set-user", Some(sub)) => {
+            let uid = sub.value_of("uid").unwrap();
+            let uid: u32 = uid.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid user id {:?}", uid),
+                )
+            })?;
+            let max_bytes = parse_limit(sub, "max-bytes")?;
+            let max_inodes = parse_limit(sub, "max-inodes")?;
+            match sql::set_user_quota(&conn, uid, max_bytes, max_inodes) {
+                Err(ref err) if sql::quota_exceeded(err) => Err(io::Error::other(
+                    "the user's usage is already over the limit",
+                )),
+                Err(err) => Err(err.into()),
+                Ok(()) => Ok(()),
+            }
+        }
+        ("report", Some(_)) => {
+            println!(
+                "{:>10} {:>14} {:>14} {:>10} {:>10}",
+                "UID", "BYTES", "MAX BYTES", "INODES", "MAX INODES"
+            );
+            let limit = |max: Option<i64>| max.map_or("-".to_string(), |max| max.to_string());
+            for q in sql::list_user_quotas(&conn)? {
+                println!(
+                    "{:>10} {:>14} {:>14} {:>10} {:>10}",
+                    q.uid,
+                    q.bytes,
+                    limit(q.max_bytes),
+                    q.inodes,
+                    limit(q.max_inodes)
+                );
+            }
+            Ok(())
+        }
+        ("list", Some(_)) => {
+            println!(
+                "{:>14} {:>14} {:>10} {:>10}  PATH",
+                "BYTES", "MAX BYTES", "INODES", "MAX INODES"
+            );
+            let limit = |max: Option<i64>| max.map_or("-".to_string(), |max| max.to_string());
+            for q in sql::list_quotas(&conn)? {
+                println!(
+                    "{:>14} {:>14} {:>10} {:>10}  {}",
+                    q.bytes,
+                    limit(q.max_bytes),
+                    q.inodes,
+                    limit(q.max_inodes),
+                    path_of(&conn, q.ino)?.display()
+                );
+            }
+            Ok(())
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Look up a path within the filesystem that must name a directory.
+fn resolve_dir(conn: &Connection, path: &OsStr) -> io::Result<u64> {
+    let attr = resolve(conn, Path::new(path))?;
+    if attr.kind != FileType::Directory {
+        return Err(io::Error::other(format!(
+            "{} is not a directory",
+            Path::new(path).display()
+        )));
+    }
+    Ok(attr.ino)
+}
+
+/// Rewrite the blocks of every file at a new block size, one file per
+/// transaction, resuming a migration that was interrupted.
+fn migrate_block_size(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = conn_opts.connect()?;
+    if check_schema_version(&conn)? < migrate::latest() {
+        return Err(io::Error::other(
+            "the schema is not up to date, bring it up to date with `init` first",
+        ));
+    }
+    let to = parse_block_size(matches, "to")?;
+    let from = match sql::stored_block_size(&conn)? {
+        Some(from) => from,
+        None => {
+            // Nothing has been stored yet.
+            sql::load_block_size(&conn, to)?;
+            println!("the filesystem will store {} byte blocks", to);
+            return Ok(());
+        }
+    };
+    if from == to && sql::block_resize_target(&conn)?.is_none() {
+        println!("the filesystem already stores {} byte blocks", to);
+        return Ok(());
+    }
+    let versions = sql::count_file_versions(&conn)?;
+    if versions > 0 {
+        if !matches.is_present("discard-versions") {
+            return Err(io::Error::other(format!(
+                "{} file versions are kept in {} byte blocks, discard them with --discard-versions",
+                versions, from
+            )));
+        }
+        sql::delete_file_versions(&conn)?;
+    }
+
+    let (target, mut cursor) = sql::begin_block_resize(&conn, to)?;
+    if target != to {
+        return Err(io::Error::other(format!(
+            "a migration to {} byte blocks is under way, finish it with --to {} first",
+            target, target
+        )));
+    }
+    let mut files = 0;
+    loop {
+        let batch = sql::files_after(&conn, cursor, gc::BATCH)?;
+        if batch.is_empty() {
+            break;
+        }
+        for (ino, size) in batch {
+            sql::resize_file_blocks(&conn, ino, size, from, to)?;
+            cursor = ino;
+            files += 1;
+        }
+        info!("rewrote {} files, up to inode {}", files, cursor);
+    }
+    sql::finish_block_resize(&conn, to)?;
+    println!(
+        "rewrote the blocks of {} files from {} to {} bytes",
+        files, from, to
+    );
+    Ok(())
+}
+
+/// Create, list or drop the filesystems held in the database.
+fn filesystems(conn_opts: &ConnOptions, matches: &clap::ArgMatches) -> io::Result<()> {
+    let conn = connect_existing(conn_opts)?;
+    match matches.subcommand() {
+        ("create", Some(sub)) => {
+            let name = sub.value_of("name").unwrap();
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+            if sql::create_filesystem(&conn, name, uid, gid)?.is_none() {
+                return Err(io::Error::other(format!(
+                    "there already is a filesystem named {:?}",
+                    name
+                )));
+            }
+            println!("created filesystem {}", name);
+            Ok(())
+        }
+        ("list", Some(_)) => {
+            println!(
+                "{:<20} {:>20} {:>20} {:>10} {:>14}  CREATED",
+                "NAME", "ID", "ROOT", "INODES", "BYTES"
+            );
+            for fs in sql::list_filesystems(&conn)? {
+                println!(
+                    "{:<20} {:>20} {:>20} {:>10} {:>14}  {}",
+                    fs.name,
+                    fs.fs_id,
+                    fs.root,
+                    fs.inodes,
+                    fs.bytes,
+                    format_time(fs.created)
+                );
+            }
+            Ok(())
+        }
+        ("drop", Some(sub)) => {
+            let name = sub.value_of("name").unwrap();
+            if name == sql::DEFAULT_FS {
+                return Err(io::Error::other("the default filesystem cannot be dropped"));
+            }
+            let fs_id = sql::drop_filesystem(&conn, name)?.ok_or_else(|| {
+                io::Error::other(format!("there is no filesystem named {:?}", name))
+            })?;
+            sql::load_block_size(&conn, sql::DEFAULT_BLOCK_SIZE)?;
+            let inodes = gc::collect_filesystem(&conn, fs_id, gc::BATCH, Duration::from_secs(0))?;
+            println!("dropped filesystem {} and deleted {} inodes", name, inodes);
+            Ok(())
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// The path of ino within the filesystem, following the first entry found
+/// for each inode on the way up to the root.
+fn path_of(conn: &Connection, mut ino: u64) -> io::Result<PathBuf> {
+    let mut names = Vec::new();
+    while ino != sql::root() {
+        match sql::find_dir_ent(conn, ino)? {
+            Some((dir, name)) => {
+                names.push(name);
+                ino = dir;
+            }
+            None => break,
+        }
+    }
+    let mut path = PathBuf::from("/");
+    for name in names.iter().rev() {
+        path.push(OsStr::from_bytes(name));
+    }
+    Ok(path)
+}
+
+/// Look up a path within the filesystem, relative to its root.
+fn resolve<C: GenericConnection>(conn: &C, path: &Path) -> io::Result<fuse::FileAttr> {
+    let mut attr = sql::lookup_inode(conn, sql::root())?
+        .ok_or_else(|| io::Error::other("the filesystem has no root directory"))?;
+    for name in path.iter().filter(|name| *name != "/" && *name != ".") {
+        (attr, _) = sql::lookup_dir_ent(conn, attr.ino, name.as_bytes())?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}: no such file or directory", path.display()),
+            )
+        })?;
+    }
+    Ok(attr)
+}
+
+/// Format a time as UTC, to the second.
+fn format_time(t: time::Timespec) -> String {
+    time::at_utc(t)
+        .strftime("%Y-%m-%d %H:%M:%S")
+        .map(|t| t.to_string())
+        .unwrap_or_default()
+}
+
+/// The command-line interface.
+fn app() -> App<'static, 'static> {
+    App::new("CockroachFS")
         .version("0.1.0")
         .about("Filesystem backed by CockroachDB")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
         .arg(
-            Arg::with_name("mountpoint")
-                .short("m")
-                .long("mountpoint")
+            Arg::with_name("url")
+                .global(true)
+                .long("url")
                 .takes_value(true)
-                .help("The location to mount the filesystem"),
+                .env("COCKROACHFS_URL")
+                .value_name("URL")
+                .help("Connection URL, as in postgres://user@host:26257/database?sslmode=verify-full"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("user")
+                .global(true)
+                .short("u")
+                .long("user")
+                .takes_value(true)
+                .help("The SQL user to connect as"),
+        )
+        .arg(
+            Arg::with_name("password-file")
+                .global(true)
+                .long("password-file")
+                .takes_value(true)
+                .help("A file containing the SQL user's password"),
+        )
+        .arg(
+            Arg::with_name("password")
+                .global(true)
+                .short("W")
+                .long("password")
+                .help("Prompt for the SQL user's password if it is not otherwise provided"),
+        )
+        .arg(
+            Arg::with_name("token-file")
+                .global(true)
+                .long("token-file")
+                .takes_value(true)
+                .conflicts_with_all(&["token-command", "password-file", "password"])
+                .help("A file containing a JWT to authenticate with, reread when it expires"),
+        )
+        .arg(
+            Arg::with_name("token-command")
+                .global(true)
+                .long("token-command")
+                .takes_value(true)
+                .conflicts_with_all(&["password-file", "password"])
+                .help("A shell command that prints a fresh JWT to authenticate with"),
+        )
+        .arg(
+            Arg::with_name("cloud-cluster")
+                .global(true)
+                .long("cloud-cluster")
+                .takes_value(true)
+                .value_name("ROUTING_ID")
+                .help("Connect to the CockroachDB Cloud cluster with this routing id"),
+        )
+        .arg(
+            Arg::with_name("fs-name")
+                .global(true)
+                .long("fs-name")
+                .takes_value(true)
+                .env("COCKROACHFS_FS_NAME")
+                .value_name("NAME")
+                .help("Use the filesystem of this name within the database [default: default]"),
+        )
+        .arg(
+            Arg::with_name("certs-dir")
+                .global(true)
+                .long("certs-dir")
+                .takes_value(true)
+                .conflicts_with_all(&["ssl-cert", "ssl-key", "ssl-root-cert"])
+                .help("Directory containing ca.crt and client.<user>.crt/.key"),
+        )
+        .arg(
+            Arg::with_name("ssl-cert")
+                .global(true)
+                .long("ssl-cert")
+                .takes_value(true)
+                .help("Client certificate to authenticate with"),
+        )
+        .arg(
+            Arg::with_name("ssl-key")
+                .global(true)
+                .long("ssl-key")
+                .takes_value(true)
+                .help("Private key of the client certificate"),
+        )
+        .arg(
+            Arg::with_name("ssl-root-cert")
+                .global(true)
+                .long("ssl-root-cert")
+                .takes_value(true)
+                .help("Certificate authority to verify the server against"),
+        )
+        .arg(
+            Arg::with_name("sslmode")
+                .global(true)
+                .long("sslmode")
+                .takes_value(true)
+                .possible_values(&[
+                    "disable",
+                    "allow",
+                    "prefer",
+                    "require",
+                    "verify-ca",
+                    "verify-full",
+                ])
+                .help("How to negotiate TLS, defaulting to verify-full when certificates are given"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .global(true)
+                .long("config")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Read flags from a TOML file, overridden by those given on the command line"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .global(true)
+                .long("log-level")
+                .takes_value(true)
+                .env("COCKROACHFS_LOG")
+                .default_value("info")
+                .value_name("LEVELS")
+                .help("Level to log at, optionally followed by levels for modules, as in info,cockroach_fuse::sql=debug"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .global(true)
+                .long("log-format")
+                .takes_value(true)
+                .default_value("text")
+                .possible_values(&["text", "json"])
+                .help("Format of log records"),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Create the filesystem, or upgrade its schema to the latest version")
+                .arg(
+                    Arg::with_name("block-size")
+                        .long("block-size")
+                        .takes_value(true)
+                        .default_value("8192")
+                        .value_name("BYTES")
+                        .help("Size of the blocks file data is stored in, fixed when the filesystem is created"),
+                )
+                .arg(
+                    Arg::with_name("name-max")
+                        .long("name-max")
+                        .takes_value(true)
+                        .default_value("255")
+                        .value_name("BYTES")
+                        .help("Longest file name accepted, fixed when the filesystem is created"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Print the pending schema migrations without applying them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("Mount the filesystem")
+                .arg(
+                    Arg::with_name("mountpoint")
+                        .short("m")
+                        .long("mountpoint")
+                        .takes_value(true)
+                        .help("The location to mount the filesystem"),
+                )
+                .arg(
+                    Arg::with_name("subpath")
+                        .long("subpath")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Mount only the directory at this path within the filesystem"),
+                )
+                .arg(
+                    Arg::with_name("read-endpoint")
+                        .long("read-endpoint")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("HOST:PORT")
+                        .help("Another gateway that reads may be sent to if it has lower latency"),
+                )
+                .arg(
+                    Arg::with_name("follower-reads")
+                        .long("follower-reads")
+                        .help("Serve reads from nearby replicas a few seconds stale, for read-mostly mounts"),
+                )
+                .arg(
+                    Arg::with_name("staleness")
+                        .long("staleness")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("CLASS=SECONDS")
+                        .help("Let reads for a class of operation be this stale: lookup, getattr, readdir, readlink, read, xattr or statfs"),
+                )
+                .arg(
+                    Arg::with_name("control-socket")
+                        .long("control-socket")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Serve commands from `ctl` or crfsctl on a Unix socket at this path while mounted"),
+                )
+                .arg(
+                    Arg::with_name("journal")
+                        .long("journal")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Journal writes to this file while the database is unreachable"),
+                )
+                .arg(
+                    Arg::with_name("pool-size")
+                        .long("pool-size")
+                        .takes_value(true)
+                        .default_value("0")
+                        .value_name("N")
+                        .help("Number of extra connections serving reads and writes concurrently"),
+                )
+                .arg(
+                    Arg::with_name("nosuid")
+                        .long("nosuid")
+                        .help("Ignore setuid and setgid bits on all files"),
+                )
+                .arg(
+                    Arg::with_name("noexec")
+                        .long("noexec")
+                        .help("Do not allow files to be executed"),
+                )
+                .arg(
+                    Arg::with_name("atime")
+                        .long("atime")
+                        .takes_value(true)
+                        .possible_values(&["noatime", "relatime", "strictatime"])
+                        .default_value("noatime")
+                        .help("When reading a file or listing a directory updates its access time"),
+                )
+                .arg(
+                    Arg::with_name("allow-other")
+                        .long("allow-other")
+                        .conflicts_with("allow-root")
+                        .help("Allow all users to access the filesystem"),
+                )
+                .arg(
+                    Arg::with_name("allow-root")
+                        .long("allow-root")
+                        .help("Allow root, in addition to the mounting user, to access the filesystem"),
+                )
+                .arg(
+                    Arg::with_name("no-auto-unmount")
+                        .long("no-auto-unmount")
+                        .help("Leave the filesystem mounted if the process exits unexpectedly"),
+                )
+                .arg(
+                    Arg::with_name("uid-map")
+                        .long("uid-map")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("STORED:LOCAL")
+                        .help("Present files owned by a stored user id as owned by a local user id"),
+                )
+                .arg(
+                    Arg::with_name("gid-map")
+                        .long("gid-map")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("STORED:LOCAL")
+                        .help("Present files owned by a stored group id as owned by a local group id"),
+                )
+                .arg(
+                    Arg::with_name("root-squash")
+                        .long("root-squash")
+                        .conflicts_with("all-squash")
+                        .help("Treat requests from root as coming from the anonymous user"),
+                )
+                .arg(
+                    Arg::with_name("all-squash")
+                        .long("all-squash")
+                        .help("Treat all requests as coming from the anonymous user"),
+                )
+                .arg(
+                    Arg::with_name("anon-uid")
+                        .long("anon-uid")
+                        .takes_value(true)
+                        .default_value("65534")
+                        .help("The user id of the anonymous user"),
+                )
+                .arg(
+                    Arg::with_name("anon-gid")
+                        .long("anon-gid")
+                        .takes_value(true)
+                        .default_value("65534")
+                        .help("The group id of the anonymous user"),
+                )
+                .arg(
+                    Arg::with_name("uid")
+                        .long("uid")
+                        .takes_value(true)
+                        .help("Present all files as owned by this user id"),
+                )
+                .arg(
+                    Arg::with_name("gid")
+                        .long("gid")
+                        .takes_value(true)
+                        .help("Present all files as owned by this group id"),
+                )
+                .arg(
+                    Arg::with_name("umask")
+                        .long("umask")
+                        .takes_value(true)
+                        .help("Present all files with these octal permission bits cleared"),
+                )
+                .arg(
+                    Arg::with_name("cache-size")
+                        .long("cache-size")
+                        .takes_value(true)
+                        .default_value("64")
+                        .value_name("MiB")
+                        .help("Memory budget shared by all in-process caches"),
+                )
+                .arg(
+                    Arg::with_name("attr-cache-ttl")
+                        .long("attr-cache-ttl")
+                        .takes_value(true)
+                        .default_value("0")
+                        .value_name("SECONDS")
+                        .help("How long to serve file attributes from the in-process cache, or 0 to never"),
+                )
+                .arg(
+                    Arg::with_name("block-cache-ttl")
+                        .long("block-cache-ttl")
+                        .takes_value(true)
+                        .default_value("0")
+                        .value_name("SECONDS")
+                        .help("How long to serve file data from the in-process cache, or 0 to never"),
+                )
+                .arg(
+                    Arg::with_name("readahead")
+                        .long("readahead")
+                        .takes_value(true)
+                        .default_value("0")
+                        .value_name("BLOCKS")
+                        .help("Blocks to prefetch ahead of sequential reads (needs --pool-size and --block-cache-ttl)"),
+                )
+                .arg(
+                    Arg::with_name("write-back")
+                        .long("write-back")
+                        .help("Buffer writes to open files until they are flushed, synced or closed"),
+                )
+                .arg(
+                    Arg::with_name("write-lease")
+                        .long("write-lease")
+                        .takes_value(true)
+                        .default_value("30")
+                        .value_name("SECONDS")
+                        .help("How long a mount's lease on buffering writes to a file lasts unless renewed"),
+                )
+                .arg(
+                    Arg::with_name("lease-conflict")
+                        .long("lease-conflict")
+                        .takes_value(true)
+                        .default_value("write-through")
+                        .possible_values(&["write-through", "wait", "break"])
+                        .help("What --write-back does with a file another mount holds the write lease on"),
+                )
+                .arg(
+                    Arg::with_name("versioning")
+                        .long("versioning")
+                        .help("Keep the prior contents of files whenever they are written or truncated"),
+                )
+                .arg(
+                    Arg::with_name("dedup")
+                        .long("dedup")
+                        .help("Store blocks with the same contents once, shared by every file that has them"),
+                )
+                .arg(
+                    Arg::with_name("reconnect-timeout")
+                        .long("reconnect-timeout")
+                        .takes_value(true)
+                        .default_value("30")
+                        .value_name("SECONDS")
+                        .help("Keep trying to replace a broken database connection for this long before failing with EIO, or 0 to not reconnect"),
+                )
+                .arg(
+                    Arg::with_name("offline-reads")
+                        .long("offline-reads")
+                        .help("Serve cached data read-only while the database is unreachable"),
+                )
+                .arg(
+                    Arg::with_name("stats-interval")
+                        .long("stats-interval")
+                        .takes_value(true)
+                        .default_value("60")
+                        .value_name("SECONDS")
+                        .help("How often to log a summary of operation metrics, or 0 to never"),
+                )
+                .arg(
+                    Arg::with_name("gc-interval")
+                        .long("gc-interval")
+                        .takes_value(true)
+                        .default_value("0")
+                        .value_name("SECONDS")
+                        .help("How often to collect unreachable inodes and blocks in the background, or 0 to never"),
+                )
+                .arg(
+                    Arg::with_name("scrub-interval")
+                        .long("scrub-interval")
+                        .takes_value(true)
+                        .default_value("0")
+                        .value_name("SECONDS")
+                        .help("How often to scrub blocks against their checksums and inodes in the background, or 0 to never"),
+                )
+                .arg(
+                    Arg::with_name("otlp-endpoint")
+                        .long("otlp-endpoint")
+                        .takes_value(true)
+                        .value_name("URL")
+                        .help("Send traces of operations to an OpenTelemetry collector, as in http://localhost:4318"),
+                )
+                .arg(
+                    Arg::with_name("check-on-mount")
+                        .long("check-on-mount")
+                        .takes_value(true)
+                        .min_values(0)
+                        .require_equals(true)
+                        .possible_values(&["quick", "full"])
+                        .help("Check the filesystem for inconsistencies before mounting it"),
+                )
+                .arg(
+                    Arg::with_name("check-warn-only")
+                        .long("check-warn-only")
+                        .requires("check-on-mount")
+                        .help("Mount even if --check-on-mount finds problems"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check the filesystem for inconsistencies")
+                .arg(
+                    Arg::with_name("full")
+                        .long("full")
+                        .help("Also run the checks that scan every inode and block"),
+                )
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Run every check and repair what can be without losing data (unmount first)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Delete inodes and blocks that are no longer reachable"),
+        )
+        .subcommand(
+            SubCommand::with_name("scrub")
+                .about("Check every block against its checksum and its inode, resuming an interrupted scrub")
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Delete unreachable blocks and correct block counts (unmount first)"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("stats").about("Print how much the filesystem stores"))
+        .subcommand(
+            SubCommand::with_name("quota")
+                .about("Limit how much may be stored beneath a directory or owned by a user")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Set the limits on a directory, replacing any it had")
+                        .arg(
+                            Arg::with_name("path")
+                                .required(true)
+                                .value_name("PATH")
+                                .help("Path of the directory within the filesystem"),
+                        )
+                        .arg(
+                            Arg::with_name("max-bytes")
+                                .long("max-bytes")
+                                .takes_value(true)
+                                .value_name("BYTES")
+                                .help("Most bytes of file data beneath the directory, or 0 for no limit"),
+                        )
+                        .arg(
+                            Arg::with_name("max-inodes")
+                                .long("max-inodes")
+                                .takes_value(true)
+                                .value_name("COUNT")
+                                .help("Most files and directories beneath the directory, or 0 for no limit"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("clear")
+                        .about("Remove the quota on a directory")
+                        .arg(
+                            Arg::with_name("path")
+                                .required(true)
+                                .value_name("PATH")
+                                .help("Path of the directory within the filesystem"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("List every quota and its usage"))
+                .subcommand(
+                    SubCommand::with_name("set-user")
+                        .about("Set the limits on the files a user owns, replacing any it had")
+                        .arg(
+                            Arg::with_name("uid")
+                                .required(true)
+                                .value_name("UID")
+                                .help("User id, as stored in the filesystem"),
+                        )
+                        .arg(
+                            Arg::with_name("max-bytes")
+                                .long("max-bytes")
+                                .takes_value(true)
+                                .value_name("BYTES")
+                                .help("Most bytes of file data the user may own, or 0 for no limit"),
+                        )
+                        .arg(
+                            Arg::with_name("max-inodes")
+                                .long("max-inodes")
+                                .takes_value(true)
+                                .value_name("COUNT")
+                                .help("Most files and directories the user may own, or 0 for no limit"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("report").about("Show usage and limits by user")),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate-block-size")
+                .about("Rewrite every file in blocks of a new size (unmount first)")
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("SIZE")
+                        .help("New block size in bytes, or with a K or M suffix, as in 64KiB"),
+                )
+                .arg(
+                    Arg::with_name("discard-versions")
+                        .long("discard-versions")
+                        .help("Delete the file versions kept, which cannot be rewritten"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fs")
+                .about("Manage the filesystems held in the database")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create an empty filesystem, owned by the current user")
+                        .arg(
+                            Arg::with_name("name")
+                                .required(true)
+                                .value_name("NAME")
+                                .help("Name to select the filesystem by with --fs-name"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("List every filesystem and its size"))
+                .subcommand(
+                    SubCommand::with_name("drop")
+                        .about("Delete a filesystem and everything in it (unmount it first)")
+                        .arg(
+                            Arg::with_name("name")
+                                .required(true)
+                                .value_name("NAME")
+                                .help("Name of the filesystem"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("versions")
+                .about("List the versions kept of a file, or restore one")
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .value_name("PATH")
+                        .help("Path of the file within the filesystem"),
+                )
+                .arg(
+                    Arg::with_name("restore")
+                        .long("restore")
+                        .takes_value(true)
+                        .value_name("VERSION")
+                        .help("Restore the file to a version, keeping its current contents as a new one"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("clone")
+                .about("Copy a file without copying its data until either copy is written")
+                .arg(
+                    Arg::with_name("source")
+                        .required(true)
+                        .value_name("SOURCE")
+                        .help("Path of the file within the filesystem"),
+                )
+                .arg(
+                    Arg::with_name("dest")
+                        .required(true)
+                        .value_name("DEST")
+                        .help("Path within the filesystem of the clone to create"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write a subtree to a tar archive without mounting the filesystem")
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .default_value("/")
+                        .value_name("PATH")
+                        .help("Path within the filesystem of the subtree to export"),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("FILE")
+                        .help("Archive to write, compressed if it ends in .gz or .tgz, or - for standard output"),
+                )
+                .arg(
+                    Arg::with_name("as-of")
+                        .long("as-of")
+                        .takes_value(true)
+                        .value_name("TIME")
+                        .help("Export the subtree as it was at a time, such as -10s or a timestamp, for a consistent snapshot"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Back up the filesystem with CockroachDB's BACKUP")
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("URI")
+                        .help("Backup collection to add the backup to, as in s3://bucket/path?AUTH=implicit"),
+                )
+                .arg(
+                    Arg::with_name("as-of")
+                        .long("as-of")
+                        .takes_value(true)
+                        .value_name("TIME")
+                        .help("Back up the filesystem as it was at a time, such as -10s or a timestamp"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restore the filesystem from its latest backup, as a database that does not exist yet")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("URI")
+                        .help("Backup collection to restore the latest backup of"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("s3-gateway")
+                .about("Serve the filesystem over a minimal, unauthenticated S3 API instead of mounting it")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:9000")
+                        .value_name("ADDR")
+                        .help("Address to listen for S3 requests on, open to anyone who can reach it"),
+                )
+                .arg(
+                    Arg::with_name("pool-size")
+                        .long("pool-size")
+                        .takes_value(true)
+                        .default_value("4")
+                        .value_name("N")
+                        .help("Number of connections serving requests concurrently"),
+                )
+                .arg(
+                    Arg::with_name("uid")
+                        .long("uid")
+                        .takes_value(true)
+                        .help("User id to own the buckets, directories and objects created (default: the gateway's)"),
+                )
+                .arg(
+                    Arg::with_name("gid")
+                        .long("gid")
+                        .takes_value(true)
+                        .help("Group id to own the buckets, directories and objects created (default: the gateway's)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve-webdav")
+                .about("Serve the filesystem over unauthenticated WebDAV instead of mounting it")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:8080")
+                        .value_name("ADDR")
+                        .help("Address to listen for WebDAV requests on, open to anyone who can reach it"),
+                )
+                .arg(
+                    Arg::with_name("pool-size")
+                        .long("pool-size")
+                        .takes_value(true)
+                        .default_value("4")
+                        .value_name("N")
+                        .help("Number of connections serving requests concurrently"),
+                )
+                .arg(
+                    Arg::with_name("uid")
+                        .long("uid")
+                        .takes_value(true)
+                        .help("User id to own the directories and files created (default: the server's)"),
+                )
+                .arg(
+                    Arg::with_name("gid")
+                        .long("gid")
+                        .takes_value(true)
+                        .help("Group id to own the directories and files created (default: the server's)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve-nfs")
+                .about("Serve the filesystem over NFSv3 instead of mounting it, trusting the credentials clients send")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:2049")
+                        .value_name("ADDR")
+                        .help("Address to serve both the MOUNT and NFS programs on, which clients give as port and mountport"),
+                )
+                .arg(
+                    Arg::with_name("pool-size")
+                        .long("pool-size")
+                        .takes_value(true)
+                        .default_value("4")
+                        .value_name("N")
+                        .help("Number of connections serving requests concurrently"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve-9p")
+                .about("Serve the filesystem over 9P2000.L to virtual machines and WSL, leaving permission checks to clients")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:564")
+                        .value_name("ADDR")
+                        .help("Address to serve on"),
+                )
+                .arg(
+                    Arg::with_name("socket")
+                        .long("socket")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Unix socket to serve on instead of an address, such as one QEMU or WSL forwards"),
+                )
+                .arg(
+                    Arg::with_name("pool-size")
+                        .long("pool-size")
+                        .takes_value(true)
+                        .default_value("4")
+                        .value_name("N")
+                        .help("Number of connections serving requests concurrently"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ctl")
+                .about("Inspect or adjust a running mount through its control socket, as the binary does when run as crfsctl")
+                .arg(
+                    Arg::with_name("socket")
+                        .long("socket")
+                        .short("s")
+                        .takes_value(true)
+                        .required(true)
+                        .env("CRFS_CONTROL_SOCKET")
+                        .value_name("PATH")
+                        .help("The mount's --control-socket"),
+                )
+                .arg(
+                    Arg::with_name("command")
+                        .required(true)
+                        .possible_values(&["status", "log-level", "drop-caches", "fsck", "gc"])
+                        .help("What to do: report status, set the log levels, drop caches, check, or collect garbage"),
+                )
+                .arg(
+                    Arg::with_name("args")
+                        .multiple(true)
+                        .help("Arguments of the command: the levels for log-level, and quick or full for fsck"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Load a local directory tree without mounting the filesystem")
+                .arg(
+                    Arg::with_name("local")
+                        .required(true)
+                        .value_name("LOCAL_DIR")
+                        .help("Local directory whose contents to load"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .value_name("PATH")
+                        .help("Path within the filesystem of the directory to load them into"),
+                ),
+        )
+}
 
-    let conn = Connection::connect("postgres://root@localhost:26257/cockroachfs", TlsMode::None)?;
+/// Check the filesystem before mounting it, refusing to mount it if problems
+/// are found unless warn_only is set.
+fn check_on_mount(conn: &Connection, level: check::Level, warn_only: bool) -> io::Result<()> {
+    let problems = check::run(conn, level)?;
+    for problem in &problems {
+        warn!("check: {}", problem);
+    }
+    if !problems.is_empty() && !warn_only {
+        return Err(io::Error::other(format!(
+            "found {} problems, refusing to mount (use --check-warn-only to mount anyway)",
+            problems.len()
+        )));
+    }
+    Ok(())
+}
 
-    let path_str = matches.value_of("mountpoint").unwrap_or("./mountpoint");
-    let path = Path::new(path_str);
+/// Parse a HOST:PORT endpoint.
+fn parse_endpoint(endpoint: &str) -> io::Result<(String, u16)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid endpoint {:?}, expected HOST:PORT", endpoint),
+        )
+    };
+    let idx = endpoint.rfind(':').ok_or_else(invalid)?;
+    let port = endpoint[idx + 1..].parse().map_err(|_| invalid())?;
+    Ok((endpoint[..idx].to_string(), port))
+}
+
+/// Parse the user or group id given to a flag, if any.
+fn parse_id(matches: &clap::ArgMatches, name: &str) -> io::Result<Option<u32>> {
+    match matches.value_of(name) {
+        None => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid --{} {:?}: {}", name, value, e),
+            )
+        }),
+    }
+}
+
+/// Parse the octal permission mask given to --umask, if any.
+fn parse_umask(matches: &clap::ArgMatches) -> io::Result<Option<u16>> {
+    match matches.value_of("umask") {
+        None => Ok(None),
+        Some(value) => match u16::from_str_radix(value, 8) {
+            Ok(umask) if umask <= 0o7777 => Ok(Some(umask)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid --umask {:?}", value),
+            )),
+        },
+    }
+}
+
+/// Parse the cache memory budget given in MiB, returning it in bytes.
+fn parse_cache_size(matches: &clap::ArgMatches) -> io::Result<usize> {
+    let value = matches.value_of("cache-size").unwrap();
+    value.parse::<usize>().map(|mb| mb << 20).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid --cache-size {:?}: {}", value, e),
+        )
+    })
+}
+
+/// Parse a quota limit given to flag, where 0 means no limit.
+fn parse_limit(matches: &clap::ArgMatches, flag: &str) -> io::Result<Option<i64>> {
+    let value = match matches.value_of(flag) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.parse::<i64>() {
+        Ok(0) => Ok(None),
+        Ok(limit) if limit > 0 => Ok(Some(limit)),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid --{} {:?}: must not be negative", flag, value),
+        )),
+        Err(e) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid --{} {:?}: {}", flag, value, e),
+        )),
+    }
+}
+
+/// Parse a duration in seconds given to flag, where 0 disables whatever it
+/// configures.
+fn parse_seconds(matches: &clap::ArgMatches, flag: &str) -> io::Result<Option<Duration>> {
+    let value = matches.value_of(flag).unwrap();
+    match value.parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+        Err(e) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid --{} {:?}: {}", flag, value, e),
+        )),
+    }
+}
+
+/// Parse settings of how stale reads for a class of operation may be, each
+/// given as CLASS=SECONDS.
+fn parse_staleness(values: Option<clap::Values>) -> io::Result<Vec<(sql::ReadClass, Duration)>> {
+    let invalid = |value: &str, msg: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid --staleness {:?}: {}", value, msg),
+        )
+    };
+    let mut settings = Vec::new();
+    for value in values.into_iter().flatten() {
+        let eq = value
+            .find('=')
+            .ok_or_else(|| invalid(value, "expected CLASS=SECONDS".to_string()))?;
+        let class = value[..eq].parse().map_err(|e| invalid(value, e))?;
+        let secs: u64 = value[eq + 1..]
+            .parse()
+            .map_err(|e: std::num::ParseIntError| invalid(value, e.to_string()))?;
+        settings.push((class, Duration::from_secs(secs)));
+    }
+    Ok(settings)
+}
+
+/// Parse the block size, which must be a power of two from MIN_BLOCK_SIZE to
+/// MAX_BLOCK_SIZE.
+fn parse_block_size(matches: &clap::ArgMatches, flag: &str) -> io::Result<i64> {
+    let value = matches.value_of(flag).unwrap();
+    let (digits, unit) = value.split_at(
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+    let shift = match unit {
+        "" => 0,
+        "K" | "KiB" => 10,
+        "M" | "MiB" => 20,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid --{} {:?}: unknown unit {:?}", flag, value, unit),
+            ))
+        }
+    };
+    match digits.parse::<i64>().map(|n| n << shift) {
+        Ok(size) if (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&size) && size.count_ones() == 1 => {
+            Ok(size)
+        }
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "invalid --{} {:?}: must be a power of two from {} to {}",
+                flag, value, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+            ),
+        )),
+        Err(e) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid --{} {:?}: {}", flag, value, e),
+        )),
+    }
+}
+
+/// Parse the longest file name a new filesystem accepts.
+fn parse_name_max(matches: &clap::ArgMatches) -> io::Result<i64> {
+    let value = matches.value_of("name-max").unwrap();
+    match value.parse::<i64>() {
+        Ok(len) if (1..=MAX_NAME_MAX).contains(&len) => Ok(len),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "invalid --name-max {:?}: must be from 1 to {}",
+                value, MAX_NAME_MAX
+            ),
+        )),
+        Err(e) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid --name-max {:?}: {}", value, e),
+        )),
+    }
+}
+
+/// Refuse a setting fixed when the filesystem was created if it was given
+/// explicitly and differs from the stored one.
+fn check_fixed(
+    matches: &clap::ArgMatches,
+    flag: &str,
+    requested: i64,
+    stored: i64,
+) -> io::Result<()> {
+    if stored != requested && matches.occurrences_of(flag) > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "--{} {} differs from the filesystem's value of {}",
+                flag, requested, stored
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Parse the number of pooled connections.
+fn parse_pool_size(matches: &clap::ArgMatches) -> io::Result<usize> {
+    let value = matches.value_of("pool-size").unwrap();
+    value.parse::<usize>().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid --pool-size {:?}: {}", value, e),
+        )
+    })
+}
+
+/// Parse the number of blocks to read ahead, which needs the pool to read
+/// them and the block cache to hold them.
+fn parse_readahead(matches: &clap::ArgMatches) -> io::Result<u32> {
+    let value = matches.value_of("readahead").unwrap();
+    let blocks = value.parse::<u32>().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid --readahead {:?}: {}", value, e),
+        )
+    })?;
+    if blocks > 0
+        && (parse_pool_size(matches)? == 0 || parse_seconds(matches, "block-cache-ttl")?.is_none())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--readahead needs --pool-size and --block-cache-ttl",
+        ));
+    }
+    Ok(blocks)
+}
+
+/// Parse the pairs given to an id mapping flag.
+fn parse_id_map(values: Option<clap::Values>) -> io::Result<IdMap> {
+    IdMap::parse(values.into_iter().flatten())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
 
-    let crfs = CockroachFS::new(conn);
-    return mount(crfs, &path, &[]);
+/// Verify that the current user is permitted to mount with allow_other or
+/// allow_root. Root can always do so, while other users need fuse.conf to
+/// contain the user_allow_other option.
+fn check_user_allow_other() -> io::Result<()> {
+    if cfg!(not(target_os = "linux")) || unsafe { libc::geteuid() } == 0 {
+        return Ok(());
+    }
+    let permitted = match File::open(FUSE_CONF) {
+        Ok(file) => BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .any(|line| line.split('#').next().unwrap().trim() == "user_allow_other"),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => false,
+        Err(err) => return Err(err),
+    };
+    if !permitted {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "--allow-other and --allow-root require user_allow_other in {}",
+                FUSE_CONF
+            ),
+        ));
+    }
+    Ok(())
 }
@@ -0,0 +1,1146 @@
+//! A store that keeps the filesystem in memory, so that the FUSE layer can be
+//! tested without a database.
+//!
+//! It follows the statements in sql.rs closely enough for what the FUSE
+//! layer relies on: files are stored sparsely in whole blocks, removing the
+//! last entry referring to an inode removes the inode, and listings are
+//! ordered and resumed by the same cookies. Quotas, versions and staleness
+//! are left out.
+
+use super::error::CrfsError;
+use super::sql::{self, DirEntry, Lock};
+use super::store::{BlockStore, MetadataStore, Store};
+use fuse::{FileAttr, FileType, FUSE_ROOT_ID};
+use libc::{F_RDLCK, F_UNLCK, F_WRLCK};
+use postgres::Result;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::Timespec;
+
+/// A filesystem kept in memory. Clones share it, as connections to the same
+/// database do.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    inodes: HashMap<u64, Inode>,
+    /// The inode each directory entry refers to, by directory and name.
+    entries: BTreeMap<(u64, Vec<u8>), u64>,
+    /// Stored blocks, each a whole block long, by inode and index.
+    blocks: BTreeMap<(u64, i64), Vec<u8>>,
+    locks: Vec<HeldLock>,
+    /// The session holding each write lease, by inode, and when it expires.
+    leases: HashMap<u64, (String, Instant)>,
+    /// The last inode number, generation and session handed out.
+    last_ino: u64,
+    last_generation: u64,
+    last_session: u64,
+    /// Number of writes to inodes so far, which versions them.
+    writes: u64,
+    /// Whether operations fail as they do when the database is unreachable.
+    unreachable: bool,
+}
+
+struct Inode {
+    attr: FileAttr,
+    generation: u64,
+    /// Target of a symlink.
+    target: Option<String>,
+    xattrs: BTreeMap<String, Vec<u8>>,
+    /// The number of writes to inodes as of the last one to this inode.
+    version: u64,
+}
+
+/// A byte-range lock held on an inode.
+struct HeldLock {
+    ino: u64,
+    session: String,
+    owner: u64,
+    start: u64,
+    end: u64,
+    exclusive: bool,
+    pid: u32,
+    expires: Instant,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// Fail every operation from now on as if the database could not be
+    /// reached, or stop doing so.
+    pub fn set_unreachable(&self, unreachable: bool) {
+        self.state.lock().unwrap().unreachable = unreachable;
+    }
+
+    /// Run op on the state, unless the store is unreachable.
+    fn with<T, F>(&self, op: F) -> Result<T>
+    where
+        F: FnOnce(&mut State) -> T,
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.unreachable {
+            let err = io::Error::new(io::ErrorKind::ConnectionRefused, "store unreachable");
+            return Err(err.into());
+        }
+        Ok(op(&mut state))
+    }
+
+    /// Like with, for operations that fail with errors of their own.
+    fn mutate<T, F>(&self, op: F) -> std::result::Result<T, CrfsError>
+    where
+        F: FnOnce(&mut State) -> std::result::Result<T, CrfsError>,
+    {
+        self.with(op)?
+    }
+}
+
+impl State {
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        self.inodes.get(&ino).map(|inode| inode.attr)
+    }
+
+    fn entry(&self, parent: u64, name: &[u8]) -> Option<(FileAttr, u64)> {
+        let ino = self.entries.get(&(parent, name.to_vec()))?;
+        let inode = &self.inodes[ino];
+        Some((inode.attr, inode.generation))
+    }
+
+    /// Change inode ino with change, recording the write.
+    fn write_inode<F: FnOnce(&mut FileAttr)>(&mut self, ino: u64, change: F) -> Option<FileAttr> {
+        self.writes += 1;
+        let inode = self.inodes.get_mut(&ino)?;
+        inode.version = self.writes;
+        change(&mut inode.attr);
+        Some(inode.attr)
+    }
+
+    fn insert_inode(
+        &mut self,
+        kind: FileType,
+        perm: u16,
+        rdev: u32,
+        owner: (u32, u32),
+        target: Option<String>,
+    ) -> (FileAttr, u64) {
+        self.last_ino += 1;
+        self.last_generation += 1;
+        self.writes += 1;
+        let now = time::get_time();
+        let attr = FileAttr {
+            ino: self.last_ino,
+            size: target.as_ref().map_or(0, |target| target.len() as u64),
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind,
+            perm,
+            nlink: 1,
+            uid: owner.0,
+            gid: owner.1,
+            rdev,
+            flags: 0,
+        };
+        let inode = Inode {
+            attr,
+            generation: self.last_generation,
+            target,
+            xattrs: BTreeMap::new(),
+            version: self.writes,
+        };
+        self.inodes.insert(attr.ino, inode);
+        (attr, self.last_generation)
+    }
+
+    /// Link ino into parent as name, failing with Exists if there is such an
+    /// entry already.
+    fn insert_entry(
+        &mut self,
+        parent: u64,
+        name: &[u8],
+        ino: u64,
+    ) -> std::result::Result<(), CrfsError> {
+        if !self.inodes.contains_key(&parent) {
+            return Err(CrfsError::NotFound);
+        }
+        if self.entries.contains_key(&(parent, name.to_vec())) {
+            return Err(CrfsError::Exists);
+        }
+        self.entries.insert((parent, name.to_vec()), ino);
+        self.touch_dir(parent);
+        Ok(())
+    }
+
+    /// Record that the entries of directory ino changed.
+    fn touch_dir(&mut self, ino: u64) {
+        let now = time::get_time();
+        self.write_inode(ino, |attr| {
+            attr.mtime = now;
+            attr.ctime = now;
+        });
+    }
+
+    fn has_children(&self, ino: u64) -> bool {
+        self.entries
+            .range((ino, Vec::new())..)
+            .next()
+            .is_some_and(|((dir, _), _)| *dir == ino)
+    }
+
+    /// Account for an entry referring to ino having been removed, removing
+    /// the inode if it was the last one.
+    fn drop_link(&mut self, ino: u64) {
+        let attr = self.attr(ino).unwrap();
+        if attr.nlink > 1 && attr.kind != FileType::Directory {
+            let now = time::get_time();
+            self.write_inode(ino, |attr| {
+                attr.nlink -= 1;
+                attr.ctime = now;
+            });
+            return;
+        }
+        self.inodes.remove(&ino);
+        self.blocks.retain(|&(file, _), _| file != ino);
+        self.locks.retain(|lock| lock.ino != ino);
+        self.leases.remove(&ino);
+    }
+
+    /// The directory an entry for ino lies in.
+    fn parent(&self, ino: u64) -> Option<u64> {
+        self.entries
+            .iter()
+            .find(|(_, &child)| child == ino)
+            .map(|((dir, _), _)| *dir)
+    }
+
+    /// Whether directory ino is dir or lies beneath it.
+    fn is_beneath(&self, mut ino: u64, dir: u64) -> bool {
+        while ino != sql::root() {
+            if ino == dir {
+                return true;
+            }
+            ino = match self.parent(ino) {
+                Some(parent) => parent,
+                None => return false,
+            };
+        }
+        dir == sql::root()
+    }
+
+    /// Drop the data of ino beyond size, zeroing the rest of the last block
+    /// kept.
+    fn truncate_blocks(&mut self, ino: u64, size: u64) {
+        let block_size = sql::block_size();
+        let cur_size = self.inodes[&ino].attr.size as i64;
+        let keep = cmp::min(cur_size, size as i64);
+        let keep_blocks = (keep + block_size - 1) / block_size;
+        self.blocks
+            .retain(|&(file, idx), _| file != ino || idx < keep_blocks);
+        if keep % block_size != 0 {
+            if let Some(bytes) = self.blocks.get_mut(&(ino, keep / block_size)) {
+                for byte in &mut bytes[(keep % block_size) as usize..] {
+                    *byte = 0;
+                }
+            }
+        }
+        let blocks = self.blocks.range((ino, 0)..(ino + 1, 0)).count() as u64;
+        self.write_inode(ino, |attr| attr.blocks = blocks);
+    }
+
+    fn entries_from<T, F>(&self, ino: u64, cookie: i64, limit: i64, entry: F) -> Vec<(T, i64)>
+    where
+        F: Fn(DirEntry, FileAttr) -> T,
+    {
+        let mut rows: Vec<(i64, Vec<u8>, u64)> = self
+            .entries
+            .range((ino, Vec::new())..)
+            .take_while(|((dir, _), _)| *dir == ino)
+            .map(|((_, name), &child)| (name_hash(name), name.clone(), child))
+            .filter(|(hash, _, _)| *hash >= cookie >> sql::COOKIE_SEQ_BITS)
+            .collect();
+        rows.sort();
+        let rows = rows
+            .into_iter()
+            .map(|(hash, child_name, child_ino)| {
+                let attr = self.inodes[&child_ino].attr;
+                let ent = DirEntry {
+                    child_ino,
+                    child_kind: attr.kind,
+                    child_name,
+                };
+                (hash, entry(ent, attr))
+            })
+            .collect();
+        sql::number_listing(rows, cookie, limit)
+    }
+
+    fn conflicting_lock(
+        &self,
+        ino: u64,
+        session: &str,
+        owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+    ) -> Option<Lock> {
+        let now = Instant::now();
+        let exclusive = typ == F_WRLCK as u32;
+        self.locks
+            .iter()
+            .find(|lock| {
+                lock.ino == ino
+                    && !(lock.session == session && lock.owner == owner)
+                    && lock.start <= end
+                    && lock.end >= start
+                    && (lock.exclusive || exclusive)
+                    && lock.expires > now
+            })
+            .map(|lock| Lock {
+                session: lock.session.clone(),
+                start: lock.start,
+                end: lock.end,
+                typ: if lock.exclusive { F_WRLCK } else { F_RDLCK } as u32,
+                pid: lock.pid,
+            })
+    }
+}
+
+/// The hash of a name that listings are ordered by, as the name_hash column
+/// of dir_entries computes it.
+fn name_hash(name: &[u8]) -> i64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in name {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    (hash & 0x7f_ffff_ffff_ffff) as i64
+}
+
+impl MetadataStore for MemoryStore {
+    fn lookup_inode(&self, ino: u64) -> std::result::Result<Option<FileAttr>, CrfsError> {
+        Ok(self.with(|state| state.attr(ino))?)
+    }
+
+    fn lookup_inode_generation(
+        &self,
+        ino: u64,
+    ) -> std::result::Result<Option<(FileAttr, u64)>, CrfsError> {
+        Ok(self.with(|state| {
+            state
+                .inodes
+                .get(&ino)
+                .map(|inode| (inode.attr, inode.generation))
+        })?)
+    }
+
+    fn lookup_inode_kind(&self, ino: u64) -> Result<Option<FileType>> {
+        self.with(|state| state.attr(ino).map(|attr| attr.kind))
+    }
+
+    fn generations(&self, inos: &[u64]) -> Result<HashMap<u64, u64>> {
+        self.with(|state| {
+            inos.iter()
+                .filter_map(|ino| state.inodes.get(ino))
+                .map(|inode| (inode.attr.ino, inode.generation))
+                .collect()
+        })
+    }
+
+    fn inode_version(&self, ino: u64) -> Result<Option<String>> {
+        self.with(|state| {
+            state
+                .inodes
+                .get(&ino)
+                .map(|inode| inode.version.to_string())
+        })
+    }
+
+    fn lookup_dir_ent(
+        &self,
+        parent: u64,
+        name: &[u8],
+    ) -> std::result::Result<Option<(FileAttr, u64)>, CrfsError> {
+        Ok(self.with(|state| state.entry(parent, name))?)
+    }
+
+    fn read_dir_from(&self, ino: u64, cookie: i64, limit: i64) -> Result<Vec<(DirEntry, i64)>> {
+        self.with(|state| state.entries_from(ino, cookie, limit, |ent, _| ent))
+    }
+
+    fn read_dir_plus_from(
+        &self,
+        ino: u64,
+        cookie: i64,
+        limit: i64,
+    ) -> Result<Vec<((DirEntry, FileAttr), i64)>> {
+        self.with(|state| state.entries_from(ino, cookie, limit, |ent, attr| (ent, attr)))
+    }
+
+    fn create_root(&self, uid: u32, gid: u32) -> Result<bool> {
+        self.with(|state| {
+            if state.inodes.contains_key(&FUSE_ROOT_ID) {
+                return false;
+            }
+            let last_ino = cmp::max(state.last_ino, FUSE_ROOT_ID);
+            state.last_ino = FUSE_ROOT_ID - 1;
+            state.insert_inode(FileType::Directory, 0o755, 0, (uid, gid), None);
+            state.last_ino = last_ino;
+            true
+        })
+    }
+
+    fn create_inode(
+        &self,
+        parent: u64,
+        name: &[u8],
+        ft: FileType,
+        perm: u16,
+        rdev: u32,
+        owner: (u32, u32),
+    ) -> std::result::Result<(FileAttr, u64), CrfsError> {
+        self.mutate(|state| {
+            if parent != 0 && state.entry(parent, name).is_some() {
+                return Err(CrfsError::Exists);
+            }
+            let (attr, generation) = state.insert_inode(ft, perm, rdev, owner, None);
+            if parent != 0 {
+                if let Err(err) = state.insert_entry(parent, name, attr.ino) {
+                    state.inodes.remove(&attr.ino);
+                    return Err(err);
+                }
+            }
+            Ok((attr, generation))
+        })
+    }
+
+    fn create_symlink(
+        &self,
+        parent: u64,
+        name: &[u8],
+        target: &str,
+        uid: u32,
+        gid: u32,
+    ) -> std::result::Result<(FileAttr, u64), CrfsError> {
+        self.mutate(|state| {
+            if state.entry(parent, name).is_some() {
+                return Err(CrfsError::Exists);
+            }
+            let target = Some(target.to_string());
+            let (attr, generation) =
+                state.insert_inode(FileType::Symlink, 0o777, 0, (uid, gid), target);
+            if let Err(err) = state.insert_entry(parent, name, attr.ino) {
+                state.inodes.remove(&attr.ino);
+                return Err(err);
+            }
+            Ok((attr, generation))
+        })
+    }
+
+    fn read_symlink(&self, ino: u64) -> Result<Option<String>> {
+        self.with(|state| {
+            state
+                .inodes
+                .get(&ino)
+                .and_then(|inode| inode.target.clone())
+        })
+    }
+
+    fn update_inode(
+        &self,
+        ino: u64,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+        chgtime: Option<Timespec>,
+        crtime: Option<Timespec>,
+        kind: Option<FileType>,
+        perm: Option<u16>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        flags: Option<u32>,
+    ) -> std::result::Result<FileAttr, CrfsError> {
+        self.mutate(|state| {
+            if !state.inodes.contains_key(&ino) {
+                return Err(CrfsError::Stale);
+            }
+            if let Some(size) = size {
+                state.truncate_blocks(ino, size);
+            }
+            let now = time::get_time();
+            let attr = state.write_inode(ino, |attr| {
+                attr.size = size.unwrap_or(attr.size);
+                attr.atime = atime.unwrap_or(attr.atime);
+                attr.mtime = mtime.unwrap_or(if size.is_some() { now } else { attr.mtime });
+                attr.ctime = chgtime.unwrap_or(now);
+                attr.crtime = crtime.unwrap_or(attr.crtime);
+                attr.kind = kind.unwrap_or(attr.kind);
+                attr.perm = perm.unwrap_or(attr.perm);
+                attr.uid = uid.unwrap_or(attr.uid);
+                attr.gid = gid.unwrap_or(attr.gid);
+                attr.flags = flags.unwrap_or(attr.flags);
+            });
+            Ok(attr.unwrap())
+        })
+    }
+
+    fn update_atimes(&self, atimes: &[(u64, Timespec)], strict: bool) -> Result<u64> {
+        self.with(|state| {
+            let mut updated = 0;
+            for &(ino, time) in atimes {
+                let attr = match state.attr(ino) {
+                    Some(attr) => attr,
+                    None => continue,
+                };
+                let day_old = attr.atime.sec < time.sec - 24 * 60 * 60;
+                if attr.atime < time
+                    && (strict || attr.atime <= attr.mtime || attr.atime <= attr.ctime || day_old)
+                {
+                    state.write_inode(ino, |attr| attr.atime = time);
+                    updated += 1;
+                }
+            }
+            updated
+        })
+    }
+
+    fn link(
+        &self,
+        ino: u64,
+        parent: u64,
+        newname: &[u8],
+    ) -> std::result::Result<(FileAttr, u64), CrfsError> {
+        self.mutate(|state| {
+            let generation = match state.inodes.get(&ino) {
+                Some(inode) if inode.attr.kind != FileType::RegularFile => {
+                    return Err(CrfsError::NotPermitted)
+                }
+                Some(inode) => inode.generation,
+                None => return Err(CrfsError::Stale),
+            };
+            state.insert_entry(parent, newname, ino)?;
+            let now = time::get_time();
+            let attr = state.write_inode(ino, |attr| {
+                attr.nlink += 1;
+                attr.ctime = now;
+            });
+            Ok((attr.unwrap(), generation))
+        })
+    }
+
+    fn unlink(&self, parent: u64, name: &[u8], dir: bool) -> std::result::Result<(), CrfsError> {
+        self.mutate(|state| {
+            let (attr, _) = state.entry(parent, name).ok_or(CrfsError::NotFound)?;
+            match (dir, attr.kind == FileType::Directory) {
+                (false, true) => return Err(CrfsError::IsDir),
+                (true, false) => return Err(CrfsError::NotDir),
+                (true, true) if state.has_children(attr.ino) => return Err(CrfsError::NotEmpty),
+                _ => {}
+            }
+            state.entries.remove(&(parent, name.to_vec()));
+            state.drop_link(attr.ino);
+            state.touch_dir(parent);
+            Ok(())
+        })
+    }
+
+    fn rename_dir_ent(
+        &self,
+        parent: u64,
+        name: &[u8],
+        new_parent: u64,
+        new_name: &[u8],
+    ) -> std::result::Result<(), CrfsError> {
+        self.mutate(|state| {
+            let (src, _) = state.entry(parent, name).ok_or(CrfsError::NotFound)?;
+            let is_dir = src.kind == FileType::Directory;
+            if is_dir && new_parent != parent && state.is_beneath(new_parent, src.ino) {
+                return Err(CrfsError::IntoItself);
+            }
+            if let Some((dst, _)) = state.entry(new_parent, new_name) {
+                if dst.ino == src.ino {
+                    return Ok(());
+                }
+                match (is_dir, dst.kind == FileType::Directory) {
+                    (false, true) => return Err(CrfsError::IsDir),
+                    (true, false) => return Err(CrfsError::NotDir),
+                    (true, true) if state.has_children(dst.ino) => return Err(CrfsError::NotEmpty),
+                    _ => {}
+                }
+                state.entries.remove(&(new_parent, new_name.to_vec()));
+                state.drop_link(dst.ino);
+            }
+            state.entries.remove(&(parent, name.to_vec()));
+            state
+                .entries
+                .insert((new_parent, new_name.to_vec()), src.ino);
+            let now = time::get_time();
+            state.write_inode(src.ino, |attr| attr.ctime = now);
+            state.touch_dir(parent);
+            if new_parent != parent {
+                state.touch_dir(new_parent);
+            }
+            Ok(())
+        })
+    }
+
+    fn get_xattr(&self, ino: u64, name: &str) -> Result<Option<Vec<u8>>> {
+        self.with(|state| {
+            state
+                .inodes
+                .get(&ino)
+                .and_then(|inode| inode.xattrs.get(name).cloned())
+        })
+    }
+
+    fn list_xattrs(&self, ino: u64) -> Result<Vec<String>> {
+        self.with(|state| {
+            state
+                .inodes
+                .get(&ino)
+                .map_or_else(Vec::new, |inode| inode.xattrs.keys().cloned().collect())
+        })
+    }
+
+    fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        create: bool,
+        replace: bool,
+    ) -> std::result::Result<bool, CrfsError> {
+        self.mutate(|state| {
+            let inode = state.inodes.get_mut(&ino).ok_or(CrfsError::NotFound)?;
+            let exists = inode.xattrs.contains_key(name);
+            if create && exists {
+                return Err(CrfsError::Exists);
+            }
+            if replace && !create && !exists {
+                return Ok(false);
+            }
+            inode.xattrs.insert(name.to_string(), value.to_vec());
+            let now = time::get_time();
+            state.write_inode(ino, |attr| attr.ctime = now);
+            Ok(true)
+        })
+    }
+
+    fn remove_xattr(&self, ino: u64, name: &str) -> Result<bool> {
+        self.with(|state| {
+            let removed = state
+                .inodes
+                .get_mut(&ino)
+                .is_some_and(|inode| inode.xattrs.remove(name).is_some());
+            if removed {
+                let now = time::get_time();
+                state.write_inode(ino, |attr| attr.ctime = now);
+            }
+            removed
+        })
+    }
+
+    fn usage(&self) -> Result<(u64, u64)> {
+        self.with(|state| (state.blocks.len() as u64, state.inodes.len() as u64))
+    }
+
+    fn store_capacity(&self) -> Result<(u64, u64)> {
+        // Unknown, as it is to users who may not read crdb_internal.
+        self.with(|_| (0, 0))
+    }
+
+    fn new_session(&self) -> Result<String> {
+        self.with(|state| {
+            state.last_session += 1;
+            format!("session-{}", state.last_session)
+        })
+    }
+
+    fn conflicting_lock(
+        &self,
+        ino: u64,
+        session: &str,
+        owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+    ) -> Result<Option<Lock>> {
+        self.with(|state| state.conflicting_lock(ino, session, owner, start, end, typ))
+    }
+
+    fn set_lock(
+        &self,
+        ino: u64,
+        session: &str,
+        owner: u64,
+        lock: &Lock,
+        lease: Duration,
+    ) -> Result<Option<Lock>> {
+        self.with(|state| {
+            let unlock = lock.typ == F_UNLCK as u32;
+            if !unlock {
+                let conflict =
+                    state.conflicting_lock(ino, session, owner, lock.start, lock.end, lock.typ);
+                if conflict.is_some() {
+                    return conflict;
+                }
+            }
+            let expires = Instant::now() + lease;
+            let mut remaining = Vec::new();
+            state.locks.retain(|held| {
+                let overlaps = held.ino == ino
+                    && held.session == session
+                    && held.owner == owner
+                    && held.start <= lock.end
+                    && held.end >= lock.start;
+                if overlaps {
+                    if held.start < lock.start {
+                        remaining.push((held.start, lock.start - 1, held.exclusive, held.pid));
+                    }
+                    if held.end > lock.end {
+                        remaining.push((lock.end + 1, held.end, held.exclusive, held.pid));
+                    }
+                }
+                !overlaps
+            });
+            if !unlock {
+                let exclusive = lock.typ == F_WRLCK as u32;
+                remaining.push((lock.start, lock.end, exclusive, lock.pid));
+            }
+            for (start, end, exclusive, pid) in remaining {
+                state.locks.push(HeldLock {
+                    ino,
+                    session: session.to_string(),
+                    owner,
+                    start,
+                    end,
+                    exclusive,
+                    pid,
+                    expires,
+                });
+            }
+            None
+        })
+    }
+
+    fn release_locks(&self, ino: u64, session: &str, owner: u64) -> Result<u64> {
+        self.with(|state| {
+            let before = state.locks.len();
+            state.locks.retain(|lock| {
+                !(lock.ino == ino && lock.session == session && lock.owner == owner)
+            });
+            (before - state.locks.len()) as u64
+        })
+    }
+
+    fn renew_locks(&self, session: &str, lease: Duration) -> Result<u64> {
+        self.with(|state| {
+            let expires = Instant::now() + lease;
+            let mut renewed = 0;
+            for lock in state
+                .locks
+                .iter_mut()
+                .filter(|lock| lock.session == session)
+            {
+                lock.expires = expires;
+                renewed += 1;
+            }
+            renewed
+        })
+    }
+
+    fn release_session_locks(&self, session: &str) -> Result<u64> {
+        self.with(|state| {
+            let before = state.locks.len();
+            state.locks.retain(|lock| lock.session != session);
+            (before - state.locks.len()) as u64
+        })
+    }
+
+    fn take_write_lease(
+        &self,
+        ino: u64,
+        session: &str,
+        duration: Duration,
+        force: bool,
+    ) -> Result<bool> {
+        self.with(|state| {
+            let now = Instant::now();
+            let free = match state.leases.get(&ino) {
+                Some((holder, expires)) => force || holder == session || *expires < now,
+                None => true,
+            };
+            if free {
+                state
+                    .leases
+                    .insert(ino, (session.to_string(), now + duration));
+            }
+            free
+        })
+    }
+
+    fn release_write_lease(&self, ino: u64, session: &str) -> Result<u64> {
+        self.with(|state| match state.leases.get(&ino) {
+            Some((holder, _)) if holder == session => {
+                state.leases.remove(&ino);
+                1
+            }
+            _ => 0,
+        })
+    }
+
+    fn release_session_leases(&self, session: &str) -> Result<u64> {
+        self.with(|state| {
+            let before = state.leases.len();
+            state.leases.retain(|_, (holder, _)| holder != session);
+            (before - state.leases.len()) as u64
+        })
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn read_data(&self, ino: u64, offset: i64, size: usize) -> Result<Option<Vec<u8>>> {
+        self.with(|state| {
+            let cur_size = state.attr(ino)?.size as i64;
+            if offset >= cur_size || size == 0 {
+                return Some(Vec::new());
+            }
+            let size = cmp::min(size as i64, cur_size - offset) as usize;
+            let block_size = sql::block_size();
+            let end = offset + size as i64;
+            let mut data = vec![0; size];
+            let blocks = (ino, offset / block_size)..=(ino, (end - 1) / block_size);
+            for (&(_, idx), bytes) in state.blocks.range(blocks) {
+                let block_start = idx * block_size;
+                let from = cmp::max(offset, block_start);
+                let to = cmp::min(end, block_start + block_size);
+                data[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+                    &bytes[(from - block_start) as usize..(to - block_start) as usize],
+                );
+            }
+            Some(data)
+        })
+    }
+
+    fn write_data(
+        &self,
+        ino: u64,
+        offset: Option<i64>,
+        data: &[u8],
+    ) -> std::result::Result<i64, CrfsError> {
+        self.mutate(|state| {
+            let cur_size = state.attr(ino).ok_or(CrfsError::Stale)?.size as i64;
+            let offset = offset.unwrap_or(cur_size);
+            let block_size = sql::block_size();
+            let end = offset + data.len() as i64;
+            if !data.is_empty() {
+                for idx in offset / block_size..=(end - 1) / block_size {
+                    let block_start = idx * block_size;
+                    let from = cmp::max(offset, block_start);
+                    let to = cmp::min(end, block_start + block_size);
+                    let bytes = state
+                        .blocks
+                        .entry((ino, idx))
+                        .or_insert_with(|| vec![0; block_size as usize]);
+                    bytes[(from - block_start) as usize..(to - block_start) as usize]
+                        .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+                }
+            }
+            let blocks = state.blocks.range((ino, 0)..(ino + 1, 0)).count() as u64;
+            let now = time::get_time();
+            state.write_inode(ino, |attr| {
+                attr.size = cmp::max(attr.size, end as u64);
+                attr.blocks = blocks;
+                attr.mtime = now;
+                attr.ctime = now;
+            });
+            Ok(offset)
+        })
+    }
+}
+
+impl Store for MemoryStore {
+    fn broken(&self) -> bool {
+        self.state.lock().unwrap().unreachable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+    use proptest::test_runner::Config;
+
+    /// The names paths are made of, two deep at most, so that operations
+    /// often meet what earlier ones left behind.
+    const NAMES: &[&str] = &["a", "b", "c"];
+
+    /// Bounds on where writes and reads start and how long they are, chosen
+    /// to cross a few blocks of the default size.
+    const MAX_OFFSET: i64 = 20000;
+    const MAX_LEN: usize = 10000;
+
+    /// Longest sequence of operations.
+    const MAX_OPS: usize = 40;
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Create(String),
+        Mkdir(String),
+        /// Write len bytes of a value at an offset, or at the end of the
+        /// file.
+        Write(String, Option<i64>, usize, u8),
+        Truncate(String, u64),
+        Read(String, i64, usize),
+        Rename(String, String),
+        Link(String, String),
+        Unlink(String),
+        Rmdir(String),
+    }
+
+    /// What each file should read back as, by inode.
+    type Files = HashMap<u64, Vec<u8>>;
+
+    fn path() -> impl Strategy<Value = String> {
+        vec(select(NAMES), 1..=2).prop_map(|names| names.join("/"))
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => path().prop_map(Op::Create),
+            2 => path().prop_map(Op::Mkdir),
+            4 => (path(), prop::option::weighted(0.8, 0..MAX_OFFSET), 1..MAX_LEN, any::<u8>())
+                .prop_map(|(path, offset, len, byte)| Op::Write(path, offset, len, byte)),
+            2 => (path(), 0..MAX_OFFSET as u64 + MAX_LEN as u64)
+                .prop_map(|(path, size)| Op::Truncate(path, size)),
+            2 => (path(), 0..MAX_OFFSET, 1..MAX_LEN)
+                .prop_map(|(path, offset, len)| Op::Read(path, offset, len)),
+            2 => (path(), path()).prop_map(|(from, to)| Op::Rename(from, to)),
+            1 => (path(), path()).prop_map(|(from, to)| Op::Link(from, to)),
+            2 => path().prop_map(Op::Unlink),
+            1 => path().prop_map(Op::Rmdir),
+        ]
+    }
+
+    /// The directory the entry at path would be in, and its name, if the
+    /// directory exists.
+    fn resolve<S: Store>(store: &S, path: &str) -> Option<(u64, Vec<u8>)> {
+        let (dir, name) = match path.split_once('/') {
+            Some((dir, name)) => (dir, name),
+            None => return Some((FUSE_ROOT_ID, path.as_bytes().to_vec())),
+        };
+        match store.lookup_dir_ent(FUSE_ROOT_ID, dir.as_bytes()).unwrap() {
+            Some((attr, _)) if attr.kind == FileType::Directory => {
+                Some((attr.ino, name.as_bytes().to_vec()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The regular file at path, if there is one.
+    fn file<S: Store>(store: &S, path: &str) -> Option<u64> {
+        let (dir, name) = resolve(store, path)?;
+        match store.lookup_dir_ent(dir, &name).unwrap() {
+            Some((attr, _)) if attr.kind == FileType::RegularFile => Some(attr.ino),
+            _ => None,
+        }
+    }
+
+    /// Run op on store, keeping files up to date with what it writes, and
+    /// check that what it reads is what was written. Operations the store
+    /// refuses are left for the invariants to judge.
+    fn apply<S: Store>(
+        store: &S,
+        files: &mut Files,
+        op: &Op,
+    ) -> std::result::Result<(), TestCaseError> {
+        match op {
+            Op::Create(path) | Op::Mkdir(path) => {
+                let kind = match op {
+                    Op::Mkdir(_) => FileType::Directory,
+                    _ => FileType::RegularFile,
+                };
+                if let Some((dir, name)) = resolve(store, path) {
+                    if let Ok((attr, _)) = store.create_inode(dir, &name, kind, 0o755, 0, (0, 0)) {
+                        if kind == FileType::RegularFile {
+                            files.insert(attr.ino, Vec::new());
+                        }
+                    }
+                }
+            }
+            Op::Write(path, offset, len, byte) => {
+                if let Some(ino) = file(store, path) {
+                    let data = vec![*byte; *len];
+                    let at = store.write_data(ino, *offset, &data).unwrap();
+                    let contents = files.get_mut(&ino).unwrap();
+                    prop_assert_eq!(at, offset.unwrap_or(contents.len() as i64));
+                    let (start, end) = (at as usize, at as usize + len);
+                    if contents.len() < end {
+                        contents.resize(end, 0);
+                    }
+                    contents[start..end].copy_from_slice(&data);
+                }
+            }
+            Op::Truncate(path, size) => {
+                if let Some(ino) = file(store, path) {
+                    let attr = store
+                        .update_inode(
+                            ino,
+                            Some(*size),
+                            None,
+                            None,
+                            None,
+                            None,
+                            None,
+                            None,
+                            None,
+                            None,
+                            None,
+                        )
+                        .unwrap();
+                    prop_assert_eq!(attr.size, *size);
+                    files.get_mut(&ino).unwrap().resize(*size as usize, 0);
+                }
+            }
+            Op::Read(path, offset, len) => {
+                if let Some(ino) = file(store, path) {
+                    let data = store.read_data(ino, *offset, *len).unwrap().unwrap();
+                    let contents = &files[&ino];
+                    let start = cmp::min(*offset as usize, contents.len());
+                    let end = cmp::min(start + len, contents.len());
+                    prop_assert!(data[..] == contents[start..end], "read of {:?}", op);
+                }
+            }
+            Op::Rename(from, to) => {
+                if let (Some((parent, name)), Some((new_parent, new_name))) =
+                    (resolve(store, from), resolve(store, to))
+                {
+                    let _ = store.rename_dir_ent(parent, &name, new_parent, &new_name);
+                }
+            }
+            Op::Link(path, new_path) => {
+                if let (Some(ino), Some((parent, name))) =
+                    (file(store, path), resolve(store, new_path))
+                {
+                    let _ = store.link(ino, parent, &name);
+                }
+            }
+            Op::Unlink(path) | Op::Rmdir(path) => {
+                if let Some((parent, name)) = resolve(store, path) {
+                    let _ = store.unlink(parent, &name, matches!(op, Op::Rmdir(_)));
+                }
+            }
+        }
+        files.retain(|&ino, _| store.lookup_inode(ino).unwrap().is_some());
+        Ok(())
+    }
+
+    /// Check that every entry lies in a directory and refers to an inode
+    /// with as many links as it has entries, reachable from the root; that
+    /// each file's size covers exactly the blocks it counts, which are
+    /// zeroed past its end; and that each file reads back as written.
+    fn check(store: &MemoryStore, files: &Files) -> std::result::Result<(), TestCaseError> {
+        let state = store.state.lock().unwrap();
+        let mut links: HashMap<u64, u32> = HashMap::new();
+        for ((dir, name), child) in &state.entries {
+            let parent = state.inodes.get(dir).map(|inode| inode.attr.kind);
+            prop_assert_eq!(parent, Some(FileType::Directory), "parent of {:?}", name);
+            prop_assert!(state.inodes.contains_key(child), "inode of {:?}", name);
+            *links.entry(*child).or_default() += 1;
+        }
+        let reachable = |mut ino: u64| {
+            for _ in 0..=state.inodes.len() {
+                if ino == FUSE_ROOT_ID {
+                    return true;
+                }
+                ino = match state.parent(ino) {
+                    Some(parent) => parent,
+                    None => return false,
+                };
+            }
+            false
+        };
+        let block_size = sql::block_size();
+        for (&ino, inode) in state.inodes.iter().filter(|(&ino, _)| ino != FUSE_ROOT_ID) {
+            let attr = inode.attr;
+            prop_assert_eq!(
+                links.get(&ino).copied(),
+                Some(attr.nlink),
+                "links to {}",
+                ino
+            );
+            prop_assert!(
+                reachable(ino),
+                "inode {} is not reachable from the root",
+                ino
+            );
+            let blocks = state.blocks.range((ino, 0)..(ino + 1, 0));
+            prop_assert_eq!(
+                blocks.clone().count() as u64,
+                attr.blocks,
+                "blocks of {}",
+                ino
+            );
+            for (&(_, idx), bytes) in blocks {
+                let past_end = attr.size as i64 - idx * block_size;
+                prop_assert!(past_end > 0, "block {} of {} is past its end", idx, ino);
+                let tail = &bytes[cmp::min(past_end, block_size) as usize..];
+                prop_assert!(
+                    tail.iter().all(|&b| b == 0),
+                    "tail of block {} of {}",
+                    idx,
+                    ino
+                );
+            }
+        }
+        let stray = state
+            .blocks
+            .keys()
+            .find(|(ino, _)| !state.inodes.contains_key(ino));
+        prop_assert_eq!(stray, None, "a block of a removed inode");
+        drop(state);
+
+        for (&ino, contents) in files {
+            let attr = store.lookup_inode(ino).unwrap().unwrap();
+            prop_assert_eq!(attr.size, contents.len() as u64, "size of {}", ino);
+            let data = store
+                .read_data(ino, 0, contents.len() + 1)
+                .unwrap()
+                .unwrap();
+            prop_assert!(
+                data == *contents,
+                "inode {} reads back other than written",
+                ino
+            );
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(Config {
+            failure_persistence: None,
+            ..Config::default()
+        })]
+
+        #[test]
+        fn random_operations(ops in vec(op(), 1..MAX_OPS)) {
+            let store = MemoryStore::new();
+            store.create_root(0, 0).unwrap();
+            let mut files = Files::new();
+            for op in &ops {
+                apply(&store, &mut files, op)?;
+                check(&store, &files)?;
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Operation counts and latencies, periodically summarized to the log so that
+//! there is something to look at without an external metrics stack.
+
+use super::cache::Kind;
+use super::sql;
+use log::info;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of power-of-two latency buckets, in microseconds.
+const LATENCY_BUCKETS: usize = 40;
+
+/// A handle to metrics shared by everything that records them.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Mutex<Interval>>,
+}
+
+/// Metrics recorded since the last summary.
+struct Interval {
+    start: Instant,
+    ops: BTreeMap<&'static str, u64>,
+    latency: [u64; LATENCY_BUCKETS],
+    cache: BTreeMap<Kind, (u64, u64)>,
+}
+
+impl Interval {
+    fn new() -> Interval {
+        Interval {
+            start: Instant::now(),
+            ops: BTreeMap::new(),
+            latency: [0; LATENCY_BUCKETS],
+            cache: BTreeMap::new(),
+        }
+    }
+
+    /// Upper bound of the latency bucket containing the 99th percentile.
+    fn p99(&self) -> Duration {
+        let total: u64 = self.latency.iter().sum();
+        let mut seen = 0;
+        for (i, n) in self.latency.iter().enumerate() {
+            seen += n;
+            if seen * 100 >= total * 99 {
+                return Duration::from_micros(1 << i);
+            }
+        }
+        Duration::from_micros(0)
+    }
+
+    fn summary(&self, retries: u64) -> String {
+        let secs = self.start.elapsed().as_secs_f64().max(1.0);
+        let total: u64 = self.ops.values().sum();
+        let mut line = format!("stats: {:.1} ops/s", total as f64 / secs);
+        if total > 0 {
+            let by_op: Vec<String> = self
+                .ops
+                .iter()
+                .map(|(op, n)| format!("{} {:.1}", op, *n as f64 / secs))
+                .collect();
+            line += &format!(" ({}), p99 {:?}", by_op.join(", "), self.p99());
+        }
+        for (kind, (hits, misses)) in &self.cache {
+            if hits + misses > 0 {
+                let rate = 100.0 * *hits as f64 / (hits + misses) as f64;
+                line += &format!(", {} cache hit {:.1}%", kind, rate);
+            }
+        }
+        if retries > 0 {
+            line += &format!(", {} transaction retries", retries);
+        }
+        line
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            inner: Arc::new(Mutex::new(Interval::new())),
+        }
+    }
+
+    /// Start timing an operation. It is recorded when the returned timer is
+    /// dropped.
+    pub fn start(&self, op: &'static str) -> OpTimer {
+        OpTimer {
+            metrics: self.clone(),
+            op,
+            start: Instant::now(),
+        }
+    }
+
+    /// Record a cache lookup.
+    pub fn cache_lookup(&self, kind: Kind, hit: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let counts = inner.cache.entry(kind).or_insert((0, 0));
+        if hit {
+            counts.0 += 1;
+        } else {
+            counts.1 += 1;
+        }
+    }
+
+    /// Log a summary of the metrics every interval from a background thread.
+    pub fn report_every(&self, interval: Duration) {
+        let metrics = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            info!("{}", metrics.take_summary());
+        });
+    }
+
+    /// Summarize the metrics recorded since the last summary, leaving the
+    /// interval running.
+    pub fn summary(&self) -> String {
+        self.inner.lock().unwrap().summary(sql::retries())
+    }
+
+    /// Summarize the metrics recorded since the last summary and start a new
+    /// interval.
+    pub fn take_summary(&self) -> String {
+        let mut inner = self.inner.lock().unwrap();
+        let summary = inner.summary(sql::take_retries());
+        *inner = Interval::new();
+        summary
+    }
+
+    fn record(&self, op: &'static str, elapsed: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.ops.entry(op).or_insert(0) += 1;
+        let micros = elapsed.as_micros() as u64;
+        let bucket = (64 - micros.leading_zeros() as usize).min(LATENCY_BUCKETS - 1);
+        inner.latency[bucket] += 1;
+    }
+}
+
+/// Times a single operation.
+pub struct OpTimer {
+    metrics: Metrics,
+    op: &'static str,
+    start: Instant,
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        self.metrics.record(self.op, self.start.elapsed());
+    }
+}
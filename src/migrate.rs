@@ -0,0 +1,475 @@
+//! Versioned schema migrations.
+//!
+//! Each migration is a list of statements that are checkpointed as they
+//! complete, so that an interrupted migration resumes from the first
+//! unfinished statement instead of starting over. Long data migrations should
+//! be split into many statements that each rewrite a bounded set of rows.
+//!
+//! The version reached is also recorded as `schema_version` in `fs_meta`,
+//! alongside the settings the filesystem was created with. A binary refuses
+//! to use a filesystem whose schema is newer than the latest it knows.
+
+use super::sql;
+use log::info;
+use postgres::{GenericConnection, Result};
+
+pub struct Migration {
+    /// Schema version after the migration has been applied.
+    pub version: i64,
+    pub description: &'static str,
+    pub steps: &'static [&'static str],
+    /// Tables whose rows are rewritten by the migration.
+    pub rewrites: &'static [&'static str],
+    /// Statements that undo the migration, printed as part of its plan.
+    pub rollback: &'static [&'static str],
+}
+
+/// All migrations, in the order they are applied.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        steps: sql::SCHEMAS,
+        rewrites: &[],
+        rollback: &[
+            "DROP TABLE blocks",
+            "DROP TABLE dir_entries",
+            "DROP TABLE inodes",
+            "DROP SEQUENCE inode_alloc",
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "symlink targets",
+        steps: &["ALTER TABLE inodes ADD COLUMN IF NOT EXISTS target STRING"],
+        rewrites: &[],
+        rollback: &["ALTER TABLE inodes DROP COLUMN target"],
+    },
+    Migration {
+        version: 3,
+        description: "extended attributes",
+        steps: &["CREATE TABLE IF NOT EXISTS xattrs (
+            ino   INT8   NOT NULL REFERENCES inodes (ino) ON DELETE CASCADE,
+            name  STRING NOT NULL,
+            value BYTES  NOT NULL,
+            PRIMARY KEY (ino, name)
+        )"],
+        rewrites: &[],
+        rollback: &["DROP TABLE xattrs"],
+    },
+    Migration {
+        version: 4,
+        description: "byte-range locks",
+        steps: &["CREATE TABLE IF NOT EXISTS file_locks (
+            ino         INT8   NOT NULL REFERENCES inodes (ino) ON DELETE CASCADE,
+            session     STRING NOT NULL,
+            owner       INT8   NOT NULL,
+            range_start INT8   NOT NULL,
+            range_end   INT8   NOT NULL,
+            -- Whether this is a write lock rather than a read lock
+            exclusive   BOOL   NOT NULL,
+            pid         INT4   NOT NULL,
+            PRIMARY KEY (ino, session, owner, range_start)
+        )"],
+        rewrites: &[],
+        rollback: &["DROP TABLE file_locks"],
+    },
+    Migration {
+        version: 5,
+        description: "configurable block size",
+        steps: &[
+            "CREATE TABLE IF NOT EXISTS fs_meta (
+                key   STRING NOT NULL PRIMARY KEY,
+                value INT8   NOT NULL
+            )",
+            // Filesystems that already hold files keep the block size they
+            // were created with. New ones record theirs when first mounted.
+            "INSERT INTO fs_meta (key, value)
+             SELECT 'block_size', 8192 WHERE EXISTS (SELECT 1 FROM inodes)
+             ON CONFLICT (key) DO NOTHING",
+            "ALTER TABLE blocks DROP CONSTRAINT IF EXISTS check_bytes",
+            "ALTER TABLE blocks ALTER COLUMN bytes DROP DEFAULT",
+        ],
+        rewrites: &[],
+        rollback: &[
+            "ALTER TABLE blocks ALTER COLUMN bytes SET DEFAULT repeat(x'00'::STRING, 8192)::BYTES",
+            "ALTER TABLE blocks ADD CONSTRAINT check_bytes CHECK (length(bytes) = 8192)",
+            "DROP TABLE fs_meta",
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "remove orphaned roots",
+        // Every mount used to create a new root directory, while the kernel
+        // only ever used the first, at inode 1. The others are empty and
+        // unreachable.
+        steps: &["DELETE FROM inodes
+            WHERE kind = 'S_IFDIR' AND ino != 1
+            AND NOT EXISTS (SELECT 1 FROM dir_entries WHERE child_ino = inodes.ino)
+            AND NOT EXISTS (SELECT 1 FROM dir_entries WHERE dir_ino = inodes.ino)"],
+        rewrites: &["inodes"],
+        rollback: &[],
+    },
+    Migration {
+        version: 7,
+        description: "byte string file names",
+        // Names are arbitrary bytes on Unix, so they cannot be kept in a
+        // STRING column. A primary key column cannot change type in place,
+        // so the entries are copied into a new table that replaces the old.
+        steps: &[
+            "CREATE TABLE IF NOT EXISTS dir_entries_bytes (
+                dir_ino    INT8   NOT NULL REFERENCES inodes (ino) ON DELETE RESTRICT,
+                child_name BYTES  NOT NULL,
+                child_kind STRING NOT NULL,
+                child_ino  INT8   NOT NULL, -- REFERENCES inodes (ino)
+                PRIMARY KEY (dir_ino, child_name)
+            )",
+            "UPSERT INTO dir_entries_bytes (dir_ino, child_name, child_kind, child_ino)
+             SELECT dir_ino, convert_to(child_name, 'UTF8'), child_kind, child_ino
+             FROM dir_entries",
+            "DROP TABLE IF EXISTS dir_entries",
+            "ALTER TABLE IF EXISTS dir_entries_bytes RENAME TO dir_entries",
+        ],
+        rewrites: &["dir_entries"],
+        // Fails if any name is not valid UTF-8.
+        rollback: &[
+            "CREATE TABLE dir_entries_string (
+                dir_ino    INT8   NOT NULL REFERENCES inodes (ino) ON DELETE RESTRICT,
+                child_name STRING NOT NULL,
+                child_kind STRING NOT NULL,
+                child_ino  INT8   NOT NULL,
+                PRIMARY KEY (dir_ino, child_name)
+            )",
+            "INSERT INTO dir_entries_string
+             SELECT dir_ino, convert_from(child_name, 'UTF8'), child_kind, child_ino
+             FROM dir_entries",
+            "DROP TABLE dir_entries",
+            "ALTER TABLE dir_entries_string RENAME TO dir_entries",
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "file versions",
+        steps: &[
+            "CREATE TABLE IF NOT EXISTS file_versions (
+                ino     INT8      NOT NULL REFERENCES inodes (ino) ON DELETE CASCADE,
+                version INT8      NOT NULL,
+                -- Size and time of last modification at the version
+                size    INT8      NOT NULL,
+                mtime   TIMESTAMP NOT NULL,
+                -- When the version was replaced
+                saved   TIMESTAMP NOT NULL DEFAULT now(),
+                PRIMARY KEY (ino, version)
+            )",
+            "CREATE TABLE IF NOT EXISTS block_versions (
+                ino       INT8  NOT NULL,
+                version   INT8  NOT NULL,
+                block_idx INT8  NOT NULL,
+                -- Contents of the block before the version was replaced, or
+                -- NULL if the block was not stored
+                bytes     BYTES,
+                PRIMARY KEY (ino, version, block_idx),
+                FOREIGN KEY (ino, version) REFERENCES file_versions (ino, version)
+                    ON DELETE CASCADE
+            )",
+        ],
+        rewrites: &[],
+        rollback: &["DROP TABLE block_versions", "DROP TABLE file_versions"],
+    },
+    Migration {
+        version: 9,
+        description: "write leases",
+        steps: &["CREATE TABLE IF NOT EXISTS write_leases (
+            ino     INT8      NOT NULL PRIMARY KEY REFERENCES inodes (ino) ON DELETE CASCADE,
+            -- Mount session holding the lease
+            session STRING    NOT NULL,
+            expires TIMESTAMP NOT NULL
+        )"],
+        rewrites: &[],
+        rollback: &["DROP TABLE write_leases"],
+    },
+    Migration {
+        version: 10,
+        description: "directory quotas",
+        steps: &[
+            // The quota each inode is charged to, if any. Quotas form a tree
+            // through the quota their own directory is charged to.
+            "ALTER TABLE inodes ADD COLUMN IF NOT EXISTS quota_ino INT8",
+            "CREATE INDEX IF NOT EXISTS inodes_quota_ino_idx ON inodes (quota_ino)",
+            "CREATE TABLE IF NOT EXISTS quotas (
+                ino        INT8 NOT NULL PRIMARY KEY REFERENCES inodes (ino) ON DELETE CASCADE,
+                -- Limits, if any
+                max_bytes  INT8,
+                max_inodes INT8,
+                -- Usage of everything beneath the directory
+                bytes      INT8 NOT NULL DEFAULT 0,
+                inodes     INT8 NOT NULL DEFAULT 0,
+                CONSTRAINT quota_bytes CHECK (bytes <= max_bytes),
+                CONSTRAINT quota_inodes CHECK (inodes <= max_inodes)
+            )",
+        ],
+        rewrites: &[],
+        rollback: &[
+            "DROP TABLE quotas",
+            "DROP INDEX inodes@inodes_quota_ino_idx",
+            "ALTER TABLE inodes DROP COLUMN quota_ino",
+        ],
+    },
+    Migration {
+        version: 11,
+        description: "user quotas",
+        steps: &[
+            "CREATE TABLE IF NOT EXISTS user_quotas (
+                uid        INT4 NOT NULL PRIMARY KEY,
+                -- Limits, if any
+                max_bytes  INT8,
+                max_inodes INT8,
+                -- Usage of the files the user owns
+                bytes      INT8 NOT NULL DEFAULT 0,
+                inodes     INT8 NOT NULL DEFAULT 0,
+                CONSTRAINT quota_bytes CHECK (bytes <= max_bytes),
+                CONSTRAINT quota_inodes CHECK (inodes <= max_inodes)
+            )",
+            "UPSERT INTO user_quotas (uid, bytes, inodes)
+             SELECT uid, sum(size)::INT8, count(*) FROM inodes GROUP BY uid",
+        ],
+        rewrites: &[],
+        rollback: &["DROP TABLE user_quotas"],
+    },
+    Migration {
+        version: 12,
+        description: "block checksums and scrub progress",
+        steps: &[
+            // Recomputed on every write of a block, and verified by scrub.
+            "ALTER TABLE blocks ADD COLUMN IF NOT EXISTS checksum INT8 AS (crc32c(bytes)) STORED",
+            "CREATE TABLE IF NOT EXISTS scrub_progress (
+                -- There is only ever one scrub under way
+                id        INT8      NOT NULL PRIMARY KEY CHECK (id = 1),
+                -- Last block checked
+                file_ino  INT8      NOT NULL DEFAULT 0,
+                block_idx INT8      NOT NULL DEFAULT -1,
+                -- Last inode whose block count was checked
+                ino       INT8,
+                started   TIMESTAMP NOT NULL DEFAULT now()
+            )",
+        ],
+        rewrites: &["blocks"],
+        rollback: &[
+            "DROP TABLE scrub_progress",
+            "ALTER TABLE blocks DROP COLUMN checksum",
+        ],
+    },
+    Migration {
+        version: 13,
+        description: "block deduplication",
+        steps: &[
+            // A block either holds its bytes or refers by hash to a chunk
+            // that holds them, shared with every other block of the same
+            // contents.
+            "ALTER TABLE blocks ALTER COLUMN bytes DROP NOT NULL",
+            "ALTER TABLE blocks ADD COLUMN IF NOT EXISTS hash STRING",
+            "CREATE TABLE IF NOT EXISTS chunk_store (
+                -- SHA-256 of the bytes, in hex
+                hash     STRING NOT NULL PRIMARY KEY,
+                bytes    BYTES  NOT NULL,
+                -- Number of blocks referring to the chunk
+                refcount INT8   NOT NULL
+            )",
+            "CREATE VIEW IF NOT EXISTS block_bytes AS
+             SELECT b.file_ino, b.block_idx, IFNULL(b.bytes, c.bytes) AS bytes
+             FROM blocks b LEFT JOIN chunk_store c ON c.hash = b.hash",
+        ],
+        rewrites: &[],
+        rollback: &[
+            "DROP VIEW block_bytes",
+            "DROP TABLE chunk_store",
+            "ALTER TABLE blocks DROP COLUMN hash",
+            "ALTER TABLE blocks ALTER COLUMN bytes SET NOT NULL",
+        ],
+    },
+    Migration {
+        version: 14,
+        description: "inode generations",
+        // NFS file handles name an inode by its number and generation, so
+        // that a handle to a deleted inode is never taken for another inode
+        // given the same number, as can happen once the allocating sequence
+        // is reset or restored.
+        steps: &[
+            "ALTER TABLE inodes ADD COLUMN IF NOT EXISTS generation INT8 NOT NULL DEFAULT unique_rowid()",
+        ],
+        rewrites: &["inodes"],
+        rollback: &["ALTER TABLE inodes DROP COLUMN generation"],
+    },
+    Migration {
+        version: 15,
+        description: "filesystems",
+        // A database holds any number of filesystems, each with a root
+        // directory of its own. Inode numbers are allocated across all of
+        // them, so only inodes record which one they belong to; the entries,
+        // blocks and other rows of an inode belong to the same one.
+        steps: &[
+            "CREATE TABLE IF NOT EXISTS filesystems (
+                fs_id    INT8      NOT NULL PRIMARY KEY,
+                name     STRING    NOT NULL UNIQUE,
+                root_ino INT8      NOT NULL,
+                created  TIMESTAMP NOT NULL DEFAULT now()
+            )",
+            "INSERT INTO filesystems (fs_id, name, root_ino) VALUES (0, 'default', 1)
+             ON CONFLICT DO NOTHING",
+            "ALTER TABLE inodes ADD COLUMN IF NOT EXISTS fs_id INT8 NOT NULL DEFAULT 0",
+            "CREATE INDEX IF NOT EXISTS inodes_fs_id_idx ON inodes (fs_id)",
+        ],
+        rewrites: &["inodes"],
+        rollback: &[
+            "DROP INDEX inodes@inodes_fs_id_idx",
+            "ALTER TABLE inodes DROP COLUMN fs_id",
+            "DROP TABLE filesystems",
+        ],
+    },
+    Migration {
+        version: 16,
+        description: "byte-range lock expiry",
+        // Mounts renew their locks while they run, so the locks of one that
+        // died are ignored once they expire.
+        steps: &[
+            "ALTER TABLE file_locks ADD COLUMN IF NOT EXISTS expires TIMESTAMP NOT NULL
+             DEFAULT now()",
+        ],
+        rewrites: &["file_locks"],
+        rollback: &["ALTER TABLE file_locks DROP COLUMN expires"],
+    },
+    Migration {
+        version: 17,
+        description: "directory entry name hashes",
+        // FUSE listings are ordered by the hash of each entry's name, so
+        // that the offset the kernel resumes a listing from can name the
+        // entry it resumes after.
+        steps: &[
+            "ALTER TABLE dir_entries ADD COLUMN IF NOT EXISTS name_hash INT8 NOT NULL
+             AS (fnv64a(child_name) & 36028797018963967) STORED",
+            "CREATE INDEX IF NOT EXISTS dir_entries_name_hash_idx
+             ON dir_entries (dir_ino, name_hash) STORING (child_kind, child_ino)",
+        ],
+        rewrites: &["dir_entries"],
+        rollback: &[
+            "DROP INDEX dir_entries@dir_entries_name_hash_idx",
+            "ALTER TABLE dir_entries DROP COLUMN name_hash",
+        ],
+    },
+];
+
+const MIGRATIONS_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (
+    -- Schema version the migration moves to
+    version INT8 NOT NULL PRIMARY KEY,
+    -- Number of steps completed
+    steps   INT8 NOT NULL DEFAULT 0,
+    -- Whether every step has completed
+    done    BOOL NOT NULL DEFAULT false
+)";
+
+/// Version of the migration that creates fs_meta, from which on the schema
+/// version is recorded there as well.
+const FS_META_VERSION: i64 = 5;
+
+/// The latest schema version this binary knows how to use.
+pub fn latest() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// The current schema version, which is 0 for a fresh database.
+pub fn current_version<C: GenericConnection>(conn: &C) -> Result<i64> {
+    conn.execute(MIGRATIONS_SCHEMA, &[])?;
+    let version: Option<i64> = conn
+        .query("SELECT max(version) FROM schema_migrations WHERE done", &[])?
+        .get(0)
+        .get(0);
+    Ok(version.unwrap_or(0))
+}
+
+/// Migrations that have not yet been applied, along with the number of steps
+/// of each that already completed.
+pub fn pending<C: GenericConnection>(conn: &C) -> Result<Vec<(&'static Migration, usize)>> {
+    let version = current_version(conn)?;
+    let mut pending = Vec::new();
+    for m in MIGRATIONS.iter().filter(|m| m.version > version) {
+        let rows = conn.query(
+            "SELECT steps FROM schema_migrations WHERE version = $1",
+            &[&m.version],
+        )?;
+        let done = if rows.is_empty() {
+            0
+        } else {
+            rows.get(0).get::<_, i64>(0) as usize
+        };
+        pending.push((m, done));
+    }
+    Ok(pending)
+}
+
+/// Apply all pending migrations.
+pub fn apply<C: GenericConnection>(conn: &C) -> Result<()> {
+    for (m, done) in pending(conn)? {
+        info!(
+            "migrating to schema version {}: {}",
+            m.version, m.description
+        );
+        conn.execute(
+            "UPSERT INTO schema_migrations (version, steps) VALUES ($1, $2)",
+            &[&m.version, &(done as i64)],
+        )?;
+        for (i, step) in m.steps.iter().enumerate().skip(done) {
+            conn.execute(step, &[])?;
+            conn.execute(
+                "UPDATE schema_migrations SET steps = $1 WHERE version = $2",
+                &[&(i as i64 + 1), &m.version],
+            )?;
+        }
+        conn.execute(
+            "UPDATE schema_migrations SET done = true WHERE version = $1",
+            &[&m.version],
+        )?;
+    }
+    let version = current_version(conn)?;
+    if version >= FS_META_VERSION {
+        conn.execute(
+            "UPSERT INTO fs_meta (key, value) VALUES ('schema_version', $1)",
+            &[&version],
+        )?;
+    }
+    Ok(())
+}
+
+/// Print the statements that apply would run, an estimate of the rows they
+/// rewrite, and how to roll each migration back, without changing anything.
+pub fn dry_run<C: GenericConnection>(conn: &C) -> Result<()> {
+    let pending = pending(conn)?;
+    if pending.is_empty() {
+        println!("schema is up to date at version {}", current_version(conn)?);
+        return Ok(());
+    }
+    let missing = sql::missing_tables(conn)?;
+    for (m, done) in pending {
+        println!("-- version {}: {}", m.version, m.description);
+        if done > 0 {
+            println!("-- resuming after {} of {} steps", done, m.steps.len());
+        }
+        for step in &m.steps[done..] {
+            println!("{};", step);
+        }
+        let mut rows = 0;
+        for table in m.rewrites {
+            if !missing.iter().any(|t| t == table) {
+                rows += conn
+                    .query(&format!("SELECT count(*) FROM {}", table), &[])?
+                    .get(0)
+                    .get::<_, i64>(0);
+            }
+        }
+        println!("-- estimated rows rewritten: {}", rows);
+        println!("-- rollback:");
+        for stmt in m.rollback {
+            println!("--   {};", stmt);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,1347 @@
+//! An NFSv3 frontend to the filesystem, for clients that cannot run FUSE but
+//! can mount it over the network. The MOUNT and NFS programs are served over
+//! TCP on a single port, and no portmapper is registered with, so clients
+//! name the port when mounting:
+//!
+//!     mount -t nfs -o vers=3,proto=tcp,port=2049,mountport=2049,nolock host:/ /mnt
+//!
+//! File handles hold the filesystem's id and an inode's number and
+//! generation. They stay valid across restarts of the server, and one whose
+//! inode has been deleted is answered as stale even if its number is ever
+//! given to another inode.
+//!
+//! Callers are who their AUTH_UNIX credentials say they are, and are checked
+//! against permission bits as such, so the server should only listen where
+//! every client is trusted to send honest credentials. Locking (NLM) is not
+//! served, hence nolock.
+
+use super::error::CrfsError;
+use super::pool::Pool;
+use super::sql;
+use super::xdr::{Reader, Writer};
+use fuse::{FileAttr, FileType};
+use log::{debug, warn};
+use postgres::Connection;
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::Timespec;
+
+const MOUNT_PROGRAM: u32 = 100_005;
+const NFS_PROGRAM: u32 = 100_003;
+
+/// Most bytes read or written by a READ or WRITE.
+const MAX_IO: u32 = 1 << 20;
+
+/// Longest RPC record accepted, which leaves room for a WRITE of MAX_IO
+/// bytes.
+const MAX_RECORD: usize = MAX_IO as usize + (64 << 10);
+
+/// Directory entries read per query.
+const DIR_BATCH: i64 = 1000;
+
+/// Most READDIR cookies remembered before they are forgotten at once.
+const MAX_COOKIES: usize = 10_000;
+
+/// Free bytes and inodes reported when the cluster's capacity is unknown.
+const NOMINAL_FREE: u64 = 1 << 40;
+
+/// Length of a file handle: a version byte, then the filesystem's id and the
+/// inode's number and generation.
+const HANDLE_LEN: usize = 25;
+const HANDLE_VERSION: u8 = 1;
+
+/// Identity of callers that send no AUTH_UNIX credentials.
+const NOBODY: u32 = 65534;
+
+// Status codes of NFS results.
+const NFS3_OK: u32 = 0;
+const NFS3ERR_PERM: u32 = 1;
+const NFS3ERR_NOENT: u32 = 2;
+const NFS3ERR_IO: u32 = 5;
+const NFS3ERR_ACCES: u32 = 13;
+const NFS3ERR_EXIST: u32 = 17;
+const NFS3ERR_XDEV: u32 = 18;
+const NFS3ERR_NOTDIR: u32 = 20;
+const NFS3ERR_ISDIR: u32 = 21;
+const NFS3ERR_INVAL: u32 = 22;
+const NFS3ERR_ROFS: u32 = 30;
+const NFS3ERR_NAMETOOLONG: u32 = 63;
+const NFS3ERR_NOTEMPTY: u32 = 66;
+const NFS3ERR_DQUOT: u32 = 69;
+const NFS3ERR_STALE: u32 = 70;
+const NFS3ERR_BADHANDLE: u32 = 10001;
+const NFS3ERR_NOT_SYNC: u32 = 10002;
+const NFS3ERR_BAD_COOKIE: u32 = 10003;
+const NFS3ERR_NOTSUPP: u32 = 10004;
+const NFS3ERR_TOOSMALL: u32 = 10005;
+const NFS3ERR_BADTYPE: u32 = 10007;
+
+// Status codes of MOUNT results.
+const MNT3_OK: u32 = 0;
+const MNT3ERR_NOENT: u32 = 2;
+const MNT3ERR_NOTDIR: u32 = 20;
+const MNT3ERR_SERVERFAULT: u32 = 10006;
+
+// Bits of ACCESS requests and results.
+const ACCESS_READ: u32 = 0x01;
+const ACCESS_LOOKUP: u32 = 0x02;
+const ACCESS_MODIFY: u32 = 0x04;
+const ACCESS_EXTEND: u32 = 0x08;
+const ACCESS_DELETE: u32 = 0x10;
+const ACCESS_EXECUTE: u32 = 0x20;
+
+// Permission bits, as they apply to the caller.
+const R_OK: u32 = 4;
+const W_OK: u32 = 2;
+const X_OK: u32 = 1;
+
+/// Serve requests accepted on listener from the pool's connections. fsid
+/// identifies the filesystem in file handles, and names longer than
+/// name_max are refused.
+pub fn serve(listener: TcpListener, pool: Pool, fsid: u64, name_max: u32) -> io::Result<()> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let server = Arc::new(Server {
+        fsid,
+        name_max,
+        verifier: nanos.to_be_bytes(),
+        cookies: Mutex::new(HashMap::new()),
+    });
+    let pool = Arc::new(pool);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("nfs: accept {}", err);
+                continue;
+            }
+        };
+        let server = server.clone();
+        let pool = pool.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve_connection(server, &pool, stream) {
+                debug!("nfs: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Read the calls sent on stream, serving each on the pool so that a
+/// client's calls run concurrently, and send the replies back as they are
+/// ready.
+fn serve_connection(server: Arc<Server>, pool: &Pool, stream: TcpStream) -> io::Result<()> {
+    let out = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut r = BufReader::new(stream);
+    while let Some(record) = read_record(&mut r)? {
+        let server = server.clone();
+        let out = out.clone();
+        pool.execute(move |conn| {
+            if let Some(reply) = server.call(conn, &record) {
+                if let Err(err) = write_record(&mut *out.lock().unwrap(), &reply) {
+                    debug!("nfs: {}", err);
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Read the next record sent on a stream, joining its fragments, or None
+/// at the end of the stream.
+fn read_record<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut record = Vec::new();
+    loop {
+        let mut header = [0; 4];
+        match r.read_exact(&mut header) {
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof && record.is_empty() => {
+                return Ok(None)
+            }
+            res => res?,
+        }
+        let header = u32::from_be_bytes(header);
+        let len = (header & 0x7fff_ffff) as usize;
+        if record.len() + len > MAX_RECORD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "oversized RPC record",
+            ));
+        }
+        let start = record.len();
+        record.resize(start + len, 0);
+        r.read_exact(&mut record[start..])?;
+        if header & 0x8000_0000 != 0 {
+            return Ok(Some(record));
+        }
+    }
+}
+
+fn write_record<W: Write>(out: &mut W, reply: &[u8]) -> io::Result<()> {
+    let mut record = Vec::with_capacity(4 + reply.len());
+    record.extend_from_slice(&(0x8000_0000 | reply.len() as u32).to_be_bytes());
+    record.extend_from_slice(reply);
+    out.write_all(&record)
+}
+
+/// Who made a call.
+struct Cred {
+    uid: u32,
+    gid: u32,
+    gids: Vec<u32>,
+}
+
+impl Cred {
+    /// Decode the credentials of a call with the given flavor.
+    fn new(flavor: u32, body: &[u8]) -> io::Result<Cred> {
+        if flavor != 1 {
+            return Ok(Cred {
+                uid: NOBODY,
+                gid: NOBODY,
+                gids: Vec::new(),
+            });
+        }
+        let mut r = Reader::new(body);
+        let _stamp = r.u32()?;
+        let _machine = r.opaque()?;
+        let uid = r.u32()?;
+        let gid = r.u32()?;
+        let count = r.u32()?.min(16);
+        let gids = (0..count).map(|_| r.u32()).collect::<io::Result<_>>()?;
+        Ok(Cred { uid, gid, gids })
+    }
+
+    fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.gids.contains(&gid)
+    }
+
+    fn owns(&self, attr: &FileAttr) -> bool {
+        self.uid == 0 || self.uid == attr.uid
+    }
+
+    /// Whether the caller may access the file attr describes as mask, a
+    /// combination of R_OK, W_OK and X_OK.
+    fn permitted(&self, attr: &FileAttr, mask: u32) -> bool {
+        let perm = u32::from(attr.perm);
+        if self.uid == 0 {
+            // Root may do anything but execute a file nobody may execute.
+            return mask & X_OK == 0 || attr.kind == FileType::Directory || perm & 0o111 != 0;
+        }
+        let bits = if self.uid == attr.uid {
+            perm >> 6
+        } else if self.in_group(attr.gid) {
+            perm >> 3
+        } else {
+            perm
+        };
+        bits & mask == mask
+    }
+
+    fn check(&self, attr: &FileAttr, mask: u32) -> Result<(), Fail> {
+        if self.permitted(attr, mask) {
+            Ok(())
+        } else {
+            Err(Fail::Status(NFS3ERR_ACCES))
+        }
+    }
+
+    /// Like check, but letting the owner of a file read and write it
+    /// whatever its permissions, as it may once it has opened the file.
+    fn check_io(&self, attr: &FileAttr, mask: u32) -> Result<(), Fail> {
+        if self.uid == attr.uid {
+            return Ok(());
+        }
+        self.check(attr, mask)
+    }
+}
+
+/// Why a procedure failed.
+enum Fail {
+    /// Its arguments could not be decoded.
+    Garbage,
+    /// It is answered with an NFS status other than NFS3_OK.
+    Status(u32),
+}
+
+impl From<io::Error> for Fail {
+    fn from(_: io::Error) -> Fail {
+        Fail::Garbage
+    }
+}
+
+impl From<postgres::Error> for Fail {
+    fn from(err: postgres::Error) -> Fail {
+        Fail::from(CrfsError::from(err))
+    }
+}
+
+impl From<CrfsError> for Fail {
+    fn from(err: CrfsError) -> Fail {
+        if let Some(err) = err.backend() {
+            warn!("nfs: {}", err);
+        }
+        Fail::Status(nfs_status(err.errno()))
+    }
+}
+
+/// The NFS status an errno is answered with. NFS statuses share the numbers
+/// of the errnos they stand for, except for those Linux numbers
+/// differently.
+fn nfs_status(errno: i32) -> u32 {
+    match errno {
+        libc::EPERM => NFS3ERR_PERM,
+        libc::ENOENT => NFS3ERR_NOENT,
+        libc::EACCES => NFS3ERR_ACCES,
+        libc::EEXIST => NFS3ERR_EXIST,
+        libc::EXDEV => NFS3ERR_XDEV,
+        libc::ENOTDIR => NFS3ERR_NOTDIR,
+        libc::EISDIR => NFS3ERR_ISDIR,
+        libc::EINVAL => NFS3ERR_INVAL,
+        libc::EROFS => NFS3ERR_ROFS,
+        libc::ENAMETOOLONG => NFS3ERR_NAMETOOLONG,
+        libc::ENOTEMPTY => NFS3ERR_NOTEMPTY,
+        libc::EDQUOT => NFS3ERR_DQUOT,
+        libc::ESTALE => NFS3ERR_STALE,
+        _ => NFS3ERR_IO,
+    }
+}
+
+/// A requested change to an attribute's time.
+enum SetTime {
+    Keep,
+    ServerTime,
+    ClientTime(Timespec),
+}
+
+impl SetTime {
+    fn time(&self) -> Option<Timespec> {
+        match *self {
+            SetTime::Keep => None,
+            SetTime::ServerTime => Some(time::get_time()),
+            SetTime::ClientTime(t) => Some(t),
+        }
+    }
+}
+
+/// Requested changes to a file's attributes.
+struct SetAttr {
+    mode: Option<u16>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: SetTime,
+    mtime: SetTime,
+}
+
+impl SetAttr {
+    fn decode(args: &mut Reader) -> io::Result<SetAttr> {
+        let mode = option(args, |args| args.u32())?;
+        let uid = option(args, |args| args.u32())?;
+        let gid = option(args, |args| args.u32())?;
+        let size = option(args, |args| args.u64())?;
+        let atime = set_time(args)?;
+        let mtime = set_time(args)?;
+        Ok(SetAttr {
+            mode: mode.map(|mode| (mode & 0o7777) as u16),
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+        })
+    }
+}
+
+fn option<T, F>(args: &mut Reader, f: F) -> io::Result<Option<T>>
+where
+    F: FnOnce(&mut Reader) -> io::Result<T>,
+{
+    if args.bool()? {
+        f(args).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+fn set_time(args: &mut Reader) -> io::Result<SetTime> {
+    match args.u32()? {
+        1 => Ok(SetTime::ServerTime),
+        2 => Ok(SetTime::ClientTime(nfs_time(args)?)),
+        _ => Ok(SetTime::Keep),
+    }
+}
+
+fn nfs_time(args: &mut Reader) -> io::Result<Timespec> {
+    let sec = args.u32()?;
+    let nsec = args.u32()?;
+    Ok(Timespec::new(i64::from(sec), nsec.min(999_999_999) as i32))
+}
+
+struct Server {
+    fsid: u64,
+    name_max: u32,
+    /// Identifies this run of the server in WRITE and COMMIT replies. Writes
+    /// are durable once answered, so clients never have to resend them.
+    verifier: [u8; 8],
+    /// Names of the entries READDIR replies ended at, by directory and
+    /// cookie, for resuming listings.
+    cookies: Mutex<HashMap<(u64, u64), Vec<u8>>>,
+}
+
+impl Server {
+    /// Answer the RPC call record, or None if it cannot be answered.
+    fn call(&self, conn: &Connection, record: &[u8]) -> Option<Vec<u8>> {
+        let mut args = Reader::new(record);
+        let xid = args.u32().ok()?;
+        if args.u32().ok()? != 0 {
+            // Not a call.
+            return None;
+        }
+        let mut out = Writer::default();
+        out.u32(xid);
+        out.u32(1);
+        let rpc_vers = args.u32().ok()?;
+        if rpc_vers != 2 {
+            // MSG_DENIED, RPC_MISMATCH
+            out.u32(1);
+            out.u32(0);
+            out.u32(2);
+            out.u32(2);
+            return Some(out.into_inner());
+        }
+        let prog = args.u32().ok()?;
+        let vers = args.u32().ok()?;
+        let procedure = args.u32().ok()?;
+        let flavor = args.u32().ok()?;
+        let cred = args
+            .opaque()
+            .ok()
+            .and_then(|body| Cred::new(flavor, body).ok());
+        let verf = args.u32().and_then(|_| args.opaque());
+        // MSG_ACCEPTED, with an AUTH_NONE verifier
+        out.u32(0);
+        out.u32(0);
+        out.opaque(&[]);
+        let cred = match (cred, verf) {
+            (Some(cred), Ok(_)) => cred,
+            _ => {
+                // GARBAGE_ARGS
+                out.u32(4);
+                return Some(out.into_inner());
+            }
+        };
+        if prog != NFS_PROGRAM && prog != MOUNT_PROGRAM {
+            // PROG_UNAVAIL
+            out.u32(1);
+            return Some(out.into_inner());
+        }
+        if vers != 3 {
+            // PROG_MISMATCH
+            out.u32(2);
+            out.u32(3);
+            out.u32(3);
+            return Some(out.into_inner());
+        }
+        debug!("nfs: program {} procedure {}", prog, procedure);
+        let res = if prog == MOUNT_PROGRAM {
+            self.mount(conn, procedure, &mut args)
+        } else {
+            self.nfs(conn, &cred, procedure, &mut args)
+        };
+        match res {
+            Some(Ok(body)) => {
+                // SUCCESS
+                out.u32(0);
+                // The results of every NFS procedure but NULL start with
+                // their status.
+                if prog == NFS_PROGRAM && procedure != 0 {
+                    out.u32(NFS3_OK);
+                }
+                out.fixed(&body.into_inner());
+            }
+            Some(Err(Fail::Status(status))) => {
+                out.u32(0);
+                out.u32(status);
+                // The results of a failed procedure end with attributes,
+                // which are all left out.
+                for _ in 0..failed_attrs(procedure) {
+                    out.bool(false);
+                }
+            }
+            Some(Err(Fail::Garbage)) => out.u32(4),
+            // PROC_UNAVAIL
+            None => out.u32(3),
+        }
+        Some(out.into_inner())
+    }
+
+    /// Serve a procedure of the MOUNT program, or None if there is no such
+    /// procedure.
+    fn mount(
+        &self,
+        conn: &Connection,
+        procedure: u32,
+        args: &mut Reader,
+    ) -> Option<Result<Writer, Fail>> {
+        let mut out = Writer::default();
+        match procedure {
+            // NULL, UMNT and UMNTALL, since mounts are not recorded
+            0 | 3 | 4 => {}
+            // MNT
+            1 => {
+                let path = match args.opaque() {
+                    Ok(path) => path,
+                    Err(err) => return Some(Err(err.into())),
+                };
+                match self.mount_point(conn, path) {
+                    Ok(Ok(handle)) => {
+                        out.u32(MNT3_OK);
+                        out.opaque(&handle);
+                        // AUTH_UNIX is the only flavor accepted.
+                        out.u32(1);
+                        out.u32(1);
+                    }
+                    Ok(Err(status)) => out.u32(status),
+                    Err(err) => {
+                        warn!("nfs: mount {}", err);
+                        out.u32(MNT3ERR_SERVERFAULT);
+                    }
+                }
+            }
+            // DUMP
+            2 => out.bool(false),
+            // EXPORT: the root, to everyone
+            5 => {
+                out.bool(true);
+                out.opaque(b"/");
+                out.bool(false);
+                out.bool(false);
+            }
+            _ => return None,
+        }
+        Some(Ok(out))
+    }
+
+    /// The handle of the directory at path, which clients may mount, or the
+    /// MOUNT status refusing it.
+    fn mount_point(
+        &self,
+        conn: &Connection,
+        path: &[u8],
+    ) -> postgres::Result<Result<Vec<u8>, u32>> {
+        let mut ino = sql::root();
+        for name in path.split(|&b| b == b'/').filter(|n| !n.is_empty()) {
+            match sql::lookup_dir_ent(conn, ino, name)? {
+                Some((attr, _)) if attr.kind == FileType::Directory => ino = attr.ino,
+                Some(_) => return Ok(Err(MNT3ERR_NOTDIR)),
+                None => return Ok(Err(MNT3ERR_NOENT)),
+            }
+        }
+        Ok(match sql::lookup_inode_generation(conn, ino)? {
+            Some((_, generation)) => Ok(self.handle(ino, generation)),
+            None => Err(MNT3ERR_NOENT),
+        })
+    }
+
+    /// Serve a procedure of the NFS program, or None if there is no such
+    /// procedure.
+    fn nfs(
+        &self,
+        conn: &Connection,
+        cred: &Cred,
+        procedure: u32,
+        args: &mut Reader,
+    ) -> Option<Result<Writer, Fail>> {
+        let res = match procedure {
+            0 => Ok(Writer::default()),
+            1 => self.getattr(conn, args),
+            2 => self.setattr(conn, cred, args),
+            3 => self.lookup(conn, cred, args),
+            4 => self.access(conn, cred, args),
+            5 => self.readlink(conn, args),
+            6 => self.read(conn, cred, args),
+            7 => self.write(conn, cred, args),
+            8 => self.create(conn, cred, args),
+            9 => self.mkdir(conn, cred, args),
+            10 => self.symlink(conn, cred, args),
+            11 => self.mknod(conn, cred, args),
+            12 => self.remove(conn, cred, args, false),
+            13 => self.remove(conn, cred, args, true),
+            14 => self.rename(conn, cred, args),
+            15 => self.link(conn, cred, args),
+            16 => self.readdir(conn, cred, args, false),
+            17 => self.readdir(conn, cred, args, true),
+            18 => self.fsstat(conn, args),
+            19 => self.fsinfo(conn, args),
+            20 => self.pathconf(conn, args),
+            21 => self.commit(conn, args),
+            _ => return None,
+        };
+        Some(res)
+    }
+
+    fn handle(&self, ino: u64, generation: u64) -> Vec<u8> {
+        let mut handle = Vec::with_capacity(HANDLE_LEN);
+        handle.push(HANDLE_VERSION);
+        handle.extend_from_slice(&self.fsid.to_be_bytes());
+        handle.extend_from_slice(&ino.to_be_bytes());
+        handle.extend_from_slice(&generation.to_be_bytes());
+        handle
+    }
+
+    /// The file a handle names.
+    fn file(&self, conn: &Connection, handle: &[u8]) -> Result<FileAttr, Fail> {
+        if handle.len() != HANDLE_LEN || handle[0] != HANDLE_VERSION {
+            return Err(Fail::Status(NFS3ERR_BADHANDLE));
+        }
+        let word = |i: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&handle[1 + 8 * i..9 + 8 * i]);
+            u64::from_be_bytes(bytes)
+        };
+        if word(0) != self.fsid {
+            return Err(Fail::Status(NFS3ERR_STALE));
+        }
+        match sql::lookup_inode_generation(conn, word(1))? {
+            Some((attr, generation)) if generation == word(2) => Ok(attr),
+            _ => Err(Fail::Status(NFS3ERR_STALE)),
+        }
+    }
+
+    /// The directory a handle names, which the caller may change entries
+    /// of, and a name for an entry in it.
+    fn dir_op<'a>(
+        &self,
+        conn: &Connection,
+        cred: &Cred,
+        args: &mut Reader<'a>,
+    ) -> Result<(FileAttr, &'a [u8]), Fail> {
+        let handle = args.opaque()?;
+        let name = args.opaque()?;
+        let dir = self.file(conn, handle)?;
+        if dir.kind != FileType::Directory {
+            return Err(Fail::Status(NFS3ERR_NOTDIR));
+        }
+        cred.check(&dir, W_OK | X_OK)?;
+        Ok((dir, name))
+    }
+
+    /// Check that name may be given to a new entry.
+    fn check_name(&self, name: &[u8]) -> Result<(), Fail> {
+        match name {
+            b"" => Err(Fail::Status(NFS3ERR_INVAL)),
+            b"." | b".." => Err(Fail::Status(NFS3ERR_EXIST)),
+            _ if name.len() > self.name_max as usize => Err(Fail::Status(NFS3ERR_NAMETOOLONG)),
+            _ if name.contains(&b'/') => Err(Fail::Status(NFS3ERR_INVAL)),
+            _ => Ok(()),
+        }
+    }
+
+    fn fattr(&self, out: &mut Writer, attr: &FileAttr) {
+        out.u32(match attr.kind {
+            FileType::RegularFile => 1,
+            FileType::Directory => 2,
+            FileType::BlockDevice => 3,
+            FileType::CharDevice => 4,
+            FileType::Symlink => 5,
+            FileType::Socket => 6,
+            FileType::NamedPipe => 7,
+        });
+        out.u32(u32::from(attr.perm));
+        out.u32(attr.nlink);
+        out.u32(attr.uid);
+        out.u32(attr.gid);
+        out.u64(attr.size);
+        out.u64(attr.blocks * sql::block_size() as u64);
+        out.u32((attr.rdev >> 8) & 0xfff);
+        out.u32((attr.rdev & 0xff) | ((attr.rdev >> 12) & 0xfff00));
+        out.u64(self.fsid);
+        out.u64(attr.ino);
+        for t in &[attr.atime, attr.mtime, attr.ctime] {
+            out.u32(t.sec.max(0) as u32);
+            out.u32(t.nsec.max(0) as u32);
+        }
+    }
+
+    fn post_op_attr(&self, out: &mut Writer, attr: Option<&FileAttr>) {
+        out.bool(attr.is_some());
+        if let Some(attr) = attr {
+            self.fattr(out, attr);
+        }
+    }
+
+    /// Write the attributes of a changed file, leaving out those it had
+    /// before.
+    fn wcc_data(&self, out: &mut Writer, after: Option<&FileAttr>) {
+        out.bool(false);
+        self.post_op_attr(out, after);
+    }
+
+    /// Write a file's current attributes, if it still exists.
+    fn current_attr(&self, conn: &Connection, out: &mut Writer, ino: u64) {
+        let attr = sql::lookup_inode(conn, ino).ok().flatten();
+        self.post_op_attr(out, attr.as_ref());
+    }
+
+    /// Write the results of creating attr, of the given generation, in
+    /// directory dir.
+    fn created(
+        &self,
+        conn: &Connection,
+        attr: &FileAttr,
+        generation: u64,
+        dir: u64,
+    ) -> Result<Writer, Fail> {
+        let mut out = Writer::default();
+        out.bool(true);
+        out.opaque(&self.handle(attr.ino, generation));
+        self.post_op_attr(&mut out, Some(attr));
+        out.bool(false);
+        self.current_attr(conn, &mut out, dir);
+        Ok(out)
+    }
+
+    /// Apply the changes to a newly created file that creating it did not.
+    fn init_attr(
+        &self,
+        conn: &Connection,
+        cred: &Cred,
+        attr: FileAttr,
+        set: &SetAttr,
+    ) -> Result<FileAttr, Fail> {
+        // Only root may give away what it creates.
+        let uid = set.uid.filter(|_| cred.uid == 0);
+        let gid = set.gid.filter(|&gid| cred.uid == 0 || cred.in_group(gid));
+        let size = set.size.filter(|&size| size != attr.size);
+        let (atime, mtime) = (set.atime.time(), set.mtime.time());
+        if uid.is_none() && gid.is_none() && size.is_none() && atime.is_none() && mtime.is_none() {
+            return Ok(attr);
+        }
+        Ok(sql::update_inode(
+            conn, attr.ino, size, atime, mtime, None, None, None, None, uid, gid, None,
+        )?)
+    }
+
+    fn getattr(&self, conn: &Connection, args: &mut Reader) -> Result<Writer, Fail> {
+        let attr = self.file(conn, args.opaque()?)?;
+        let mut out = Writer::default();
+        self.fattr(&mut out, &attr);
+        Ok(out)
+    }
+
+    fn setattr(&self, conn: &Connection, cred: &Cred, args: &mut Reader) -> Result<Writer, Fail> {
+        let handle = args.opaque()?;
+        let set = SetAttr::decode(args)?;
+        let guard = option(args, nfs_time)?;
+        let attr = self.file(conn, handle)?;
+        if guard.is_some_and(|ctime| ctime != attr.ctime) {
+            return Err(Fail::Status(NFS3ERR_NOT_SYNC));
+        }
+        let owner = cred.owns(&attr);
+        if set.mode.is_some() && !owner {
+            return Err(Fail::Status(NFS3ERR_PERM));
+        }
+        if set.uid.is_some_and(|uid| uid != attr.uid) && cred.uid != 0 {
+            return Err(Fail::Status(NFS3ERR_PERM));
+        }
+        if set.gid.is_some_and(|gid| {
+            gid != attr.gid && (!owner || (cred.uid != 0 && !cred.in_group(gid)))
+        }) {
+            return Err(Fail::Status(NFS3ERR_PERM));
+        }
+        if set.size.is_some() {
+            match attr.kind {
+                FileType::RegularFile => cred.check_io(&attr, W_OK)?,
+                FileType::Directory => return Err(Fail::Status(NFS3ERR_ISDIR)),
+                _ => return Err(Fail::Status(NFS3ERR_INVAL)),
+            }
+        }
+        for time in &[&set.atime, &set.mtime] {
+            match time {
+                SetTime::Keep => {}
+                SetTime::ServerTime if !owner => cred.check(&attr, W_OK)?,
+                SetTime::ClientTime(_) if !owner => return Err(Fail::Status(NFS3ERR_PERM)),
+                _ => {}
+            }
+        }
+        let attr = sql::update_inode(
+            conn,
+            attr.ino,
+            set.size,
+            set.atime.time(),
+            set.mtime.time(),
+            None,
+            None,
+            None,
+            set.mode,
+            set.uid,
+            set.gid,
+            None,
+        )?;
+        let mut out = Writer::default();
+        self.wcc_data(&mut out, Some(&attr));
+        Ok(out)
+    }
+
+    fn lookup(&self, conn: &Connection, cred: &Cred, args: &mut Reader) -> Result<Writer, Fail> {
+        let handle = args.opaque()?;
+        let name = args.opaque()?;
+        let dir = self.file(conn, handle)?;
+        if dir.kind != FileType::Directory {
+            return Err(Fail::Status(NFS3ERR_NOTDIR));
+        }
+        cred.check(&dir, X_OK)?;
+        let found = match name {
+            b".." if dir.ino != sql::root() => match sql::find_dir_ent(conn, dir.ino)? {
+                Some((parent, _)) => sql::lookup_inode_generation(conn, parent)?,
+                None => None,
+            },
+            b"." | b".." => sql::lookup_inode_generation(conn, dir.ino)?,
+            _ => sql::lookup_dir_ent(conn, dir.ino, name)?,
+        };
+        let (attr, generation) = found.ok_or(Fail::Status(NFS3ERR_NOENT))?;
+        let mut out = Writer::default();
+        out.opaque(&self.handle(attr.ino, generation));
+        self.post_op_attr(&mut out, Some(&attr));
+        self.post_op_attr(&mut out, Some(&dir));
+        Ok(out)
+    }
+
+    fn access(&self, conn: &Connection, cred: &Cred, args: &mut Reader) -> Result<Writer, Fail> {
+        let attr = self.file(conn, args.opaque()?)?;
+        let requested = args.u32()?;
+        let dir = attr.kind == FileType::Directory;
+        let mut allowed = 0;
+        if cred.permitted(&attr, R_OK) {
+            allowed |= ACCESS_READ;
+        }
+        if cred.permitted(&attr, W_OK) {
+            allowed |= ACCESS_MODIFY | ACCESS_EXTEND;
+            if dir {
+                allowed |= ACCESS_DELETE;
+            }
+        }
+        if cred.permitted(&attr, X_OK) {
+            allowed |= if dir { ACCESS_LOOKUP } else { ACCESS_EXECUTE };
+        }
+        let mut out = Writer::default();
+        self.post_op_attr(&mut out, Some(&attr));
+        out.u32(requested & allowed);
+        Ok(out)
+    }
+
+    fn readlink(&self, conn: &Connection, args: &mut Reader) -> Result<Writer, Fail> {
+        let attr = self.file(conn, args.opaque()?)?;
+        if attr.kind != FileType::Symlink {
+            return Err(Fail::Status(NFS3ERR_INVAL));
+        }
+        let target = sql::read_symlink(conn, attr.ino)?.ok_or(Fail::Status(NFS3ERR_STALE))?;
+        let mut out = Writer::default();
+        self.post_op_attr(&mut out, Some(&attr));
+        out.opaque(target.as_bytes());
+        Ok(out)
+    }
+
+    fn read(&self, conn: &Connection, cred: &Cred, args: &mut Reader) -> Result<Writer, Fail> {
+        let handle = args.opaque()?;
+        let offset = args.u64()?;
+        let count = args.u32()?.min(MAX_IO);
+        let attr = self.file(conn, handle)?;
+        match attr.kind {
+            FileType::RegularFile => {}
+            FileType::Directory => return Err(Fail::Status(NFS3ERR_ISDIR)),
+            _ => return Err(Fail::Status(NFS3ERR_INVAL)),
+        }
+        cred.check_io(&attr, R_OK)?;
+        let data = if offset < attr.size && count > 0 {
+            let len = (attr.size - offset).min(u64::from(count)) as usize;
+            sql::read_data(conn, attr.ino, offset as i64, len)?
+                .ok_or(Fail::Status(NFS3ERR_STALE))?
+        } else {
+            Vec::new()
+        };
+        let mut out = Writer::default();
+        self.post_op_attr(&mut out, Some(&attr));
+        out.u32(data.len() as u32);
+        out.bool(offset + data.len() as u64 >= attr.size);
+        out.opaque(&data);
+        Ok(out)
+    }
+
+    fn write(&self, conn: &Connection, cred: &Cred, args: &mut Reader) -> Result<Writer, Fail> {
+        let handle = args.opaque()?;
+        let offset = args.u64()?;
+        let count = args.u32()? as usize;
+        let _stable = args.u32()?;
+        let data = args.opaque()?;
+        let data = &data[..count.min(data.len())];
+        let attr = self.file(conn, handle)?;
+        match attr.kind {
+            FileType::RegularFile => {}
+            FileType::Directory => return Err(Fail::Status(NFS3ERR_ISDIR)),
+            _ => return Err(Fail::Status(NFS3ERR_INVAL)),
+        }
+        cred.check_io(&attr, W_OK)?;
+        sql::write_data(conn, attr.ino, Some(offset as i64), data)?;
+        let mut out = Writer::default();
+        out.bool(false);
+        self.current_attr(conn, &mut out, attr.ino);
+        out.u32(data.len() as u32);
+        // FILE_SYNC, since every write is committed before it is answered.
+        out.u32(2);
+        out.fixed(&self.verifier);
+        Ok(out)
+    }
+
+    fn create(&self, conn: &Connection, cred: &Cred, args: &mut Reader) -> Result<Writer, Fail> {
+        let (dir, name) = self.dir_op_args(args)?;
+        let how = args.u32()?;
+        let (set, verifier) = match how {
+            0 | 1 => (Some(SetAttr::decode(args)?), None),
+            2 => (None, Some(args.fixed(8)?)),
+            _ => return Err(Fail::Garbage),
+        };
+        let (dir, name) = self.checked_dir_op(conn, cred, dir, name)?;
+        // An exclusive create keeps its verifier in the file's times, which
+        // the client then sets, so that a retransmitted create can tell the
+        // file it made apart from one that was already there.
+        let verifier_times = verifier.map(|verifier| {
+            let mut r = Reader::new(verifier);
+            let atime = r.u32().unwrap_or(0);
+            let mtime = r.u32().unwrap_or(0);
+            (
+                Timespec::new(i64::from(atime), 0),
+                Timespec::new(i64::from(mtime), 0),
+            )
+        });
+        if let Some((existing, generation)) = sql::lookup_dir_ent(conn, dir.ino, name)? {
+            let retransmitted =
+                verifier_times.is_some_and(|times| times == (existing.atime, existing.mtime));
+            if how == 0 && existing.kind == FileType::RegularFile {
+                cred.check_io(&existing, W_OK)?;
+                let set = set.as_ref().unwrap();
+                let attr = match set.size {
+                    Some(size) => sql::update_inode(
+                        conn,
+                        existing.ino,
+                        Some(size),
+                        None,
+                        None,
+                        None,
+                        None,
+                        None,
+                        None,
+                        None,
+                        None,
+                        None,
+                    )?,
+                    None => existing,
+                };
+                return self.created(conn, &attr, generation, dir.ino);
+            }
+            if retransmitted {
+                return self.created(conn, &existing, generation, dir.ino);
+            }
+            return Err(Fail::Status(NFS3ERR_EXIST));
+        }
+        let mode = set.as_ref().and_then(|set| set.mode).unwrap_or(0o644);
+        let (attr, generation) = sql::create_inode(
+            conn,
+            dir.ino,
+            name,
+            FileType::RegularFile,
+            mode,
+            0,
+            (cred.uid, cred.gid),
+        )?;
+        let attr = match (&set, verifier_times) {
+            (Some(set), _) => self.init_attr(conn, cred, attr, set)?,
+            (None, Some((atime, mtime))) => sql::update_inode(
+                conn,
+                attr.ino,
+                None,
+                Some(atime),
+                Some(mtime),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )?,
+            (None, None) => attr,
+        };
+        self.created(conn, &attr, generation, dir.ino)
+    }
+
+    /// Decode the handle and name of the directory entry a procedure is to
+    /// create, before the rest of its arguments.
+    fn dir_op_args<'a>(&self, args: &mut Reader<'a>) -> Result<(&'a [u8], &'a [u8]), Fail> {
+        Ok((args.opaque()?, args.opaque()?))
+    }
+
+    /// The directory the handle dir names, in which the caller may create
+    /// an entry called name.
+    fn checked_dir_op<'a>(
+        &self,
+        conn: &Connection,
+        cred: &Cred,
+        dir: &[u8],
+        name: &'a [u8],
+    ) -> Result<(FileAttr, &'a [u8]), Fail> {
+        let dir = self.file(conn, dir)?;
+        if dir.kind != FileType::Directory {
+            return Err(Fail::Status(NFS3ERR_NOTDIR));
+        }
+        cred.check(&dir, W_OK | X_OK)?;
+        self.check_name(name)?;
+        Ok((dir, name))
+    }
+
+    fn mkdir(&self, conn: &Connection, cred: &Cred, args: &mut Reader) -> Result<Writer, Fail> {
+        let (dir, name) = self.dir_op_args(args)?;
+        let set = SetAttr::decode(args)?;
+        let (dir, name) = self.checked_dir_op(conn, cred, dir, name)?;
+        self.make(conn, cred, &dir, name, FileType::Directory, 0o755, 0, &set)
+    }
+
+    fn mknod(&self, conn: &Connection, cred: &Cred, args: &mut Reader) -> Result<Writer, Fail> {
+        let (dir, name) = self.dir_op_args(args)?;
+        let (kind, set, rdev) = match args.u32()? {
+            kind @ (3 | 4) => {
+                let set = SetAttr::decode(args)?;
+                let major = args.u32()?;
+                let minor = args.u32()?;
+                let kind = if kind == 3 {
+                    FileType::BlockDevice
+                } else {
+                    FileType::CharDevice
+                };
+                // Device numbers are kept as the kernel encodes them for FUSE.
+                let rdev = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+                (kind, set, rdev)
+            }
+            6 => (FileType::Socket, SetAttr::decode(args)?, 0),
+            7 => (FileType::NamedPipe, SetAttr::decode(args)?, 0),
+            1 | 2 | 5 => return Err(Fail::Status(NFS3ERR_BADTYPE)),
+            _ => return Err(Fail::Garbage),
+        };
+        let (dir, name) = self.checked_dir_op(conn, cred, dir, name)?;
+        if (kind == FileType::BlockDevice || kind == FileType::CharDevice) && cred.uid != 0 {
+            return Err(Fail::Status(NFS3ERR_PERM));
+        }
+        self.make(conn, cred, &dir, name, kind, 0o644, rdev, &set)
+    }
+
+    /// Create an inode of kind in directory dir, unless name is taken.
+    #[allow(clippy::too_many_arguments)]
+    fn make(
+        &self,
+        conn: &Connection,
+        cred: &Cred,
+        dir: &FileAttr,
+        name: &[u8],
+        kind: FileType,
+        default_mode: u16,
+        rdev: u32,
+        set: &SetAttr,
+    ) -> Result<Writer, Fail> {
+        if sql::lookup_dir_ent(conn, dir.ino, name)?.is_some() {
+            return Err(Fail::Status(NFS3ERR_EXIST));
+        }
+        let mode = set.mode.unwrap_or(default_mode);
+        let (attr, generation) =
+            sql::create_inode(conn, dir.ino, name, kind, mode, rdev, (cred.uid, cred.gid))?;
+        let attr = self.init_attr(conn, cred, attr, set)?;
+        self.created(conn, &attr, generation, dir.ino)
+    }
+
+    fn symlink(&self, conn: &Connection, cred: &Cred, args: &mut Reader) -> Result<Writer, Fail> {
+        let (dir, name) = self.dir_op_args(args)?;
+        let set = SetAttr::decode(args)?;
+        let target = args.opaque()?;
+        let (dir, name) = self.checked_dir_op(conn, cred, dir, name)?;
+        // Targets are stored as strings.
+        let target = std::str::from_utf8(target).map_err(|_| Fail::Status(NFS3ERR_INVAL))?;
+        if sql::lookup_dir_ent(conn, dir.ino, name)?.is_some() {
+            return Err(Fail::Status(NFS3ERR_EXIST));
+        }
+        let (attr, generation) =
+            sql::create_symlink(conn, dir.ino, name, target, cred.uid, cred.gid)?;
+        let attr = self.init_attr(conn, cred, attr, &set)?;
+        self.created(conn, &attr, generation, dir.ino)
+    }
+
+    fn remove(
+        &self,
+        conn: &Connection,
+        cred: &Cred,
+        args: &mut Reader,
+        is_dir: bool,
+    ) -> Result<Writer, Fail> {
+        let (dir, name) = self.dir_op(conn, cred, args)?;
+        match name {
+            b"." => return Err(Fail::Status(NFS3ERR_INVAL)),
+            b".." => return Err(Fail::Status(NFS3ERR_NOTEMPTY)),
+            _ => sql::unlink(conn, dir.ino, name, is_dir)?,
+        }
+        let mut out = Writer::default();
+        out.bool(false);
+        self.current_attr(conn, &mut out, dir.ino);
+        Ok(out)
+    }
+
+    fn rename(&self, conn: &Connection, cred: &Cred, args: &mut Reader) -> Result<Writer, Fail> {
+        let (from_dir, from_name) = self.dir_op(conn, cred, args)?;
+        let (to_dir, to_name) = self.dir_op(conn, cred, args)?;
+        if matches!(from_name, b"." | b"..") {
+            return Err(Fail::Status(NFS3ERR_INVAL));
+        }
+        self.check_name(to_name)?;
+        sql::rename_dir_ent(conn, from_dir.ino, from_name, to_dir.ino, to_name)?;
+        let mut out = Writer::default();
+        out.bool(false);
+        self.current_attr(conn, &mut out, from_dir.ino);
+        out.bool(false);
+        self.current_attr(conn, &mut out, to_dir.ino);
+        Ok(out)
+    }
+
+    fn link(&self, conn: &Connection, cred: &Cred, args: &mut Reader) -> Result<Writer, Fail> {
+        let handle = args.opaque()?;
+        let (dir, name) = self.dir_op_args(args)?;
+        let attr = self.file(conn, handle)?;
+        let (dir, name) = self.checked_dir_op(conn, cred, dir, name)?;
+        match attr.kind {
+            FileType::RegularFile => {}
+            FileType::Directory => return Err(Fail::Status(NFS3ERR_ISDIR)),
+            _ => return Err(Fail::Status(NFS3ERR_NOTSUPP)),
+        }
+        if sql::lookup_dir_ent(conn, dir.ino, name)?.is_some() {
+            return Err(Fail::Status(NFS3ERR_EXIST));
+        }
+        sql::link(conn, attr.ino, dir.ino, name)?;
+        let mut out = Writer::default();
+        self.current_attr(conn, &mut out, attr.ino);
+        out.bool(false);
+        self.current_attr(conn, &mut out, dir.ino);
+        Ok(out)
+    }
+
+    /// Serve READDIR, or READDIRPLUS if plus is set, which also gives the
+    /// handle and attributes of each entry.
+    fn readdir(
+        &self,
+        conn: &Connection,
+        cred: &Cred,
+        args: &mut Reader,
+        plus: bool,
+    ) -> Result<Writer, Fail> {
+        let handle = args.opaque()?;
+        let cookie = args.u64()?;
+        let _verifier = args.fixed(8)?;
+        let count = args.u32()?;
+        let count = if plus {
+            // The total size of the reply limits it, not that of the names.
+            args.u32()?
+        } else {
+            count
+        };
+        let dir = self.file(conn, handle)?;
+        if dir.kind != FileType::Directory {
+            return Err(Fail::Status(NFS3ERR_NOTDIR));
+        }
+        cred.check(&dir, R_OK)?;
+
+        let mut out = Writer::default();
+        self.post_op_attr(&mut out, Some(&dir));
+        out.fixed(&[0; 8]);
+        // Room is left for what follows the entries and for the reply's
+        // header.
+        let budget = (count as usize).saturating_sub(128);
+        let mut after = self.cookie_name(conn, dir.ino, cookie)?;
+        let mut cookie = cookie;
+        let mut entries = 0;
+        let eof = loop {
+            let ents: Vec<(Vec<u8>, u64, Option<FileAttr>)> = if plus {
+                sql::read_dir_plus(conn, dir.ino, &after, DIR_BATCH)?
+                    .into_iter()
+                    .map(|(ent, attr)| (ent.child_name, ent.child_ino, Some(attr)))
+                    .collect()
+            } else {
+                sql::read_dir(conn, dir.ino, &after, DIR_BATCH)?
+                    .into_iter()
+                    .map(|ent| (ent.child_name, ent.child_ino, None))
+                    .collect()
+            };
+            let generations = if plus {
+                let inos: Vec<u64> = ents.iter().map(|(_, ino, _)| *ino).collect();
+                sql::generations(conn, &inos)?
+            } else {
+                HashMap::new()
+            };
+            let full = ents.len() as i64 == DIR_BATCH;
+            let mut fits = true;
+            for (name, ino, attr) in ents {
+                let mut entry = Writer::default();
+                entry.bool(true);
+                entry.u64(ino);
+                entry.opaque(&name);
+                entry.u64(cookie + 1);
+                if plus {
+                    self.post_op_attr(&mut entry, attr.as_ref());
+                    let generation = generations.get(&ino);
+                    entry.bool(generation.is_some());
+                    if let Some(&generation) = generation {
+                        entry.opaque(&self.handle(ino, generation));
+                    }
+                }
+                let entry = entry.into_inner();
+                if out.written() + entry.len() > budget {
+                    fits = false;
+                    break;
+                }
+                out.fixed(&entry);
+                cookie += 1;
+                entries += 1;
+                after = name;
+            }
+            if !fits {
+                break false;
+            }
+            if !full {
+                break true;
+            }
+        };
+        if entries == 0 && !eof {
+            return Err(Fail::Status(NFS3ERR_TOOSMALL));
+        }
+        if entries > 0 {
+            let mut cookies = self.cookies.lock().unwrap();
+            if cookies.len() >= MAX_COOKIES {
+                cookies.clear();
+            }
+            cookies.insert((dir.ino, cookie), after);
+        }
+        out.bool(false);
+        out.bool(eof);
+        Ok(out)
+    }
+
+    /// The name of the entry of directory dir that cookie follows, which is
+    /// the cookie-th entry if it was not handed out by this server.
+    fn cookie_name(&self, conn: &Connection, dir: u64, cookie: u64) -> Result<Vec<u8>, Fail> {
+        if cookie == 0 {
+            return Ok(Vec::new());
+        }
+        if let Some(name) = self.cookies.lock().unwrap().get(&(dir, cookie)) {
+            return Ok(name.clone());
+        }
+        let mut after = Vec::new();
+        let mut skipped = 0;
+        loop {
+            let ents = sql::read_dir(conn, dir, &after, DIR_BATCH)?;
+            let full = ents.len() as i64 == DIR_BATCH;
+            for ent in ents {
+                skipped += 1;
+                if skipped == cookie {
+                    return Ok(ent.child_name);
+                }
+                after = ent.child_name;
+            }
+            if !full {
+                return Err(Fail::Status(NFS3ERR_BAD_COOKIE));
+            }
+        }
+    }
+
+    fn fsstat(&self, conn: &Connection, args: &mut Reader) -> Result<Writer, Fail> {
+        let attr = self.file(conn, args.opaque()?)?;
+        let (blocks, files) = sql::usage(conn)?;
+        let block_size = sql::block_size() as u64;
+        let free = match sql::store_capacity(conn) {
+            Ok((capacity, available)) if capacity > 0 => available,
+            _ => NOMINAL_FREE,
+        };
+        let mut out = Writer::default();
+        self.post_op_attr(&mut out, Some(&attr));
+        out.u64(blocks * block_size + free);
+        out.u64(free);
+        out.u64(free);
+        out.u64(files + NOMINAL_FREE);
+        out.u64(NOMINAL_FREE);
+        out.u64(NOMINAL_FREE);
+        out.u32(0);
+        Ok(out)
+    }
+
+    fn fsinfo(&self, conn: &Connection, args: &mut Reader) -> Result<Writer, Fail> {
+        let attr = self.file(conn, args.opaque()?)?;
+        let block_size = sql::block_size() as u32;
+        let mut out = Writer::default();
+        self.post_op_attr(&mut out, Some(&attr));
+        // Reads and writes: most, preferred and multiple.
+        for _ in 0..2 {
+            out.u32(MAX_IO);
+            out.u32(MAX_IO);
+            out.u32(block_size);
+        }
+        // Preferred READDIR size.
+        out.u32(64 << 10);
+        out.u64(i64::MAX as u64);
+        // Times are kept to the microsecond.
+        out.u32(0);
+        out.u32(1000);
+        // FSF3_LINK, FSF3_SYMLINK, FSF3_HOMOGENEOUS and FSF3_CANSETTIME
+        out.u32(0x1b);
+        Ok(out)
+    }
+
+    fn pathconf(&self, conn: &Connection, args: &mut Reader) -> Result<Writer, Fail> {
+        let attr = self.file(conn, args.opaque()?)?;
+        let mut out = Writer::default();
+        self.post_op_attr(&mut out, Some(&attr));
+        out.u32(i32::MAX as u32);
+        out.u32(self.name_max);
+        // no_trunc, chown_restricted, case_insensitive, case_preserving
+        out.bool(true);
+        out.bool(true);
+        out.bool(false);
+        out.bool(true);
+        Ok(out)
+    }
+
+    fn commit(&self, conn: &Connection, args: &mut Reader) -> Result<Writer, Fail> {
+        let attr = self.file(conn, args.opaque()?)?;
+        let _offset = args.u64()?;
+        let _count = args.u32()?;
+        let mut out = Writer::default();
+        self.wcc_data(&mut out, Some(&attr));
+        out.fixed(&self.verifier);
+        Ok(out)
+    }
+}
+
+/// The number of optional attributes, each left out, that end the results
+/// of an NFS procedure that failed.
+fn failed_attrs(procedure: u32) -> usize {
+    match procedure {
+        // GETATTR
+        1 => 0,
+        // SETATTR, WRITE, CREATE, MKDIR, SYMLINK, MKNOD, REMOVE, RMDIR and
+        // COMMIT end with wcc_data.
+        2 | 7..=13 | 21 => 2,
+        // RENAME ends with two.
+        14 => 4,
+        // LINK ends with post_op_attr and wcc_data.
+        15 => 3,
+        // The rest end with post_op_attr.
+        _ => 1,
+    }
+}
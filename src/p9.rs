@@ -0,0 +1,1103 @@
+//! A 9P2000.L frontend to the filesystem, for QEMU/KVM guests and WSL2
+//! distributions that can attach a 9P share but cannot run FUSE. Guests
+//! connect over TCP or a Unix socket:
+//!
+//!     mount -t 9p -o trans=tcp,port=564,version=9p2000.L,access=client host /mnt
+//!
+//! Operations go through the same sql layer as mounts. Permissions are left
+//! to the guest's kernel to check, as access=client has it do, and files are
+//! created owned by the uid each guest user attaches as and the gid the
+//! guest gives. Locks are granted without being recorded, so they only
+//! exclude processes within a guest.
+
+use super::error::CrfsError;
+use super::pool::Pool;
+use super::sql;
+use fuse::{FileAttr, FileType};
+use log::{debug, warn};
+use postgres::Connection;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use time::Timespec;
+
+const VERSION: &[u8] = b"9P2000.L";
+
+/// Bytes of a read or write message besides its data.
+const IOHDRSZ: u32 = 24;
+
+/// Largest message accepted, whatever size a client asks for.
+const MAX_MSIZE: u32 = (1 << 20) + IOHDRSZ;
+
+/// The fid and uid given when there is none.
+const NOFID: u32 = !0;
+
+/// Identity of callers that attach without a uid.
+const NOBODY: u32 = 65534;
+
+/// Directory entries read per query.
+const DIR_BATCH: i64 = 1000;
+
+/// Largest extended attribute value accepted.
+const MAX_XATTR: u64 = 64 << 10;
+
+/// Magic number of 9P filesystems in statfs.
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+/// Free blocks and inodes reported when the cluster's capacity is unknown.
+const NOMINAL_FREE: u64 = 1 << 40;
+
+// Message types. Each reply's type is one more than its request's.
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TXATTRCREATE: u8 = 32;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TAUTH: u8 = 102;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+// Attributes set by Tsetattr.
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+/// Attributes given by Rgetattr: the basic ones, birth time and generation.
+const GETATTR_VALID: u64 = 0x7ff | 0x800 | 0x1000;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// A connection from a guest.
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+/// Serve the connections from incoming on the pool's connections.
+pub fn serve<S, I>(incoming: I, pool: Pool) -> io::Result<()>
+where
+    S: Stream,
+    I: Iterator<Item = io::Result<S>>,
+{
+    let pool = Arc::new(pool);
+    for stream in incoming {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("9p: accept {}", err);
+                continue;
+            }
+        };
+        let pool = pool.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve_connection(&pool, stream) {
+                debug!("9p: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Read the requests sent on stream, serving each on the pool so that a
+/// guest's requests run concurrently, and send the replies back as they are
+/// ready.
+fn serve_connection<S: Stream>(pool: &Pool, stream: S) -> io::Result<()> {
+    let session = Arc::new(Session {
+        msize: Mutex::new(MAX_MSIZE),
+        fids: Mutex::new(HashMap::new()),
+        pending: Mutex::new(HashSet::new()),
+        done: Condvar::new(),
+        out: Mutex::new(Box::new(stream.try_clone()?)),
+    });
+    let mut r = BufReader::new(stream);
+    loop {
+        let mut size = [0; 4];
+        match r.read_exact(&mut size) {
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            res => res?,
+        }
+        let size = u32::from_le_bytes(size);
+        if !(7..=MAX_MSIZE).contains(&size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid 9P message size",
+            ));
+        }
+        let mut msg = vec![0; size as usize - 4];
+        r.read_exact(&mut msg)?;
+        let kind = msg[0];
+        let tag = u16::from_le_bytes([msg[1], msg[2]]);
+        match kind {
+            TVERSION => {
+                // Versioning starts a new session, so it waits for the
+                // requests of the old one.
+                let mut pending = session.pending.lock().unwrap();
+                while !pending.is_empty() {
+                    pending = session.done.wait(pending).unwrap();
+                }
+                drop(pending);
+                let reply = session.version(&mut Msg::new(&msg[3..]));
+                session.send(kind, tag, reply);
+            }
+            TFLUSH => {
+                // A flush is answered once the request it names has been,
+                // since requests cannot be cancelled once the pool has them.
+                let session = session.clone();
+                thread::spawn(move || {
+                    let old = Msg::new(&msg[3..]).u16().unwrap_or(tag);
+                    let mut pending = session.pending.lock().unwrap();
+                    while pending.contains(&old) {
+                        pending = session.done.wait(pending).unwrap();
+                    }
+                    drop(pending);
+                    session.send(kind, tag, Ok(Reply::default()));
+                });
+            }
+            _ => {
+                session.pending.lock().unwrap().insert(tag);
+                let session = session.clone();
+                pool.execute(move |conn| {
+                    let reply = session.serve(conn, kind, &mut Msg::new(&msg[3..]));
+                    session.send(kind, tag, reply);
+                    session.pending.lock().unwrap().remove(&tag);
+                    session.done.notify_all();
+                });
+            }
+        }
+    }
+}
+
+/// Decodes the fields of a request, in order.
+struct Msg<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Msg<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Msg { buf }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Errno> {
+        if self.buf.len() < len {
+            return Err(Errno(libc::EPROTO));
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Errno> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Errno> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Errno> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> Result<u64, Errno> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn str(&mut self) -> Result<&'a [u8], Errno> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    /// A name to look up or give to a directory entry.
+    fn name(&mut self) -> Result<&'a [u8], Errno> {
+        match self.str()? {
+            b"" | b"." | b".." => Err(Errno(libc::EINVAL)),
+            name if name.contains(&b'/') => Err(Errno(libc::EINVAL)),
+            name => Ok(name),
+        }
+    }
+}
+
+/// Encodes the fields of a reply, in order.
+#[derive(Default)]
+struct Reply {
+    buf: Vec<u8>,
+}
+
+impl Reply {
+    fn u8(&mut self, n: u8) {
+        self.buf.push(n);
+    }
+
+    fn u16(&mut self, n: u16) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &[u8]) {
+        self.u16(s.len() as u16);
+        self.buf.extend_from_slice(s);
+    }
+
+    fn data(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+
+    fn qid(&mut self, kind: FileType, ino: u64) {
+        self.u8(match kind {
+            FileType::Directory => 0x80,
+            FileType::Symlink => 0x02,
+            _ => 0,
+        });
+        self.u32(0);
+        self.u64(ino);
+    }
+}
+
+/// A Linux error number, answered with Rlerror.
+struct Errno(i32);
+
+impl From<postgres::Error> for Errno {
+    fn from(err: postgres::Error) -> Errno {
+        Errno::from(CrfsError::from(err))
+    }
+}
+
+impl From<CrfsError> for Errno {
+    fn from(err: CrfsError) -> Errno {
+        if let Some(err) = err.backend() {
+            warn!("9p: {}", err);
+        }
+        Errno(err.errno())
+    }
+}
+
+/// What a fid stands for besides its file.
+#[derive(Clone)]
+enum State {
+    Closed,
+    Open {
+        append: bool,
+        /// Offset and name of the entry the last Treaddir ended at.
+        listed: Option<(u64, Vec<u8>)>,
+    },
+    /// An extended attribute, or the list of names of them, being read.
+    XattrRead(Vec<u8>),
+    /// An extended attribute being written, set once the fid is clunked.
+    XattrWrite {
+        name: String,
+        size: u64,
+        flags: u32,
+        value: Vec<u8>,
+    },
+}
+
+#[derive(Clone)]
+struct Fid {
+    ino: u64,
+    /// The uid the fid was attached as.
+    uid: u32,
+    /// The directory and name the fid was walked to, if known.
+    entry: Option<(u64, Vec<u8>)>,
+    state: State,
+}
+
+struct Session {
+    msize: Mutex<u32>,
+    fids: Mutex<HashMap<u32, Fid>>,
+    /// Tags of the requests being served.
+    pending: Mutex<HashSet<u16>>,
+    /// Notified whenever a request has been answered.
+    done: Condvar,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Session {
+    fn send(&self, kind: u8, tag: u16, reply: Result<Reply, Errno>) {
+        let (kind, body) = match reply {
+            Ok(reply) => (kind + 1, reply.buf),
+            Err(Errno(errno)) => {
+                let mut reply = Reply::default();
+                reply.u32(errno as u32);
+                (RLERROR, reply.buf)
+            }
+        };
+        let mut msg = Vec::with_capacity(7 + body.len());
+        msg.extend_from_slice(&(7 + body.len() as u32).to_le_bytes());
+        msg.push(kind);
+        msg.extend_from_slice(&tag.to_le_bytes());
+        msg.extend_from_slice(&body);
+        let mut out = self.out.lock().unwrap();
+        if let Err(err) = out.write_all(&msg).and_then(|()| out.flush()) {
+            debug!("9p: {}", err);
+        }
+    }
+
+    fn fid(&self, fid: u32) -> Result<Fid, Errno> {
+        self.fids
+            .lock()
+            .unwrap()
+            .get(&fid)
+            .cloned()
+            .ok_or(Errno(libc::EBADF))
+    }
+
+    fn set_fid(&self, fid: u32, f: Fid) {
+        self.fids.lock().unwrap().insert(fid, f);
+    }
+
+    /// Most bytes of data a read or write may carry.
+    fn iounit(&self) -> u32 {
+        *self.msize.lock().unwrap() - IOHDRSZ
+    }
+
+    fn version(&self, msg: &mut Msg) -> Result<Reply, Errno> {
+        let msize = msg.u32()?;
+        let version = msg.str()?;
+        self.fids.lock().unwrap().clear();
+        let msize = msize.clamp(4096, MAX_MSIZE);
+        *self.msize.lock().unwrap() = msize;
+        let mut reply = Reply::default();
+        reply.u32(msize);
+        if version.starts_with(VERSION) {
+            reply.str(VERSION);
+        } else {
+            reply.str(b"unknown");
+        }
+        Ok(reply)
+    }
+
+    fn serve(&self, conn: &Connection, kind: u8, msg: &mut Msg) -> Result<Reply, Errno> {
+        let mut reply = Reply::default();
+        let r = &mut reply;
+        match kind {
+            TAUTH => return Err(Errno(libc::EOPNOTSUPP)),
+            TATTACH => self.attach(conn, msg, r)?,
+            TWALK => self.walk(conn, msg, r)?,
+            TLOPEN => self.lopen(conn, msg, r)?,
+            TLCREATE => self.lcreate(conn, msg, r)?,
+            TSYMLINK => self.symlink(conn, msg, r)?,
+            TMKNOD => self.mknod(conn, msg, r)?,
+            TMKDIR => self.mkdir(conn, msg, r)?,
+            TRENAME => self.rename(conn, msg)?,
+            TRENAMEAT => self.renameat(conn, msg)?,
+            TUNLINKAT => self.unlinkat(conn, msg)?,
+            TREMOVE => self.remove(conn, msg)?,
+            TLINK => self.link(conn, msg)?,
+            TREADLINK => self.readlink(conn, msg, r)?,
+            TGETATTR => self.getattr(conn, msg, r)?,
+            TSETATTR => self.setattr(conn, msg)?,
+            TXATTRWALK => self.xattrwalk(conn, msg, r)?,
+            TXATTRCREATE => self.xattrcreate(msg)?,
+            TREADDIR => self.readdir(conn, msg, r)?,
+            TREAD => self.read(conn, msg, r)?,
+            TWRITE => self.write(conn, msg, r)?,
+            TCLUNK => self.clunk(conn, msg)?,
+            TSTATFS => self.statfs(conn, msg, r)?,
+            TFSYNC => {
+                // Writes are committed before they are answered.
+                self.fid(msg.u32()?)?;
+            }
+            TLOCK => {
+                self.fid(msg.u32()?)?;
+                // P9_LOCK_SUCCESS
+                r.u8(0);
+            }
+            TGETLOCK => {
+                self.fid(msg.u32()?)?;
+                let _kind = msg.u8()?;
+                let start = msg.u64()?;
+                let length = msg.u64()?;
+                let proc_id = msg.u32()?;
+                let client_id = msg.str()?;
+                r.u8(libc::F_UNLCK as u8);
+                r.u64(start);
+                r.u64(length);
+                r.u32(proc_id);
+                r.str(client_id);
+            }
+            _ => return Err(Errno(libc::EOPNOTSUPP)),
+        }
+        Ok(reply)
+    }
+
+    /// The attributes of a fid's file.
+    fn attr(&self, conn: &Connection, fid: &Fid) -> Result<FileAttr, Errno> {
+        sql::lookup_inode(conn, fid.ino)?.ok_or(Errno(libc::ESTALE))
+    }
+
+    /// The directory a fid names.
+    fn dir(&self, conn: &Connection, fid: u32) -> Result<Fid, Errno> {
+        let fid = self.fid(fid)?;
+        if self.attr(conn, &fid)?.kind != FileType::Directory {
+            return Err(Errno(libc::ENOTDIR));
+        }
+        Ok(fid)
+    }
+
+    /// The directory entry a fid was walked to.
+    fn entry(&self, conn: &Connection, fid: &Fid) -> Result<(u64, Vec<u8>), Errno> {
+        match &fid.entry {
+            Some(entry) => Ok(entry.clone()),
+            // The root, or a directory reached through "..".
+            None if fid.ino == sql::root() => Err(Errno(libc::EBUSY)),
+            None => sql::find_dir_ent(conn, fid.ino)?.ok_or(Errno(libc::ESTALE)),
+        }
+    }
+
+    fn attach(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let fid = msg.u32()?;
+        let _afid = msg.u32()?;
+        let _uname = msg.str()?;
+        let aname = msg.str()?;
+        let uid = match msg.u32()? {
+            NOFID => NOBODY,
+            uid => uid,
+        };
+        let mut ino = sql::root();
+        let mut entry = None;
+        for name in aname.split(|&b| b == b'/').filter(|n| !n.is_empty()) {
+            match sql::lookup_dir_ent(conn, ino, name)? {
+                Some((attr, _)) if attr.kind == FileType::Directory => {
+                    entry = Some((ino, name.to_vec()));
+                    ino = attr.ino;
+                }
+                Some(_) => return Err(Errno(libc::ENOTDIR)),
+                None => return Err(Errno(libc::ENOENT)),
+            }
+        }
+        self.set_fid(
+            fid,
+            Fid {
+                ino,
+                uid,
+                entry,
+                state: State::Closed,
+            },
+        );
+        r.qid(FileType::Directory, ino);
+        Ok(())
+    }
+
+    fn walk(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let fid = self.fid(msg.u32()?)?;
+        let newfid = msg.u32()?;
+        let count = msg.u16()?;
+        let names = (0..count)
+            .map(|_| msg.str())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut ino = fid.ino;
+        let mut entry = fid.entry.clone();
+        let mut qids = Vec::new();
+        for (i, &name) in names.iter().enumerate() {
+            let attr = match name {
+                b"." => sql::lookup_inode(conn, ino)?,
+                b".." if ino == sql::root() => sql::lookup_inode(conn, ino)?,
+                b".." => match sql::find_dir_ent(conn, ino)? {
+                    Some((parent, _)) => sql::lookup_inode(conn, parent)?,
+                    None => None,
+                },
+                _ => match sql::lookup_inode_kind(conn, ino)? {
+                    Some(FileType::Directory) => {
+                        sql::lookup_dir_ent(conn, ino, name)?.map(|(attr, _)| attr)
+                    }
+                    _ if i == 0 => return Err(Errno(libc::ENOTDIR)),
+                    _ => None,
+                },
+            };
+            let attr = match attr {
+                Some(attr) => attr,
+                None if i == 0 => return Err(Errno(libc::ENOENT)),
+                // A partial walk answers with the names found, without
+                // making newfid.
+                None => break,
+            };
+            entry = match name {
+                b"." => entry,
+                b".." => None,
+                _ => Some((ino, name.to_vec())),
+            };
+            ino = attr.ino;
+            qids.push((attr.kind, attr.ino));
+        }
+        if qids.len() == names.len() {
+            self.set_fid(
+                newfid,
+                Fid {
+                    ino,
+                    uid: fid.uid,
+                    entry,
+                    state: State::Closed,
+                },
+            );
+        }
+        r.u16(qids.len() as u16);
+        for (kind, ino) in qids {
+            r.qid(kind, ino);
+        }
+        Ok(())
+    }
+
+    fn lopen(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let id = msg.u32()?;
+        let flags = msg.u32()? as i32;
+        let mut fid = self.fid(id)?;
+        let attr = self.attr(conn, &fid)?;
+        let writing = flags & libc::O_ACCMODE != libc::O_RDONLY;
+        if attr.kind == FileType::RegularFile
+            && writing
+            && flags & libc::O_TRUNC != 0
+            && attr.size > 0
+        {
+            sql::update_inode(
+                conn,
+                attr.ino,
+                Some(0),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )?;
+        }
+        fid.state = State::Open {
+            append: flags & libc::O_APPEND != 0,
+            listed: None,
+        };
+        self.set_fid(id, fid);
+        r.qid(attr.kind, attr.ino);
+        r.u32(self.iounit());
+        Ok(())
+    }
+
+    fn lcreate(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let id = msg.u32()?;
+        let name = msg.name()?;
+        let flags = msg.u32()? as i32;
+        let mode = msg.u32()?;
+        let gid = msg.u32()?;
+        let dir = self.dir(conn, id)?;
+        let (attr, _) = sql::create_inode(
+            conn,
+            dir.ino,
+            name,
+            FileType::RegularFile,
+            (mode & 0o7777) as u16,
+            0,
+            (dir.uid, gid),
+        )?;
+        // The fid moves from the directory to the file it created.
+        self.set_fid(
+            id,
+            Fid {
+                ino: attr.ino,
+                uid: dir.uid,
+                entry: Some((dir.ino, name.to_vec())),
+                state: State::Open {
+                    append: flags & libc::O_APPEND != 0,
+                    listed: None,
+                },
+            },
+        );
+        r.qid(attr.kind, attr.ino);
+        r.u32(self.iounit());
+        Ok(())
+    }
+
+    fn symlink(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let dir = self.dir(conn, msg.u32()?)?;
+        let name = msg.name()?;
+        let target = msg.str()?;
+        let gid = msg.u32()?;
+        // Targets are stored as strings.
+        let target = std::str::from_utf8(target).map_err(|_| Errno(libc::EINVAL))?;
+        let (attr, _) = sql::create_symlink(conn, dir.ino, name, target, dir.uid, gid)?;
+        r.qid(attr.kind, attr.ino);
+        Ok(())
+    }
+
+    fn mknod(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let dir = self.dir(conn, msg.u32()?)?;
+        let name = msg.name()?;
+        let mode = msg.u32()?;
+        let major = msg.u32()?;
+        let minor = msg.u32()?;
+        let gid = msg.u32()?;
+        let kind = match mode & libc::S_IFMT {
+            libc::S_IFREG => FileType::RegularFile,
+            libc::S_IFCHR => FileType::CharDevice,
+            libc::S_IFBLK => FileType::BlockDevice,
+            libc::S_IFIFO => FileType::NamedPipe,
+            libc::S_IFSOCK => FileType::Socket,
+            _ => return Err(Errno(libc::EINVAL)),
+        };
+        // Device numbers are kept as the kernel encodes them for FUSE.
+        let rdev = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+        let (attr, _) = sql::create_inode(
+            conn,
+            dir.ino,
+            name,
+            kind,
+            (mode & 0o7777) as u16,
+            rdev,
+            (dir.uid, gid),
+        )?;
+        r.qid(attr.kind, attr.ino);
+        Ok(())
+    }
+
+    fn mkdir(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let dir = self.dir(conn, msg.u32()?)?;
+        let name = msg.name()?;
+        let mode = msg.u32()?;
+        let gid = msg.u32()?;
+        let (attr, _) = sql::create_inode(
+            conn,
+            dir.ino,
+            name,
+            FileType::Directory,
+            (mode & 0o7777) as u16,
+            0,
+            (dir.uid, gid),
+        )?;
+        r.qid(attr.kind, attr.ino);
+        Ok(())
+    }
+
+    fn rename(&self, conn: &Connection, msg: &mut Msg) -> Result<(), Errno> {
+        let id = msg.u32()?;
+        let mut fid = self.fid(id)?;
+        let dir = self.dir(conn, msg.u32()?)?;
+        let name = msg.name()?;
+        let (parent, old_name) = self.entry(conn, &fid)?;
+        move_entry(conn, parent, &old_name, dir.ino, name)?;
+        fid.entry = Some((dir.ino, name.to_vec()));
+        if let Some(current) = self.fids.lock().unwrap().get_mut(&id) {
+            current.entry = fid.entry;
+        }
+        Ok(())
+    }
+
+    fn renameat(&self, conn: &Connection, msg: &mut Msg) -> Result<(), Errno> {
+        let old_dir = self.dir(conn, msg.u32()?)?;
+        let old_name = msg.name()?;
+        let new_dir = self.dir(conn, msg.u32()?)?;
+        let new_name = msg.name()?;
+        move_entry(conn, old_dir.ino, old_name, new_dir.ino, new_name)
+    }
+
+    fn unlinkat(&self, conn: &Connection, msg: &mut Msg) -> Result<(), Errno> {
+        let dir = self.dir(conn, msg.u32()?)?;
+        let name = msg.name()?;
+        let flags = msg.u32()?;
+        Ok(sql::unlink(conn, dir.ino, name, flags & AT_REMOVEDIR != 0)?)
+    }
+
+    fn remove(&self, conn: &Connection, msg: &mut Msg) -> Result<(), Errno> {
+        let id = msg.u32()?;
+        let fid = self.fid(id)?;
+        // The fid is clunked whether or not its file is removed.
+        self.fids.lock().unwrap().remove(&id);
+        let attr = self.attr(conn, &fid)?;
+        let (parent, name) = self.entry(conn, &fid)?;
+        Ok(sql::unlink(
+            conn,
+            parent,
+            &name,
+            attr.kind == FileType::Directory,
+        )?)
+    }
+
+    fn link(&self, conn: &Connection, msg: &mut Msg) -> Result<(), Errno> {
+        let dir = self.dir(conn, msg.u32()?)?;
+        let fid = self.fid(msg.u32()?)?;
+        let name = msg.name()?;
+        if self.attr(conn, &fid)?.kind == FileType::Directory {
+            return Err(Errno(libc::EPERM));
+        }
+        sql::link(conn, fid.ino, dir.ino, name)?;
+        Ok(())
+    }
+
+    fn readlink(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let fid = self.fid(msg.u32()?)?;
+        let target = sql::read_symlink(conn, fid.ino)?.ok_or(Errno(libc::EINVAL))?;
+        r.str(target.as_bytes());
+        Ok(())
+    }
+
+    fn getattr(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let fid = self.fid(msg.u32()?)?;
+        let _mask = msg.u64()?;
+        let (attr, generation) =
+            sql::lookup_inode_generation(conn, fid.ino)?.ok_or(Errno(libc::ESTALE))?;
+        let block_size = sql::block_size() as u64;
+        let format = match attr.kind {
+            FileType::NamedPipe => libc::S_IFIFO,
+            FileType::CharDevice => libc::S_IFCHR,
+            FileType::BlockDevice => libc::S_IFBLK,
+            FileType::Directory => libc::S_IFDIR,
+            FileType::RegularFile => libc::S_IFREG,
+            FileType::Symlink => libc::S_IFLNK,
+            FileType::Socket => libc::S_IFSOCK,
+        };
+        r.u64(GETATTR_VALID);
+        r.qid(attr.kind, attr.ino);
+        r.u32(format | u32::from(attr.perm));
+        r.u32(attr.uid);
+        r.u32(attr.gid);
+        r.u64(u64::from(attr.nlink));
+        r.u64(u64::from(attr.rdev));
+        r.u64(attr.size);
+        r.u64(block_size);
+        // In 512-byte units.
+        r.u64(attr.blocks * block_size / 512);
+        for t in &[attr.atime, attr.mtime, attr.ctime, attr.crtime] {
+            r.u64(t.sec as u64);
+            r.u64(t.nsec as u64);
+        }
+        r.u64(generation);
+        r.u64(0);
+        Ok(())
+    }
+
+    fn setattr(&self, conn: &Connection, msg: &mut Msg) -> Result<(), Errno> {
+        let fid = self.fid(msg.u32()?)?;
+        let valid = msg.u32()?;
+        let mode = msg.u32()?;
+        let uid = msg.u32()?;
+        let gid = msg.u32()?;
+        let size = msg.u64()?;
+        let atime = Timespec::new(msg.u64()? as i64, msg.u64()? as i32);
+        let mtime = Timespec::new(msg.u64()? as i64, msg.u64()? as i32);
+        let set = |bit| valid & bit != 0;
+        let time = |bit, given_bit, given| match (set(bit), set(given_bit)) {
+            (false, _) => None,
+            (true, true) => Some(given),
+            (true, false) => Some(time::get_time()),
+        };
+        sql::update_inode(
+            conn,
+            fid.ino,
+            Some(size).filter(|_| set(SETATTR_SIZE)),
+            time(SETATTR_ATIME, SETATTR_ATIME_SET, atime),
+            time(SETATTR_MTIME, SETATTR_MTIME_SET, mtime),
+            None,
+            None,
+            None,
+            Some((mode & 0o7777) as u16).filter(|_| set(SETATTR_MODE)),
+            Some(uid).filter(|_| set(SETATTR_UID)),
+            Some(gid).filter(|_| set(SETATTR_GID)),
+            None,
+        )?;
+        Ok(())
+    }
+
+    fn xattrwalk(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let fid = self.fid(msg.u32()?)?;
+        let newfid = msg.u32()?;
+        let name = msg.str()?;
+        let value = if name.is_empty() {
+            let mut names = Vec::new();
+            for name in sql::list_xattrs(conn, fid.ino)? {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+            names
+        } else {
+            let name = std::str::from_utf8(name).map_err(|_| Errno(libc::ENODATA))?;
+            sql::get_xattr(conn, fid.ino, name)?.ok_or(Errno(libc::ENODATA))?
+        };
+        r.u64(value.len() as u64);
+        self.set_fid(
+            newfid,
+            Fid {
+                state: State::XattrRead(value),
+                ..fid
+            },
+        );
+        Ok(())
+    }
+
+    fn xattrcreate(&self, msg: &mut Msg) -> Result<(), Errno> {
+        let id = msg.u32()?;
+        let fid = self.fid(id)?;
+        let name = msg.str()?;
+        let size = msg.u64()?;
+        let flags = msg.u32()?;
+        // Names are stored as strings.
+        let name = String::from_utf8(name.to_vec()).map_err(|_| Errno(libc::EINVAL))?;
+        if size > MAX_XATTR {
+            return Err(Errno(libc::E2BIG));
+        }
+        self.set_fid(
+            id,
+            Fid {
+                state: State::XattrWrite {
+                    name,
+                    size,
+                    flags,
+                    value: Vec::with_capacity(size as usize),
+                },
+                ..fid
+            },
+        );
+        Ok(())
+    }
+
+    fn readdir(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let id = msg.u32()?;
+        let fid = self.fid(id)?;
+        let offset = msg.u64()?;
+        let count = msg.u32()?.min(self.iounit()) as usize;
+        let (append, listed) = match fid.state {
+            State::Open { append, listed } => (append, listed),
+            _ => return Err(Errno(libc::EBADF)),
+        };
+        // Offsets count entries. A listing usually resumes where the last
+        // one ended, after the name it ended at.
+        let mut after = match listed {
+            _ if offset == 0 => Vec::new(),
+            Some((listed, name)) if listed == offset => name,
+            _ => nth_name(conn, fid.ino, offset)?,
+        };
+        let mut entries = Vec::new();
+        let mut next = offset;
+        'list: loop {
+            let ents = sql::read_dir(conn, fid.ino, &after, DIR_BATCH)?;
+            let full = ents.len() as i64 == DIR_BATCH;
+            for ent in ents {
+                // qid, offset, type and name
+                if entries.len() + 13 + 8 + 1 + 2 + ent.child_name.len() > count {
+                    break 'list;
+                }
+                let mut entry = Reply::default();
+                entry.qid(ent.child_kind, ent.child_ino);
+                next += 1;
+                entry.u64(next);
+                entry.u8(dirent_type(ent.child_kind));
+                entry.str(&ent.child_name);
+                entries.extend_from_slice(&entry.buf);
+                after = ent.child_name;
+            }
+            if !full {
+                break;
+            }
+        }
+        r.data(&entries);
+        if let Some(current) = self.fids.lock().unwrap().get_mut(&id) {
+            current.state = State::Open {
+                append,
+                listed: Some((next, after)),
+            };
+        }
+        Ok(())
+    }
+
+    fn read(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let fid = self.fid(msg.u32()?)?;
+        let offset = msg.u64()?;
+        let count = msg.u32()?.min(self.iounit()) as usize;
+        match fid.state {
+            State::XattrRead(value) => {
+                let start = (offset as usize).min(value.len());
+                let end = (start + count).min(value.len());
+                r.data(&value[start..end]);
+            }
+            State::Open { .. } => {
+                if sql::lookup_inode_kind(conn, fid.ino)? == Some(FileType::Directory) {
+                    return Err(Errno(libc::EISDIR));
+                }
+                let data = sql::read_data(conn, fid.ino, offset as i64, count)?
+                    .ok_or(Errno(libc::ESTALE))?;
+                r.data(&data);
+            }
+            _ => return Err(Errno(libc::EBADF)),
+        }
+        Ok(())
+    }
+
+    fn write(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        let id = msg.u32()?;
+        let fid = self.fid(id)?;
+        let offset = msg.u64()?;
+        let count = msg.u32()? as usize;
+        let data = msg.bytes(count)?;
+        match fid.state {
+            State::XattrWrite { size, .. } => {
+                let mut fids = self.fids.lock().unwrap();
+                if let Some(Fid {
+                    state: State::XattrWrite { value, .. },
+                    ..
+                }) = fids.get_mut(&id)
+                {
+                    if offset != value.len() as u64 || value.len() + data.len() > size as usize {
+                        return Err(Errno(libc::EINVAL));
+                    }
+                    value.extend_from_slice(data);
+                }
+            }
+            State::Open { append, .. } => {
+                // Appends go to the end of the file as it is in the
+                // database, which the guest may not have seen yet.
+                let offset = if append { None } else { Some(offset as i64) };
+                sql::write_data(conn, fid.ino, offset, data)?;
+            }
+            _ => return Err(Errno(libc::EBADF)),
+        }
+        r.u32(data.len() as u32);
+        Ok(())
+    }
+
+    fn clunk(&self, conn: &Connection, msg: &mut Msg) -> Result<(), Errno> {
+        let id = msg.u32()?;
+        let fid = self
+            .fids
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .ok_or(Errno(libc::EBADF))?;
+        if let State::XattrWrite {
+            name,
+            size,
+            flags,
+            value,
+        } = fid.state
+        {
+            if value.len() as u64 != size {
+                return Err(Errno(libc::EINVAL));
+            }
+            let create = flags & libc::XATTR_CREATE as u32 != 0;
+            let replace = flags & libc::XATTR_REPLACE as u32 != 0;
+            // Removing an attribute writes an empty one that must exist.
+            if size == 0 && replace {
+                if !sql::remove_xattr(conn, fid.ino, &name)? {
+                    return Err(Errno(libc::ENODATA));
+                }
+            } else if !sql::set_xattr(conn, fid.ino, &name, &value, create, replace)? {
+                return Err(Errno(libc::ENODATA));
+            }
+        }
+        Ok(())
+    }
+
+    fn statfs(&self, conn: &Connection, msg: &mut Msg, r: &mut Reply) -> Result<(), Errno> {
+        self.fid(msg.u32()?)?;
+        let (blocks, files) = sql::usage(conn)?;
+        let block_size = sql::block_size() as u64;
+        let free = match sql::store_capacity(conn) {
+            Ok((capacity, available)) if capacity > 0 => available / block_size,
+            _ => NOMINAL_FREE,
+        };
+        r.u32(V9FS_MAGIC);
+        r.u32(block_size as u32);
+        r.u64(blocks + free);
+        r.u64(free);
+        r.u64(free);
+        r.u64(files + NOMINAL_FREE);
+        r.u64(NOMINAL_FREE);
+        r.u64(0);
+        r.u32(sql::load_name_max(conn, sql::DEFAULT_NAME_MAX)? as u32);
+        Ok(())
+    }
+}
+
+/// Move the entry name in directory dir to new_name in new_dir.
+fn move_entry(
+    conn: &Connection,
+    dir: u64,
+    name: &[u8],
+    new_dir: u64,
+    new_name: &[u8],
+) -> Result<(), Errno> {
+    Ok(sql::rename_dir_ent(conn, dir, name, new_dir, new_name)?)
+}
+
+/// The name of the n-th entry of directory dir, counting from one.
+fn nth_name(conn: &Connection, dir: u64, n: u64) -> Result<Vec<u8>, Errno> {
+    let mut after = Vec::new();
+    let mut seen = 0;
+    loop {
+        let ents = sql::read_dir(conn, dir, &after, DIR_BATCH)?;
+        let full = ents.len() as i64 == DIR_BATCH;
+        for ent in ents {
+            seen += 1;
+            if seen == n {
+                return Ok(ent.child_name);
+            }
+            after = ent.child_name;
+        }
+        if !full {
+            // Past the end, so the listing is over.
+            return Ok(after);
+        }
+    }
+}
+
+fn dirent_type(kind: FileType) -> u8 {
+    match kind {
+        FileType::NamedPipe => libc::DT_FIFO,
+        FileType::CharDevice => libc::DT_CHR,
+        FileType::Directory => libc::DT_DIR,
+        FileType::BlockDevice => libc::DT_BLK,
+        FileType::RegularFile => libc::DT_REG,
+        FileType::Symlink => libc::DT_LNK,
+        FileType::Socket => libc::DT_SOCK,
+    }
+}
@@ -0,0 +1,100 @@
+//! A fixed-size pool of database connections that serve filesystem operations
+//! concurrently.
+//!
+//! The FUSE session dispatches requests from a single thread, so an operation
+//! that waits on the database holds up every other process using the mount.
+//! Operations handed to the pool instead run on whichever pooled connection is
+//! free, each from its own worker thread, and reply to the kernel from there.
+
+use super::store::Store;
+use super::trace;
+use log::{info, warn};
+use postgres::Connection;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// An operation to run on a pooled connection.
+type Job<C> = Box<dyn FnOnce(&C) + Send>;
+
+/// Opens a connection to replace a pooled one that has broken.
+pub type Connector<C = Connection> = Arc<dyn Fn() -> io::Result<C> + Send + Sync>;
+
+pub struct Pool<C = Connection> {
+    jobs: Sender<Job<C>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<C: Store> Pool<C> {
+    /// Serve operations from the given connections, one worker thread per
+    /// connection, replacing broken ones using connector if given.
+    pub fn new(conns: Vec<C>, connector: Option<Connector<C>>) -> Pool<C> {
+        let (jobs, queue) = mpsc::channel::<Job<C>>();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = conns
+            .into_iter()
+            .map(|conn| {
+                let queue = queue.clone();
+                let connector = connector.clone();
+                thread::spawn(move || work(conn, queue, connector))
+            })
+            .collect();
+        Pool { jobs, workers }
+    }
+
+    /// Run op on the next free connection.
+    pub fn execute<F>(&self, op: F)
+    where
+        F: FnOnce(&C) + Send + 'static,
+    {
+        // The operation is traced as part of the one that handed it over.
+        let ctx = trace::current();
+        let job = move |conn: &C| {
+            let _entered = trace::enter(ctx);
+            let _span = trace::start("pool".to_string(), trace::KIND_INTERNAL);
+            op(conn)
+        };
+        // The workers only exit once the pool, and with it the sender, has
+        // been dropped.
+        self.jobs.send(Box::new(job)).unwrap();
+    }
+
+    /// Number of connections serving operations.
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Wait for the operations already handed to the pool to finish.
+    pub fn join(self) {
+        drop(self.jobs);
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Run jobs from the queue on conn until the pool is dropped. A connection
+/// that a job finds broken is replaced before the next job, if possible.
+fn work<C: Store>(
+    mut conn: C,
+    queue: Arc<Mutex<Receiver<Job<C>>>>,
+    connector: Option<Connector<C>>,
+) {
+    loop {
+        let job = match queue.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        job(&conn);
+        if let (true, Some(connector)) = (conn.broken(), &connector) {
+            match connector() {
+                Ok(new) => {
+                    info!("pool: reconnected to the database");
+                    conn = new;
+                }
+                Err(err) => warn!("pool: reconnect {}", err),
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Routing of read-only statements to the lowest-latency gateway in
+//! multi-region deployments.
+//!
+//! Any gateway serves consistent reads, so reads can go to whichever
+//! reachable gateway is closest while writes stay on the primary connection.
+//! With follower reads, reads are served slightly stale by the replicas
+//! nearest the gateway instead, through connections of their own.
+
+use postgres::Connection;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the latency to each gateway is measured.
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait for a gateway before considering it unreachable.
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Router<C = Connection> {
+    /// Addresses of the primary gateway followed by each read gateway.
+    addrs: Vec<String>,
+    /// Connections to the read gateways.
+    readers: Vec<C>,
+    /// Connection that reads sent to the primary gateway go through, if not
+    /// the primary connection.
+    primary_reader: Option<C>,
+    /// The most recently measured latency to the primary followed by each
+    /// read gateway, or None if it was unreachable.
+    latencies: Arc<Mutex<Vec<Option<Duration>>>>,
+}
+
+impl<C> Router<C> {
+    /// Route reads between the primary and the given read gateways, each
+    /// identified by a host:port address.
+    pub fn new(primary: String, readers: Vec<(String, C)>) -> Router<C> {
+        let mut addrs = vec![primary];
+        let mut conns = Vec::with_capacity(readers.len());
+        for (addr, conn) in readers {
+            addrs.push(addr);
+            conns.push(conn);
+        }
+        // Prefer the primary until latencies have been measured.
+        let mut initial = vec![None; addrs.len()];
+        initial[0] = Some(Duration::from_secs(0));
+        let latencies = Arc::new(Mutex::new(initial));
+        let shared = latencies.clone();
+        let pinged = addrs.clone();
+        thread::spawn(move || loop {
+            let measured = pinged.iter().map(|addr| ping(addr)).collect();
+            *shared.lock().unwrap() = measured;
+            thread::sleep(PING_INTERVAL);
+        });
+        Router {
+            addrs,
+            readers: conns,
+            primary_reader: None,
+            latencies,
+        }
+    }
+
+    /// Send reads that go to the primary gateway through conn.
+    pub fn with_primary_reader(mut self, conn: C) -> Router<C> {
+        self.primary_reader = Some(conn);
+        self
+    }
+
+    /// The address of the primary gateway followed by each read gateway, with
+    /// the latency last measured to it, or None if it was unreachable.
+    pub fn gateways(&self) -> Vec<(String, Option<Duration>)> {
+        let latencies = self.latencies.lock().unwrap();
+        self.addrs
+            .iter()
+            .cloned()
+            .zip(latencies.iter().copied())
+            .collect()
+    }
+
+    /// The connection to the lowest-latency reachable read gateway, or None
+    /// if reads should use the primary connection.
+    pub fn reader(&self) -> Option<&C> {
+        let latencies = self.latencies.lock().unwrap();
+        let best = latencies
+            .iter()
+            .enumerate()
+            .filter_map(|(i, l)| l.map(|l| (i, l)))
+            .min_by_key(|&(_, l)| l)
+            .map(|(i, _)| i);
+        match best {
+            None | Some(0) => self.primary_reader.as_ref(),
+            Some(i) => Some(&self.readers[i - 1]),
+        }
+    }
+}
+
+/// Measure how long it takes to open a TCP connection to addr.
+fn ping(addr: &str) -> Option<Duration> {
+    let sock_addr = addr.to_socket_addrs().ok()?.next()?;
+    let start = Instant::now();
+    TcpStream::connect_timeout(&sock_addr, PING_TIMEOUT).ok()?;
+    Some(start.elapsed())
+}
@@ -0,0 +1,457 @@
+//! A minimal S3-compatible gateway to the filesystem, for machines that
+//! cannot mount it. Each directory in the root is a bucket, and each regular
+//! file beneath one is an object keyed by its path within the bucket, so the
+//! gateway and mounts see the same files. ListBuckets, CreateBucket,
+//! HeadBucket, ListObjects (V1 and V2), GetObject, HeadObject and PutObject
+//! are served; everything else is answered with NotImplemented.
+//!
+//! Requests are not authenticated. Every client may read and write the whole
+//! filesystem as the gateway's owner, so it should only listen where that is
+//! acceptable.
+
+use super::error::CrfsError;
+use super::http::{self, escape, etag, http_time, iso_time, Body, Request, Response};
+use super::pool::Pool;
+use super::sql;
+use fuse::{FileAttr, FileType};
+use log::warn;
+use postgres::Connection;
+use std::fmt::Write as _;
+use std::io::{self, Read};
+use std::net::TcpListener;
+use std::sync::Arc;
+
+/// Most keys listed per response.
+const MAX_KEYS: usize = 1000;
+
+/// Directory entries read per query.
+const DIR_BATCH: i64 = 1000;
+
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// Serve requests accepted on listener from the pool's connections,
+/// creating buckets, directories and objects owned by owner.
+pub fn serve(listener: TcpListener, pool: Pool, owner: (u32, u32)) -> io::Result<()> {
+    http::serve(
+        listener,
+        pool,
+        Arc::new(move |conn, req, body| {
+            route(conn, req, body, owner).unwrap_or_else(|err| error_response(&err, &req.path))
+        }),
+    )
+}
+
+/// An S3 error, as answered to the client.
+struct S3Error {
+    status: u16,
+    code: &'static str,
+    message: String,
+}
+
+fn s3_error(status: u16, code: &'static str, message: &str) -> S3Error {
+    S3Error {
+        status,
+        code,
+        message: message.to_string(),
+    }
+}
+
+impl From<postgres::Error> for S3Error {
+    fn from(err: postgres::Error) -> S3Error {
+        S3Error::from(CrfsError::from(err))
+    }
+}
+
+impl From<CrfsError> for S3Error {
+    fn from(err: CrfsError) -> S3Error {
+        if let CrfsError::QuotaExceeded(_) = err {
+            return s3_error(403, "QuotaExceeded", "the write would go over a quota");
+        }
+        if let Some(err) = err.backend() {
+            // Errors reading the request's body reach here by way of the
+            // writes they interrupt.
+            if let Some(err) = err.as_io() {
+                return s3_error(400, "IncompleteBody", &err.to_string());
+            }
+            warn!("s3: {}", err);
+        }
+        s3_error(500, "InternalError", &err.to_string())
+    }
+}
+
+fn route(
+    conn: &Connection,
+    req: &Request,
+    body: &mut dyn Read,
+    owner: (u32, u32),
+) -> Result<Response, S3Error> {
+    let path = match req.path.split_first() {
+        Some((b'/', path)) => path,
+        _ => return Err(s3_error(400, "InvalidURI", "the path must be absolute")),
+    };
+    let (bucket, key) = match path.iter().position(|&b| b == b'/') {
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => (path, &b""[..]),
+    };
+    match (req.method.as_str(), bucket.is_empty(), key.is_empty()) {
+        ("GET", true, _) => list_buckets(conn, owner),
+        ("PUT", false, true) => create_bucket(conn, bucket, owner),
+        ("HEAD", false, true) => find_bucket(conn, bucket).map(|_| ok(Body::Empty)),
+        ("GET", false, true) => list_objects(conn, req, bucket),
+        ("GET", false, false) | ("HEAD", false, false) => get_object(conn, req, bucket, key),
+        ("PUT", false, false) => put_object(conn, req, body, bucket, key, owner),
+        _ => Err(s3_error(
+            501,
+            "NotImplemented",
+            "the gateway does not serve this request",
+        )),
+    }
+}
+
+fn ok(body: Body) -> Response {
+    Response::new(200, body)
+}
+
+fn list_buckets(conn: &Connection, owner: (u32, u32)) -> Result<Response, S3Error> {
+    let mut xml = format!(
+        "<ListAllMyBucketsResult xmlns=\"{}\"><Owner><ID>{}</ID></Owner><Buckets>",
+        XMLNS, owner.0
+    );
+    for (name, attr) in read_dir(conn, sql::root())? {
+        if attr.kind == FileType::Directory {
+            let _ = write!(
+                xml,
+                "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>",
+                escape(&String::from_utf8_lossy(&name)),
+                iso_time(attr.crtime)
+            );
+        }
+    }
+    xml.push_str("</Buckets></ListAllMyBucketsResult>");
+    Ok(ok(xml_body(xml)))
+}
+
+fn create_bucket(conn: &Connection, bucket: &[u8], owner: (u32, u32)) -> Result<Response, S3Error> {
+    valid_name(bucket)?;
+    if sql::lookup_dir_ent(conn, sql::root(), bucket)?.is_some() {
+        return Err(s3_error(
+            409,
+            "BucketAlreadyOwnedByYou",
+            "the bucket already exists",
+        ));
+    }
+    sql::create_inode(
+        conn,
+        sql::root(),
+        bucket,
+        FileType::Directory,
+        0o755,
+        0,
+        owner,
+    )?;
+    Ok(ok(Body::Empty))
+}
+
+fn find_bucket(conn: &Connection, bucket: &[u8]) -> Result<u64, S3Error> {
+    match sql::lookup_dir_ent(conn, sql::root(), bucket)? {
+        Some((attr, _)) if attr.kind == FileType::Directory => Ok(attr.ino),
+        _ => Err(s3_error(404, "NoSuchBucket", "the bucket does not exist")),
+    }
+}
+
+/// A key or common prefix in a listing.
+enum Listed {
+    Object(String, FileAttr),
+    Prefix(String),
+}
+
+impl Listed {
+    fn key(&self) -> &str {
+        match self {
+            Listed::Object(key, _) | Listed::Prefix(key) => key,
+        }
+    }
+}
+
+fn list_objects(conn: &Connection, req: &Request, bucket: &[u8]) -> Result<Response, S3Error> {
+    let dir = find_bucket(conn, bucket)?;
+    let v2 = req.param("list-type") == Some("2");
+    let prefix = req.param("prefix").unwrap_or("");
+    let delimiter = req.param("delimiter").unwrap_or("");
+    let max_keys = req
+        .param("max-keys")
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(MAX_KEYS)
+        .min(MAX_KEYS);
+    let after = if v2 {
+        req.param("continuation-token")
+            .or_else(|| req.param("start-after"))
+    } else {
+        req.param("marker")
+    }
+    .unwrap_or("");
+
+    // The walk starts at the deepest directory the prefix names in full, and
+    // only goes deeper when keys further down would not be grouped under a
+    // common prefix anyway.
+    let mut listed = Vec::new();
+    let start = match prefix.rfind('/') {
+        Some(idx) => lookup(conn, dir, &prefix.as_bytes()[..idx])?
+            .filter(|attr| attr.kind == FileType::Directory)
+            .map(|attr| (attr.ino, prefix[..=idx].to_string())),
+        None => Some((dir, String::new())),
+    };
+    let mut pending: Vec<(u64, String)> = start.into_iter().collect();
+    while let Some((ino, dir_key)) = pending.pop() {
+        for (name, attr) in read_dir(conn, ino)? {
+            if name.starts_with(http::UPLOAD_PREFIX.as_bytes()) {
+                continue;
+            }
+            let key = format!("{}{}", dir_key, String::from_utf8_lossy(&name));
+            match attr.kind {
+                FileType::Directory => {
+                    let key = key + "/";
+                    if !key.starts_with(prefix) && !prefix.starts_with(&key) {
+                        continue;
+                    }
+                    if delimiter == "/" {
+                        listed.push(Listed::Prefix(key));
+                    } else {
+                        pending.push((attr.ino, key));
+                    }
+                }
+                FileType::RegularFile if key.starts_with(prefix) => {
+                    let rest = &key[prefix.len()..];
+                    match rest.find(delimiter).filter(|_| !delimiter.is_empty()) {
+                        Some(idx) => listed.push(Listed::Prefix(
+                            key[..prefix.len() + idx + delimiter.len()].to_string(),
+                        )),
+                        None => listed.push(Listed::Object(key, attr)),
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    listed.sort_by(|a, b| a.key().cmp(b.key()));
+    listed.dedup_by(|a, b| a.key() == b.key());
+    listed.retain(|l| l.key() > after);
+    let truncated = listed.len() > max_keys;
+    listed.truncate(max_keys);
+
+    let mut xml = format!(
+        "<ListBucketResult xmlns=\"{}\"><Name>{}</Name><Prefix>{}</Prefix><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+        XMLNS,
+        escape(&String::from_utf8_lossy(bucket)),
+        escape(prefix),
+        max_keys,
+        truncated
+    );
+    if !delimiter.is_empty() {
+        let _ = write!(xml, "<Delimiter>{}</Delimiter>", escape(delimiter));
+    }
+    if v2 {
+        let _ = write!(xml, "<KeyCount>{}</KeyCount>", listed.len());
+    } else {
+        let _ = write!(xml, "<Marker>{}</Marker>", escape(after));
+    }
+    if let (true, Some(last)) = (truncated, listed.last()) {
+        let tag = if v2 {
+            "NextContinuationToken"
+        } else {
+            "NextMarker"
+        };
+        let _ = write!(xml, "<{0}>{1}</{0}>", tag, escape(last.key()));
+    }
+    for l in &listed {
+        match l {
+            Listed::Object(key, attr) => {
+                let _ = write!(
+                    xml,
+                    "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                    escape(key),
+                    iso_time(attr.mtime),
+                    escape(&etag(attr)),
+                    attr.size
+                );
+            }
+            Listed::Prefix(prefix) => {
+                let _ = write!(
+                    xml,
+                    "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                    escape(prefix)
+                );
+            }
+        }
+    }
+    xml.push_str("</ListBucketResult>");
+    Ok(ok(xml_body(xml)))
+}
+
+fn get_object(
+    conn: &Connection,
+    req: &Request,
+    bucket: &[u8],
+    key: &[u8],
+) -> Result<Response, S3Error> {
+    let dir = find_bucket(conn, bucket)?;
+    let attr = match lookup(conn, dir, key)? {
+        Some(attr) if attr.kind == FileType::RegularFile => attr,
+        _ => return Err(s3_error(404, "NoSuchKey", "the key does not exist")),
+    };
+    let mut res = ok(Body::File {
+        ino: attr.ino,
+        start: 0,
+        len: attr.size,
+    });
+    if let Some(range) = req.header("range") {
+        let (start, len) = http::parse_range(range, attr.size)
+            .ok_or_else(|| s3_error(416, "InvalidRange", "the range cannot be satisfied"))?;
+        res.status = 206;
+        res.headers.push((
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, start + len - 1, attr.size),
+        ));
+        res.body = Body::File {
+            ino: attr.ino,
+            start,
+            len,
+        };
+    }
+    res.headers
+        .push(("Content-Type", "application/octet-stream".to_string()));
+    res.headers.push(("Last-Modified", http_time(attr.mtime)));
+    res.headers.push(("ETag", etag(&attr)));
+    res.headers.push(("Accept-Ranges", "bytes".to_string()));
+    Ok(res)
+}
+
+/// Store the request's body as key, creating the directories above it. The
+/// body is written to a temporary file that then replaces the key, so that
+/// readers never see a partial object. A key ending in / is made a directory.
+fn put_object(
+    conn: &Connection,
+    req: &Request,
+    body: &mut dyn Read,
+    bucket: &[u8],
+    key: &[u8],
+    owner: (u32, u32),
+) -> Result<Response, S3Error> {
+    if req.header("x-amz-copy-source").is_some() {
+        return Err(s3_error(501, "NotImplemented", "objects cannot be copied"));
+    }
+    if !http::framed(req) {
+        return Err(s3_error(
+            411,
+            "MissingContentLength",
+            "the length is required",
+        ));
+    }
+    let mut dir = find_bucket(conn, bucket)?;
+    let is_dir = key.ends_with(b"/");
+    let key = key.strip_suffix(b"/").unwrap_or(key);
+    let names: Vec<&[u8]> = key.split(|&b| b == b'/').collect();
+    let (name, parents) = names.split_last().unwrap();
+    for parent in parents {
+        dir = make_dir(conn, dir, parent, owner)?;
+    }
+    if is_dir {
+        make_dir(conn, dir, name, owner)?;
+        return Ok(ok(Body::Empty));
+    }
+    valid_name(name)?;
+
+    match http::put_file(conn, dir, name, owner, body) {
+        Ok(()) => {}
+        Err(CrfsError::NotFound) | Err(CrfsError::Stale) => {
+            return Err(s3_error(500, "InternalError", "the upload was removed"))
+        }
+        Err(CrfsError::IsDir)
+        | Err(CrfsError::NotDir)
+        | Err(CrfsError::NotEmpty)
+        | Err(CrfsError::CrossQuota) => {
+            return Err(s3_error(409, "InvalidRequest", "a directory has the key"))
+        }
+        Err(err) => return Err(err.into()),
+    }
+    let attr = lookup(conn, dir, name)?
+        .ok_or_else(|| s3_error(500, "InternalError", "the object was removed"))?;
+    let mut res = ok(Body::Empty);
+    res.headers.push(("ETag", etag(&attr)));
+    Ok(res)
+}
+
+/// The directory name in dir, creating it if it does not exist.
+fn make_dir(conn: &Connection, dir: u64, name: &[u8], owner: (u32, u32)) -> Result<u64, S3Error> {
+    valid_name(name)?;
+    match sql::lookup_dir_ent(conn, dir, name)? {
+        Some((attr, _)) if attr.kind == FileType::Directory => Ok(attr.ino),
+        Some(_) => Err(s3_error(
+            409,
+            "InvalidRequest",
+            "an object is in the way of the key",
+        )),
+        None => Ok(
+            sql::create_inode(conn, dir, name, FileType::Directory, 0o755, 0, owner)?
+                .0
+                .ino,
+        ),
+    }
+}
+
+fn valid_name(name: &[u8]) -> Result<(), S3Error> {
+    match name {
+        b"" | b"." | b".." => Err(s3_error(400, "InvalidArgument", "invalid key")),
+        _ => Ok(()),
+    }
+}
+
+/// Look up key, a path relative to directory dir.
+fn lookup(conn: &Connection, dir: u64, key: &[u8]) -> Result<Option<FileAttr>, S3Error> {
+    let mut attr = None;
+    let mut ino = dir;
+    for name in key.split(|&b| b == b'/') {
+        if valid_name(name).is_err() {
+            return Ok(None);
+        }
+        attr = sql::lookup_dir_ent(conn, ino, name)?.map(|(attr, _)| attr);
+        match attr {
+            Some(ref a) => ino = a.ino,
+            None => return Ok(None),
+        }
+    }
+    Ok(attr)
+}
+
+/// Every entry of directory ino.
+fn read_dir(conn: &Connection, ino: u64) -> Result<Vec<(Vec<u8>, FileAttr)>, S3Error> {
+    let mut ents = Vec::new();
+    let mut after = Vec::new();
+    loop {
+        let batch = sql::read_dir_plus(conn, ino, &after, DIR_BATCH)?;
+        let full = batch.len() as i64 == DIR_BATCH;
+        ents.extend(batch.into_iter().map(|(ent, attr)| (ent.child_name, attr)));
+        match ents.last() {
+            Some((last, _)) if full => after = last.clone(),
+            _ => return Ok(ents),
+        }
+    }
+}
+
+fn error_response(err: &S3Error, resource: &[u8]) -> Response {
+    Response::new(
+        err.status,
+        xml_body(format!(
+            "<Error><Code>{}</Code><Message>{}</Message><Resource>{}</Resource></Error>",
+            err.code,
+            escape(&err.message),
+            escape(&String::from_utf8_lossy(resource))
+        )),
+    )
+}
+
+const XML_DECL: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+
+fn xml_body(xml: String) -> Body {
+    Body::Text("application/xml", format!("{}{}", XML_DECL, xml))
+}
@@ -0,0 +1,160 @@
+//! Scrubbing of stored data: every block is checked against its checksum and
+//! its inode, and every inode's block count against its blocks. A scrub
+//! walks the filesystem in batches and records how far it has got after
+//! each one, so that an interrupted scrub resumes where it left off, and
+//! runs at low priority so as to give way to the filesystem's own traffic.
+
+use super::sql;
+use log::{info, warn};
+use postgres::{Connection, GenericConnection, Result};
+use std::thread;
+use std::time::Duration;
+
+/// Blocks or inodes checked per batch, and the pause between batches, when
+/// scrubbing in the background.
+const BACKGROUND_BATCH: i64 = 100;
+const BACKGROUND_PAUSE: Duration = Duration::from_millis(100);
+
+/// Blocks or inodes checked per batch when scrubbing in the foreground.
+pub const BATCH: i64 = 1000;
+
+/// What a scrub found, and what it repaired.
+#[derive(Default)]
+pub struct Report {
+    pub blocks: u64,
+    pub inodes: u64,
+    /// Blocks whose bytes no longer match their checksum.
+    pub corrupt: u64,
+    /// Blocks of inodes that do not exist.
+    pub orphaned: u64,
+    /// Blocks that lie beyond the end of their file.
+    pub past_eof: u64,
+    /// Inodes whose block count disagrees with their blocks.
+    pub miscounted: u64,
+    pub repaired: u64,
+}
+
+impl Report {
+    /// The number of problems found.
+    pub fn problems(&self) -> u64 {
+        self.corrupt + self.orphaned + self.past_eof + self.miscounted
+    }
+}
+
+/// Scrub the filesystem, batch blocks or inodes at a time with a pause
+/// between batches, resuming the scrub under way if there is one. Blocks
+/// past the end of their file or without an inode are deleted, and block
+/// counts corrected, if repair is set; corrupt blocks can only be reported.
+pub fn scrub<C: GenericConnection>(
+    conn: &C,
+    batch: i64,
+    pause: Duration,
+    repair: bool,
+) -> Result<Report> {
+    let mut report = Report::default();
+    let mut progress = sql::scrub_progress(conn)?;
+    if progress.block != (0, -1) || progress.ino.is_some() {
+        info!(
+            "scrub: resuming the scrub started at {}",
+            time::at_utc(progress.started).rfc3339()
+        );
+    }
+
+    while progress.ino.is_none() {
+        let blocks = sql::scrub_blocks(conn, progress.block, batch)?;
+        let mut unreachable = Vec::new();
+        for block in &blocks {
+            let at = (block.file_ino, block.block_idx);
+            if !block.intact {
+                warn!(
+                    "scrub: block {} of inode {} does not match its checksum",
+                    block.block_idx, block.file_ino
+                );
+                report.corrupt += 1;
+            }
+            match block.size {
+                None => {
+                    report.orphaned += 1;
+                    unreachable.push(at);
+                }
+                Some(size) if block.block_idx * sql::block_size() >= size => {
+                    report.past_eof += 1;
+                    unreachable.push(at);
+                }
+                Some(_) => {}
+            }
+        }
+        report.blocks += blocks.len() as u64;
+        if repair && !unreachable.is_empty() {
+            report.repaired += sql::delete_unreachable_blocks(conn, &unreachable)?;
+        }
+        match blocks.last() {
+            Some(last) if blocks.len() as i64 == batch => {
+                progress.block = (last.file_ino, last.block_idx);
+                sql::save_scrub_progress(conn, &progress)?;
+                thread::sleep(pause);
+            }
+            _ => {
+                progress.ino = Some(0);
+                sql::save_scrub_progress(conn, &progress)?;
+            }
+        }
+    }
+
+    while let Some(after) = progress.ino {
+        let counts = sql::scrub_block_counts(conn, after, batch)?;
+        for &(ino, recorded, actual) in &counts {
+            if recorded != actual {
+                warn!(
+                    "scrub: inode {} records {} blocks but has {}",
+                    ino, recorded, actual
+                );
+                report.miscounted += 1;
+                if repair {
+                    sql::fix_block_count(conn, ino)?;
+                    report.repaired += 1;
+                }
+            }
+        }
+        report.inodes += counts.len() as u64;
+        match counts.last() {
+            Some(&(last, _, _)) if counts.len() as i64 == batch => {
+                progress.ino = Some(last);
+                sql::save_scrub_progress(conn, &progress)?;
+                thread::sleep(pause);
+            }
+            _ => break,
+        }
+    }
+    sql::finish_scrub(conn)?;
+    Ok(report)
+}
+
+/// Scrub the filesystem on conn every interval from a background thread,
+/// reporting rather than repairing what it finds.
+pub fn run_in_background(conn: Connection, interval: Duration) {
+    thread::spawn(move || {
+        if let Err(err) = sql::use_low_priority(&conn) {
+            warn!("scrub {}", err);
+        }
+        loop {
+            thread::sleep(interval);
+            match scrub(&conn, BACKGROUND_BATCH, BACKGROUND_PAUSE, false) {
+                Err(err) => warn!("scrub {}", err),
+                Ok(report) if report.problems() == 0 => info!(
+                    "scrub checked {} blocks and {} inodes and found no problems",
+                    report.blocks, report.inodes
+                ),
+                Ok(report) => warn!(
+                    "scrub checked {} blocks and {} inodes and found {} corrupt blocks, {} orphaned blocks, {} blocks past the end of their file and {} wrong block counts",
+                    report.blocks,
+                    report.inodes,
+                    report.corrupt,
+                    report.orphaned,
+                    report.past_eof,
+                    report.miscounted
+                ),
+            }
+        }
+    });
+}
@@ -1,10 +1,23 @@
-use fuse::{FileAttr, FileType};
+use super::error::CrfsError;
+use super::trace;
+use fuse::{FileAttr, FileType, FUSE_ROOT_ID};
+use libc::{F_RDLCK, F_UNLCK, F_WRLCK};
+use log::debug;
+use postgres::error;
 use postgres::rows::Row;
-use postgres::{GenericConnection, Result};
+use postgres::types::ToSql;
+use postgres::{Error, GenericConnection, Result};
+use std::cell::Cell;
 use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::panic::Location;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use time::Timespec;
 
-const SCHEMAS: &[&str] = &[
+pub const SCHEMAS: &[&str] = &[
     "CREATE SEQUENCE IF NOT EXISTS inode_alloc",
     "CREATE TABLE IF NOT EXISTS inodes (
         -- Inode number
@@ -51,404 +64,3210 @@ const SCHEMAS: &[&str] = &[
     )",
 ];
 
-const DATA_BLOCK_SIZE: i64 = 8 << 10 /* 8KB */;
+/// Tables created by SCHEMAS.
+pub const TABLES: &[&str] = &["inodes", "dir_entries", "blocks"];
+
+/// Attempts made at an operation before a retryable error is returned.
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Delay before the first retry, doubled for each one after it up to
+/// MAX_BACKOFF.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Number of retries since last taken by take_retries.
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Whether this thread is inside with_retry.
+    static RETRYING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Block size of filesystems created without choosing one.
+pub const DEFAULT_BLOCK_SIZE: i64 = 8 << 10 /* 8KB */;
+
+/// Longest file name accepted by filesystems created without choosing a
+/// limit.
+pub const DEFAULT_NAME_MAX: i64 = 255;
+
+/// Block size of the mounted filesystem, loaded by load_block_size.
+static BLOCK_SIZE: AtomicI64 = AtomicI64::new(DEFAULT_BLOCK_SIZE);
+
+/// Size of the blocks that file data is stored in.
+pub fn block_size() -> i64 {
+    BLOCK_SIZE.load(Ordering::Relaxed)
+}
+
+/// Load the block size the filesystem was created with, recording requested
+/// as its block size first if the filesystem has none yet.
+pub fn load_block_size<C: GenericConnection>(conn: &C, requested: i64) -> Result<i64> {
+    let size = load_setting(conn, "block_size", requested)?;
+    BLOCK_SIZE.store(size, Ordering::Relaxed);
+    Ok(size)
+}
+
+/// Load the longest file name the filesystem accepts, recording requested as
+/// its limit first if the filesystem has none yet.
+pub fn load_name_max<C: GenericConnection>(conn: &C, requested: i64) -> Result<i64> {
+    load_setting(conn, "name_max", requested)
+}
+
+/// Load the filesystem's id, which tells its NFS file handles apart from
+/// those of other filesystems, choosing one first if it has none yet.
+pub fn load_fsid<C: GenericConnection>(conn: &C) -> Result<u64> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as i64;
+    load_setting(conn, "fsid", nanos ^ (i64::from(process::id()) << 32)).map(|id| id as u64)
+}
+
+/// Load a setting fixed when the filesystem is created, recording requested
+/// as its value first if the filesystem has none yet.
+fn load_setting<C: GenericConnection>(conn: &C, key: &str, requested: i64) -> Result<i64> {
+    with_retry(|| {
+        conn.execute(
+            "INSERT INTO fs_meta (key, value) VALUES ($1, $2)
+             ON CONFLICT (key) DO NOTHING",
+            &[&key, &requested],
+        )?;
+        conn.query("SELECT value FROM fs_meta WHERE key = $1", &[&key])
+            .map(|rows| rows.get(0).get(0))
+    })
+}
+
+/// Name of the filesystem every database holds, whose root is FUSE_ROOT_ID.
+pub const DEFAULT_FS: &str = "default";
+
+/// Id and root directory of the filesystem selected by use_filesystem.
+static FS_ID: AtomicI64 = AtomicI64::new(0);
+static ROOT: AtomicU64 = AtomicU64::new(FUSE_ROOT_ID);
+
+/// Id of the selected filesystem, recorded with each inode created.
+pub fn fs_id() -> i64 {
+    FS_ID.load(Ordering::Relaxed)
+}
+
+/// Root directory of the selected filesystem.
+pub fn root() -> u64 {
+    ROOT.load(Ordering::Relaxed)
+}
+
+/// Select the filesystem named name as the one whose root is served and
+/// whose id new inodes are recorded with. Returns whether there is one.
+pub fn use_filesystem<C: GenericConnection>(conn: &C, name: &str) -> Result<bool> {
+    if name == DEFAULT_FS {
+        // The default filesystem predates the registry.
+        FS_ID.store(0, Ordering::Relaxed);
+        ROOT.store(FUSE_ROOT_ID, Ordering::Relaxed);
+        return Ok(true);
+    }
+    let rows = match conn.query(
+        "SELECT fs_id, root_ino FROM filesystems WHERE name = $1",
+        &[&name],
+    ) {
+        Err(ref err) if err.code() == Some(&error::UNDEFINED_TABLE) => return Ok(false),
+        res => res?,
+    };
+    if rows.is_empty() {
+        return Ok(false);
+    }
+    let row = rows.get(0);
+    FS_ID.store(row.get(0), Ordering::Relaxed);
+    ROOT.store(row.get::<_, i64>(1) as u64, Ordering::Relaxed);
+    Ok(true)
+}
+
+/// Root directories of every filesystem in the database.
+pub fn roots<C: GenericConnection>(conn: &C) -> Result<Vec<i64>> {
+    match conn.query("SELECT root_ino FROM filesystems", &[]) {
+        Err(ref err) if err.code() == Some(&error::UNDEFINED_TABLE) => {
+            Ok(vec![FUSE_ROOT_ID as i64])
+        }
+        res => Ok(res?.iter().map(|row| row.get(0)).collect()),
+    }
+}
+
+/// Create a filesystem named name, with an empty root directory owned by uid
+/// and gid. Returns its id, or None if there already is one of that name.
+pub fn create_filesystem<C: GenericConnection>(
+    conn: &C,
+    name: &str,
+    uid: u32,
+    gid: u32,
+) -> Result<Option<i64>> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let row = txn.query(
+            "INSERT INTO inodes (kind, uid, gid, fs_id)
+             VALUES ($1, $2, $3, unique_rowid())
+             RETURNING ino, fs_id",
+            &[
+                &file_type_to_str(FileType::Directory),
+                &(uid as i32),
+                &(gid as i32),
+            ],
+        )?;
+        let (root, fs_id): (i64, i64) = (row.get(0).get(0), row.get(0).get(1));
+        match txn.execute(
+            "INSERT INTO filesystems (fs_id, name, root_ino) VALUES ($1, $2, $3)",
+            &[&fs_id, &name, &root],
+        ) {
+            Err(ref err) if err.code() == Some(&error::UNIQUE_VIOLATION) => return Ok(None),
+            res => res?,
+        };
+        charge_user(&txn, uid, 0, 1)?;
+        txn.commit()?;
+        Ok(Some(fs_id))
+    })
+}
+
+/// A filesystem in the database, as listed by list_filesystems.
+pub struct Filesystem {
+    pub name: String,
+    pub fs_id: i64,
+    pub root: u64,
+    pub created: Timespec,
+    /// Number of inodes
+    pub inodes: u64,
+    /// Bytes stored in its files
+    pub bytes: u64,
+}
+
+/// Every filesystem in the database, by name.
+pub fn list_filesystems<C: GenericConnection>(conn: &C) -> Result<Vec<Filesystem>> {
+    with_retry(|| {
+        let rows = conn.query(
+            "SELECT f.name, f.fs_id, f.root_ino, f.created,
+                    count(i.ino), IFNULL(sum(i.size), 0)::INT8
+             FROM filesystems f LEFT JOIN inodes i ON i.fs_id = f.fs_id
+             GROUP BY f.name, f.fs_id, f.root_ino, f.created
+             ORDER BY f.name",
+            &[],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|row| Filesystem {
+                name: row.get(0),
+                fs_id: row.get(1),
+                root: row.get::<_, i64>(2) as u64,
+                created: row.get(3),
+                inodes: row.get::<_, i64>(4) as u64,
+                bytes: row.get::<_, i64>(5) as u64,
+            })
+            .collect())
+    })
+}
+
+/// Remove the filesystem named name from the registry, so that nothing of it
+/// is reachable any longer, and return its id, or None if there is none of
+/// that name. Its inodes are left to unlink_filesystem and gc.
+pub fn drop_filesystem<C: GenericConnection>(conn: &C, name: &str) -> Result<Option<i64>> {
+    with_retry(|| {
+        conn.query(
+            "DELETE FROM filesystems WHERE name = $1 RETURNING fs_id",
+            &[&name],
+        )
+        .map(|rows| rows.iter().next().map(|row| row.get(0)))
+    })
+}
+
+/// Delete up to limit directory entries of the dropped filesystem fs_id,
+/// leaving its inodes orphaned for gc to delete. Returns the number deleted.
+pub fn unlink_filesystem<C: GenericConnection>(conn: &C, fs_id: i64, limit: i64) -> Result<u64> {
+    with_retry(|| {
+        conn.execute(
+            "DELETE FROM dir_entries
+             WHERE dir_ino IN (SELECT ino FROM inodes WHERE fs_id = $1)
+             LIMIT $2",
+            &[&fs_id, &limit],
+        )
+    })
+}
+
+/// Whether writes keep what they overwrite, set by set_versioning.
+static VERSIONING: AtomicBool = AtomicBool::new(false);
+
+/// Keep the prior contents of a file as a new version whenever it is written
+/// or truncated.
+pub fn set_versioning(on: bool) {
+    VERSIONING.store(on, Ordering::Relaxed);
+}
+
+fn versioning() -> bool {
+    VERSIONING.load(Ordering::Relaxed)
+}
+
+/// Whether blocks are written as shared chunks, set by set_dedup.
+static DEDUP: AtomicBool = AtomicBool::new(false);
+
+/// Store the blocks written from now on in chunk_store, once for each
+/// distinct content, instead of in the blocks themselves. Blocks written
+/// either way can be read, whatever this is set to.
+pub fn set_dedup(on: bool) {
+    DEDUP.store(on, Ordering::Relaxed);
+}
+
+fn dedup() -> bool {
+    DEDUP.load(Ordering::Relaxed)
+}
+
+/// Classes of operations that may be allowed to read stale data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadClass {
+    Lookup,
+    Getattr,
+    Readdir,
+    Readlink,
+    Read,
+    Xattr,
+    Statfs,
+}
+
+impl ReadClass {
+    const ALL: [ReadClass; 7] = [
+        ReadClass::Lookup,
+        ReadClass::Getattr,
+        ReadClass::Readdir,
+        ReadClass::Readlink,
+        ReadClass::Read,
+        ReadClass::Xattr,
+        ReadClass::Statfs,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            ReadClass::Lookup => "lookup",
+            ReadClass::Getattr => "getattr",
+            ReadClass::Readdir => "readdir",
+            ReadClass::Readlink => "readlink",
+            ReadClass::Read => "read",
+            ReadClass::Xattr => "xattr",
+            ReadClass::Statfs => "statfs",
+        }
+    }
+}
+
+impl std::str::FromStr for ReadClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ReadClass, String> {
+        ReadClass::ALL
+            .iter()
+            .find(|class| class.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<&str> = ReadClass::ALL.iter().map(|class| class.name()).collect();
+                format!(
+                    "unknown operation class {:?}, expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// How stale each class of read may be, in milliseconds, set by
+/// set_staleness. Reads of a class set to 0 are always fresh.
+static STALENESS: [AtomicU64; 7] = [const { AtomicU64::new(0) }; 7];
+
+thread_local! {
+    /// The class of operation this thread is serving, if it only reads.
+    static READ_CLASS: Cell<Option<ReadClass>> = const { Cell::new(None) };
+}
+
+/// Allow reads made for operations of class to be served as of up to
+/// staleness in the past.
+pub fn set_staleness(class: ReadClass, staleness: Duration) {
+    STALENESS[class as usize].store(staleness.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Marks the current thread as serving an operation of a class until it is
+/// dropped.
+pub struct ReadClassGuard {
+    outer: Option<ReadClass>,
+}
+
+/// Mark the current thread as serving an operation of class, whose reads
+/// follow the staleness set for it.
+pub fn read_class(class: ReadClass) -> ReadClassGuard {
+    ReadClassGuard {
+        outer: READ_CLASS.with(|current| current.replace(Some(class))),
+    }
+}
+
+impl Drop for ReadClassGuard {
+    fn drop(&mut self) {
+        READ_CLASS.with(|current| current.set(self.outer));
+    }
+}
+
+/// Run read, which only reads, with retries. Reads for an operation whose
+/// class may be served stale run in a transaction as of that far in the
+/// past, which the closest replica can serve; the rest read the present.
+#[track_caller]
+fn read_only<C, T, F>(conn: &C, mut read: F) -> Result<T>
+where
+    C: GenericConnection,
+    F: FnMut(&dyn GenericConnection) -> Result<T>,
+{
+    let staleness = match READ_CLASS.with(|current| current.get()) {
+        Some(class) => STALENESS[class as usize].load(Ordering::Relaxed),
+        None => 0,
+    };
+    if staleness == 0 {
+        return with_retry(|| read(conn));
+    }
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        txn.batch_execute(&format!(
+            "SET TRANSACTION AS OF SYSTEM TIME '-{}ms'",
+            staleness
+        ))?;
+        let res = read(&txn)?;
+        txn.commit()?;
+        Ok(res)
+    })
+}
+
+/// A byte-range lock. The range is inclusive at both ends.
+#[derive(Debug)]
+pub struct Lock {
+    /// Mount session holding the lock
+    pub session: String,
+    pub start: u64,
+    pub end: u64,
+    pub typ: u32,
+    pub pid: u32,
+}
+
+/// A prior version of a file's contents.
+#[derive(Debug)]
+pub struct FileVersion {
+    pub version: i64,
+    pub size: u64,
+    pub mtime: Timespec,
+    /// When the version was replaced
+    pub saved: Timespec,
+}
 
 #[derive(Debug)]
 pub struct DirEntry {
-    pub dir_ino: u64,
     pub child_ino: u64,
     pub child_kind: FileType,
-    pub child_name: String,
-}
-
-pub fn create_schema<C: GenericConnection>(conn: &C) -> Result<()> {
-    for table in SCHEMAS {
-        conn.execute(table, &[]).map(|_| ())?;
-    }
-    Ok(())
+    pub child_name: Vec<u8>,
 }
 
+/// Create an inode of kind ft with permissions perm, owned by owner's uid and
+/// gid, and link it into parent as name.
 pub fn create_inode<C: GenericConnection>(
     conn: &C,
     parent: u64,
-    name: &str,
+    name: &[u8],
     ft: FileType,
+    perm: u16,
     rdev: u32,
-) -> Result<FileAttr> {
-    let kind_str = file_type_to_str(ft);
-    let txn = conn.transaction()?;
-    let attr = txn
-        .query(
-            "INSERT INTO inodes (kind, rdev)
-             VALUES ($1, $2)
-             RETURNING *",
-            &[&kind_str, &(rdev as i32)],
-        )
-        .map(|rows| row_to_file_attr(rows.get(0)))?;
-    if parent != 0 {
+    owner: (u32, u32),
+) -> std::result::Result<(FileAttr, u64), CrfsError> {
+    Ok(with_retry(|| {
+        let kind_str = file_type_to_str(ft);
+        let (uid, gid) = owner;
+        let txn = conn.transaction()?;
+        let quota = if parent != 0 {
+            dir_quota(&txn, parent)?
+        } else {
+            None
+        };
+        let (attr, generation) = txn
+            .query(
+                "INSERT INTO inodes (kind, perm, rdev, uid, gid, quota_ino, fs_id)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)
+                 RETURNING *",
+                &[
+                    &kind_str,
+                    &(perm as i16),
+                    &(rdev as i32),
+                    &(uid as i32),
+                    &(gid as i32),
+                    &quota,
+                    &fs_id(),
+                ],
+            )
+            .map(|rows| {
+                let row = rows.get(0);
+                let generation = row.get::<_, i64>("generation") as u64;
+                (row_to_file_attr(row), generation)
+            })?;
+        charge_quota(&txn, quota, 0, 1)?;
+        charge_user(&txn, uid, 0, 1)?;
+        if parent != 0 {
+            txn.execute(
+                "INSERT INTO dir_entries (dir_ino, child_name, child_kind, child_ino)
+                 VALUES ($1, $2, $3, $4)",
+                &[&(parent as i64), &name, &kind_str, &(attr.ino as i64)],
+            )?;
+            touch_dir(&txn, parent)?;
+        }
+        txn.commit()?;
+        Ok((attr, generation))
+    })?)
+}
+
+/// Create the root directory of the default filesystem at FUSE_ROOT_ID,
+/// unless it already exists. Returns whether it was created.
+pub fn create_root<C: GenericConnection>(conn: &C, uid: u32, gid: u32) -> Result<bool> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let created = txn.execute(
+            "INSERT INTO inodes (ino, kind, uid, gid) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (ino) DO NOTHING",
+            &[
+                &(FUSE_ROOT_ID as i64),
+                &file_type_to_str(FileType::Directory),
+                &(uid as i32),
+                &(gid as i32),
+            ],
+        )? == 1;
+        if created {
+            charge_user(&txn, uid, 0, 1)?;
+            // The root's inode number was not handed out by the allocator, so
+            // make sure the allocator never hands it out.
+            txn.query(
+                "SELECT setval('inode_alloc', greatest(
+                     (SELECT max(ino) FROM inodes),
+                     (SELECT last_value FROM inode_alloc)
+                 ))",
+                &[],
+            )?;
+        }
+        txn.commit()?;
+        Ok(created)
+    })
+}
+
+pub fn create_symlink<C: GenericConnection>(
+    conn: &C,
+    parent: u64,
+    name: &[u8],
+    target: &str,
+    uid: u32,
+    gid: u32,
+) -> std::result::Result<(FileAttr, u64), CrfsError> {
+    Ok(with_retry(|| {
+        let kind_str = file_type_to_str(FileType::Symlink);
+        let txn = conn.transaction()?;
+        let quota = dir_quota(&txn, parent)?;
+        let (attr, generation) = txn
+            .query(
+                "INSERT INTO inodes (kind, size, perm, uid, gid, target, quota_ino, fs_id)
+                 VALUES ($1, $2, 511, $3, $4, $5, $6, $7)
+                 RETURNING *",
+                &[
+                    &kind_str,
+                    &(target.len() as i64),
+                    &(uid as i32),
+                    &(gid as i32),
+                    &target,
+                    &quota,
+                    &fs_id(),
+                ],
+            )
+            .map(|rows| {
+                let row = rows.get(0);
+                let generation = row.get::<_, i64>("generation") as u64;
+                (row_to_file_attr(row), generation)
+            })?;
+        charge_quota(&txn, quota, target.len() as i64, 1)?;
+        charge_user(&txn, uid, target.len() as i64, 1)?;
         txn.execute(
-            "INSERT INTO dir_entries
+            "INSERT INTO dir_entries (dir_ino, child_name, child_kind, child_ino)
              VALUES ($1, $2, $3, $4)",
             &[&(parent as i64), &name, &kind_str, &(attr.ino as i64)],
         )?;
+        touch_dir(&txn, parent)?;
+        txn.commit()?;
+        Ok((attr, generation))
+    })?)
+}
+
+/// An inode to be created by create_inodes.
+pub struct NewInode {
+    pub name: Vec<u8>,
+    pub kind: FileType,
+    pub perm: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub rdev: u32,
+    pub mtime: Timespec,
+    /// Target of a symlink.
+    pub target: Option<String>,
+}
+
+/// Create inodes and link them into directory parent in one transaction of
+/// a few statements, for bulk loading. Regular files are created with the
+/// number of blocks their size takes, which are then written with
+/// write_new_blocks. Returns the inode numbers, in the order given.
+pub fn create_inodes<C: GenericConnection>(
+    conn: &C,
+    parent: u64,
+    inodes: &[NewInode],
+) -> Result<Vec<u64>> {
+    if inodes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let block_size = block_size();
+    let kinds: Vec<&str> = inodes.iter().map(|i| file_type_to_str(i.kind)).collect();
+    let sizes: Vec<i64> = inodes.iter().map(|i| i.size as i64).collect();
+    let blocks: Vec<i64> = inodes
+        .iter()
+        .map(|i| match i.kind {
+            FileType::RegularFile => (i.size as i64 + block_size - 1) / block_size,
+            _ => 0,
+        })
+        .collect();
+    let perms: Vec<i16> = inodes.iter().map(|i| i.perm as i16).collect();
+    let rdevs: Vec<i32> = inodes.iter().map(|i| i.rdev as i32).collect();
+    let uids: Vec<i32> = inodes.iter().map(|i| i.uid as i32).collect();
+    let gids: Vec<i32> = inodes.iter().map(|i| i.gid as i32).collect();
+    let targets: Vec<Option<&str>> = inodes.iter().map(|i| i.target.as_deref()).collect();
+    let mtimes: Vec<Timespec> = inodes.iter().map(|i| i.mtime).collect();
+    let names: Vec<&[u8]> = inodes.iter().map(|i| &i.name[..]).collect();
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let quota = dir_quota(&txn, parent)?;
+        let inos: Vec<i64> = txn
+            .query(
+                "SELECT nextval('inode_alloc') FROM generate_series(1, $1)",
+                &[&(inodes.len() as i64)],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        txn.execute(
+            "INSERT INTO inodes
+                 (ino, kind, size, blocks, perm, rdev, uid, gid, target, atime, mtime, quota_ino,
+                  fs_id)
+             SELECT ino, kind, size, blocks, perm, rdev, uid, gid, target, mtime, mtime, $11, $12
+             FROM unnest($1::INT8[], $2::STRING[], $3::INT8[], $4::INT8[], $5::INT2[],
+                         $6::INT4[], $7::INT4[], $8::INT4[], $9::STRING[], $10::TIMESTAMP[])
+                 AS u (ino, kind, size, blocks, perm, rdev, uid, gid, target, mtime)",
+            &[
+                &inos,
+                &kinds,
+                &sizes,
+                &blocks,
+                &perms,
+                &rdevs,
+                &uids,
+                &gids,
+                &targets,
+                &mtimes,
+                &quota,
+                &fs_id(),
+            ],
+        )?;
+        txn.execute(
+            "INSERT INTO dir_entries (dir_ino, child_name, child_kind, child_ino)
+             SELECT $1, name, kind, ino
+             FROM unnest($2::BYTES[], $3::STRING[], $4::INT8[]) AS u (name, kind, ino)",
+            &[&(parent as i64), &names, &kinds, &inos],
+        )?;
+        charge_quota(&txn, quota, sizes.iter().sum(), inodes.len() as i64)?;
+        let mut by_uid: Vec<(u32, i64, i64)> = Vec::new();
+        for inode in inodes {
+            match by_uid.iter_mut().find(|(uid, _, _)| *uid == inode.uid) {
+                Some((_, bytes, count)) => {
+                    *bytes += inode.size as i64;
+                    *count += 1;
+                }
+                None => by_uid.push((inode.uid, inode.size as i64, 1)),
+            }
+        }
+        for (uid, bytes, count) in by_uid {
+            charge_user(&txn, uid, bytes, count)?;
+        }
+        touch_dir(&txn, parent)?;
+        txn.commit()?;
+        Ok(inos.iter().map(|&ino| ino as u64).collect())
+    })
+}
+
+/// Write the blocks of files made by create_inodes, each given as its inode,
+/// index and bytes, a whole block of them, in one transaction.
+pub fn write_new_blocks<C: GenericConnection>(
+    conn: &C,
+    blocks: &[(u64, i64, Vec<u8>)],
+) -> Result<()> {
+    let inos: Vec<i64> = blocks.iter().map(|b| b.0 as i64).collect();
+    let idxs: Vec<i64> = blocks.iter().map(|b| b.1).collect();
+    let bytes: Vec<&[u8]> = blocks.iter().map(|b| &b.2[..]).collect();
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        if dedup() {
+            txn.execute(
+                "INSERT INTO chunk_store (hash, bytes, refcount)
+                 SELECT sha256(b), b, count(*) FROM unnest($1::BYTES[]) AS b GROUP BY b
+                 ON CONFLICT (hash) DO UPDATE SET refcount = chunk_store.refcount + excluded.refcount",
+                &[&bytes],
+            )?;
+            txn.execute(
+                "UPSERT INTO blocks (file_ino, block_idx, bytes, hash)
+                 SELECT ino, idx, NULL, sha256(b)
+                 FROM unnest($1::INT8[], $2::INT8[], $3::BYTES[]) AS u (ino, idx, b)",
+                &[&inos, &idxs, &bytes],
+            )?;
+        } else {
+            txn.execute(
+                "UPSERT INTO blocks (file_ino, block_idx, bytes, hash)
+                 SELECT ino, idx, b, NULL
+                 FROM unnest($1::INT8[], $2::INT8[], $3::BYTES[]) AS u (ino, idx, b)",
+                &[&inos, &idxs, &bytes],
+            )?;
+        }
+        txn.commit()
+    })
+}
+
+pub fn read_symlink<C: GenericConnection>(conn: &C, ino: u64) -> Result<Option<String>> {
+    read_only(conn, |conn| {
+        conn.query("SELECT target FROM inodes WHERE ino = $1", &[&(ino as i64)])
+            .map(|rows| {
+                if rows.is_empty() {
+                    None
+                } else {
+                    rows.get(0).get::<_, Option<String>>(0)
+                }
+            })
+    })
+}
+
+/// Remove a directory entry, which must be a directory if dir is set and
+/// must not be one otherwise. Fails with NotFound if there is no such entry,
+/// IsDir or NotDir if it is of the wrong kind, and NotEmpty if it is a
+/// directory that still has entries of its own.
+pub fn unlink<C: GenericConnection>(
+    conn: &C,
+    parent: u64,
+    name: &[u8],
+    dir: bool,
+) -> std::result::Result<(), CrfsError> {
+    with_retry(|| {
+        debug!("unlink: {} in {}", String::from_utf8_lossy(name), parent);
+        let txn = conn.transaction()?;
+        let mut inode = match lookup_dir_ent(&txn, parent, name)? {
+            Some((dir_ent, _)) => dir_ent,
+            None => return Ok(Err(CrfsError::NotFound)),
+        };
+        match (dir, inode.kind == FileType::Directory) {
+            (false, true) => return Ok(Err(CrfsError::IsDir)),
+            (true, false) => return Ok(Err(CrfsError::NotDir)),
+            (true, true) => {
+                if count_children(&txn, inode.ino)? > 0 {
+                    return Ok(Err(CrfsError::NotEmpty));
+                }
+            }
+            (false, false) => {}
+        }
+        txn.execute(
+            "DELETE FROM dir_entries
+             WHERE (dir_ino, child_name, child_ino) = ($1, $2, $3)",
+            &[&(parent as i64), &name, &(inode.ino as i64)],
+        )?;
+        drop_link(&txn, &mut inode)?;
+        touch_dir(&txn, parent)?;
+        txn.commit()?;
+        Ok(Ok(()))
+    })?
+}
+
+/// Account for a directory entry referring to inode having been removed,
+/// deleting the inode along with its blocks if it was the last one.
+fn drop_link<C: GenericConnection>(conn: &C, inode: &mut FileAttr) -> Result<()> {
+    inode.nlink -= 1;
+    if inode.nlink == 0 || inode.kind == FileType::Directory {
+        release_chunks(
+            conn,
+            "SELECT hash FROM blocks WHERE file_ino = $1",
+            &[&(inode.ino as i64)],
+        )?;
+        let rows = conn.query(
+            "DELETE FROM inodes WHERE ino = $1 RETURNING quota_ino, size, uid",
+            &[&(inode.ino as i64)],
+        )?;
+        for row in rows.iter() {
+            let size: i64 = row.get(1);
+            charge_quota(conn, row.get(0), -size, -1)?;
+            charge_user(conn, row.get::<_, i32>(2) as u32, -size, -1)?;
+        }
+    } else {
+        update_nlink(conn, inode.ino, inode.nlink)?;
     }
-    txn.commit()?;
-    Ok(attr)
+    Ok(())
+}
+
+/// Record that the entries of directory ino changed, which modifies it.
+fn touch_dir<C: GenericConnection>(conn: &C, ino: u64) -> Result<u64> {
+    conn.execute(
+        "UPDATE inodes SET mtime = now(), ctime = now() WHERE ino = $1",
+        &[&(ino as i64)],
+    )
+}
+
+/// Record that the metadata of ino, but not its contents, changed.
+fn touch_ctime<C: GenericConnection>(conn: &C, ino: u64) -> Result<u64> {
+    conn.execute(
+        "UPDATE inodes SET ctime = now() WHERE ino = $1",
+        &[&(ino as i64)],
+    )
+}
+
+fn count_children<C: GenericConnection>(conn: &C, ino: u64) -> Result<i64> {
+    conn.query(
+        "SELECT count(*) FROM dir_entries WHERE dir_ino = $1",
+        &[&(ino as i64)],
+    )
+    .map(|rows| rows.get(0).get(0))
+}
+
+/// Link inode ino into parent as newname. Fails with Stale if ino no longer
+/// exists, and NotPermitted if it is not a regular file.
+pub fn link<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    parent: u64,
+    newname: &[u8],
+) -> std::result::Result<(FileAttr, u64), CrfsError> {
+    with_retry(|| {
+        debug!(
+            "link: {} as {} in {}",
+            ino,
+            String::from_utf8_lossy(newname),
+            parent
+        );
+        let txn = conn.transaction()?;
+        let inode_opt = lookup_inode_generation(&txn, ino)?;
+        let (mut inode, generation) = match inode_opt {
+            Some(found) => found,
+            None => return Ok(Err(CrfsError::Stale)),
+        };
+        if inode.kind != FileType::RegularFile {
+            return Ok(Err(CrfsError::NotPermitted));
+        }
+        let kind_str = file_type_to_str(inode.kind);
+        txn.execute(
+            "INSERT INTO dir_entries (dir_ino, child_name, child_kind, child_ino)
+             VALUES ($1, $2, $3, $4)",
+            &[&(parent as i64), &newname, &kind_str, &(ino as i64)],
+        )?;
+        inode.nlink += 1;
+        update_nlink(&txn, inode.ino, inode.nlink)?;
+        touch_dir(&txn, parent)?;
+        txn.commit()?;
+        Ok(Ok((inode, generation)))
+    })?
+}
+
+pub fn lookup_inode_kind<C: GenericConnection>(conn: &C, ino: u64) -> Result<Option<FileType>> {
+    read_only(conn, |conn| {
+        conn.query("SELECT kind FROM inodes WHERE ino = $1", &[&(ino as i64)])
+            .map(|rows| {
+                if rows.len() == 0 {
+                    None
+                } else {
+                    str_to_file_type(rows.get(0).get(0))
+                }
+            })
+    })
+}
+
+pub fn lookup_inode<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+) -> std::result::Result<Option<FileAttr>, CrfsError> {
+    Ok(read_only(conn, |conn| {
+        conn.query("SELECT * FROM inodes WHERE ino = $1", &[&(ino as i64)])
+            .map(|rows| {
+                if rows.len() == 0 {
+                    None
+                } else {
+                    Some(row_to_file_attr(rows.get(0)))
+                }
+            })
+    })?)
+}
+
+/// Like lookup_inode, but also get the inode's generation, which tells it
+/// apart from any other inode ever given the same number.
+pub fn lookup_inode_generation<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+) -> std::result::Result<Option<(FileAttr, u64)>, CrfsError> {
+    Ok(read_only(conn, |conn| {
+        conn.query("SELECT * FROM inodes WHERE ino = $1", &[&(ino as i64)])
+            .map(|rows| {
+                rows.iter().next().map(|row| {
+                    let generation = row.get::<_, i64>("generation") as u64;
+                    (row_to_file_attr(row), generation)
+                })
+            })
+    })?)
+}
+
+/// The generations of those of inos that exist.
+pub fn generations<C: GenericConnection>(conn: &C, inos: &[u64]) -> Result<HashMap<u64, u64>> {
+    let inos: Vec<i64> = inos.iter().map(|&ino| ino as i64).collect();
+    read_only(conn, |conn| {
+        conn.query(
+            "SELECT ino, generation FROM inodes WHERE ino = ANY($1)",
+            &[&inos],
+        )
+        .map(|rows| {
+            rows.iter()
+                .map(|row| (row.get::<_, i64>(0) as u64, row.get::<_, i64>(1) as u64))
+                .collect()
+        })
+    })
+}
+
+pub fn update_inode<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    size: Option<u64>,
+    atime: Option<Timespec>,
+    mtime: Option<Timespec>,
+    chgtime: Option<Timespec>,
+    crtime: Option<Timespec>,
+    kind: Option<FileType>,
+    perm: Option<u16>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    flags: Option<u32>,
+) -> std::result::Result<FileAttr, CrfsError> {
+    let attr = with_retry(|| {
+        let file_type = kind.map(file_type_to_str);
+        let txn = conn.transaction()?;
+        if let Some(size) = size {
+            if !truncate_blocks(&txn, ino, size)? {
+                return Ok(None);
+            }
+        }
+        if let Some(uid) = uid {
+            // The file's usage moves to its new owner.
+            let rows = txn.query(
+                "SELECT uid, size FROM inodes WHERE ino = $1",
+                &[&(ino as i64)],
+            )?;
+            if let Some(row) = rows.iter().next() {
+                let old_uid = row.get::<_, i32>(0) as u32;
+                if old_uid != uid {
+                    let size = size.map_or(row.get(1), |size| size as i64);
+                    charge_user(&txn, old_uid, -size, -1)?;
+                    charge_user(&txn, uid, size, 1)?;
+                }
+            }
+        }
+        let attr = txn
+            .query(
+                "UPDATE inodes SET
+               size   = IFNULL($1, size),
+               atime  = IFNULL($2, atime),
+               mtime  = IFNULL($3, CASE WHEN $1 IS NULL THEN mtime ELSE now() END),
+               ctime  = IFNULL($4, now()),
+               crtime = IFNULL($5, crtime),
+               kind   = IFNULL($6, kind),
+               perm   = IFNULL($7, perm),
+               uid    = IFNULL($8, uid),
+               gid    = IFNULL($9, gid),
+               flags  = IFNULL($10, flags)
+             WHERE ino = $11
+             RETURNING *",
+                &[
+                    &size.map(|s| s as i64),
+                    &atime,
+                    &mtime,
+                    &chgtime,
+                    &crtime,
+                    &file_type,
+                    &perm.map(|p| p as i16),
+                    &uid.map(|p| p as i32),
+                    &gid.map(|p| p as i32),
+                    &flags.map(|p| p as i32),
+                    &(ino as i64),
+                ],
+            )
+            .map(|rows| {
+                if rows.len() == 0 {
+                    None
+                } else {
+                    Some(row_to_file_attr(rows.get(0)))
+                }
+            })?;
+        txn.commit()?;
+        Ok(attr)
+    })?;
+    attr.ok_or(CrfsError::Stale)
+}
+
+/// Record when each inode was last read. Unless strict is set, an inode's
+/// atime only moves when it is no newer than its mtime or ctime, or is more
+/// than a day old, as with relatime. An atime never moves backwards.
+pub fn update_atimes<C: GenericConnection>(
+    conn: &C,
+    atimes: &[(u64, Timespec)],
+    strict: bool,
+) -> Result<u64> {
+    let inos: Vec<i64> = atimes.iter().map(|&(ino, _)| ino as i64).collect();
+    let times: Vec<Timespec> = atimes.iter().map(|&(_, time)| time).collect();
+    with_retry(|| {
+        conn.execute(
+            "UPDATE inodes SET atime = a.time
+             FROM unnest($1::INT8[], $2::TIMESTAMP[]) AS a (ino, time)
+             WHERE inodes.ino = a.ino
+               AND inodes.atime < a.time
+               AND ($3 OR inodes.atime <= inodes.mtime
+                       OR inodes.atime <= inodes.ctime
+                       OR inodes.atime < a.time - INTERVAL '1 day')",
+            &[&inos, &times, &strict],
+        )
+    })
+}
+
+/// Drop the data of a file beyond size, so that extending the file later
+/// reads back zeros rather than its old contents. Growing a file adds no
+/// blocks; reads fill the missing ones with zeros.
+fn truncate_blocks<C: GenericConnection>(conn: &C, ino: u64, size: u64) -> Result<bool> {
+    let rows = conn.query(
+        "SELECT size, blocks, quota_ino, uid FROM inodes WHERE ino = $1",
+        &[&(ino as i64)],
+    )?;
+    if rows.is_empty() {
+        return Ok(false);
+    }
+    let row = rows.get(0);
+    let (cur_size, cur_blocks): (i64, i64) = (row.get(0), row.get(1));
+    charge_quota(conn, row.get(2), size as i64 - cur_size, 0)?;
+    charge_user(conn, row.get::<_, i32>(3) as u32, size as i64 - cur_size, 0)?;
+
+    let block_size = block_size();
+    let keep = cmp::min(cur_size, size as i64);
+    let keep_blocks = (keep + block_size - 1) / block_size;
+    if versioning() && size as i64 != cur_size {
+        // Keep every block that is removed or has its tail zeroed.
+        let idxs: Vec<i64> = conn
+            .query(
+                "SELECT block_idx FROM blocks WHERE file_ino = $1 AND block_idx >= $2",
+                &[&(ino as i64), &(keep / block_size)],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        save_version(conn, ino, &idxs)?;
+    }
+    release_chunks(
+        conn,
+        "SELECT hash FROM blocks WHERE file_ino = $1 AND block_idx >= $2",
+        &[&(ino as i64), &keep_blocks],
+    )?;
+    let deleted = conn.execute(
+        "DELETE FROM blocks WHERE file_ino = $1 AND block_idx >= $2",
+        &[&(ino as i64), &keep_blocks],
+    )?;
+    let tail = keep % block_size;
+    if tail != 0 {
+        // Zero the rest of the last block that is kept. The bytes are edited
+        // here rather than with SQL string functions, which would treat them
+        // as text.
+        let last = keep / block_size;
+        let rows = conn.query(
+            "SELECT bytes FROM block_bytes WHERE file_ino = $1 AND block_idx = $2",
+            &[&(ino as i64), &last],
+        )?;
+        if !rows.is_empty() {
+            let mut bytes: Vec<u8> = rows.get(0).get(0);
+            bytes.truncate(tail as usize);
+            bytes.resize(block_size as usize, 0);
+            upsert_blocks(conn, ino, last, &[&bytes])?;
+        }
+    }
+    conn.execute(
+        "UPDATE inodes SET blocks = $1 WHERE ino = $2",
+        &[&(cur_blocks - deleted as i64), &(ino as i64)],
+    )?;
+    Ok(true)
+}
+
+/// List up to limit entries of directory ino, in name order, starting after
+/// the entry named after.
+pub fn read_dir<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    after: &[u8],
+    limit: i64,
+) -> Result<Vec<DirEntry>> {
+    read_only(conn, |conn| {
+        conn.query(
+            "SELECT * FROM dir_entries WHERE dir_ino = $1 AND child_name > $2
+             ORDER BY child_name LIMIT $3",
+            &[&(ino as i64), &after, &limit],
+        )
+        .map(|rows| {
+            rows.iter()
+                .map(|row| DirEntry {
+                    child_name: row.get(1),
+                    child_kind: str_to_file_type(row.get(2)).unwrap(),
+                    child_ino: row.get::<_, i64>(3) as u64,
+                })
+                .collect()
+        })
+    })
+}
+
+/// Like read_dir, but also get the attributes of each entry's inode.
+pub fn read_dir_plus<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    after: &[u8],
+    limit: i64,
+) -> Result<Vec<(DirEntry, FileAttr)>> {
+    read_only(conn, |conn| {
+        conn.query(
+            "SELECT i.*, d.child_name FROM dir_entries d
+             JOIN inodes i ON i.ino = d.child_ino
+             WHERE d.dir_ino = $1 AND d.child_name > $2
+             ORDER BY d.child_name LIMIT $3",
+            &[&(ino as i64), &after, &limit],
+        )
+        .map(|rows| {
+            rows.iter()
+                .map(|row| {
+                    let ent = DirEntry {
+                        child_name: row.get("child_name"),
+                        child_kind: str_to_file_type(row.get("kind")).unwrap(),
+                        child_ino: row.get::<_, i64>("ino") as u64,
+                    };
+                    (ent, row_to_file_attr(row))
+                })
+                .collect()
+        })
+    })
+}
+
+/// Low bits of a listing cookie, numbering the entries whose names hash
+/// alike. The bits above them hold the hash.
+pub const COOKIE_SEQ_BITS: u32 = 8;
+const COOKIE_SEQ_MASK: i64 = (1 << COOKIE_SEQ_BITS) - 1;
+
+/// Give each of rows, a listing ordered by name hash and name that resumed
+/// after cookie, the cookie that resumes the listing after it, dropping the
+/// rows listed before cookie and keeping at most limit. An entry's cookie
+/// only changes if it is among more than COOKIE_SEQ_MASK entries whose
+/// names hash alike, which 55-bit hashes all but never are.
+pub fn number_listing<T>(rows: Vec<(i64, T)>, cookie: i64, limit: i64) -> Vec<(T, i64)> {
+    let (hash, skip) = (cookie >> COOKIE_SEQ_BITS, cookie & COOKIE_SEQ_MASK);
+    let (mut prev, mut seq) = (hash, 0);
+    let mut listed = Vec::new();
+    for (row_hash, row) in rows {
+        if row_hash != prev {
+            prev = row_hash;
+            seq = 0;
+        }
+        seq += 1;
+        if row_hash == hash && seq <= skip {
+            continue;
+        }
+        if listed.len() as i64 == limit {
+            break;
+        }
+        listed.push((
+            row,
+            (row_hash << COOKIE_SEQ_BITS) | cmp::min(seq, COOKIE_SEQ_MASK),
+        ));
+    }
+    listed
+}
+
+/// List up to limit entries of directory ino in the order of the hashes of
+/// their names, starting after the entry given cookie, or from the start if
+/// cookie is 0. Each entry comes with the cookie that resumes the listing
+/// after it, which is derived from its name, so that it stays valid however
+/// the directory changes.
+pub fn read_dir_from<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    cookie: i64,
+    limit: i64,
+) -> Result<Vec<(DirEntry, i64)>> {
+    read_only(conn, |conn| {
+        conn.query(
+            "SELECT name_hash, child_name, child_kind, child_ino FROM dir_entries
+             WHERE dir_ino = $1 AND name_hash >= $2
+             ORDER BY name_hash, child_name LIMIT $3",
+            &[
+                &(ino as i64),
+                &(cookie >> COOKIE_SEQ_BITS),
+                &(limit + (cookie & COOKIE_SEQ_MASK)),
+            ],
+        )
+        .map(|rows| {
+            let rows = rows
+                .iter()
+                .map(|row| {
+                    let ent = DirEntry {
+                        child_name: row.get(1),
+                        child_kind: str_to_file_type(row.get(2)).unwrap(),
+                        child_ino: row.get::<_, i64>(3) as u64,
+                    };
+                    (row.get(0), ent)
+                })
+                .collect();
+            number_listing(rows, cookie, limit)
+        })
+    })
+}
+
+/// Like read_dir_from, but also get the attributes of each entry's inode.
+pub fn read_dir_plus_from<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    cookie: i64,
+    limit: i64,
+) -> Result<Vec<((DirEntry, FileAttr), i64)>> {
+    read_only(conn, |conn| {
+        conn.query(
+            "SELECT i.*, d.child_name, d.name_hash FROM dir_entries d
+             JOIN inodes i ON i.ino = d.child_ino
+             WHERE d.dir_ino = $1 AND d.name_hash >= $2
+             ORDER BY d.name_hash, d.child_name LIMIT $3",
+            &[
+                &(ino as i64),
+                &(cookie >> COOKIE_SEQ_BITS),
+                &(limit + (cookie & COOKIE_SEQ_MASK)),
+            ],
+        )
+        .map(|rows| {
+            let rows = rows
+                .iter()
+                .map(|row| {
+                    let ent = DirEntry {
+                        child_name: row.get("child_name"),
+                        child_kind: str_to_file_type(row.get("kind")).unwrap(),
+                        child_ino: row.get::<_, i64>("ino") as u64,
+                    };
+                    (row.get("name_hash"), (ent, row_to_file_attr(row)))
+                })
+                .collect();
+            number_listing(rows, cookie, limit)
+        })
+    })
+}
+
+pub fn lookup_dir_ent<C: GenericConnection>(
+    conn: &C,
+    parent: u64,
+    name: &[u8],
+) -> std::result::Result<Option<(FileAttr, u64)>, CrfsError> {
+    Ok(read_only(conn, |conn| {
+        conn.query(
+            "SELECT i.* FROM inodes i 
+             JOIN dir_entries d 
+             ON i.ino = d.child_ino 
+             WHERE d.dir_ino = $1 AND d.child_name = $2",
+            &[&(parent as i64), &name],
+        )
+        .map(|rows| {
+            rows.iter().next().map(|row| {
+                let generation = row.get::<_, i64>("generation") as u64;
+                (row_to_file_attr(row), generation)
+            })
+        })
+    })?)
+}
+
+pub fn update_nlink<C: GenericConnection>(conn: &C, ino: u64, nlink: u32) -> Result<()> {
+    with_retry(|| {
+        conn.execute(
+            "UPDATE inodes
+             SET (nlink, ctime) = ($1, now())
+             WHERE (ino) = ($2)",
+            &[&(nlink as i32), &(ino as i64)],
+        )?;
+        return Ok(());
+    })
+}
+
+/// Move a directory entry, replacing any entry already at the destination
+/// as long as it is of a compatible kind. Fails with NotFound if there is no
+/// such entry; IsDir if a file was to replace a directory; NotDir if a
+/// directory was to replace something else; NotEmpty if the directory it
+/// was to replace has entries of its own; and CrossQuota if a directory was
+/// to move out from under the quota it is charged to.
+pub fn rename_dir_ent<C: GenericConnection>(
+    conn: &C,
+    parent: u64,
+    name: &[u8],
+    new_parent: u64,
+    new_name: &[u8],
+) -> std::result::Result<(), CrfsError> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let src = match lookup_dir_ent(&txn, parent, name)? {
+            Some((src, _)) => src,
+            None => return Ok(Err(CrfsError::NotFound)),
+        };
+        // Checked within the transaction, since a concurrent rename could
+        // otherwise detach a cycle of directories from the tree.
+        if src.kind == FileType::Directory
+            && new_parent != parent
+            && is_beneath(&txn, new_parent, src.ino)?
+        {
+            return Ok(Err(CrfsError::IntoItself));
+        }
+        let (src_quota, dst_quota) = (inode_quota(&txn, src.ino)?, dir_quota(&txn, new_parent)?);
+        if src_quota != dst_quota {
+            // Files move their usage with them, but a directory would have to
+            // move the usage of everything beneath it.
+            if src.kind == FileType::Directory {
+                return Ok(Err(CrfsError::CrossQuota));
+            }
+            charge_quota(&txn, dst_quota, src.size as i64, 1)?;
+            charge_quota(&txn, src_quota, -(src.size as i64), -1)?;
+            txn.execute(
+                "UPDATE inodes SET quota_ino = $1 WHERE ino = $2",
+                &[&dst_quota, &(src.ino as i64)],
+            )?;
+        }
+        if let Some((mut dst, _)) = lookup_dir_ent(&txn, new_parent, new_name)? {
+            if dst.ino == src.ino {
+                // Both names already refer to the same file.
+                return Ok(Ok(()));
+            }
+            match (
+                src.kind == FileType::Directory,
+                dst.kind == FileType::Directory,
+            ) {
+                (false, true) => return Ok(Err(CrfsError::IsDir)),
+                (true, false) => return Ok(Err(CrfsError::NotDir)),
+                (true, true) => {
+                    if count_children(&txn, dst.ino)? > 0 {
+                        return Ok(Err(CrfsError::NotEmpty));
+                    }
+                }
+                (false, false) => {}
+            }
+            txn.execute(
+                "DELETE FROM dir_entries
+                 WHERE (dir_ino, child_name) = ($1, $2)",
+                &[&(new_parent as i64), &new_name],
+            )?;
+            drop_link(&txn, &mut dst)?;
+        }
+        txn.execute(
+            "UPDATE dir_entries
+             SET   (dir_ino, child_name) = ($1, $2)
+             WHERE (dir_ino, child_name) = ($3, $4)",
+            &[&(new_parent as i64), &new_name, &(parent as i64), &name],
+        )?;
+        touch_ctime(&txn, src.ino)?;
+        touch_dir(&txn, parent)?;
+        if new_parent != parent {
+            touch_dir(&txn, new_parent)?;
+        }
+        txn.commit()?;
+        Ok(Ok(()))
+    })?
+}
+
+pub fn read_data<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    offset: i64,
+    size: usize,
+) -> Result<Option<Vec<u8>>> {
+    read_only(conn, |conn| {
+        let txn = conn.transaction()?;
+        let cur_inode: Option<i64> = txn
+            .query("SELECT size FROM inodes WHERE ino = $1", &[&(ino as i64)])
+            .map(|rows| {
+                if rows.len() == 0 {
+                    None
+                } else {
+                    Some(rows.get(0).get(0))
+                }
+            })?;
+        let cur_size = match cur_inode {
+            Some(cur_size) => cur_size,
+            None => return Ok(None),
+        };
+        if offset >= cur_size || size == 0 {
+            txn.commit()?;
+            return Ok(Some(Vec::new()));
+        }
+        let size = cmp::min(size as i64, cur_size - offset) as usize;
+
+        // Only the requested bytes of each block are fetched: the first and
+        // last blocks are trimmed to the range in SQL. They are copied straight
+        // into their position in a single buffer sized for the reply, from the
+        // raw row bytes to avoid an intermediate allocation per block.
+        let block_size = block_size();
+        let end = offset + size as i64;
+        let start_block = offset / block_size;
+        let end_block = (end - 1) / block_size;
+        let mut data = vec![0; size];
+        let rows = txn.query(
+            "SELECT block_idx,
+                    substring(bytes,
+                              greatest($4 - block_idx * $6, 0) + 1,
+                              least($5 - block_idx * $6, $6) - greatest($4 - block_idx * $6, 0))
+             FROM block_bytes
+             WHERE file_ino = $1 AND block_idx BETWEEN $2 AND $3",
+            &[
+                &(ino as i64),
+                &start_block,
+                &end_block,
+                &offset,
+                &end,
+                &block_size,
+            ],
+        )?;
+        for row in rows.iter() {
+            let from = cmp::max(offset, row.get::<_, i64>(0) * block_size);
+            let bytes = row.get_bytes(1).unwrap_or(&[]);
+            let to = cmp::min(end, from + bytes.len() as i64);
+            if from >= to {
+                continue;
+            }
+            data[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&bytes[..(to - from) as usize]);
+        }
+
+        txn.commit()?;
+        Ok(Some(data))
+    })
+}
+
+/// Write data to ino at offset, or at the end of the file if offset is None.
+/// Returns the offset the data was written at, or Stale if ino no longer
+/// exists.
+pub fn write_data<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    offset: Option<i64>,
+    data: &[u8],
+) -> std::result::Result<i64, CrfsError> {
+    let written = with_retry(|| {
+        let txn = conn.transaction()?;
+        // The inode is locked so that concurrent appends, from this mount or
+        // others, each find the end of the file left by the one before.
+        let cur_inode: Option<(i64, i64, Option<i64>, i32)> = txn
+            .query(
+                "SELECT size, blocks, quota_ino, uid FROM inodes WHERE ino = $1 FOR UPDATE",
+                &[&(ino as i64)],
+            )
+            .map(|rows| {
+                if rows.len() == 0 {
+                    None
+                } else {
+                    let row = rows.get(0);
+                    Some((row.get(0), row.get(1), row.get(2), row.get(3)))
+                }
+            })?;
+        let (cur_size, cur_blocks, quota, uid) = match cur_inode {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let offset = offset.unwrap_or(cur_size);
+
+        // Files are stored sparsely: blocks that have never been written are
+        // not stored, and read back as zeros. The inode's block count is the
+        // number of blocks stored, so count the ones this write adds.
+        let block_size = block_size();
+        let end = offset + data.len() as i64;
+        let mut added = 0;
+        if !data.is_empty() {
+            let first = offset / block_size;
+            let last = (end - 1) / block_size;
+
+            // Blocks that are only partly overwritten are read back and merged
+            // with the new data, so that every block can be written whole by a
+            // single statement. Blocks that are overwritten entirely are not
+            // read, only counted.
+            let partial =
+                |block: i64| block * block_size < offset || (block + 1) * block_size > end;
+            let edge = |block: i64| if partial(block) { block } else { -1 };
+            let rows = txn.query(
+                "SELECT block_idx, CASE WHEN block_idx IN ($4, $5) THEN bytes END
+                 FROM block_bytes WHERE file_ino = $1 AND block_idx BETWEEN $2 AND $3",
+                &[&(ino as i64), &first, &last, &edge(first), &edge(last)],
+            )?;
+            let mut merged: Vec<(i64, Vec<u8>)> = Vec::new();
+            for block in [first, last].iter().cloned() {
+                if !partial(block) || merged.iter().any(|(idx, _)| *idx == block) {
+                    continue;
+                }
+                let mut bytes = rows
+                    .iter()
+                    .find(|row| row.get::<_, i64>(0) == block)
+                    .and_then(|row| row.get::<_, Option<Vec<u8>>>(1))
+                    .unwrap_or_default();
+                bytes.resize(block_size as usize, 0);
+                let block_start = block * block_size;
+                let from = cmp::max(offset, block_start);
+                let to = cmp::min(end, block_start + block_size);
+                bytes[(from - block_start) as usize..(to - block_start) as usize]
+                    .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+                merged.push((block, bytes));
+            }
+
+            if versioning() {
+                let idxs: Vec<i64> = (first..=last).collect();
+                save_version(&txn, ino, &idxs)?;
+            }
+            let blocks: Vec<&[u8]> = (first..=last)
+                .map(|block| match merged.iter().find(|(idx, _)| *idx == block) {
+                    Some((_, bytes)) => bytes.as_slice(),
+                    None => {
+                        let start = (block * block_size - offset) as usize;
+                        &data[start..start + block_size as usize]
+                    }
+                })
+                .collect();
+            upsert_blocks(&txn, ino, first, &blocks)?;
+            added = blocks.len() as i64 - rows.len() as i64;
+        }
+
+        // Update the inode with the new size and block count.
+        let new_size = cmp::max(cur_size, end);
+        let new_blocks = cur_blocks + added;
+        charge_quota(&txn, quota, new_size - cur_size, 0)?;
+        charge_user(&txn, uid as u32, new_size - cur_size, 0)?;
+        let num_updated = txn.execute(
+            "UPDATE inodes SET size = $1, blocks = $2, mtime = now(), ctime = now()
+             WHERE ino = $3",
+            &[&new_size, &new_blocks, &(ino as i64)],
+        )?;
+        if num_updated != 1 {
+            return Ok(None);
+        }
+
+        txn.commit()?;
+        Ok(Some(offset))
+    })?;
+    written.ok_or(CrfsError::Stale)
+}
+
+/// Keep the blocks of ino at idxs, along with its size and modification time,
+/// as a new version before they are changed. Blocks that are not stored are
+/// kept as NULL, so that restoring the version removes them again.
+fn save_version<C: GenericConnection>(conn: &C, ino: u64, idxs: &[i64]) -> Result<()> {
+    let rows = conn.query(
+        "INSERT INTO file_versions (ino, version, size, mtime)
+         SELECT ino,
+                IFNULL((SELECT max(version) FROM file_versions WHERE ino = $1), 0) + 1,
+                size, mtime
+         FROM inodes WHERE ino = $1
+         RETURNING version",
+        &[&(ino as i64)],
+    )?;
+    if rows.is_empty() || idxs.is_empty() {
+        return Ok(());
+    }
+    let version: i64 = rows.get(0).get(0);
+    conn.execute(
+        "INSERT INTO block_versions (ino, version, block_idx, bytes)
+         SELECT $1, $2, i.idx, b.bytes
+         FROM unnest($3::INT8[]) AS i (idx)
+         LEFT JOIN block_bytes b ON b.file_ino = $1 AND b.block_idx = i.idx",
+        &[&(ino as i64), &version, &idxs],
+    )?;
+    Ok(())
+}
+
+/// The versions kept of ino, oldest first.
+pub fn list_versions<C: GenericConnection>(conn: &C, ino: u64) -> Result<Vec<FileVersion>> {
+    with_retry(|| {
+        conn.query(
+            "SELECT version, size, mtime, saved FROM file_versions
+             WHERE ino = $1 ORDER BY version",
+            &[&(ino as i64)],
+        )
+        .map(|rows| {
+            rows.iter()
+                .map(|row| FileVersion {
+                    version: row.get(0),
+                    size: row.get::<_, i64>(1) as u64,
+                    mtime: row.get(2),
+                    saved: row.get(3),
+                })
+                .collect()
+        })
+    })
+}
+
+/// Restore ino to its contents at version, first keeping its current contents
+/// as a new version whether or not versioning is on. Returns false if there is
+/// no such version.
+pub fn restore_version<C: GenericConnection>(conn: &C, ino: u64, version: i64) -> Result<bool> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let rows = txn.query(
+            "SELECT size, quota_ino, uid FROM inodes WHERE ino = $1 FOR UPDATE",
+            &[&(ino as i64)],
+        )?;
+        if rows.is_empty() {
+            return Ok(false);
+        }
+        let row = rows.get(0);
+        let (cur_size, quota, uid): (i64, Option<i64>, i32) = (row.get(0), row.get(1), row.get(2));
+        let rows = txn.query(
+            "SELECT size FROM file_versions WHERE ino = $1 AND version = $2",
+            &[&(ino as i64), &version],
+        )?;
+        if rows.is_empty() {
+            return Ok(false);
+        }
+        let size: i64 = rows.get(0).get(0);
+        charge_quota(&txn, quota, size - cur_size, 0)?;
+        charge_user(&txn, uid as u32, size - cur_size, 0)?;
+        let end_block = (size + block_size() - 1) / block_size();
+
+        // Each block changed since the version was kept by the first change
+        // made to it. Blocks past the end of the file at the version are
+        // removed.
+        let saved: Vec<(i64, Option<Vec<u8>>)> = txn
+            .query(
+                "SELECT DISTINCT ON (block_idx) block_idx, bytes FROM block_versions
+                 WHERE ino = $1 AND version >= $2
+                 ORDER BY block_idx, version",
+                &[&(ino as i64), &version],
+            )?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        let mut idxs: Vec<i64> = txn
+            .query(
+                "SELECT block_idx FROM blocks WHERE file_ino = $1 AND block_idx >= $2",
+                &[&(ino as i64), &end_block],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        for (idx, _) in &saved {
+            if !idxs.contains(idx) {
+                idxs.push(*idx);
+            }
+        }
+        save_version(&txn, ino, &idxs)?;
+
+        for (idx, bytes) in &saved {
+            match bytes {
+                Some(bytes) => upsert_blocks(&txn, ino, *idx, &[bytes])?,
+                None => {
+                    release_chunks(
+                        &txn,
+                        "SELECT hash FROM blocks WHERE file_ino = $1 AND block_idx = $2",
+                        &[&(ino as i64), idx],
+                    )?;
+                    txn.execute(
+                        "DELETE FROM blocks WHERE file_ino = $1 AND block_idx = $2",
+                        &[&(ino as i64), idx],
+                    )?
+                }
+            };
+        }
+        release_chunks(
+            &txn,
+            "SELECT hash FROM blocks WHERE file_ino = $1 AND block_idx >= $2",
+            &[&(ino as i64), &end_block],
+        )?;
+        txn.execute(
+            "DELETE FROM blocks WHERE file_ino = $1 AND block_idx >= $2",
+            &[&(ino as i64), &end_block],
+        )?;
+        txn.execute(
+            "UPDATE inodes SET size = $2,
+               blocks = (SELECT count(*) FROM blocks WHERE file_ino = $1),
+               mtime = now(), ctime = now()
+             WHERE ino = $1",
+            &[&(ino as i64), &size],
+        )?;
+        txn.commit()?;
+        Ok(true)
+    })
+}
+
+/// Overwrite a run of contiguous, complete blocks starting at first_block
+/// using a single statement, as shared chunks in dedup mode.
+fn upsert_blocks<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    first_block: i64,
+    blocks: &[&[u8]],
+) -> Result<u64> {
+    let ino = ino as i64;
+    let idxs: Vec<i64> = (0..blocks.len() as i64).map(|i| first_block + i).collect();
+    release_chunks(
+        conn,
+        "SELECT hash FROM blocks WHERE file_ino = $1 AND block_idx = ANY($2)",
+        &[&ino, &idxs],
+    )?;
+    if dedup() {
+        conn.execute(
+            "INSERT INTO chunk_store (hash, bytes, refcount)
+             SELECT sha256(b), b, count(*) FROM unnest($1::BYTES[]) AS b GROUP BY b
+             ON CONFLICT (hash) DO UPDATE SET refcount = chunk_store.refcount + excluded.refcount",
+            &[&blocks],
+        )?;
+        return conn.execute(
+            "UPSERT INTO blocks (file_ino, block_idx, bytes, hash)
+             SELECT $1, idx, NULL, sha256(b)
+             FROM unnest($2::INT8[], $3::BYTES[]) AS u (idx, b)",
+            &[&ino, &idxs, &blocks],
+        );
+    }
+    let mut values = Vec::with_capacity(blocks.len());
+    let mut params: Vec<&dyn ToSql> = Vec::with_capacity(1 + 2 * blocks.len());
+    params.push(&ino);
+    for (idx, bytes) in idxs.iter().zip(blocks) {
+        values.push(format!(
+            "($1, ${}, ${}, NULL)",
+            params.len() + 1,
+            params.len() + 2
+        ));
+        params.push(idx);
+        params.push(bytes);
+    }
+    conn.execute(
+        &format!(
+            "UPSERT INTO blocks (file_ino, block_idx, bytes, hash) VALUES {}",
+            values.join(", ")
+        ),
+        &params,
+    )
+}
+
+/// Clone the regular file src into parent as name. Rather than copying its
+/// blocks, the clone refers to the same chunks as src, moving src's own
+/// blocks into chunks first, so that a block is only copied once either file
+/// overwrites it. Returns None if src no longer exists.
+pub fn clone_file<C: GenericConnection>(
+    conn: &C,
+    src: u64,
+    parent: u64,
+    name: &[u8],
+) -> Result<Option<FileAttr>> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        txn.execute(
+            "INSERT INTO chunk_store (hash, bytes, refcount)
+             SELECT sha256(bytes), bytes, count(*) FROM blocks
+             WHERE file_ino = $1 AND hash IS NULL GROUP BY bytes
+             ON CONFLICT (hash) DO UPDATE SET refcount = chunk_store.refcount + excluded.refcount",
+            &[&(src as i64)],
+        )?;
+        txn.execute(
+            "UPDATE blocks SET hash = sha256(bytes), bytes = NULL
+             WHERE file_ino = $1 AND hash IS NULL",
+            &[&(src as i64)],
+        )?;
+        let quota = dir_quota(&txn, parent)?;
+        let rows = txn.query(
+            "INSERT INTO inodes (kind, size, blocks, perm, uid, gid, quota_ino, fs_id)
+             SELECT kind, size, blocks, perm, uid, gid, $2, fs_id FROM inodes WHERE ino = $1
+             RETURNING *",
+            &[&(src as i64), &quota],
+        )?;
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        let attr = row_to_file_attr(rows.get(0));
+        txn.execute(
+            "INSERT INTO blocks (file_ino, block_idx, bytes, hash)
+             SELECT $2, block_idx, NULL, hash FROM blocks WHERE file_ino = $1",
+            &[&(src as i64), &(attr.ino as i64)],
+        )?;
+        txn.execute(
+            "UPDATE chunk_store c SET refcount = c.refcount + r.n
+             FROM (SELECT hash, count(*) AS n FROM blocks WHERE file_ino = $1 GROUP BY hash) AS r
+             WHERE c.hash = r.hash",
+            &[&(attr.ino as i64)],
+        )?;
+        charge_quota(&txn, quota, attr.size as i64, 1)?;
+        charge_user(&txn, attr.uid, attr.size as i64, 1)?;
+        txn.execute(
+            "INSERT INTO dir_entries (dir_ino, child_name, child_kind, child_ino)
+             VALUES ($1, $2, $3, $4)",
+            &[
+                &(parent as i64),
+                &name,
+                &file_type_to_str(attr.kind),
+                &(attr.ino as i64),
+            ],
+        )?;
+        touch_dir(&txn, parent)?;
+        txn.commit()?;
+        Ok(Some(attr))
+    })
+}
+
+/// Drop a reference to a shared chunk for each hash listed by blocks, a query
+/// of the hashes of the blocks about to be overwritten or deleted, deleting
+/// chunks that are left unreferenced.
+fn release_chunks<C: GenericConnection>(
+    conn: &C,
+    blocks: &str,
+    params: &[&dyn ToSql],
+) -> Result<()> {
+    let rows = conn.query(
+        &format!(
+            "UPDATE chunk_store c SET refcount = c.refcount - r.n
+             FROM (SELECT hash, count(*) AS n FROM ({}) AS b (hash)
+                   WHERE hash IS NOT NULL GROUP BY hash) AS r
+             WHERE c.hash = r.hash
+             RETURNING c.hash, c.refcount",
+            blocks
+        ),
+        params,
+    )?;
+    let unreferenced: Vec<String> = rows
+        .iter()
+        .filter(|row| row.get::<_, i64>(1) <= 0)
+        .map(|row| row.get(0))
+        .collect();
+    if !unreferenced.is_empty() {
+        conn.execute(
+            "DELETE FROM chunk_store WHERE hash = ANY($1) AND refcount <= 0",
+            &[&unreferenced],
+        )?;
+    }
+    Ok(())
+}
+
+/// An opaque version of an inode that changes whenever its row is written,
+/// taken from the row's MVCC timestamp.
+pub fn inode_version<C: GenericConnection>(conn: &C, ino: u64) -> Result<Option<String>> {
+    with_retry(|| {
+        conn.query(
+            "SELECT crdb_internal_mvcc_timestamp::STRING FROM inodes WHERE ino = $1",
+            &[&(ino as i64)],
+        )
+        .map(|rows| {
+            if rows.len() == 0 {
+                None
+            } else {
+                Some(rows.get(0).get(0))
+            }
+        })
+    })
+}
+
+pub fn get_xattr<C: GenericConnection>(conn: &C, ino: u64, name: &str) -> Result<Option<Vec<u8>>> {
+    read_only(conn, |conn| {
+        conn.query(
+            "SELECT value FROM xattrs WHERE ino = $1 AND name = $2",
+            &[&(ino as i64), &name],
+        )
+        .map(|rows| {
+            if rows.is_empty() {
+                None
+            } else {
+                Some(rows.get(0).get(0))
+            }
+        })
+    })
+}
+
+pub fn list_xattrs<C: GenericConnection>(conn: &C, ino: u64) -> Result<Vec<String>> {
+    read_only(conn, |conn| {
+        conn.query(
+            "SELECT name FROM xattrs WHERE ino = $1 ORDER BY name",
+            &[&(ino as i64)],
+        )
+        .map(|rows| rows.iter().map(|row| row.get(0)).collect())
+    })
+}
+
+/// Set an extended attribute. With create, fail with a unique violation if
+/// it already exists. With replace, return false instead of creating it if it
+/// does not.
+pub fn set_xattr<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    name: &str,
+    value: &[u8],
+    create: bool,
+    replace: bool,
+) -> Result<bool> {
+    with_retry(|| {
+        let stmt = if create {
+            "INSERT INTO xattrs VALUES ($1, $2, $3)"
+        } else if replace {
+            "UPDATE xattrs SET value = $3 WHERE ino = $1 AND name = $2"
+        } else {
+            "UPSERT INTO xattrs VALUES ($1, $2, $3)"
+        };
+        let txn = conn.transaction()?;
+        let set = txn.execute(stmt, &[&(ino as i64), &name, &value])? > 0;
+        if set {
+            touch_ctime(&txn, ino)?;
+        }
+        txn.commit()?;
+        Ok(set)
+    })
+}
+
+pub fn remove_xattr<C: GenericConnection>(conn: &C, ino: u64, name: &str) -> Result<bool> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let removed = txn.execute(
+            "DELETE FROM xattrs WHERE ino = $1 AND name = $2",
+            &[&(ino as i64), &name],
+        )? > 0;
+        if removed {
+            touch_ctime(&txn, ino)?;
+        }
+        txn.commit()?;
+        Ok(removed)
+    })
+}
+
+/// Serve read-only transactions and statements run on conn from the nearest
+/// replica, as of follower_read_timestamp() a few seconds in the past.
+/// Statements that write fail on conn from then on.
+pub fn use_follower_reads<C: GenericConnection>(conn: &C) -> Result<()> {
+    conn.batch_execute("SET default_transaction_use_follower_reads = on")
+}
+
+/// A new identifier for a mount, distinguishing its lock owners from those
+/// of every other mount.
+pub fn new_session<C: GenericConnection>(conn: &C) -> Result<String> {
+    with_retry(|| {
+        conn.query("SELECT gen_random_uuid()::STRING", &[])
+            .map(|rows| rows.get(0).get(0))
+    })
+}
+
+/// A lock held by another owner that conflicts with owner taking a lock of
+/// type typ over start..=end. Expired locks are ignored.
+pub fn conflicting_lock<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    session: &str,
+    owner: u64,
+    start: u64,
+    end: u64,
+    typ: u32,
+) -> Result<Option<Lock>> {
+    with_retry(|| {
+        conn.query(
+            "SELECT session, range_start, range_end, exclusive, pid FROM file_locks
+             WHERE ino = $1 AND NOT (session = $2 AND owner = $3)
+             AND range_start <= $5 AND range_end >= $4
+             AND (exclusive OR $6) AND expires > now()
+             LIMIT 1",
+            &[
+                &(ino as i64),
+                &session,
+                &(owner as i64),
+                &(start as i64),
+                &(end as i64),
+                &(typ == F_WRLCK as u32),
+            ],
+        )
+        .map(|rows| {
+            if rows.is_empty() {
+                None
+            } else {
+                let row = rows.get(0);
+                Some(Lock {
+                    session: row.get(0),
+                    start: row.get::<_, i64>(1) as u64,
+                    end: row.get::<_, i64>(2) as u64,
+                    typ: lock_type(row.get(3)),
+                    pid: row.get::<_, i32>(4) as u32,
+                })
+            }
+        })
+    })
+}
+
+/// Take, change or release (with F_UNLCK) owner's lock over start..=end,
+/// splitting any of its locks that partially overlap the range. The locks
+/// left expire after lease unless renewed. Returns the conflicting lock
+/// instead if another owner holds one.
+pub fn set_lock<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    session: &str,
+    owner: u64,
+    lock: &Lock,
+    lease: Duration,
+) -> Result<Option<Lock>> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        if lock.typ != F_UNLCK as u32 {
+            let conflict =
+                conflicting_lock(&txn, ino, session, owner, lock.start, lock.end, lock.typ)?;
+            if conflict.is_some() {
+                return Ok(conflict);
+            }
+        }
+        let overlapping = txn.query(
+            "DELETE FROM file_locks
+             WHERE ino = $1 AND session = $2 AND owner = $3
+             AND range_start <= $5 AND range_end >= $4
+             RETURNING range_start, range_end, exclusive, pid",
+            &[
+                &(ino as i64),
+                &session,
+                &(owner as i64),
+                &(lock.start as i64),
+                &(lock.end as i64),
+            ],
+        )?;
+        let mut remaining = Vec::new();
+        for row in overlapping.iter() {
+            let (start, end) = (row.get::<_, i64>(0), row.get::<_, i64>(1));
+            let (exclusive, pid) = (row.get::<_, bool>(2), row.get::<_, i32>(3));
+            if start < lock.start as i64 {
+                remaining.push((start, lock.start as i64 - 1, exclusive, pid));
+            }
+            if end > lock.end as i64 {
+                remaining.push((lock.end as i64 + 1, end, exclusive, pid));
+            }
+        }
+        if lock.typ != F_UNLCK as u32 {
+            remaining.push((
+                lock.start as i64,
+                lock.end as i64,
+                lock.typ == F_WRLCK as u32,
+                lock.pid as i32,
+            ));
+        }
+        for (start, end, exclusive, pid) in remaining {
+            txn.execute(
+                "INSERT INTO file_locks
+                 (ino, session, owner, range_start, range_end, exclusive, pid, expires)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7,
+                         now() + $8::INT8 * INTERVAL '1 millisecond')",
+                &[
+                    &(ino as i64),
+                    &session,
+                    &(owner as i64),
+                    &start,
+                    &end,
+                    &exclusive,
+                    &pid,
+                    &(lease.as_millis() as i64),
+                ],
+            )?;
+        }
+        txn.commit()?;
+        Ok(None)
+    })
+}
+
+/// Release every lock owner holds on ino.
+pub fn release_locks<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    session: &str,
+    owner: u64,
+) -> Result<u64> {
+    with_retry(|| {
+        conn.execute(
+            "DELETE FROM file_locks WHERE ino = $1 AND session = $2 AND owner = $3",
+            &[&(ino as i64), &session, &(owner as i64)],
+        )
+    })
+}
+
+/// Extend every lock held through a mount to expire after lease.
+pub fn renew_locks<C: GenericConnection>(conn: &C, session: &str, lease: Duration) -> Result<u64> {
+    with_retry(|| {
+        conn.execute(
+            "UPDATE file_locks SET expires = now() + $2::INT8 * INTERVAL '1 millisecond'
+             WHERE session = $1",
+            &[&session, &(lease.as_millis() as i64)],
+        )
+    })
+}
+
+/// Release every lock held through a mount.
+pub fn release_session_locks<C: GenericConnection>(conn: &C, session: &str) -> Result<u64> {
+    with_retry(|| conn.execute("DELETE FROM file_locks WHERE session = $1", &[&session]))
+}
+
+/// Limits on the usage of a directory and everything beneath it.
+#[derive(Debug)]
+pub struct Quota {
+    pub ino: u64,
+    pub max_bytes: Option<i64>,
+    pub max_inodes: Option<i64>,
+    pub bytes: i64,
+    pub inodes: i64,
+}
+
+/// Limits on the usage of the files a user owns.
+#[derive(Debug)]
+pub struct UserQuota {
+    pub uid: u32,
+    pub max_bytes: Option<i64>,
+    pub max_inodes: Option<i64>,
+    pub bytes: i64,
+    pub inodes: i64,
+}
+
+/// Whether err is a quota's limit being exceeded.
+pub fn quota_exceeded(err: &Error) -> bool {
+    err.code() == Some(&error::CHECK_VIOLATION)
+}
+
+/// The quota inodes created in directory dir are charged to: dir's own, if
+/// it has one, or else the one dir itself is charged to.
+fn dir_quota<C: GenericConnection>(conn: &C, dir: u64) -> Result<Option<i64>> {
+    let rows = conn.query(
+        "SELECT IF(EXISTS (SELECT 1 FROM quotas WHERE ino = $1), $1, quota_ino)
+         FROM inodes WHERE ino = $1",
+        &[&(dir as i64)],
+    )?;
+    Ok(if rows.is_empty() {
+        None
+    } else {
+        rows.get(0).get(0)
+    })
+}
+
+/// The quota ino is charged to.
+fn inode_quota<C: GenericConnection>(conn: &C, ino: u64) -> Result<Option<i64>> {
+    let rows = conn.query(
+        "SELECT quota_ino FROM inodes WHERE ino = $1",
+        &[&(ino as i64)],
+    )?;
+    Ok(if rows.is_empty() {
+        None
+    } else {
+        rows.get(0).get(0)
+    })
+}
+
+/// Add bytes and inodes to the usage of quota and of every quota enclosing
+/// it. Fails with a check violation if that takes any of them over a limit.
+fn charge_quota<C: GenericConnection>(
+    conn: &C,
+    quota: Option<i64>,
+    bytes: i64,
+    inodes: i64,
+) -> Result<()> {
+    let quota = match quota {
+        Some(quota) if bytes != 0 || inodes != 0 => quota,
+        _ => return Ok(()),
+    };
+    conn.execute(
+        "WITH RECURSIVE chain (ino) AS (
+             SELECT $1::INT8
+             UNION ALL
+             SELECT i.quota_ino FROM inodes i JOIN chain ON i.ino = chain.ino
+             WHERE i.quota_ino IS NOT NULL
+         )
+         UPDATE quotas SET bytes = bytes + $2, inodes = inodes + $3
+         WHERE ino IN (SELECT ino FROM chain)",
+        &[&quota, &bytes, &inodes],
+    )?;
+    Ok(())
+}
+
+/// Add bytes and inodes to the usage of the files owned by uid. Fails with a
+/// check violation if that takes it over the user's limits.
+fn charge_user<C: GenericConnection>(conn: &C, uid: u32, bytes: i64, inodes: i64) -> Result<()> {
+    if bytes == 0 && inodes == 0 {
+        return Ok(());
+    }
+    conn.execute(
+        "INSERT INTO user_quotas (uid, bytes, inodes) VALUES ($1, $2, $3)
+         ON CONFLICT (uid) DO UPDATE
+         SET bytes = user_quotas.bytes + excluded.bytes,
+             inodes = user_quotas.inodes + excluded.inodes",
+        &[&(uid as i32), &bytes, &inodes],
+    )?;
+    Ok(())
+}
+
+/// Limit the usage of the files owned by uid, or remove its limits if both
+/// are None. Fails with a check violation if the usage is already over a
+/// limit.
+pub fn set_user_quota<C: GenericConnection>(
+    conn: &C,
+    uid: u32,
+    max_bytes: Option<i64>,
+    max_inodes: Option<i64>,
+) -> Result<()> {
+    with_retry(|| {
+        conn.execute(
+            "INSERT INTO user_quotas (uid, max_bytes, max_inodes) VALUES ($1, $2, $3)
+             ON CONFLICT (uid) DO UPDATE
+             SET max_bytes = excluded.max_bytes, max_inodes = excluded.max_inodes",
+            &[&(uid as i32), &max_bytes, &max_inodes],
+        )?;
+        Ok(())
+    })
+}
+
+/// Usage and limits of every user that owns files or has limits, by uid.
+pub fn list_user_quotas<C: GenericConnection>(conn: &C) -> Result<Vec<UserQuota>> {
+    with_retry(|| {
+        conn.query(
+            "SELECT uid, max_bytes, max_inodes, bytes, inodes FROM user_quotas
+             WHERE inodes != 0 OR max_bytes IS NOT NULL OR max_inodes IS NOT NULL
+             ORDER BY uid",
+            &[],
+        )
+        .map(|rows| {
+            rows.iter()
+                .map(|row| UserQuota {
+                    uid: row.get::<_, i32>(0) as u32,
+                    max_bytes: row.get(1),
+                    max_inodes: row.get(2),
+                    bytes: row.get(3),
+                    inodes: row.get(4),
+                })
+                .collect()
+        })
+    })
+}
+
+/// Limit the usage of directory dir and everything beneath it. Setting the
+/// first quota on a directory charges what is already beneath it, so it
+/// walks the whole tree. Fails with a check violation if the usage is
+/// already over a limit.
+pub fn set_quota<C: GenericConnection>(
+    conn: &C,
+    dir: u64,
+    max_bytes: Option<i64>,
+    max_inodes: Option<i64>,
+) -> Result<()> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let updated = txn.execute(
+            "UPDATE quotas SET max_bytes = $2, max_inodes = $3 WHERE ino = $1",
+            &[&(dir as i64), &max_bytes, &max_inodes],
+        )?;
+        if updated == 0 {
+            // Take over charging what is beneath dir from the quota it was
+            // charged to, which goes on counting it through dir. Quotas
+            // further down are charged as a whole and not descended into.
+            let outer = inode_quota(&txn, dir)?;
+            let (mut bytes, mut inodes) = (0, 0);
+            let mut seen = HashSet::new();
+            let mut dirs = vec![dir as i64];
+            while let Some(parent) = dirs.pop() {
+                let rows = txn.query(
+                    "SELECT i.ino, i.kind, i.size, i.quota_ino, q.bytes, q.inodes
+                     FROM dir_entries d
+                     JOIN inodes i ON i.ino = d.child_ino
+                     LEFT JOIN quotas q ON q.ino = i.ino
+                     WHERE d.dir_ino = $1",
+                    &[&parent],
+                )?;
+                for row in rows.iter() {
+                    let ino: i64 = row.get(0);
+                    if row.get::<_, Option<i64>>(3) != outer || !seen.insert(ino) {
+                        continue;
+                    }
+                    bytes += row.get::<_, i64>(2);
+                    inodes += 1;
+                    match (row.get::<_, Option<i64>>(4), row.get::<_, Option<i64>>(5)) {
+                        (Some(b), Some(i)) => {
+                            bytes += b;
+                            inodes += i;
+                        }
+                        _ if row.get::<_, String>(1) == "S_IFDIR" => dirs.push(ino),
+                        _ => {}
+                    }
+                }
+            }
+            let inos: Vec<i64> = seen.into_iter().collect();
+            txn.execute(
+                "UPDATE inodes SET quota_ino = $1 WHERE ino = ANY($2)",
+                &[&(dir as i64), &inos],
+            )?;
+            txn.execute(
+                "INSERT INTO quotas (ino, max_bytes, max_inodes, bytes, inodes)
+                 VALUES ($1, $2, $3, $4, $5)",
+                &[&(dir as i64), &max_bytes, &max_inodes, &bytes, &inodes],
+            )?;
+        }
+        txn.commit()?;
+        Ok(())
+    })
+}
+
+/// Remove the quota on directory dir, charging what is beneath it to the
+/// quota dir is charged to. Returns whether dir had a quota.
+pub fn clear_quota<C: GenericConnection>(conn: &C, dir: u64) -> Result<bool> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        if txn.execute("DELETE FROM quotas WHERE ino = $1", &[&(dir as i64)])? == 0 {
+            return Ok(false);
+        }
+        // The enclosing quotas already count everything beneath dir.
+        txn.execute(
+            "UPDATE inodes SET quota_ino = (SELECT quota_ino FROM inodes WHERE ino = $1)
+             WHERE quota_ino = $1",
+            &[&(dir as i64)],
+        )?;
+        txn.commit()?;
+        Ok(true)
+    })
+}
+
+/// Every quota, by directory.
+pub fn list_quotas<C: GenericConnection>(conn: &C) -> Result<Vec<Quota>> {
+    with_retry(|| {
+        conn.query(
+            "SELECT ino, max_bytes, max_inodes, bytes, inodes FROM quotas ORDER BY ino",
+            &[],
+        )
+        .map(|rows| {
+            rows.iter()
+                .map(|row| Quota {
+                    ino: row.get::<_, i64>(0) as u64,
+                    max_bytes: row.get(1),
+                    max_inodes: row.get(2),
+                    bytes: row.get(3),
+                    inodes: row.get(4),
+                })
+                .collect()
+        })
+    })
+}
+
+/// The directory and name of an entry referring to ino, found by scanning
+/// every entry.
+pub fn find_dir_ent<C: GenericConnection>(conn: &C, ino: u64) -> Result<Option<(u64, Vec<u8>)>> {
+    with_retry(|| {
+        conn.query(
+            "SELECT dir_ino, child_name FROM dir_entries WHERE child_ino = $1 LIMIT 1",
+            &[&(ino as i64)],
+        )
+        .map(|rows| {
+            rows.iter()
+                .next()
+                .map(|row| (row.get::<_, i64>(0) as u64, row.get(1)))
+        })
+    })
+}
+
+/// Whether directory ino is dir or lies beneath it, found by walking up
+/// from ino with find_dir_ent. A directory cannot be renamed into such an
+/// ino.
+fn is_beneath<C: GenericConnection>(conn: &C, mut ino: u64, dir: u64) -> Result<bool> {
+    while ino != root() {
+        if ino == dir {
+            return Ok(true);
+        }
+        ino = match find_dir_ent(conn, ino)? {
+            Some((parent, _)) => parent,
+            None => return Ok(false),
+        };
+    }
+    Ok(dir == root())
+}
+
+/// Take or renew session's write lease on ino for duration, breaking any
+/// lease another session holds on it if force is set. Returns whether session
+/// holds the lease.
+pub fn take_write_lease<C: GenericConnection>(
+    conn: &C,
+    ino: u64,
+    session: &str,
+    duration: Duration,
+    force: bool,
+) -> Result<bool> {
+    with_retry(|| {
+        conn.query(
+            "INSERT INTO write_leases (ino, session, expires)
+             VALUES ($1, $2, now() + $3::INT8 * INTERVAL '1 millisecond')
+             ON CONFLICT (ino) DO UPDATE
+             SET session = excluded.session, expires = excluded.expires
+             WHERE $4 OR write_leases.session = excluded.session
+                OR write_leases.expires < now()
+             RETURNING 1",
+            &[
+                &(ino as i64),
+                &session,
+                &(duration.as_millis() as i64),
+                &force,
+            ],
+        )
+        .map(|rows| !rows.is_empty())
+    })
+}
+
+/// Give up session's write lease on ino, if it still holds it.
+pub fn release_write_lease<C: GenericConnection>(conn: &C, ino: u64, session: &str) -> Result<u64> {
+    with_retry(|| {
+        conn.execute(
+            "DELETE FROM write_leases WHERE ino = $1 AND session = $2",
+            &[&(ino as i64), &session],
+        )
+    })
+}
+
+/// Give up every write lease session holds.
+pub fn release_session_leases<C: GenericConnection>(conn: &C, session: &str) -> Result<u64> {
+    with_retry(|| conn.execute("DELETE FROM write_leases WHERE session = $1", &[&session]))
+}
+
+/// Number of data blocks and inodes in use.
+pub fn usage<C: GenericConnection>(conn: &C) -> Result<(u64, u64)> {
+    read_only(conn, |conn| {
+        let rows = conn.query(
+            "SELECT (SELECT count(*) FROM blocks), (SELECT count(*) FROM inodes)",
+            &[],
+        )?;
+        let row = rows.get(0);
+        Ok((row.get::<_, i64>(0) as u64, row.get::<_, i64>(1) as u64))
+    })
+}
+
+/// Number of inodes of each kind, and their total size in bytes.
+pub fn usage_by_kind<C: GenericConnection>(conn: &C) -> Result<Vec<(FileType, u64, u64)>> {
+    with_retry(|| {
+        conn.query(
+            "SELECT kind, count(*), IFNULL(sum(size), 0)::INT8 FROM inodes
+             GROUP BY kind ORDER BY kind",
+            &[],
+        )
+        .map(|rows| {
+            rows.iter()
+                .filter_map(|row| {
+                    let kind = str_to_file_type(row.get(0))?;
+                    Some((
+                        kind,
+                        row.get::<_, i64>(1) as u64,
+                        row.get::<_, i64>(2) as u64,
+                    ))
+                })
+                .collect()
+        })
+    })
+}
+
+/// Delete up to limit inodes other than roots that no directory entry refers
+/// to and that have no entries of their own, along with their blocks.
+/// Returns the number of inodes deleted.
+pub fn delete_orphaned_inodes<C: GenericConnection>(
+    conn: &C,
+    roots: &[i64],
+    limit: i64,
+) -> Result<u64> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let inos: Vec<i64> = txn
+            .query(
+                "SELECT i.ino FROM inodes i
+                 WHERE i.ino != ALL($1)
+                 AND NOT EXISTS (SELECT 1 FROM dir_entries WHERE child_ino = i.ino)
+                 AND NOT EXISTS (SELECT 1 FROM dir_entries WHERE dir_ino = i.ino)
+                 LIMIT $2",
+                &[&roots, &limit],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        release_chunks(
+            &txn,
+            "SELECT hash FROM blocks WHERE file_ino = ANY($1)",
+            &[&inos],
+        )?;
+        let deleted = txn.execute("DELETE FROM inodes WHERE ino = ANY($1)", &[&inos])?;
+        txn.commit()?;
+        Ok(deleted)
+    })
+}
+
+/// Delete up to limit blocks that lie entirely beyond the end of their file,
+/// correcting the block counts of the files they belonged to. Returns the
+/// number of blocks deleted.
+pub fn delete_blocks_past_eof<C: GenericConnection>(conn: &C, limit: i64) -> Result<u64> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let (inos, idxs): (Vec<i64>, Vec<i64>) = txn
+            .query(
+                "SELECT b.file_ino, b.block_idx FROM blocks b
+                 JOIN inodes i ON i.ino = b.file_ino
+                 WHERE b.block_idx * $1 >= i.size
+                 LIMIT $2",
+                &[&block_size(), &limit],
+            )?
+            .iter()
+            .map(|row| (row.get::<_, i64>(0), row.get::<_, i64>(1)))
+            .unzip();
+        release_chunks(
+            &txn,
+            "SELECT b.hash FROM unnest($1::INT8[], $2::INT8[]) AS u (file_ino, block_idx)
+             JOIN blocks b ON b.file_ino = u.file_ino AND b.block_idx = u.block_idx",
+            &[&inos, &idxs],
+        )?;
+        let rows = txn.query(
+            "DELETE FROM blocks WHERE (file_ino, block_idx) IN (
+                 SELECT * FROM unnest($1::INT8[], $2::INT8[])
+             )
+             RETURNING file_ino",
+            &[&inos, &idxs],
+        )?;
+        let mut inos: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+        inos.sort_unstable();
+        inos.dedup();
+        txn.execute(
+            "UPDATE inodes
+             SET blocks = (SELECT count(*) FROM blocks WHERE file_ino = inodes.ino)
+             WHERE ino = ANY($1)",
+            &[&inos],
+        )?;
+        txn.commit()?;
+        Ok(rows.len() as u64)
+    })
+}
+
+/// Where a scrub has got to, so that it can resume after being interrupted.
+pub struct ScrubProgress {
+    /// The last block checked, as (file_ino, block_idx).
+    pub block: (i64, i64),
+    /// The last inode whose block count was checked, once every block has
+    /// been.
+    pub ino: Option<i64>,
+    pub started: Timespec,
+}
+
+/// A block as checked by a scrub.
+pub struct ScrubbedBlock {
+    pub file_ino: i64,
+    pub block_idx: i64,
+    /// Whether the block's bytes still match their checksum.
+    pub intact: bool,
+    /// The size of the block's file, or None if it has no inode.
+    pub size: Option<i64>,
+}
+
+/// Make the transaction txn read the database as it was at time, given in any
+/// form AS OF SYSTEM TIME accepts, such as '-10s' or a timestamp.
+pub fn read_as_of<C: GenericConnection>(txn: &C, time: &str) -> Result<()> {
+    txn.batch_execute(&format!(
+        "SET TRANSACTION AS OF SYSTEM TIME {}",
+        quote_literal(time)
+    ))
+}
+
+/// Back up the filesystem's database, as of time if given, to the backup
+/// collection at uri with CockroachDB's BACKUP. Returns the rows and bytes
+/// backed up.
+pub fn backup<C: GenericConnection>(
+    conn: &C,
+    uri: &str,
+    as_of: Option<&str>,
+) -> Result<(i64, i64)> {
+    let as_of = match as_of {
+        Some(time) => format!(" AS OF SYSTEM TIME {}", quote_literal(time)),
+        None => String::new(),
+    };
+    let rows = conn.query(
+        &format!(
+            "BACKUP DATABASE {} INTO {}{}",
+            quote_ident(&current_database(conn)?),
+            quote_literal(uri),
+            as_of
+        ),
+        &[],
+    )?;
+    let row = rows.get(0);
+    Ok((row.get("rows"), row.get("bytes")))
 }
 
-pub fn unlink<C: GenericConnection>(conn: &C, parent: u64, name: &str) -> Result<Option<()>> {
-    println!("unlink: {} in {}", name, parent);
-    let txn = conn.transaction()?;
-    let mut inode = match lookup_dir_ent(&txn, parent, name)? {
-        Some(dir_ent) => dir_ent,
-        None => return Ok(None),
-    };
-    txn.execute(
-        "DELETE FROM dir_entries
-         WHERE (dir_ino, child_name, child_ino) = ($1, $2, $3)",
-        &[&(parent as i64), &name, &(inode.ino as i64)],
+/// Restore the latest backup in the collection at uri as the database conn
+/// is connected to, which must not exist yet, with CockroachDB's RESTORE.
+/// Returns the restored database's name.
+pub fn restore<C: GenericConnection>(conn: &C, uri: &str) -> Result<String> {
+    let backed_up: String = conn
+        .query(
+            &format!(
+                "SELECT object_name FROM [SHOW BACKUP FROM LATEST IN {}]
+                 WHERE object_type = 'database'",
+                quote_literal(uri)
+            ),
+            &[],
+        )?
+        .get(0)
+        .get(0);
+    let db = current_database(conn)?;
+    conn.execute(
+        &format!(
+            "RESTORE DATABASE {} FROM LATEST IN {} WITH new_db_name = {}",
+            quote_ident(&backed_up),
+            quote_literal(uri),
+            quote_literal(&db)
+        ),
+        &[],
     )?;
-    inode.nlink -= 1;
-    if inode.nlink == 0 {
-        txn.execute("DELETE FROM inodes WHERE ino = $1", &[&(inode.ino as i64)])?;
+    Ok(db)
+}
+
+/// The block size the filesystem records, if it has recorded one yet.
+pub fn stored_block_size<C: GenericConnection>(conn: &C) -> Result<Option<i64>> {
+    let rows = conn.query("SELECT value FROM fs_meta WHERE key = 'block_size'", &[])?;
+    Ok(if rows.is_empty() {
+        None
     } else {
-        update_nlink(&txn, inode.ino, inode.nlink)?;
-    }
-    txn.commit()?;
-    return Ok(Some(()));
+        Some(rows.get(0).get(0))
+    })
 }
 
-pub fn link<C: GenericConnection>(
-    conn: &C,
-    ino: u64,
-    parent: u64,
-    newname: &str,
-) -> Result<Option<FileAttr>> {
-    println!("link: {} as {} in {}", ino, newname, parent);
-    let txn = conn.transaction()?;
-    let inode_opt = lookup_inode(&txn, ino)?;
-    let mut inode = match inode_opt {
-        Some(inode) => inode,
-        None => return Ok(None),
-    };
-    // TODO(ajwerner): return a better error if inode is a dir.
-    if inode.kind != FileType::RegularFile {
-        return Ok(None);
+/// New blocks assembled at a time by resize_file_blocks, in blocks of the
+/// larger of the two sizes.
+const RESIZE_SPAN_BLOCKS: i64 = 64;
+
+/// The block size that a migration under way is rewriting blocks to, if any.
+/// The filesystem cannot be used until the migration finishes, since its
+/// files are stored in blocks of either size.
+pub fn block_resize_target<C: GenericConnection>(conn: &C) -> Result<Option<i64>> {
+    match conn.query("SELECT value FROM fs_meta WHERE key = 'resize_to'", &[]) {
+        Err(ref err) if err.code() == Some(&error::UNDEFINED_TABLE) => Ok(None),
+        Err(err) => Err(err),
+        Ok(rows) => Ok(rows.iter().next().map(|row| row.get(0))),
     }
-    let kind_str = file_type_to_str(inode.kind);
-    txn.execute(
-        "INSERT INTO dir_entries
-         VALUES ($1, $2, $3, $4)",
-        &[&(parent as i64), &newname, &kind_str, &(ino as i64)],
-    )?;
-    inode.nlink += 1;
-    update_nlink(&txn, inode.ino, inode.nlink)?;
-    txn.commit()?;
-    Ok(Some(inode))
 }
 
-pub fn lookup_inode_kind<C: GenericConnection>(conn: &C, ino: u64) -> Result<Option<FileType>> {
-    conn.query("SELECT kind FROM inodes WHERE ino = $1", &[&(ino as i64)])
-        .map(|rows| {
-            if rows.len() == 0 {
-                None
-            } else {
-                str_to_file_type(rows.get(0).get(0))
-            }
-        })
+/// Begin rewriting blocks to size to, unless a migration is already under
+/// way. Returns the size being migrated to, which is that of the migration
+/// already under way if there is one, and the last inode it rewrote.
+pub fn begin_block_resize<C: GenericConnection>(conn: &C, to: i64) -> Result<(i64, u64)> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        txn.execute(
+            "INSERT INTO fs_meta (key, value) VALUES ('resize_to', $1), ('resize_cursor', 0)
+             ON CONFLICT (key) DO NOTHING",
+            &[&to],
+        )?;
+        let rows = txn.query(
+            "SELECT (SELECT value FROM fs_meta WHERE key = 'resize_to'),
+                    (SELECT value FROM fs_meta WHERE key = 'resize_cursor')",
+            &[],
+        )?;
+        txn.commit()?;
+        let row = rows.get(0);
+        Ok((row.get(0), row.get::<_, i64>(1) as u64))
+    })
 }
 
-pub fn lookup_inode<C: GenericConnection>(conn: &C, ino: u64) -> Result<Option<FileAttr>> {
-    conn.query("SELECT * FROM inodes WHERE ino = $1", &[&(ino as i64)])
+/// Up to limit regular files after inode after, in inode order, with their
+/// sizes.
+pub fn files_after<C: GenericConnection>(
+    conn: &C,
+    after: u64,
+    limit: i64,
+) -> Result<Vec<(u64, u64)>> {
+    with_retry(|| {
+        conn.query(
+            "SELECT ino, size FROM inodes WHERE ino > $1 AND kind = $2 ORDER BY ino LIMIT $3",
+            &[
+                &(after as i64),
+                &file_type_to_str(FileType::RegularFile),
+                &limit,
+            ],
+        )
         .map(|rows| {
-            if rows.len() == 0 {
-                None
-            } else {
-                Some(row_to_file_attr(rows.get(0)))
-            }
+            rows.iter()
+                .map(|row| (row.get::<_, i64>(0) as u64, row.get::<_, i64>(1) as u64))
+                .collect()
         })
+    })
 }
 
-pub fn update_inode<C: GenericConnection>(
+/// Rewrite the blocks of file ino, size bytes long, from blocks of from bytes
+/// into blocks of to bytes, and record it as the last inode rewritten, all in
+/// one transaction. The new blocks are written at negative indexes while the
+/// old ones are read, and then take their place. Holes stay holes, and blocks
+/// shared with clones are copied. Returns the number of blocks written.
+pub fn resize_file_blocks<C: GenericConnection>(
     conn: &C,
     ino: u64,
-    size: Option<u64>,
-    atime: Option<Timespec>,
-    mtime: Option<Timespec>,
-    chgtime: Option<Timespec>,
-    crtime: Option<Timespec>,
-    kind: Option<FileType>,
-    perm: Option<u16>,
-    uid: Option<u32>,
-    gid: Option<u32>,
-    flags: Option<u32>,
-) -> Result<Option<FileAttr>> {
-    let file_type = kind.map(file_type_to_str);
-    conn.query(
-        "UPDATE inodes SET
-           size   = IFNULL($1, size),
-           atime  = IFNULL($2, atime),
-           mtime  = IFNULL($3, mtime),
-           ctime  = IFNULL($4, ctime),
-           crtime = IFNULL($5, crtime),
-           kind   = IFNULL($6, kind),
-           perm   = IFNULL($7, perm),
-           uid    = IFNULL($8, uid),
-           gid    = IFNULL($9, gid),
-           flags  = IFNULL($10, flags)
-         WHERE ino = $11
-         RETURNING *",
-        &[
-            &size.map(|s| s as i64),
-            &atime,
-            &mtime,
-            &chgtime,
-            &crtime,
-            &file_type,
-            &perm.map(|p| p as i16),
-            &uid.map(|p| p as i32),
-            &gid.map(|p| p as i32),
-            &flags.map(|p| p as i32),
-            &(ino as i64),
-        ],
-    )
-    .map(|rows| {
-        if rows.len() == 0 {
-            None
-        } else {
-            Some(row_to_file_attr(rows.get(0)))
+    size: u64,
+    from: i64,
+    to: i64,
+) -> Result<i64> {
+    let ino = ino as i64;
+    let size = size as i64;
+    let span = cmp::max(from, to) * RESIZE_SPAN_BLOCKS;
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let mut written = 0;
+        let mut start = 0;
+        while start < size {
+            let rows = txn.query(
+                "SELECT block_idx, bytes FROM block_bytes
+                 WHERE file_ino = $1 AND block_idx >= $2 AND block_idx < $3",
+                &[&ino, &(start / from), &((start + span) / from)],
+            )?;
+            if rows.is_empty() {
+                start += span;
+                continue;
+            }
+            let mut buf = vec![0; span as usize];
+            let mut stored = vec![false; (span / to) as usize];
+            for row in rows.iter() {
+                let bytes: Vec<u8> = row.get(1);
+                let offset = (row.get::<_, i64>(0) * from - start) as usize;
+                let len = cmp::min(bytes.len(), from as usize);
+                buf[offset..offset + len].copy_from_slice(&bytes[..len]);
+                let first = offset / to as usize;
+                let last = (offset + from as usize - 1) / to as usize;
+                for s in &mut stored[first..=last] {
+                    *s = true;
+                }
+            }
+            let count = (cmp::min(span, size - start) + to - 1) / to;
+            let mut idxs = Vec::new();
+            let mut blocks = Vec::new();
+            for i in 0..count {
+                if stored[i as usize] {
+                    idxs.push(-1 - (start / to + i));
+                    blocks.push(&buf[(i * to) as usize..((i + 1) * to) as usize]);
+                }
+            }
+            if !idxs.is_empty() {
+                written += txn.execute(
+                    "INSERT INTO blocks (file_ino, block_idx, bytes)
+                     SELECT $1, idx, b FROM unnest($2::INT8[], $3::BYTES[]) AS u (idx, b)",
+                    &[&ino, &idxs, &blocks],
+                )? as i64;
+            }
+            start += span;
         }
+        release_chunks(
+            &txn,
+            "SELECT hash FROM blocks WHERE file_ino = $1 AND block_idx >= 0",
+            &[&ino],
+        )?;
+        txn.execute(
+            "DELETE FROM blocks WHERE file_ino = $1 AND block_idx >= 0",
+            &[&ino],
+        )?;
+        txn.execute(
+            "UPDATE blocks SET block_idx = -1 - block_idx WHERE file_ino = $1",
+            &[&ino],
+        )?;
+        txn.execute(
+            "UPDATE inodes SET blocks = $2 WHERE ino = $1",
+            &[&ino, &written],
+        )?;
+        txn.execute(
+            "UPDATE fs_meta SET value = $1 WHERE key = 'resize_cursor'",
+            &[&ino],
+        )?;
+        txn.commit()?;
+        Ok(written)
     })
 }
 
-pub fn read_dir<C: GenericConnection>(conn: &C, ino: u64, offset: i64) -> Result<Vec<DirEntry>> {
-    conn.query(
-        "SELECT * FROM dir_entries WHERE dir_ino = $1 ORDER BY child_name OFFSET $2 ROWS",
-        &[&(ino as i64), &(offset)],
-    )
-    .map(|rows| {
-        rows.iter()
-            .map(|row| DirEntry {
-                dir_ino: row.get::<_, i64>(0) as u64,
-                child_name: row.get(1),
-                child_kind: str_to_file_type(row.get(2)).unwrap(),
-                child_ino: row.get::<_, i64>(3) as u64,
-            })
-            .collect()
+/// Finish a block size migration once every file has been rewritten, making
+/// to the filesystem's block size.
+pub fn finish_block_resize<C: GenericConnection>(conn: &C, to: i64) -> Result<()> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        txn.execute(
+            "UPSERT INTO fs_meta (key, value) VALUES ('block_size', $1)",
+            &[&to],
+        )?;
+        txn.execute(
+            "DELETE FROM fs_meta WHERE key IN ('resize_to', 'resize_cursor')",
+            &[],
+        )?;
+        txn.commit()
     })
 }
 
-pub fn lookup_dir_ent<C: GenericConnection>(
-    conn: &C,
-    parent: u64,
-    name: &str,
-) -> Result<Option<FileAttr>> {
-    conn.query(
-        "SELECT i.* FROM inodes i 
-         JOIN dir_entries d 
-         ON i.ino = d.child_ino 
-         WHERE d.dir_ino = $1 AND d.child_name = $2",
-        &[&(parent as i64), &name],
-    )
-    .map(|rows| {
-        if rows.len() == 0 {
-            None
-        } else {
-            Some(row_to_file_attr(rows.get(0)))
-        }
+/// Number of file versions kept.
+pub fn count_file_versions<C: GenericConnection>(conn: &C) -> Result<i64> {
+    with_retry(|| count(conn, "SELECT count(*) FROM file_versions"))
+}
+
+/// Delete every file version kept, along with its blocks.
+pub fn delete_file_versions<C: GenericConnection>(conn: &C) -> Result<u64> {
+    with_retry(|| conn.execute("DELETE FROM file_versions", &[]))
+}
+
+/// Release every byte-range lock and write lease, as after restoring a
+/// backup, when none of the mounts that took them are left.
+pub fn release_all_sessions<C: GenericConnection>(conn: &C) -> Result<u64> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let released = txn.execute("DELETE FROM file_locks", &[])?
+            + txn.execute("DELETE FROM write_leases", &[])?;
+        txn.commit()?;
+        Ok(released)
     })
 }
 
-pub fn update_nlink<C: GenericConnection>(conn: &C, ino: u64, nlink: u32) -> Result<()> {
-    conn.execute(
-        "UPDATE inodes
-         SET (nlink) = ($1)
-         WHERE (ino) = ($2)",
-        &[&(nlink as i32), &(ino as i64)],
-    )?;
-    return Ok(());
+fn current_database<C: GenericConnection>(conn: &C) -> Result<String> {
+    conn.query("SELECT current_database()", &[])
+        .map(|rows| rows.get(0).get(0))
 }
 
-pub fn rename_dir_ent<C: GenericConnection>(
-    conn: &C,
-    parent: u64,
-    name: &str,
-    new_parent: u64,
-    new_name: &str,
-) -> Result<bool> {
-    let txn = conn.transaction()?;
-    txn.execute(
-        "DELETE FROM dir_entries
-         WHERE (dir_ino, child_name) = ($1, $2)",
-        &[&(new_parent as i64), &new_name],
-    )?;
-    let num = txn.execute(
-        "UPDATE dir_entries
-         SET   (dir_ino, child_name) = ($1, $2)
-         WHERE (dir_ino, child_name) = ($3, $4)",
-        &[&(new_parent as i64), &new_name, &(parent as i64), &name],
-    )?;
-    if num == 0 {
-        txn.set_rollback();
-        txn.finish()?;
-        return Ok(false);
-    }
-    txn.commit()?;
-    Ok(true)
+/// Quote s as an SQL string literal, for statements that take no placeholders.
+fn quote_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
 }
 
-pub fn read_data<C: GenericConnection>(
-    conn: &C,
-    ino: u64,
-    offset: i64,
-    size: usize,
-) -> Result<Option<Vec<u8>>> {
-    let txn = conn.transaction()?;
-    let cur_inode: Option<i64> = txn
-        .query("SELECT size FROM inodes WHERE ino = $1", &[&(ino as i64)])
-        .map(|rows| {
-            if rows.len() == 0 {
-                None
-            } else {
-                Some(rows.get(0).get(0))
-            }
-        })?;
-    match cur_inode {
-        Some(cur_size) => {
-            if cur_size < offset + size as i64 {
-                return Ok(None);
-            }
-        }
-        None => return Ok(None),
-    };
+/// Quote s as an SQL identifier.
+fn quote_ident(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
 
-    let start_block = offset / DATA_BLOCK_SIZE;
-    let end_block = (offset + size as i64) / DATA_BLOCK_SIZE;
-    let max_size = (end_block - start_block + 1) * DATA_BLOCK_SIZE;
-    let mut data = txn
-        .query(
-            "SELECT bytes FROM blocks 
-            WHERE file_ino = $1 AND block_idx BETWEEN $2 AND $3",
-            &[&(ino as i64), &(start_block as i64), &(end_block as i64)],
-        )?
-        .into_iter()
-        .map(|row| row.get::<_, Vec<u8>>(0))
-        .fold(
-            Vec::with_capacity(max_size as usize),
-            |mut data, mut bytes| {
-                data.append(&mut bytes);
-                data
-            },
-        );
-    data.truncate(size);
+/// Run the transactions made on conn at low priority, so that they give way
+/// to the filesystem's own when they conflict.
+pub fn use_low_priority<C: GenericConnection>(conn: &C) -> Result<()> {
+    conn.batch_execute("SET default_transaction_priority = 'low'")
+}
+
+/// The progress of the scrub under way, starting one if there is none.
+pub fn scrub_progress<C: GenericConnection>(conn: &C) -> Result<ScrubProgress> {
+    with_retry(|| {
+        conn.execute(
+            "INSERT INTO scrub_progress (id) VALUES (1) ON CONFLICT (id) DO NOTHING",
+            &[],
+        )?;
+        let rows = conn.query(
+            "SELECT file_ino, block_idx, ino, started FROM scrub_progress WHERE id = 1",
+            &[],
+        )?;
+        let row = rows.get(0);
+        Ok(ScrubProgress {
+            block: (row.get(0), row.get(1)),
+            ino: row.get(2),
+            started: row.get(3),
+        })
+    })
+}
+
+/// Record how far the scrub under way has got.
+pub fn save_scrub_progress<C: GenericConnection>(conn: &C, progress: &ScrubProgress) -> Result<()> {
+    with_retry(|| {
+        conn.execute(
+            "UPDATE scrub_progress SET file_ino = $1, block_idx = $2, ino = $3 WHERE id = 1",
+            &[&progress.block.0, &progress.block.1, &progress.ino],
+        )
+        .map(|_| ())
+    })
+}
 
-    txn.commit()?;
-    Ok(Some(data))
+/// Forget the scrub under way once it has checked everything, so that the
+/// next one starts from the beginning.
+pub fn finish_scrub<C: GenericConnection>(conn: &C) -> Result<()> {
+    with_retry(|| {
+        conn.execute("DELETE FROM scrub_progress WHERE id = 1", &[])
+            .map(|_| ())
+    })
 }
 
-pub fn write_data<C: GenericConnection>(
+/// Check up to limit blocks following after in (file_ino, block_idx) order
+/// against their checksums and their inodes. The checksums are verified in
+/// the database, so the blocks' bytes are not fetched.
+pub fn scrub_blocks<C: GenericConnection>(
     conn: &C,
-    ino: u64,
-    offset: i64,
-    data: &[u8],
-) -> Result<Option<usize>> {
-    let txn = conn.transaction()?;
-    let cur_inode: Option<(i64, i64)> = txn
-        .query(
-            "SELECT size, blocks FROM inodes WHERE ino = $1",
-            &[&(ino as i64)],
+    after: (i64, i64),
+    limit: i64,
+) -> Result<Vec<ScrubbedBlock>> {
+    with_retry(|| {
+        conn.query(
+            "SELECT b.file_ino, b.block_idx,
+                    IF(b.hash IS NULL, b.checksum = crc32c(b.bytes),
+                       IFNULL(c.hash = sha256(c.bytes), false)),
+                    i.size
+             FROM blocks b LEFT JOIN inodes i ON i.ino = b.file_ino
+             LEFT JOIN chunk_store c ON c.hash = b.hash
+             WHERE (b.file_ino, b.block_idx) > ($1, $2)
+             ORDER BY b.file_ino, b.block_idx
+             LIMIT $3",
+            &[&after.0, &after.1, &limit],
         )
         .map(|rows| {
-            if rows.len() == 0 {
-                None
-            } else {
-                let row = rows.get(0);
-                Some((row.get(0), row.get(1)))
-            }
-        })?;
-    let (cur_size, cur_blocks) = match cur_inode {
-        Some(v) => v,
-        None => return Ok(None),
-    };
+            rows.iter()
+                .map(|row| ScrubbedBlock {
+                    file_ino: row.get(0),
+                    block_idx: row.get(1),
+                    intact: row.get(2),
+                    size: row.get(3),
+                })
+                .collect()
+        })
+    })
+}
+
+/// The recorded and actual block counts of up to limit inodes following
+/// after in inode order.
+pub fn scrub_block_counts<C: GenericConnection>(
+    conn: &C,
+    after: i64,
+    limit: i64,
+) -> Result<Vec<(i64, i64, i64)>> {
+    with_retry(|| {
+        conn.query(
+            "SELECT i.ino, i.blocks, (SELECT count(*) FROM blocks WHERE file_ino = i.ino)
+             FROM (SELECT ino, blocks FROM inodes WHERE ino > $1 ORDER BY ino LIMIT $2) i
+             ORDER BY i.ino",
+            &[&after, &limit],
+        )
+        .map(|rows| {
+            rows.iter()
+                .map(|row| (row.get(0), row.get(1), row.get(2)))
+                .collect()
+        })
+    })
+}
 
-    // Pad out to the offset.
-    let before = offset / DATA_BLOCK_SIZE;
-    for i in cur_blocks..before {
+/// Delete the given blocks, found to be unreachable by a scrub, if they
+/// still are: because they have no inode or lie beyond the end of their
+/// file. Returns the number deleted.
+pub fn delete_unreachable_blocks<C: GenericConnection>(
+    conn: &C,
+    blocks: &[(i64, i64)],
+) -> Result<u64> {
+    let (inos, idxs): (Vec<i64>, Vec<i64>) = blocks.iter().cloned().unzip();
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let unreachable = "SELECT b.file_ino, b.block_idx, b.hash
+             FROM unnest($1::INT8[], $2::INT8[]) AS u (file_ino, block_idx)
+             JOIN blocks b ON b.file_ino = u.file_ino AND b.block_idx = u.block_idx
+             LEFT JOIN inodes i ON i.ino = b.file_ino
+             WHERE i.ino IS NULL OR b.block_idx * $3 >= i.size";
+        release_chunks(
+            &txn,
+            &format!("SELECT hash FROM ({})", unreachable),
+            &[&inos, &idxs, &block_size()],
+        )?;
+        let rows = txn.query(
+            &format!(
+                "DELETE FROM blocks WHERE (file_ino, block_idx) IN (
+                     SELECT file_ino, block_idx FROM ({})
+                 )
+                 RETURNING file_ino",
+                unreachable
+            ),
+            &[&inos, &idxs, &block_size()],
+        )?;
+        let mut inos: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+        inos.sort_unstable();
+        inos.dedup();
         txn.execute(
-            "INSERT INTO blocks
-             VALUES ($1, $2, DEFAULT)",
-            &[&(ino as i64), &(i as i64)],
+            "UPDATE inodes
+             SET blocks = (SELECT count(*) FROM blocks WHERE file_ino = inodes.ino)
+             WHERE ino = ANY($1)",
+            &[&inos],
         )?;
-    }
+        txn.commit()?;
+        Ok(rows.len() as u64)
+    })
+}
 
-    let mut cur_block = before;
-    let mut cur_offset = offset % DATA_BLOCK_SIZE;
-    let mut created_blocks = 0;
-    let mut data_left = data;
-    while data_left.len() > 0 {
-        let avail = (DATA_BLOCK_SIZE - cur_offset) as usize;
-        let left = data_left.len();
-        let chunk_size = if left >= avail { avail } else { left };
-        let chunk = &data_left[0..chunk_size];
-        let after = avail - chunk_size;
-        if cur_blocks <= cur_block {
-            // Create new block.
-            if cur_offset == 0 && after == 0 {
-                // Fast path.
-                txn.execute(
-                    "INSERT INTO blocks VALUES ($1, $2, $3)",
-                    &[&(ino as i64), &(cur_block as i64), &chunk],
-                )
-            } else {
-                txn.execute(
-                    "INSERT INTO blocks
-                     VALUES ($1, $2, repeat(x'00'::string, $3)::bytes || $4 || repeat(x'00'::string, $5)::bytes)",
-                    &[
-                        &(ino as i64),
-                        &(cur_block as i64),
-                        &(cur_offset as i64),
-                        &chunk,
-                        &(after as i64),
-                    ],
-                )
-            }?;
-            created_blocks = created_blocks + 1;
-        } else {
-            // Modify cur block.
-            txn.execute(
-                "UPDATE blocks
-                 SET bytes = convert_to(substr(convert_from(bytes, 'latin1'), 1, $1), 'latin1') ||
-                             $2 ||
-                             convert_to(substr(convert_from(bytes, 'latin1'), $3+1), 'latin1')
-                 WHERE file_ino = $4 AND block_idx = $5",
-                &[
-                    &(cur_offset as i64),
-                    &chunk,
-                    &(cur_offset + chunk_size as i64),
-                    &(ino as i64),
-                    &(cur_block as i64),
-                ],
-            )?;
+/// Set the block count of ino to the number of blocks it has.
+pub fn fix_block_count<C: GenericConnection>(conn: &C, ino: i64) -> Result<()> {
+    with_retry(|| {
+        conn.execute(
+            "UPDATE inodes
+             SET blocks = (SELECT count(*) FROM blocks WHERE file_ino = inodes.ino)
+             WHERE ino = $1",
+            &[&ino],
+        )
+        .map(|_| ())
+    })
+}
+
+/// Total and available bytes across the cluster's stores. This requires the
+/// privileges needed to read crdb_internal, so failure is expected for
+/// ordinary users.
+pub fn store_capacity<C: GenericConnection>(conn: &C) -> Result<(u64, u64)> {
+    with_retry(|| {
+        let rows = conn.query(
+            "SELECT IFNULL(sum(capacity), 0)::INT8, IFNULL(sum(available), 0)::INT8
+             FROM crdb_internal.kv_store_status",
+            &[],
+        )?;
+        let row = rows.get(0);
+        Ok((row.get::<_, i64>(0) as u64, row.get::<_, i64>(1) as u64))
+    })
+}
+
+/// Names of the tables in TABLES that do not exist in the database.
+pub fn missing_tables<C: GenericConnection>(conn: &C) -> Result<Vec<String>> {
+    with_retry(|| {
+        let present: Vec<String> = conn
+            .query(
+                "SELECT table_name FROM information_schema.tables
+                 WHERE table_catalog = current_database()",
+                &[],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        Ok(TABLES
+            .iter()
+            .filter(|t| !present.iter().any(|p| p == *t))
+            .map(|t| t.to_string())
+            .collect())
+    })
+}
+
+/// The highest allocated inode number and the last value handed out by the
+/// inode allocator.
+pub fn inode_alloc_bounds<C: GenericConnection>(conn: &C) -> Result<(i64, i64)> {
+    with_retry(|| {
+        let max_ino: Option<i64> = conn
+            .query("SELECT max(ino) FROM inodes", &[])?
+            .get(0)
+            .get(0);
+        let last_value: i64 = conn
+            .query("SELECT last_value FROM inode_alloc", &[])?
+            .get(0)
+            .get(0);
+        Ok((max_ino.unwrap_or(0), last_value))
+    })
+}
+
+/// Number of directory entries that refer to an inode that does not exist.
+pub fn count_dangling_dir_ents<C: GenericConnection>(conn: &C) -> Result<i64> {
+    with_retry(|| {
+        count(
+            conn,
+            "SELECT count(*) FROM dir_entries d
+             LEFT JOIN inodes i ON i.ino = d.child_ino
+             WHERE i.ino IS NULL",
+        )
+    })
+}
+
+/// Number of directory entries whose kind disagrees with their inode.
+pub fn count_mismatched_dir_ent_kinds<C: GenericConnection>(conn: &C) -> Result<i64> {
+    with_retry(|| {
+        count(
+            conn,
+            "SELECT count(*) FROM dir_entries d
+             JOIN inodes i ON i.ino = d.child_ino
+             WHERE i.kind != d.child_kind",
+        )
+    })
+}
+
+/// Number of inodes, other than roots, whose link count disagrees with the
+/// number of directory entries that refer to them.
+pub fn count_mismatched_nlinks<C: GenericConnection>(conn: &C, roots: &[i64]) -> Result<i64> {
+    with_retry(|| {
+        conn.query(
+            "SELECT count(*) FROM inodes i
+             LEFT JOIN (SELECT child_ino, count(*) AS n FROM dir_entries GROUP BY child_ino) d
+             ON i.ino = d.child_ino
+             WHERE i.ino != ALL($1) AND i.nlink != IFNULL(d.n, 0)",
+            &[&roots],
+        )
+        .map(|rows| rows.get(0).get(0))
+    })
+}
+
+/// Number of inodes whose block count disagrees with their stored blocks.
+pub fn count_mismatched_blocks<C: GenericConnection>(conn: &C) -> Result<i64> {
+    with_retry(|| {
+        count(
+            conn,
+            "SELECT count(*) FROM inodes i
+             LEFT JOIN (SELECT file_ino, count(*) AS n FROM blocks GROUP BY file_ino) b
+             ON i.ino = b.file_ino
+             WHERE i.blocks != IFNULL(b.n, 0)",
+        )
+    })
+}
+
+/// Number of inodes, other than roots, that no directory entry refers to.
+pub fn count_orphaned_inodes<C: GenericConnection>(conn: &C, roots: &[i64]) -> Result<i64> {
+    with_retry(|| {
+        conn.query(
+            "SELECT count(*) FROM inodes i
+             WHERE i.ino != ALL($1)
+             AND NOT EXISTS (SELECT 1 FROM dir_entries WHERE child_ino = i.ino)",
+            &[&roots],
+        )
+        .map(|rows| rows.get(0).get(0))
+    })
+}
+
+/// Number of directories other than roots that no directory entry refers
+/// to, left behind by mounts that each created their own root.
+pub fn count_extra_roots<C: GenericConnection>(conn: &C, roots: &[i64]) -> Result<i64> {
+    with_retry(|| {
+        conn.query(
+            "SELECT count(*) FROM inodes i
+             WHERE i.ino != ALL($1) AND i.kind = 'S_IFDIR'
+             AND NOT EXISTS (SELECT 1 FROM dir_entries WHERE child_ino = i.ino)",
+            &[&roots],
+        )
+        .map(|rows| rows.get(0).get(0))
+    })
+}
+
+/// Number of blocks that lie entirely beyond the end of their file.
+pub fn count_blocks_past_eof<C: GenericConnection>(conn: &C) -> Result<i64> {
+    with_retry(|| {
+        conn.query(
+            "SELECT count(*) FROM blocks b
+             JOIN inodes i ON i.ino = b.file_ino
+             WHERE b.block_idx * $1 >= i.size",
+            &[&block_size()],
+        )
+        .map(|rows| rows.get(0).get(0))
+    })
+}
+
+/// Delete directory entries that refer to an inode that does not exist.
+pub fn delete_dangling_dir_ents<C: GenericConnection>(conn: &C) -> Result<u64> {
+    with_retry(|| {
+        conn.execute(
+            "DELETE FROM dir_entries d
+             WHERE NOT EXISTS (SELECT 1 FROM inodes WHERE ino = d.child_ino)",
+            &[],
+        )
+    })
+}
+
+/// Make directory entries agree with the kind of their inode.
+pub fn fix_dir_ent_kinds<C: GenericConnection>(conn: &C) -> Result<u64> {
+    with_retry(|| {
+        conn.execute(
+            "UPDATE dir_entries
+             SET child_kind = (SELECT kind FROM inodes WHERE ino = dir_entries.child_ino)
+             WHERE child_kind != (SELECT kind FROM inodes WHERE ino = dir_entries.child_ino)",
+            &[],
+        )
+    })
+}
+
+/// Delete empty directories other than roots that no directory entry refers
+/// to. Returns the number deleted.
+pub fn delete_extra_roots<C: GenericConnection>(conn: &C, roots: &[i64]) -> Result<u64> {
+    with_retry(|| {
+        conn.execute(
+            "DELETE FROM inodes
+             WHERE ino != ALL($1) AND kind = 'S_IFDIR'
+             AND NOT EXISTS (SELECT 1 FROM dir_entries WHERE child_ino = inodes.ino)
+             AND NOT EXISTS (SELECT 1 FROM dir_entries WHERE dir_ino = inodes.ino)",
+            &[&roots],
+        )
+    })
+}
+
+/// Link every inode of the selected filesystem, other than roots, that no
+/// directory entry refers to into directory dir, named after its inode
+/// number. Returns the number linked.
+pub fn link_orphans<C: GenericConnection>(conn: &C, roots: &[i64], dir: u64) -> Result<u64> {
+    with_retry(|| {
+        let txn = conn.transaction()?;
+        let linked = txn.execute(
+            "INSERT INTO dir_entries (dir_ino, child_name, child_kind, child_ino)
+             SELECT $2, convert_to('#' || i.ino::STRING, 'UTF8'), i.kind, i.ino
+             FROM inodes i
+             WHERE i.ino != ALL($1) AND i.ino != $2 AND i.fs_id = $3
+             AND NOT EXISTS (SELECT 1 FROM dir_entries WHERE child_ino = i.ino)",
+            &[&roots, &(dir as i64), &fs_id()],
+        )?;
+        if linked > 0 {
+            touch_dir(&txn, dir)?;
         }
-        cur_block += 1;
-        cur_offset = 0;
-        data_left = &data_left[chunk_size..];
-    }
+        txn.commit()?;
+        Ok(linked)
+    })
+}
 
-    // Update the inode with the new size and block count.
-    let touched_size = offset + data.len() as i64;
-    let new_size = cmp::max(cur_size, touched_size);
-    let new_blocks = cur_blocks + created_blocks as i64;
-    let num_updated = txn.execute(
-        "UPDATE inodes SET size = $1, blocks = $2 WHERE ino = $3",
-        &[&new_size, &new_blocks, &(ino as i64)],
-    )?;
-    if num_updated != 1 {
-        return Ok(None);
+/// Make the link count of every inode other than roots agree with the
+/// directory entries that refer to it.
+pub fn fix_nlinks<C: GenericConnection>(conn: &C, roots: &[i64]) -> Result<u64> {
+    with_retry(|| {
+        conn.execute(
+            "UPDATE inodes
+             SET nlink = (SELECT count(*) FROM dir_entries WHERE child_ino = inodes.ino)
+             WHERE ino != ALL($1)
+             AND nlink != (SELECT count(*) FROM dir_entries WHERE child_ino = inodes.ino)",
+            &[&roots],
+        )
+    })
+}
+
+/// Make the block count of every inode agree with its stored blocks.
+pub fn fix_block_counts<C: GenericConnection>(conn: &C) -> Result<u64> {
+    with_retry(|| {
+        conn.execute(
+            "UPDATE inodes
+             SET blocks = (SELECT count(*) FROM blocks WHERE file_ino = inodes.ino)
+             WHERE blocks != (SELECT count(*) FROM blocks WHERE file_ino = inodes.ino)",
+            &[],
+        )
+    })
+}
+
+fn count<C: GenericConnection>(conn: &C, query: &str) -> Result<i64> {
+    conn.query(query, &[]).map(|rows| rows.get(0).get(0))
+}
+
+/// Run op, retrying it with exponential backoff when CockroachDB asks for its
+/// transaction to be restarted. Only the outermost call retries, since a
+/// transaction can only be restarted from its beginning.
+#[track_caller]
+fn with_retry<T, F: FnMut() -> Result<T>>(mut op: F) -> Result<T> {
+    if RETRYING.with(|r| r.replace(true)) {
+        return op();
     }
+    let caller = Location::caller();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    let res = loop {
+        // Each attempt is traced, named after the statements' place here.
+        let mut span = trace::start(
+            format!("sql {}:{}", caller.file(), caller.line()),
+            trace::KIND_CLIENT,
+        );
+        let res = op();
+        if let Some(ref mut span) = span {
+            span.set_str("db.system", "cockroachdb".to_string());
+            span.set_int("db.attempt", attempt as i64);
+            if let Err(ref err) = res {
+                span.set_error(err.to_string());
+            }
+        }
+        drop(span);
+        match res {
+            Err(ref err) if attempt < MAX_ATTEMPTS && retryable(err) => {
+                RETRIES.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+                attempt += 1;
+            }
+            res => break res,
+        }
+    };
+    RETRYING.with(|r| r.set(false));
+    res
+}
 
-    txn.commit()?;
-    Ok(Some(data.len()))
+/// Whether err means the transaction should be retried from the start.
+pub fn retryable(err: &Error) -> bool {
+    err.code() == Some(&error::T_R_SERIALIZATION_FAILURE)
+}
+
+/// The number of transaction retries since this was last called.
+pub fn take_retries() -> u64 {
+    RETRIES.swap(0, Ordering::Relaxed)
+}
+
+/// The number of transaction retries since take_retries was last called.
+pub fn retries() -> u64 {
+    RETRIES.load(Ordering::Relaxed)
 }
 
 fn row_to_file_attr(row: Row) -> FileAttr {
@@ -470,6 +3289,16 @@ fn row_to_file_attr(row: Row) -> FileAttr {
     }
 }
 
+/// The local lock type for a stored lock, which is recorded as exclusive or
+/// not because the constants differ between platforms.
+fn lock_type(exclusive: bool) -> u32 {
+    if exclusive {
+        F_WRLCK as u32
+    } else {
+        F_RDLCK as u32
+    }
+}
+
 fn file_type_to_str(ft: FileType) -> &'static str {
     match ft {
         FileType::NamedPipe => "S_IFIFO",
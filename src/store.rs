@@ -0,0 +1,473 @@
+//! The storage the FUSE layer keeps the filesystem in.
+//!
+//! The filesystem's metadata (inodes, directory entries, extended attributes,
+//! locks and leases) and its file data are reached through the MetadataStore
+//! and BlockStore traits, so that fs.rs does not depend on how or where they
+//! are stored. A database connection stores them in CockroachDB through the
+//! statements in sql.rs.
+
+use super::error::CrfsError;
+use super::sql::{self, DirEntry, Lock};
+use fuse::{FileAttr, FileType};
+use postgres::{error, Connection, Result};
+use std::collections::HashMap;
+use std::time::Duration;
+use time::Timespec;
+
+/// Inodes, directory entries, extended attributes, locks and leases.
+pub trait MetadataStore {
+    /// The attributes of inode ino, if it exists.
+    fn lookup_inode(&self, ino: u64) -> std::result::Result<Option<FileAttr>, CrfsError>;
+
+    /// The attributes and generation of inode ino, if it exists.
+    fn lookup_inode_generation(
+        &self,
+        ino: u64,
+    ) -> std::result::Result<Option<(FileAttr, u64)>, CrfsError>;
+
+    /// The kind of inode ino, if it exists.
+    fn lookup_inode_kind(&self, ino: u64) -> Result<Option<FileType>>;
+
+    /// The generations of those of inos that exist.
+    fn generations(&self, inos: &[u64]) -> Result<HashMap<u64, u64>>;
+
+    /// An opaque version of inode ino that changes whenever it is written,
+    /// if it exists.
+    fn inode_version(&self, ino: u64) -> Result<Option<String>>;
+
+    /// The attributes and generation of the inode the entry name in parent
+    /// refers to, if there is such an entry.
+    fn lookup_dir_ent(
+        &self,
+        parent: u64,
+        name: &[u8],
+    ) -> std::result::Result<Option<(FileAttr, u64)>, CrfsError>;
+
+    /// List up to limit entries of directory ino after the entry given
+    /// cookie, each with the cookie that resumes the listing after it.
+    fn read_dir_from(&self, ino: u64, cookie: i64, limit: i64) -> Result<Vec<(DirEntry, i64)>>;
+
+    /// Like read_dir_from, but also get the attributes of each entry's inode.
+    fn read_dir_plus_from(
+        &self,
+        ino: u64,
+        cookie: i64,
+        limit: i64,
+    ) -> Result<Vec<((DirEntry, FileAttr), i64)>>;
+
+    /// Create the root directory, owned by uid and gid, unless it exists.
+    /// Returns whether it was created.
+    fn create_root(&self, uid: u32, gid: u32) -> Result<bool>;
+
+    /// Create an inode of kind ft and link it into parent as name.
+    fn create_inode(
+        &self,
+        parent: u64,
+        name: &[u8],
+        ft: FileType,
+        perm: u16,
+        rdev: u32,
+        owner: (u32, u32),
+    ) -> std::result::Result<(FileAttr, u64), CrfsError>;
+
+    /// Create a symbolic link to target and link it into parent as name.
+    fn create_symlink(
+        &self,
+        parent: u64,
+        name: &[u8],
+        target: &str,
+        uid: u32,
+        gid: u32,
+    ) -> std::result::Result<(FileAttr, u64), CrfsError>;
+
+    /// The target of symbolic link ino, if it exists.
+    fn read_symlink(&self, ino: u64) -> Result<Option<String>>;
+
+    /// Change the attributes of inode ino that are given, truncating or
+    /// extending it to size.
+    #[allow(clippy::too_many_arguments)]
+    fn update_inode(
+        &self,
+        ino: u64,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+        chgtime: Option<Timespec>,
+        crtime: Option<Timespec>,
+        kind: Option<FileType>,
+        perm: Option<u16>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        flags: Option<u32>,
+    ) -> std::result::Result<FileAttr, CrfsError>;
+
+    /// Record reads of each inode at its time, where the atime policy asks
+    /// for it.
+    fn update_atimes(&self, atimes: &[(u64, Timespec)], strict: bool) -> Result<u64>;
+
+    /// Link inode ino into parent as newname.
+    fn link(
+        &self,
+        ino: u64,
+        parent: u64,
+        newname: &[u8],
+    ) -> std::result::Result<(FileAttr, u64), CrfsError>;
+
+    /// Remove the entry name from parent, which must be a directory if dir
+    /// is set and must not be one otherwise.
+    fn unlink(&self, parent: u64, name: &[u8], dir: bool) -> std::result::Result<(), CrfsError>;
+
+    /// Move the entry name in parent to new_name in new_parent, replacing
+    /// whatever is there.
+    fn rename_dir_ent(
+        &self,
+        parent: u64,
+        name: &[u8],
+        new_parent: u64,
+        new_name: &[u8],
+    ) -> std::result::Result<(), CrfsError>;
+
+    /// The value of extended attribute name of inode ino, if it is set.
+    fn get_xattr(&self, ino: u64, name: &str) -> Result<Option<Vec<u8>>>;
+
+    /// The names of the extended attributes of inode ino.
+    fn list_xattrs(&self, ino: u64) -> Result<Vec<String>>;
+
+    /// Set extended attribute name of inode ino to value. With create, fail
+    /// with Exists if it is already set. With replace, return false instead
+    /// of setting it if it is not. Fails with NotFound if ino does not exist.
+    fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        create: bool,
+        replace: bool,
+    ) -> std::result::Result<bool, CrfsError>;
+
+    /// Remove extended attribute name of inode ino. Returns whether it was
+    /// set.
+    fn remove_xattr(&self, ino: u64, name: &str) -> Result<bool>;
+
+    /// Number of data blocks and inodes in use.
+    fn usage(&self) -> Result<(u64, u64)>;
+
+    /// Total and available bytes of storage.
+    fn store_capacity(&self) -> Result<(u64, u64)>;
+
+    /// Start a mount session, returning the identifier its locks and leases
+    /// are held under.
+    fn new_session(&self) -> Result<String>;
+
+    /// A lock held by another owner that conflicts with owner taking a lock
+    /// of type typ over start..=end.
+    fn conflicting_lock(
+        &self,
+        ino: u64,
+        session: &str,
+        owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+    ) -> Result<Option<Lock>>;
+
+    /// Take, change or release owner's lock, which expires after lease
+    /// unless renewed. Returns the conflicting lock instead if another owner
+    /// holds one.
+    fn set_lock(
+        &self,
+        ino: u64,
+        session: &str,
+        owner: u64,
+        lock: &Lock,
+        lease: Duration,
+    ) -> Result<Option<Lock>>;
+
+    /// Release every lock owner holds on inode ino.
+    fn release_locks(&self, ino: u64, session: &str, owner: u64) -> Result<u64>;
+
+    /// Extend every lock held through a session to expire after lease.
+    fn renew_locks(&self, session: &str, lease: Duration) -> Result<u64>;
+
+    /// Release every lock held through a session.
+    fn release_session_locks(&self, session: &str) -> Result<u64>;
+
+    /// Take or renew session's write lease on inode ino for duration,
+    /// breaking another session's if force is set. Returns whether session
+    /// holds the lease.
+    fn take_write_lease(
+        &self,
+        ino: u64,
+        session: &str,
+        duration: Duration,
+        force: bool,
+    ) -> Result<bool>;
+
+    /// Give up session's write lease on inode ino.
+    fn release_write_lease(&self, ino: u64, session: &str) -> Result<u64>;
+
+    /// Give up every write lease held through a session.
+    fn release_session_leases(&self, session: &str) -> Result<u64>;
+}
+
+/// File data.
+pub trait BlockStore {
+    /// Up to size bytes of inode ino from offset, fewer at the end of the
+    /// file, or None if the inode does not exist.
+    fn read_data(&self, ino: u64, offset: i64, size: usize) -> Result<Option<Vec<u8>>>;
+
+    /// Write data to inode ino at offset, or at its end if offset is None,
+    /// returning the offset it was written at, or Stale if ino no longer
+    /// exists.
+    fn write_data(
+        &self,
+        ino: u64,
+        offset: Option<i64>,
+        data: &[u8],
+    ) -> std::result::Result<i64, CrfsError>;
+}
+
+/// A connection to the storage, which the FUSE layer holds one of and hands
+/// more of to the pool.
+pub trait Store: MetadataStore + BlockStore + Send + 'static {
+    /// Whether the connection has broken and should be replaced.
+    fn broken(&self) -> bool;
+}
+
+impl MetadataStore for Connection {
+    fn lookup_inode(&self, ino: u64) -> std::result::Result<Option<FileAttr>, CrfsError> {
+        sql::lookup_inode(self, ino)
+    }
+
+    fn lookup_inode_generation(
+        &self,
+        ino: u64,
+    ) -> std::result::Result<Option<(FileAttr, u64)>, CrfsError> {
+        sql::lookup_inode_generation(self, ino)
+    }
+
+    fn lookup_inode_kind(&self, ino: u64) -> Result<Option<FileType>> {
+        sql::lookup_inode_kind(self, ino)
+    }
+
+    fn generations(&self, inos: &[u64]) -> Result<HashMap<u64, u64>> {
+        sql::generations(self, inos)
+    }
+
+    fn inode_version(&self, ino: u64) -> Result<Option<String>> {
+        sql::inode_version(self, ino)
+    }
+
+    fn lookup_dir_ent(
+        &self,
+        parent: u64,
+        name: &[u8],
+    ) -> std::result::Result<Option<(FileAttr, u64)>, CrfsError> {
+        sql::lookup_dir_ent(self, parent, name)
+    }
+
+    fn read_dir_from(&self, ino: u64, cookie: i64, limit: i64) -> Result<Vec<(DirEntry, i64)>> {
+        sql::read_dir_from(self, ino, cookie, limit)
+    }
+
+    fn read_dir_plus_from(
+        &self,
+        ino: u64,
+        cookie: i64,
+        limit: i64,
+    ) -> Result<Vec<((DirEntry, FileAttr), i64)>> {
+        sql::read_dir_plus_from(self, ino, cookie, limit)
+    }
+
+    fn create_root(&self, uid: u32, gid: u32) -> Result<bool> {
+        sql::create_root(self, uid, gid)
+    }
+
+    fn create_inode(
+        &self,
+        parent: u64,
+        name: &[u8],
+        ft: FileType,
+        perm: u16,
+        rdev: u32,
+        owner: (u32, u32),
+    ) -> std::result::Result<(FileAttr, u64), CrfsError> {
+        sql::create_inode(self, parent, name, ft, perm, rdev, owner)
+    }
+
+    fn create_symlink(
+        &self,
+        parent: u64,
+        name: &[u8],
+        target: &str,
+        uid: u32,
+        gid: u32,
+    ) -> std::result::Result<(FileAttr, u64), CrfsError> {
+        sql::create_symlink(self, parent, name, target, uid, gid)
+    }
+
+    fn read_symlink(&self, ino: u64) -> Result<Option<String>> {
+        sql::read_symlink(self, ino)
+    }
+
+    fn update_inode(
+        &self,
+        ino: u64,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+        chgtime: Option<Timespec>,
+        crtime: Option<Timespec>,
+        kind: Option<FileType>,
+        perm: Option<u16>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        flags: Option<u32>,
+    ) -> std::result::Result<FileAttr, CrfsError> {
+        sql::update_inode(
+            self, ino, size, atime, mtime, chgtime, crtime, kind, perm, uid, gid, flags,
+        )
+    }
+
+    fn update_atimes(&self, atimes: &[(u64, Timespec)], strict: bool) -> Result<u64> {
+        sql::update_atimes(self, atimes, strict)
+    }
+
+    fn link(
+        &self,
+        ino: u64,
+        parent: u64,
+        newname: &[u8],
+    ) -> std::result::Result<(FileAttr, u64), CrfsError> {
+        sql::link(self, ino, parent, newname)
+    }
+
+    fn unlink(&self, parent: u64, name: &[u8], dir: bool) -> std::result::Result<(), CrfsError> {
+        sql::unlink(self, parent, name, dir)
+    }
+
+    fn rename_dir_ent(
+        &self,
+        parent: u64,
+        name: &[u8],
+        new_parent: u64,
+        new_name: &[u8],
+    ) -> std::result::Result<(), CrfsError> {
+        sql::rename_dir_ent(self, parent, name, new_parent, new_name)
+    }
+
+    fn get_xattr(&self, ino: u64, name: &str) -> Result<Option<Vec<u8>>> {
+        sql::get_xattr(self, ino, name)
+    }
+
+    fn list_xattrs(&self, ino: u64) -> Result<Vec<String>> {
+        sql::list_xattrs(self, ino)
+    }
+
+    fn set_xattr(
+        &self,
+        ino: u64,
+        name: &str,
+        value: &[u8],
+        create: bool,
+        replace: bool,
+    ) -> std::result::Result<bool, CrfsError> {
+        sql::set_xattr(self, ino, name, value, create, replace).map_err(|err| {
+            if err.code() == Some(&error::FOREIGN_KEY_VIOLATION) {
+                CrfsError::NotFound
+            } else {
+                err.into()
+            }
+        })
+    }
+
+    fn remove_xattr(&self, ino: u64, name: &str) -> Result<bool> {
+        sql::remove_xattr(self, ino, name)
+    }
+
+    fn usage(&self) -> Result<(u64, u64)> {
+        sql::usage(self)
+    }
+
+    fn store_capacity(&self) -> Result<(u64, u64)> {
+        sql::store_capacity(self)
+    }
+
+    fn new_session(&self) -> Result<String> {
+        sql::new_session(self)
+    }
+
+    fn conflicting_lock(
+        &self,
+        ino: u64,
+        session: &str,
+        owner: u64,
+        start: u64,
+        end: u64,
+        typ: u32,
+    ) -> Result<Option<Lock>> {
+        sql::conflicting_lock(self, ino, session, owner, start, end, typ)
+    }
+
+    fn set_lock(
+        &self,
+        ino: u64,
+        session: &str,
+        owner: u64,
+        lock: &Lock,
+        lease: Duration,
+    ) -> Result<Option<Lock>> {
+        sql::set_lock(self, ino, session, owner, lock, lease)
+    }
+
+    fn release_locks(&self, ino: u64, session: &str, owner: u64) -> Result<u64> {
+        sql::release_locks(self, ino, session, owner)
+    }
+
+    fn renew_locks(&self, session: &str, lease: Duration) -> Result<u64> {
+        sql::renew_locks(self, session, lease)
+    }
+
+    fn release_session_locks(&self, session: &str) -> Result<u64> {
+        sql::release_session_locks(self, session)
+    }
+
+    fn take_write_lease(
+        &self,
+        ino: u64,
+        session: &str,
+        duration: Duration,
+        force: bool,
+    ) -> Result<bool> {
+        sql::take_write_lease(self, ino, session, duration, force)
+    }
+
+    fn release_write_lease(&self, ino: u64, session: &str) -> Result<u64> {
+        sql::release_write_lease(self, ino, session)
+    }
+
+    fn release_session_leases(&self, session: &str) -> Result<u64> {
+        sql::release_session_leases(self, session)
+    }
+}
+
+impl BlockStore for Connection {
+    fn read_data(&self, ino: u64, offset: i64, size: usize) -> Result<Option<Vec<u8>>> {
+        sql::read_data(self, ino, offset, size)
+    }
+
+    fn write_data(
+        &self,
+        ino: u64,
+        offset: Option<i64>,
+        data: &[u8],
+    ) -> std::result::Result<i64, CrfsError> {
+        sql::write_data(self, ino, offset, data)
+    }
+}
+
+impl Store for Connection {
+    fn broken(&self) -> bool {
+        self.is_desynchronized()
+    }
+}
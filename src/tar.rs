@@ -0,0 +1,184 @@
+//! Writing of tar archives in the ustar format, with the GNU extensions for
+//! names and link targets longer than it allows and for sizes and ids larger
+//! than its octal fields hold.
+
+use std::io::{self, Write};
+
+const BLOCK: usize = 512;
+
+/// The kinds of entry in an archive.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    File,
+    HardLink,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Directory,
+    Fifo,
+}
+
+impl Kind {
+    fn flag(self) -> u8 {
+        match self {
+            Kind::File => b'0',
+            Kind::HardLink => b'1',
+            Kind::Symlink => b'2',
+            Kind::CharDevice => b'3',
+            Kind::BlockDevice => b'4',
+            Kind::Directory => b'5',
+            Kind::Fifo => b'6',
+        }
+    }
+}
+
+pub struct Header<'a> {
+    pub path: &'a [u8],
+    pub kind: Kind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Bytes of data that follow the header, for files.
+    pub size: u64,
+    /// Seconds since the epoch.
+    pub mtime: i64,
+    /// Target of a link.
+    pub link: &'a [u8],
+    /// Device number of a device, as the kernel encodes it for FUSE.
+    pub rdev: u32,
+}
+
+/// Writes an archive to out, one entry at a time.
+pub struct Writer<W: Write> {
+    out: W,
+    /// Data still to be written for the current entry.
+    remaining: u64,
+    /// Padding due after the current entry's data.
+    padding: usize,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W) -> Self {
+        Writer {
+            out,
+            remaining: 0,
+            padding: 0,
+        }
+    }
+
+    /// Start an entry. Its data, header.size bytes of it for a file, is then
+    /// written with write_data, and the entry ended with end_entry.
+    pub fn start_entry(&mut self, header: &Header) -> io::Result<()> {
+        if header.path.len() > 100 {
+            self.long_name(b'L', header.path)?;
+        }
+        if header.link.len() > 100 {
+            self.long_name(b'K', header.link)?;
+        }
+        let size = if header.kind == Kind::File {
+            header.size
+        } else {
+            0
+        };
+        let mut block = [0u8; BLOCK];
+        put_bytes(&mut block[0..100], header.path);
+        put_num(&mut block[100..108], u64::from(header.mode & 0o7777));
+        put_num(&mut block[108..116], u64::from(header.uid));
+        put_num(&mut block[116..124], u64::from(header.gid));
+        put_num(&mut block[124..136], size);
+        put_num(&mut block[136..148], header.mtime.max(0) as u64);
+        block[156] = header.kind.flag();
+        put_bytes(&mut block[157..257], header.link);
+        if header.kind == Kind::CharDevice || header.kind == Kind::BlockDevice {
+            let major = (header.rdev >> 8) & 0xfff;
+            let minor = (header.rdev & 0xff) | ((header.rdev >> 12) & 0xfff00);
+            put_num(&mut block[329..337], u64::from(major));
+            put_num(&mut block[337..345], u64::from(minor));
+        }
+        self.write_block(block)?;
+        self.remaining = size;
+        self.padding = padding(size);
+        Ok(())
+    }
+
+    /// Write data of the current entry, up to what its header declared.
+    /// Returns the number of bytes written.
+    pub fn write_data(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(self.remaining as usize);
+        self.out.write_all(&data[..len])?;
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+
+    /// End the current entry, filling whatever of its data was not written
+    /// with zeroes.
+    pub fn end_entry(&mut self) -> io::Result<()> {
+        let zeroes = [0u8; BLOCK];
+        while self.remaining > 0 {
+            let len = self.remaining.min(BLOCK as u64) as usize;
+            self.out.write_all(&zeroes[..len])?;
+            self.remaining -= len as u64;
+        }
+        self.out.write_all(&zeroes[..self.padding])?;
+        self.padding = 0;
+        Ok(())
+    }
+
+    /// End the archive, returning what it was written to.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0u8; 2 * BLOCK])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Write a GNU entry giving the name or link target of the next entry.
+    fn long_name(&mut self, flag: u8, name: &[u8]) -> io::Result<()> {
+        let mut block = [0u8; BLOCK];
+        put_bytes(&mut block[0..100], b"././@LongLink");
+        put_num(&mut block[100..108], 0o644);
+        put_num(&mut block[108..116], 0);
+        put_num(&mut block[116..124], 0);
+        put_num(&mut block[124..136], name.len() as u64 + 1);
+        put_num(&mut block[136..148], 0);
+        block[156] = flag;
+        self.write_block(block)?;
+        self.out.write_all(name)?;
+        self.out
+            .write_all(&[0u8; BLOCK][..padding(name.len() as u64 + 1) + 1])
+    }
+
+    /// Write a header block, after filling in its magic and checksum.
+    fn write_block(&mut self, mut block: [u8; BLOCK]) -> io::Result<()> {
+        block[257..265].copy_from_slice(b"ustar  \0");
+        block[148..156].copy_from_slice(b"        ");
+        let sum: u64 = block.iter().map(|&b| u64::from(b)).sum();
+        block[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        self.out.write_all(&block)
+    }
+}
+
+/// Bytes needed to fill size bytes of data out to a whole block.
+fn padding(size: u64) -> usize {
+    (BLOCK - (size % BLOCK as u64) as usize) % BLOCK
+}
+
+/// Copy bytes into field, truncating them if they do not fit.
+fn put_bytes(field: &mut [u8], bytes: &[u8]) {
+    let len = bytes.len().min(field.len());
+    field[..len].copy_from_slice(&bytes[..len]);
+}
+
+/// Write n into field in octal, or in GNU base-256 if it does not fit.
+fn put_num(field: &mut [u8], n: u64) {
+    let digits = field.len() - 1;
+    let octal = format!("{:0width$o}", n, width = digits);
+    if octal.len() == digits {
+        field[..digits].copy_from_slice(octal.as_bytes());
+        field[digits] = 0;
+    } else {
+        for (i, b) in field.iter_mut().rev().enumerate() {
+            *b = if i < 8 { (n >> (8 * i)) as u8 } else { 0 };
+        }
+        field[0] |= 0x80;
+    }
+}
@@ -0,0 +1,176 @@
+//! Authentication tokens (such as JWTs for CockroachDB Cloud SQL users) that
+//! are fetched from an external source and refreshed before they expire.
+
+use log::warn;
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long before its expiry a token is considered stale and refetched.
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// Where to obtain a token from.
+pub enum Source {
+    /// A file containing the token, kept up to date by something else.
+    File(PathBuf),
+    /// A shell command that prints a fresh token.
+    Command(String),
+}
+
+pub struct Token {
+    source: Source,
+    /// The last token fetched and when it expires, if known.
+    cached: RefCell<Option<(String, Option<SystemTime>)>>,
+}
+
+impl Token {
+    pub fn new(source: Source) -> Token {
+        Token {
+            source,
+            cached: RefCell::new(None),
+        }
+    }
+
+    /// A token that will remain valid for at least REFRESH_MARGIN, fetching
+    /// a new one if the cached token is missing or about to expire. Tokens
+    /// whose expiry cannot be determined are refetched every time.
+    pub fn get(&self) -> io::Result<String> {
+        if let Some((ref token, Some(exp))) = *self.cached.borrow() {
+            if SystemTime::now() + REFRESH_MARGIN < exp {
+                return Ok(token.clone());
+            }
+        }
+        let token = self.fetch()?;
+        let exp = jwt_expiry(&token);
+        if let Some(exp) = exp {
+            if exp <= SystemTime::now() {
+                warn!("fetched an authentication token that has already expired");
+            }
+        }
+        *self.cached.borrow_mut() = Some((token.clone(), exp));
+        Ok(token)
+    }
+
+    fn fetch(&self) -> io::Result<String> {
+        let token = match self.source {
+            Source::File(ref path) => fs::read_to_string(path)?,
+            Source::Command(ref cmd) => {
+                let out = Command::new("sh").arg("-c").arg(cmd).output()?;
+                if !out.status.success() {
+                    return Err(io::Error::other(format!(
+                        "token command failed with {}: {}",
+                        out.status,
+                        String::from_utf8_lossy(&out.stderr).trim()
+                    )));
+                }
+                String::from_utf8(out.stdout)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            }
+        };
+        Ok(token.trim().to_string())
+    }
+}
+
+/// The expiry of a JWT, read from the exp claim of its payload without
+/// verifying its signature. An expiry too far off to represent is treated as
+/// unknown.
+fn jwt_expiry(token: &str) -> Option<SystemTime> {
+    let payload = base64url_decode(token.split('.').nth(1)?)?;
+    let payload = String::from_utf8(payload).ok()?;
+    let rest = &payload[payload.find("\"exp\"")? + 5..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+    let secs = digits.parse().ok()?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// Decode unpadded base64url, as used by JWTs.
+fn base64url_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in s.trim_end_matches('=').bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' | b'+' => 62,
+            b'_' | b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "eyJhbGciOiJub25lIn0";
+
+    fn jwt(payload: &str) -> String {
+        format!("{}.{}.sig", HEADER, payload)
+    }
+
+    #[test]
+    fn base64url() {
+        assert_eq!(base64url_decode("aGVsbG8").unwrap(), b"hello");
+        assert_eq!(base64url_decode("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(base64url_decode("aGk_").unwrap(), b"hi?");
+        assert_eq!(base64url_decode("-_8").unwrap(), b"\xfb\xff");
+        assert_eq!(base64url_decode("").unwrap(), b"");
+        assert_eq!(base64url_decode("aGVs bG8"), None);
+        assert_eq!(base64url_decode("aGVs.bG8"), None);
+        assert_eq!(base64url_decode("aGVsbG8*"), None);
+    }
+
+    #[test]
+    fn expiry() {
+        // {"sub":"fs","exp": 1700000000}
+        let token = jwt("eyJzdWIiOiJmcyIsImV4cCI6IDE3MDAwMDAwMDB9");
+        assert_eq!(
+            jwt_expiry(&token),
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        // {"sub":"fs"}
+        assert_eq!(jwt_expiry(&jwt("eyJzdWIiOiJmcyJ9")), None);
+        // {"exp":18446744073709551615}
+        assert_eq!(
+            jwt_expiry(&jwt("eyJleHAiOjE4NDQ2NzQ0MDczNzA5NTUxNjE1fQ")),
+            None
+        );
+        assert_eq!(jwt_expiry("not a jwt"), None);
+        assert_eq!(jwt_expiry(&jwt("!!!")), None);
+    }
+
+    #[test]
+    fn expired_tokens_are_refetched() {
+        let path = std::env::temp_dir().join(format!("crfs-token-{}", std::process::id()));
+        let token = Token::new(Source::File(path.clone()));
+
+        // {"exp":1}
+        let expired = jwt("eyJleHAiOjF9");
+        fs::write(&path, format!("{}\n", expired)).unwrap();
+        assert_eq!(token.get().unwrap(), expired);
+
+        // {"exp":4102444800}
+        let fresh = jwt("eyJleHAiOjQxMDI0NDQ4MDB9");
+        fs::write(&path, &fresh).unwrap();
+        assert_eq!(token.get().unwrap(), fresh);
+
+        // The fresh token is cached until it is about to expire.
+        fs::write(&path, &expired).unwrap();
+        assert_eq!(token.get().unwrap(), fresh);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+//! Export of operation traces to an OpenTelemetry collector.
+//!
+//! Each FUSE operation is a span, with a child span for each attempt at a
+//! database operation made on its behalf, so that a slow operation can be
+//! traced to the statements it waited on. Finished spans are batched and sent
+//! to the collector with OTLP over HTTP, encoded as JSON.
+
+use super::logging::json_string;
+use log::warn;
+use std::cell::Cell;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Most spans sent to the collector at once, and the longest a finished span
+/// waits to be sent.
+const EXPORT_BATCH: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Name the filesystem reports itself as to the collector.
+const SERVICE_NAME: &str = "cockroachfs";
+
+/// OTLP span kinds.
+pub const KIND_INTERNAL: u8 = 1;
+pub const KIND_SERVER: u8 = 2;
+pub const KIND_CLIENT: u8 = 3;
+
+/// Where finished spans are sent, once tracing has been enabled.
+static EXPORTER: OnceLock<Mutex<Sender<Finished>>> = OnceLock::new();
+
+/// State of the generator of trace and span ids.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The span the current thread is working on behalf of.
+    static CURRENT: Cell<Option<Context>> = const { Cell::new(None) };
+}
+
+/// Identifies a span within its trace.
+#[derive(Clone, Copy)]
+pub struct Context {
+    trace_id: u128,
+    span_id: u64,
+}
+
+enum Attr {
+    Int(i64),
+    Str(String),
+}
+
+struct Finished {
+    ctx: Context,
+    parent: Option<u64>,
+    name: String,
+    kind: u8,
+    start: SystemTime,
+    duration: Duration,
+    attrs: Vec<(&'static str, Attr)>,
+    error: Option<String>,
+}
+
+/// A span that is open until dropped, while it is the current thread's
+/// current span.
+pub struct Span {
+    ctx: Context,
+    parent: Option<Context>,
+    name: String,
+    kind: u8,
+    start: SystemTime,
+    started: Instant,
+    attrs: Vec<(&'static str, Attr)>,
+    error: Option<String>,
+}
+
+/// Send traces to the collector at endpoint, as in http://localhost:4318.
+pub fn init(endpoint: &str) -> io::Result<()> {
+    let rest = endpoint.strip_prefix("http://").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported OTLP endpoint {:?}: must be http://", endpoint),
+        )
+    })?;
+    let (addr, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+        None => (rest, ""),
+    };
+    let (addr, path) = (addr.to_string(), format!("{}/v1/traces", path));
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    NEXT_ID.store(
+        seed ^ ((std::process::id() as u64) << 32),
+        Ordering::Relaxed,
+    );
+
+    let (spans, finished) = mpsc::channel();
+    thread::spawn(move || export(&addr, &path, finished));
+    let _ = EXPORTER.set(Mutex::new(spans));
+    Ok(())
+}
+
+/// Start a span named name as a child of the current thread's span, or as
+/// the root of a new trace if there is none. Returns None unless tracing is
+/// enabled.
+pub fn start(name: String, kind: u8) -> Option<Span> {
+    EXPORTER.get()?;
+    let parent = CURRENT.with(|current| current.get());
+    let ctx = Context {
+        trace_id: match parent {
+            Some(parent) => parent.trace_id,
+            None => (u128::from(next_id()) << 64) | u128::from(next_id()),
+        },
+        span_id: next_id(),
+    };
+    CURRENT.with(|current| current.set(Some(ctx)));
+    Some(Span {
+        ctx,
+        parent,
+        name,
+        kind,
+        start: SystemTime::now(),
+        started: Instant::now(),
+        attrs: Vec::new(),
+        error: None,
+    })
+}
+
+/// The current thread's span, to continue on another thread with enter.
+pub fn current() -> Option<Context> {
+    CURRENT.with(|current| current.get())
+}
+
+/// Make ctx the current thread's span until the returned guard is dropped.
+pub fn enter(ctx: Option<Context>) -> Entered {
+    Entered {
+        outer: CURRENT.with(|current| current.replace(ctx)),
+    }
+}
+
+/// Restores the span that was current before enter when dropped.
+pub struct Entered {
+    outer: Option<Context>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.outer));
+    }
+}
+
+impl Span {
+    pub fn set_int(&mut self, key: &'static str, value: i64) {
+        self.attrs.push((key, Attr::Int(value)));
+    }
+
+    pub fn set_str(&mut self, key: &'static str, value: String) {
+        self.attrs.push((key, Attr::Str(value)));
+    }
+
+    /// Mark the span as failed with message.
+    pub fn set_error(&mut self, message: String) {
+        self.error = Some(message);
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.parent));
+        let finished = Finished {
+            ctx: self.ctx,
+            parent: self.parent.map(|parent| parent.span_id),
+            name: std::mem::take(&mut self.name),
+            kind: self.kind,
+            start: self.start,
+            duration: self.started.elapsed(),
+            attrs: std::mem::take(&mut self.attrs),
+            error: self.error.take(),
+        };
+        if let Some(exporter) = EXPORTER.get() {
+            let _ = exporter.lock().unwrap().send(finished);
+        }
+    }
+}
+
+/// A new id, from a splitmix64 sequence.
+fn next_id() -> u64 {
+    let mut z = NEXT_ID
+        .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Send finished spans to the collector at addr in batches, until tracing
+/// stops.
+fn export(addr: &str, path: &str, finished: Receiver<Finished>) {
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now() + EXPORT_INTERVAL;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let done = match finished.recv_timeout(timeout) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        if batch.len() >= EXPORT_BATCH || Instant::now() >= deadline || done {
+            if !batch.is_empty() {
+                if let Err(err) = post(addr, path, &encode(&batch)) {
+                    warn!("trace export: dropped {} spans: {}", batch.len(), err);
+                }
+                batch.clear();
+            }
+            deadline = Instant::now() + EXPORT_INTERVAL;
+        }
+        if done {
+            return;
+        }
+    }
+}
+
+/// Encode spans as an OTLP trace export request.
+fn encode(spans: &[Finished]) -> String {
+    let mut body = format!(
+        "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[\
+         {{\"key\":\"service.name\",\"value\":{{\"stringValue\":{}}}}}]}},\
+         \"scopeSpans\":[{{\"scope\":{{\"name\":{}}},\"spans\":[",
+        json_string(SERVICE_NAME),
+        json_string(SERVICE_NAME)
+    );
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            body.push(',');
+        }
+        let start = span
+            .start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let _ = write!(
+            body,
+            "{{\"traceId\":\"{:032x}\",\"spanId\":\"{:016x}\",",
+            span.ctx.trace_id, span.ctx.span_id
+        );
+        if let Some(parent) = span.parent {
+            let _ = write!(body, "\"parentSpanId\":\"{:016x}\",", parent);
+        }
+        let _ = write!(
+            body,
+            "\"name\":{},\"kind\":{},\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\
+             \"attributes\":[",
+            json_string(&span.name),
+            span.kind,
+            start,
+            start + span.duration.as_nanos()
+        );
+        for (j, (key, value)) in span.attrs.iter().enumerate() {
+            if j > 0 {
+                body.push(',');
+            }
+            let value = match value {
+                Attr::Int(n) => format!("{{\"intValue\":\"{}\"}}", n),
+                Attr::Str(s) => format!("{{\"stringValue\":{}}}", json_string(s)),
+            };
+            let _ = write!(body, "{{\"key\":{},\"value\":{}}}", json_string(key), value);
+        }
+        body.push(']');
+        if let Some(ref message) = span.error {
+            let _ = write!(
+                body,
+                ",\"status\":{{\"code\":2,\"message\":{}}}",
+                json_string(message)
+            );
+        }
+        body.push('}');
+    }
+    body.push_str("]}]}]}");
+    body
+}
+
+/// POST a JSON body to path on the HTTP server at addr.
+fn post(addr: &str, path: &str, body: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(EXPORT_INTERVAL))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        addr,
+        body.len(),
+        body
+    )?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "collector replied {:?}",
+            status.trim()
+        ))),
+    }
+}
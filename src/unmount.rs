@@ -0,0 +1,101 @@
+//! Unmounting on termination signals, so that the filesystem shuts down
+//! cleanly rather than leaving the mountpoint wedged after the process dies.
+//!
+//! The first SIGHUP, SIGINT or SIGTERM unmounts the filesystem, which ends
+//! the FUSE session once the kernel lets go of it; a second one unmounts it
+//! even if it is busy, and a third one kills the process outright.
+
+use libc::{c_int, c_void, SIGHUP, SIGINT, SIGTERM, SIG_DFL};
+use log::{info, warn};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::thread;
+
+/// Write end of the pipe that the signal handler hands signals over on.
+static SIGNALS: AtomicI32 = AtomicI32::new(-1);
+
+/// Number of termination signals received.
+static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+/// Unmount the filesystem at mountpoint when the process receives a
+/// termination signal.
+pub fn unmount_on_signal(mountpoint: &Path) -> io::Result<()> {
+    let path = mountpoint.canonicalize()?;
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let signals = unsafe { File::from_raw_fd(fds[0]) };
+    SIGNALS.store(fds[1], Ordering::SeqCst);
+    thread::spawn(move || unmount_on(signals, path));
+    for &sig in &[SIGHUP, SIGINT, SIGTERM] {
+        unsafe {
+            libc::signal(
+                sig,
+                handle_signal as extern "C" fn(c_int) as libc::sighandler_t,
+            )
+        };
+    }
+    Ok(())
+}
+
+/// Unmount path for each signal read from signals, forcefully after the
+/// first.
+fn unmount_on(mut signals: File, path: PathBuf) {
+    let mut sig = [0u8];
+    let mut force = false;
+    while signals.read_exact(&mut sig).is_ok() {
+        info!("received signal {}, unmounting {}", sig[0], path.display());
+        if let Err(err) = unmount(&path, force) {
+            warn!("unmount {}: {}", path.display(), err);
+        }
+        force = true;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn unmount(path: &Path, force: bool) -> io::Result<()> {
+    // Only fusermount may unmount the filesystem unless running as root. A
+    // lazy unmount detaches a busy mountpoint at once.
+    let status = std::process::Command::new("fusermount")
+        .arg(if force { "-uz" } else { "-u" })
+        .arg(path)
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("fusermount {}", status)));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmount(path: &Path, force: bool) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let flags = if force { libc::MNT_FORCE } else { 0 };
+    if unsafe { libc::unmount(cpath.as_ptr(), flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+extern "C" fn handle_signal(sig: c_int) {
+    unsafe {
+        if RECEIVED.fetch_add(1, Ordering::SeqCst) >= 2 {
+            // Die the way we would have without the handler installed.
+            libc::signal(sig, SIG_DFL);
+            libc::raise(sig);
+            return;
+        }
+        let byte = sig as u8;
+        libc::write(
+            SIGNALS.load(Ordering::SeqCst),
+            &byte as *const u8 as *const c_void,
+            1,
+        );
+    }
+}
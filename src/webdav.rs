@@ -0,0 +1,653 @@
+//! A WebDAV frontend to the filesystem, for machines that cannot mount it
+//! but whose file managers can open WebDAV network locations. The root of
+//! the filesystem is served at /, directories as collections and regular
+//! files as resources; other kinds of file are left out.
+//!
+//! Compliance classes 1 and 2 are advertised, because Finder and Explorer
+//! only write to servers that support locks, but locks are granted without
+//! being recorded or enforced. Properties set with PROPPATCH are answered
+//! as set and dropped.
+//!
+//! Requests are not authenticated. Every client may read and write the whole
+//! filesystem as the server's owner, so it should only listen where that is
+//! acceptable.
+
+use super::error::CrfsError;
+use super::http::{
+    self, escape, etag, http_time, iso_time, percent_encode, Body, Request, Response,
+};
+use super::pool::Pool;
+use super::sql;
+use fuse::{FileAttr, FileType};
+use log::warn;
+use postgres::{Connection, Result};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Read};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Directory entries read per query.
+const DIR_BATCH: i64 = 1000;
+
+/// Longest PROPPATCH body read.
+const MAX_PROPPATCH: u64 = 64 << 10;
+
+const ALLOW: &str =
+    "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, COPY, MOVE, PROPFIND, PROPPATCH, LOCK, UNLOCK";
+
+/// Serve requests accepted on listener from the pool's connections,
+/// creating directories and files owned by owner.
+pub fn serve(listener: TcpListener, pool: Pool, owner: (u32, u32)) -> io::Result<()> {
+    http::serve(
+        listener,
+        pool,
+        Arc::new(move |conn, req, body| {
+            route(conn, req, body, owner).unwrap_or_else(|err| error_response(&err))
+        }),
+    )
+}
+
+fn route(
+    conn: &Connection,
+    req: &Request,
+    body: &mut dyn Read,
+    owner: (u32, u32),
+) -> Result<Response> {
+    let names = match split_path(&req.path) {
+        Some(names) => names,
+        None => return Ok(status(400)),
+    };
+    match req.method.as_str() {
+        "OPTIONS" => {
+            let mut res = status(200);
+            res.headers.push(("DAV", "1, 2".to_string()));
+            res.headers.push(("MS-Author-Via", "DAV".to_string()));
+            res.headers.push(("Allow", ALLOW.to_string()));
+            Ok(res)
+        }
+        "PROPFIND" => propfind(conn, req, &names),
+        "PROPPATCH" => proppatch(conn, &names, body),
+        "GET" | "HEAD" => get(conn, req, &names),
+        "PUT" => put(conn, req, &names, body, owner),
+        "DELETE" => delete(conn, &names),
+        "MKCOL" => mkcol(conn, req, &names, owner),
+        "COPY" | "MOVE" => copy_or_move(conn, req, &names),
+        "LOCK" => lock(conn, req, &names, owner),
+        "UNLOCK" => Ok(status(204)),
+        _ => {
+            let mut res = status(405);
+            res.headers.push(("Allow", ALLOW.to_string()));
+            Ok(res)
+        }
+    }
+}
+
+fn status(status: u16) -> Response {
+    Response::new(status, Body::Empty)
+}
+
+fn error_response(err: &postgres::Error) -> Response {
+    if sql::quota_exceeded(err) {
+        return status(507);
+    }
+    // Errors reading the request's body reach here by way of the writes they
+    // interrupt.
+    if err.as_io().is_some() {
+        return status(400);
+    }
+    warn!("webdav: {}", err);
+    Response::new(500, Body::Text("text/plain", err.to_string()))
+}
+
+/// The names along path, or None if it is not an absolute path to a file.
+fn split_path(path: &[u8]) -> Option<Vec<&[u8]>> {
+    if !path.starts_with(b"/") {
+        return None;
+    }
+    let names: Vec<&[u8]> = path
+        .split(|&b| b == b'/')
+        .filter(|n| !n.is_empty())
+        .collect();
+    if names.iter().any(|&n| n == b"." || n == b"..") {
+        return None;
+    }
+    Some(names)
+}
+
+/// The file at the end of names.
+fn resolve(conn: &Connection, names: &[&[u8]]) -> Result<Option<FileAttr>> {
+    let mut attr = match sql::lookup_inode(conn, sql::root())? {
+        Some(attr) => attr,
+        None => return Ok(None),
+    };
+    for name in names {
+        if attr.kind != FileType::Directory {
+            return Ok(None);
+        }
+        attr = match sql::lookup_dir_ent(conn, attr.ino, name)? {
+            Some((attr, _)) => attr,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(attr))
+}
+
+/// The directory holding the file at the end of names, and the file's name,
+/// or None if there is no such directory.
+fn resolve_parent<'a>(conn: &Connection, names: &[&'a [u8]]) -> Result<Option<(u64, &'a [u8])>> {
+    let (name, parents) = match names.split_last() {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    Ok(resolve(conn, parents)?
+        .filter(|attr| attr.kind == FileType::Directory)
+        .map(|attr| (attr.ino, *name)))
+}
+
+/// Every entry of directory ino that is served.
+fn read_dir(conn: &Connection, ino: u64) -> Result<Vec<(Vec<u8>, FileAttr)>> {
+    let mut ents = Vec::new();
+    let mut after = Vec::new();
+    loop {
+        let batch = sql::read_dir_plus(conn, ino, &after, DIR_BATCH)?;
+        let full = batch.len() as i64 == DIR_BATCH;
+        if let Some((last, _)) = batch.last() {
+            after = last.child_name.clone();
+        }
+        ents.extend(
+            batch
+                .into_iter()
+                .filter(|(ent, attr)| {
+                    !ent.child_name.starts_with(http::UPLOAD_PREFIX.as_bytes())
+                        && (attr.kind == FileType::Directory || attr.kind == FileType::RegularFile)
+                })
+                .map(|(ent, attr)| (ent.child_name, attr)),
+        );
+        if !full {
+            return Ok(ents);
+        }
+    }
+}
+
+/// The URL path of the file at the end of names.
+fn href(names: &[&[u8]], attr: &FileAttr) -> String {
+    let mut path = Vec::new();
+    for name in names {
+        path.push(b'/');
+        path.extend_from_slice(name);
+    }
+    if attr.kind == FileType::Directory || path.is_empty() {
+        path.push(b'/');
+    }
+    percent_encode(&path)
+}
+
+fn propfind(conn: &Connection, req: &Request, names: &[&[u8]]) -> Result<Response> {
+    let attr = match resolve(conn, names)? {
+        Some(attr) => attr,
+        None => return Ok(status(404)),
+    };
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">",
+    );
+    let name = names.last().copied().unwrap_or(b"");
+    write_props(&mut xml, &href(names, &attr), name, &attr);
+    // Depth infinity is served as depth 1, as servers may.
+    if attr.kind == FileType::Directory && req.header("depth") != Some("0") {
+        for (child_name, child) in read_dir(conn, attr.ino)? {
+            let mut child_names = names.to_vec();
+            child_names.push(&child_name);
+            write_props(&mut xml, &href(&child_names, &child), &child_name, &child);
+        }
+    }
+    xml.push_str("</D:multistatus>");
+    Ok(Response::new(
+        207,
+        Body::Text("application/xml; charset=utf-8", xml),
+    ))
+}
+
+/// Write the response giving the properties of the file attr describes,
+/// which every PROPFIND gets in full whatever it asked for.
+fn write_props(xml: &mut String, href: &str, name: &[u8], attr: &FileAttr) {
+    let _ = write!(
+        xml,
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>",
+        escape(href),
+        escape(&String::from_utf8_lossy(name))
+    );
+    if attr.kind == FileType::Directory {
+        xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        let _ = write!(
+            xml,
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>application/octet-stream</D:getcontenttype><D:getetag>{}</D:getetag>",
+            attr.size,
+            escape(&etag(attr))
+        );
+    }
+    let _ = write!(
+        xml,
+        "<D:getlastmodified>{}</D:getlastmodified><D:creationdate>{}</D:creationdate><D:supportedlock><D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry></D:supportedlock></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        http_time(attr.mtime),
+        iso_time(attr.crtime)
+    );
+}
+
+/// Answer that every property the body sets or removes was, without keeping
+/// any of them. Explorer fails copies whose timestamps it cannot set.
+fn proppatch(conn: &Connection, names: &[&[u8]], body: &mut dyn Read) -> Result<Response> {
+    let attr = match resolve(conn, names)? {
+        Some(attr) => attr,
+        None => return Ok(status(404)),
+    };
+    let mut text = String::new();
+    if body.take(MAX_PROPPATCH).read_to_string(&mut text).is_err() {
+        return Ok(status(400));
+    }
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\"><D:response><D:href>{}</D:href><D:propstat><D:prop>",
+        escape(&href(names, &attr))
+    );
+    for (name, ns) in patched_props(&text) {
+        let _ = write!(xml, "<P:{} xmlns:P=\"{}\"/>", name, escape(&ns));
+    }
+    xml.push_str(
+        "</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response></D:multistatus>",
+    );
+    Ok(Response::new(
+        207,
+        Body::Text("application/xml; charset=utf-8", xml),
+    ))
+}
+
+/// The local names and namespaces of the properties in a PROPPATCH body:
+/// the elements directly within its prop elements. Namespace prefixes are
+/// taken to mean the same throughout the body, which they do in practice.
+fn patched_props(body: &str) -> Vec<(String, String)> {
+    let mut namespaces = HashMap::new();
+    let mut tags = Vec::new();
+    for tag in body.split('<').skip(1) {
+        let tag = match tag.find('>') {
+            Some(idx) => &tag[..idx],
+            None => continue,
+        };
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        for attr in tag.split_whitespace().skip(1) {
+            if let Some(decl) = attr.strip_prefix("xmlns") {
+                if let Some(idx) = decl.find('=') {
+                    let prefix = decl[..idx].trim_start_matches(':').to_string();
+                    let uri = decl[idx + 1..].trim_end_matches('/');
+                    let uri = uri.trim_matches(|c| c == '"' || c == '\'');
+                    namespaces.insert(prefix, uri.to_string());
+                }
+            }
+        }
+        tags.push(tag);
+    }
+    let local = |qname: &str| qname.rsplit(':').next().unwrap_or("").to_string();
+    let mut props = Vec::new();
+    let mut open: Vec<String> = Vec::new();
+    for tag in tags {
+        if let Some(end) = tag.strip_prefix('/') {
+            if open.last().map(|q| q.as_str()) == Some(end.trim()) {
+                open.pop();
+            }
+            continue;
+        }
+        let qname = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+        if open.last().map(|q| local(q)).as_deref() == Some("prop") {
+            let prefix = match qname.find(':') {
+                Some(idx) => &qname[..idx],
+                None => "",
+            };
+            let ns = namespaces.get(prefix).cloned().unwrap_or_default();
+            props.push((local(qname), ns));
+        }
+        if !tag.ends_with('/') {
+            open.push(qname.to_string());
+        }
+    }
+    props
+}
+
+fn get(conn: &Connection, req: &Request, names: &[&[u8]]) -> Result<Response> {
+    let attr = match resolve(conn, names)? {
+        Some(attr) => attr,
+        None => return Ok(status(404)),
+    };
+    if attr.kind == FileType::Directory {
+        return index(conn, names, &attr);
+    }
+    if attr.kind != FileType::RegularFile {
+        return Ok(status(403));
+    }
+    let mut res = Response::new(
+        200,
+        Body::File {
+            ino: attr.ino,
+            start: 0,
+            len: attr.size,
+        },
+    );
+    if let Some(range) = req.header("range") {
+        let (start, len) = match http::parse_range(range, attr.size) {
+            Some(range) => range,
+            None => {
+                let mut res = status(416);
+                res.headers
+                    .push(("Content-Range", format!("bytes */{}", attr.size)));
+                return Ok(res);
+            }
+        };
+        res.status = 206;
+        res.headers.push((
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, start + len - 1, attr.size),
+        ));
+        res.body = Body::File {
+            ino: attr.ino,
+            start,
+            len,
+        };
+    }
+    res.headers
+        .push(("Content-Type", "application/octet-stream".to_string()));
+    res.headers.push(("Last-Modified", http_time(attr.mtime)));
+    res.headers.push(("ETag", etag(&attr)));
+    res.headers.push(("Accept-Ranges", "bytes".to_string()));
+    Ok(res)
+}
+
+/// A page linking to the entries of a directory, for browsers.
+fn index(conn: &Connection, names: &[&[u8]], attr: &FileAttr) -> Result<Response> {
+    let title = format!("/{}", String::from_utf8_lossy(&names.join(&b'/')));
+    let title = escape(&title);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head><body><h1>{0}</h1><ul>",
+        title
+    );
+    if !names.is_empty() {
+        html.push_str("<li><a href=\"../\">../</a></li>");
+    }
+    for (name, child) in read_dir(conn, attr.ino)? {
+        let mut display = String::from_utf8_lossy(&name).into_owned();
+        let mut link = percent_encode(&name);
+        if child.kind == FileType::Directory {
+            display.push('/');
+            link.push('/');
+        }
+        let _ = write!(
+            html,
+            "<li><a href=\"{}\">{}</a></li>",
+            escape(&link),
+            escape(&display)
+        );
+    }
+    html.push_str("</ul></body></html>\n");
+    Ok(Response::new(
+        200,
+        Body::Text("text/html; charset=utf-8", html),
+    ))
+}
+
+/// Store the request's body as the file at the end of names, replacing any
+/// file there. Like the S3 gateway, the body is written aside first, so that
+/// readers never see a partial file.
+fn put(
+    conn: &Connection,
+    req: &Request,
+    names: &[&[u8]],
+    body: &mut dyn Read,
+    owner: (u32, u32),
+) -> Result<Response> {
+    let (dir, name) = match resolve_parent(conn, names)? {
+        Some(parent) => parent,
+        None => return Ok(status(409)),
+    };
+    if !http::framed(req) {
+        return Ok(status(411));
+    }
+    let existed = match sql::lookup_dir_ent(conn, dir, name)? {
+        Some((attr, _)) if attr.kind == FileType::Directory => return Ok(status(405)),
+        existing => existing.is_some(),
+    };
+    match http::put_file(conn, dir, name, owner, body) {
+        Ok(()) if existed => Ok(status(204)),
+        Ok(()) => Ok(status(201)),
+        Err(CrfsError::IsDir) => Ok(status(405)),
+        Err(err) => conflict(err),
+    }
+}
+
+fn delete(conn: &Connection, names: &[&[u8]]) -> Result<Response> {
+    let (dir, name) = match resolve_parent(conn, names)? {
+        Some(parent) => parent,
+        // The root cannot be deleted.
+        None if names.is_empty() => return Ok(status(403)),
+        None => return Ok(status(404)),
+    };
+    match sql::lookup_dir_ent(conn, dir, name)? {
+        Some((attr, _)) => match remove(conn, dir, name, &attr) {
+            Ok(()) => Ok(status(204)),
+            Err(CrfsError::NotFound) => Ok(status(404)),
+            Err(err) => conflict(err),
+        },
+        None => Ok(status(404)),
+    }
+}
+
+/// The response to an operation that failed with err, which is 409 unless
+/// the database failed it.
+fn conflict(err: CrfsError) -> Result<Response> {
+    match err {
+        CrfsError::QuotaExceeded(_)
+        | CrfsError::Retryable(_)
+        | CrfsError::Unavailable(_)
+        | CrfsError::Backend(_) => Err(err.into()),
+        _ => Ok(status(409)),
+    }
+}
+
+/// Remove name, which attr describes, from directory dir, along with
+/// everything beneath it if it is a directory.
+fn remove(
+    conn: &Connection,
+    dir: u64,
+    name: &[u8],
+    attr: &FileAttr,
+) -> std::result::Result<(), CrfsError> {
+    let is_dir = attr.kind == FileType::Directory;
+    if is_dir {
+        loop {
+            let ents = sql::read_dir_plus(conn, attr.ino, b"", DIR_BATCH)?;
+            if ents.is_empty() {
+                break;
+            }
+            for (ent, child) in ents {
+                match remove(conn, attr.ino, &ent.child_name, &child) {
+                    Ok(()) | Err(CrfsError::NotFound) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+    }
+    sql::unlink(conn, dir, name, is_dir)
+}
+
+fn mkcol(conn: &Connection, req: &Request, names: &[&[u8]], owner: (u32, u32)) -> Result<Response> {
+    if req
+        .header("content-length")
+        .is_some_and(|length| length != "0")
+        || req.header("transfer-encoding").is_some()
+    {
+        return Ok(status(415));
+    }
+    let (dir, name) = match resolve_parent(conn, names)? {
+        Some(parent) => parent,
+        None if names.is_empty() => return Ok(status(405)),
+        None => return Ok(status(409)),
+    };
+    if sql::lookup_dir_ent(conn, dir, name)?.is_some() {
+        return Ok(status(405));
+    }
+    sql::create_inode(conn, dir, name, FileType::Directory, 0o755, 0, owner)?;
+    Ok(status(201))
+}
+
+/// The path of the Destination header of req, which may be a full URL.
+fn destination(req: &Request) -> Option<Vec<u8>> {
+    let dest = req.header("destination")?;
+    let path = match dest.find("://") {
+        Some(idx) => {
+            let rest = &dest[idx + 3..];
+            &rest[rest.find('/')?..]
+        }
+        None => dest,
+    };
+    let path = path.split('?').next().unwrap_or("");
+    Some(http::percent_decode(path.as_bytes()))
+}
+
+fn copy_or_move(conn: &Connection, req: &Request, names: &[&[u8]]) -> Result<Response> {
+    let dest = match destination(req) {
+        Some(dest) => dest,
+        None => return Ok(status(400)),
+    };
+    let dest_names = match split_path(&dest) {
+        Some(dest_names) => dest_names,
+        None => return Ok(status(400)),
+    };
+    if dest_names == names {
+        return Ok(status(403));
+    }
+    if dest_names.starts_with(names) {
+        // A directory cannot be copied or moved into itself.
+        return Ok(status(409));
+    }
+    let (src_dir, src_name) = match resolve_parent(conn, names)? {
+        Some(parent) => parent,
+        None if names.is_empty() => return Ok(status(403)),
+        None => return Ok(status(404)),
+    };
+    let src = match sql::lookup_dir_ent(conn, src_dir, src_name)? {
+        Some((src, _)) => src,
+        None => return Ok(status(404)),
+    };
+    let (dest_dir, dest_name) = match resolve_parent(conn, &dest_names)? {
+        Some(parent) => parent,
+        None if dest_names.is_empty() => return Ok(status(403)),
+        None => return Ok(status(409)),
+    };
+    let existing = match sql::lookup_dir_ent(conn, dest_dir, dest_name)? {
+        Some(_) if req.header("overwrite") == Some("F") => return Ok(status(412)),
+        Some((attr, _)) => Some(attr),
+        None => None,
+    };
+    let done = if req.method == "MOVE" {
+        // The rename replaces the destination atomically, unless it is a
+        // directory with entries or of another kind than the source, which
+        // has to be removed first.
+        let mut res = sql::rename_dir_ent(conn, src_dir, src_name, dest_dir, dest_name);
+        if let (Err(CrfsError::IsDir | CrfsError::NotDir | CrfsError::NotEmpty), Some(attr)) =
+            (&res, &existing)
+        {
+            if let Err(err) = remove(conn, dest_dir, dest_name, attr) {
+                return conflict(err);
+            }
+            res = sql::rename_dir_ent(conn, src_dir, src_name, dest_dir, dest_name);
+        }
+        match res {
+            Ok(()) => true,
+            Err(err) => return conflict(err),
+        }
+    } else {
+        if let Some(ref attr) = existing {
+            if let Err(err) = remove(conn, dest_dir, dest_name, attr) {
+                return conflict(err);
+            }
+        }
+        let recurse = req.header("depth") != Some("0");
+        copy(conn, &src, dest_dir, dest_name, recurse)?
+    };
+    match (done, existing.is_some()) {
+        (true, true) => Ok(status(204)),
+        (true, false) => Ok(status(201)),
+        (false, _) => Ok(status(409)),
+    }
+}
+
+/// Copy the file src describes into directory dir as name, cloning regular
+/// files rather than copying their data. Returns false if src was removed
+/// meanwhile or is not served.
+fn copy(conn: &Connection, src: &FileAttr, dir: u64, name: &[u8], recurse: bool) -> Result<bool> {
+    match src.kind {
+        FileType::Directory => {}
+        FileType::RegularFile => return Ok(sql::clone_file(conn, src.ino, dir, name)?.is_some()),
+        _ => return Ok(false),
+    }
+    let (copied, _) = sql::create_inode(
+        conn,
+        dir,
+        name,
+        FileType::Directory,
+        src.perm,
+        0,
+        (src.uid, src.gid),
+    )?;
+    if recurse {
+        for (child_name, child) in read_dir(conn, src.ino)? {
+            copy(conn, &child, copied.ino, &child_name, recurse)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Grant a lock that is neither recorded nor enforced, creating an empty
+/// file if nothing is at the end of names, as a LOCK of an unmapped URL
+/// does.
+fn lock(conn: &Connection, req: &Request, names: &[&[u8]], owner: (u32, u32)) -> Result<Response> {
+    let (attr, created) = match resolve(conn, names)? {
+        Some(attr) => (attr, false),
+        None => match resolve_parent(conn, names)? {
+            Some((dir, name)) => {
+                let (attr, _) =
+                    sql::create_inode(conn, dir, name, FileType::RegularFile, 0o644, 0, owner)?;
+                (attr, true)
+            }
+            None => return Ok(status(409)),
+        },
+    };
+    // A refresh names the lock in its If header.
+    let token = match req
+        .header("if")
+        .and_then(|cond| cond.find("opaquelocktoken:").map(|idx| &cond[idx..]))
+    {
+        Some(token) => token.split(['>', ')']).next().unwrap_or(token).to_string(),
+        None => {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            format!("opaquelocktoken:{:032x}-{:x}", nanos, attr.ino)
+        }
+    };
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery><D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:exclusive/></D:lockscope><D:depth>infinity</D:depth><D:timeout>Second-3600</D:timeout><D:locktoken><D:href>{0}</D:href></D:locktoken><D:lockroot><D:href>{1}</D:href></D:lockroot></D:activelock></D:lockdiscovery></D:prop>",
+        escape(&token),
+        escape(&href(names, &attr))
+    );
+    let mut res = Response::new(
+        if created { 201 } else { 200 },
+        Body::Text("application/xml; charset=utf-8", xml),
+    );
+    res.headers.push(("Lock-Token", format!("<{}>", token)));
+    Ok(res)
+}
@@ -0,0 +1,92 @@
+//! Encoding and decoding of the XDR data representation (RFC 4506) that ONC
+//! RPC protocols such as NFS use for their messages.
+
+use std::io;
+
+/// Decodes values from a message, in order.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.fixed(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok((u64::from(self.u32()?) << 32) | u64::from(self.u32()?))
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u32()? != 0)
+    }
+
+    /// Fixed-length opaque data of len bytes.
+    pub fn fixed(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let padded = len + pad(len);
+        if self.buf.len() < padded {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated XDR message",
+            ));
+        }
+        let (data, rest) = self.buf.split_at(padded);
+        self.buf = rest;
+        Ok(&data[..len])
+    }
+
+    /// Variable-length opaque data, which strings are as well.
+    pub fn opaque(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.fixed(len)
+    }
+}
+
+/// Encodes values into a message, in order.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u32(&mut self, n: u32) {
+        self.buf.extend_from_slice(&n.to_be_bytes());
+    }
+
+    pub fn u64(&mut self, n: u64) {
+        self.buf.extend_from_slice(&n.to_be_bytes());
+    }
+
+    pub fn bool(&mut self, b: bool) {
+        self.u32(b as u32);
+    }
+
+    pub fn fixed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        self.buf.extend_from_slice(&[0; 3][..pad(data.len())]);
+    }
+
+    pub fn opaque(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.fixed(data);
+    }
+
+    /// Bytes written so far.
+    pub fn written(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Bytes of padding after len bytes of opaque data.
+fn pad(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
@@ -0,0 +1,130 @@
+//! A cluster and a mount of the filesystem to test against, shared by the
+//! tests and benchmarks that need them.
+
+// Not every test or benchmark that includes this uses all of it.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the node to serve and the filesystem to mount.
+const STARTUP: Duration = Duration::from_secs(60);
+
+/// A single-node CockroachDB cluster, stopped when dropped. It is started
+/// from the binary given by COCKROACH [cockroach], and keeps its data in
+/// memory.
+pub struct Node {
+    pub url: String,
+    pid_file: PathBuf,
+}
+
+impl Node {
+    pub fn start(dir: &Path) -> Node {
+        let cockroach = env::var("COCKROACH").unwrap_or_else(|_| "cockroach".to_string());
+        let port = free_port();
+        let pid_file = dir.join("cockroach.pid");
+        run(Command::new(&cockroach).args([
+            "start-single-node",
+            "--insecure",
+            "--background",
+            "--store=type=mem,size=2GiB",
+            &format!("--listen-addr=127.0.0.1:{}", port),
+            "--http-addr=127.0.0.1:0",
+            &format!("--pid-file={}", pid_file.display()),
+        ]));
+        let node = Node {
+            url: format!(
+                "postgres://root@127.0.0.1:{}/cockroachfs?sslmode=disable",
+                port
+            ),
+            pid_file,
+        };
+        run(Command::new(&cockroach).args([
+            "sql",
+            "--insecure",
+            &format!("--host=127.0.0.1:{}", port),
+            "-e",
+            "CREATE DATABASE IF NOT EXISTS cockroachfs",
+        ]));
+        node
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if let Ok(pid) = fs::read_to_string(&self.pid_file) {
+            let _ = Command::new("kill").arg(pid.trim()).status();
+        }
+    }
+}
+
+/// The filesystem mounted at a mountpoint, unmounted when dropped.
+pub struct Mount {
+    mountpoint: PathBuf,
+    child: Child,
+}
+
+impl Mount {
+    /// Mount the filesystem at url with the mount options given by args,
+    /// logging to log.
+    pub fn start(crfs: &Path, url: &str, mountpoint: &Path, log: &Path, args: &[&str]) -> Mount {
+        fs::create_dir_all(mountpoint).unwrap();
+        let log = fs::File::create(log).unwrap();
+        let child = Command::new(crfs)
+            .args(["--url", url, "mount", "--mountpoint"])
+            .arg(mountpoint)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(log)
+            .spawn()
+            .expect("starting the mount");
+        let mount = Mount {
+            mountpoint: mountpoint.to_path_buf(),
+            child,
+        };
+        let started = Instant::now();
+        while !mounted(mountpoint) {
+            assert!(started.elapsed() < STARTUP, "the filesystem did not mount");
+            thread::sleep(Duration::from_millis(100));
+        }
+        mount
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        let _ = Command::new("fusermount")
+            .arg("-u")
+            .arg(&self.mountpoint)
+            .status();
+        let _ = self.child.wait();
+    }
+}
+
+/// Whether a filesystem is mounted at path.
+fn mounted(path: &Path) -> bool {
+    Command::new("mountpoint")
+        .arg("-q")
+        .arg(path)
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// A TCP port on the loopback interface that nothing is listening on.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .unwrap()
+}
+
+/// Run cmd, panicking unless it succeeds.
+pub fn run(cmd: &mut Command) {
+    let status = cmd.status().expect("running a command");
+    assert!(status.success(), "{:?} failed: {}", cmd, status);
+}
@@ -0,0 +1,268 @@
+//! POSIX compliance of a mounted filesystem, as measured by pjdfstest and a
+//! subset of xfstests. Runs only with `cargo test --features compliance`,
+//! since it needs FUSE, root, a `cockroach` binary and built checkouts of
+//! the suites, configured by:
+//!
+//!     COCKROACH        cockroach binary to start a node with [cockroach]
+//!     PJDFSTEST_DIR    pjdfstest checkout; not run if unset
+//!     XFSTESTS_DIR     xfstests checkout; not run if unset
+//!     XFSTESTS_TESTS   xfstests to run [the generic tests of TESTS below]
+//!
+//! A fresh single-node cluster is started in memory for each run. pjdfstest
+//! runs against a mount the harness makes itself, while xfstests mounts and
+//! unmounts the filesystem on its own, through a mount.fuse.crfs helper that
+//! must be installed in /sbin; the harness writes one to install.
+//!
+//! Failing tests are reported rather than failing the run, since the point
+//! is to track which behaviors pass as features land. Set
+//! COMPLIANCE_STRICT to fail the run on any failing test.
+//!
+//! Behaviors the suites do not cover, such as squashing, are checked
+//! directly against mounts of their own.
+
+#![cfg(feature = "compliance")]
+
+mod common;
+
+use common::{run, Mount, Node};
+use libc::EACCES;
+use std::env;
+use std::fs;
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::process::{self, Command};
+
+/// xfstests run unless XFSTESTS_TESTS says otherwise: generic tests of
+/// ordinary file and directory operations that need no scratch device.
+const TESTS: &[&str] = &[
+    "generic/001",
+    "generic/002",
+    "generic/005",
+    "generic/006",
+    "generic/007",
+    "generic/011",
+    "generic/013",
+    "generic/014",
+    "generic/020",
+    "generic/023",
+    "generic/024",
+    "generic/028",
+    "generic/035",
+    "generic/037",
+    "generic/069",
+    "generic/070",
+    "generic/080",
+    "generic/087",
+    "generic/088",
+    "generic/091",
+    "generic/112",
+    "generic/117",
+    "generic/124",
+    "generic/126",
+    "generic/131",
+    "generic/184",
+    "generic/193",
+    "generic/221",
+    "generic/236",
+    "generic/258",
+    "generic/294",
+    "generic/306",
+    "generic/308",
+    "generic/309",
+    "generic/313",
+    "generic/337",
+    "generic/377",
+    "generic/426",
+    "generic/434",
+    "generic/478",
+];
+
+#[test]
+fn compliance() {
+    let dir = env::temp_dir().join(format!("crfs-compliance-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let node = Node::start(&dir);
+    let crfs = Path::new(env!("CARGO_BIN_EXE_cockroach_fuse"));
+    run(Command::new(crfs).args(["--url", &node.url, "init"]));
+
+    let mut failures = Vec::new();
+    match env::var_os("PJDFSTEST_DIR") {
+        Some(suite) => failures.extend(pjdfstest(crfs, &node.url, &dir, Path::new(&suite))),
+        None => println!("pjdfstest: not run, PJDFSTEST_DIR is unset"),
+    }
+    match env::var_os("XFSTESTS_DIR") {
+        Some(suite) => failures.extend(xfstests(crfs, &node.url, &dir, Path::new(&suite))),
+        None => println!("xfstests: not run, XFSTESTS_DIR is unset"),
+    }
+
+    drop(node);
+    let _ = fs::remove_dir_all(&dir);
+    if !failures.is_empty() && env::var_os("COMPLIANCE_STRICT").is_some() {
+        panic!("{} tests failed: {}", failures.len(), failures.join(" "));
+    }
+}
+
+/// On a shared mount with --root-squash, root is treated as the anonymous
+/// user when its permissions are checked as well as when it creates files.
+#[test]
+fn root_squash() {
+    let dir = env::temp_dir().join(format!("crfs-squash-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let node = Node::start(&dir);
+    let crfs = Path::new(env!("CARGO_BIN_EXE_cockroach_fuse"));
+    run(Command::new(crfs).args(["--url", &node.url, "init"]));
+    let plain = Mount::start(
+        crfs,
+        &node.url,
+        &dir.join("plain"),
+        &dir.join("plain.log"),
+        &["--allow-other"],
+    );
+    let squashed = Mount::start(
+        crfs,
+        &node.url,
+        &dir.join("squashed"),
+        &dir.join("squashed.log"),
+        &["--allow-other", "--root-squash"],
+    );
+
+    let shared = dir.join("plain/shared");
+    fs::create_dir(&shared).unwrap();
+    fs::set_permissions(&shared, fs::Permissions::from_mode(0o777)).unwrap();
+    let private = shared.join("private");
+    fs::write(&private, "secret").unwrap();
+    fs::set_permissions(&private, fs::Permissions::from_mode(0o600)).unwrap();
+    unix_fs::chown(&private, Some(1000), Some(1000)).unwrap();
+
+    let err = fs::read(dir.join("squashed/shared/private")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(EACCES));
+    fs::write(dir.join("squashed/shared/new"), "").unwrap();
+    let new = fs::metadata(shared.join("new")).unwrap();
+    assert_eq!((new.uid(), new.gid()), (65534, 65534));
+
+    drop(squashed);
+    drop(plain);
+    drop(node);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Run pjdfstest against a mount at dir/mnt, returning the test files that
+/// failed.
+fn pjdfstest(crfs: &Path, url: &str, dir: &Path, suite: &Path) -> Vec<String> {
+    let mountpoint = dir.join("mnt");
+    let mount = Mount::start(
+        crfs,
+        url,
+        &mountpoint,
+        &dir.join("mount.log"),
+        &["--allow-other"],
+    );
+    let output = Command::new("prove")
+        .arg("-r")
+        .arg(suite.join("tests"))
+        .current_dir(&mountpoint)
+        .output()
+        .expect("running prove");
+    drop(mount);
+
+    // prove prints a line for each test file ending in "ok", or in "Failed
+    // n/m subtests" and similar for those that did not pass.
+    let mut passed = 0;
+    let mut failed = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let (file, result) = match line.split_once(" .. ") {
+            Some((file, result)) => (file.trim_end_matches('.').trim(), result.trim()),
+            None => continue,
+        };
+        if result == "ok" || result.starts_with("skipped") {
+            passed += 1;
+        } else {
+            failed.push(format!("{} ({})", file, result));
+        }
+    }
+    report("pjdfstest", passed, &failed);
+    failed
+}
+
+/// Run the selected xfstests, which mount the filesystem themselves through
+/// the mount.fuse.crfs helper, returning the tests that failed.
+fn xfstests(crfs: &Path, url: &str, dir: &Path, suite: &Path) -> Vec<String> {
+    let helper = dir.join("mount.fuse.crfs");
+    write_executable(
+        &helper,
+        &format!(
+            "#!/bin/sh\n\
+             # Mount the filesystem at $2 for xfstests, in the background.\n\
+             {crfs} --url '{url}' mount --mountpoint \"$2\" >>'{log}' 2>&1 &\n\
+             while ! mountpoint -q \"$2\"; do sleep 0.1; done\n",
+            crfs = crfs.display(),
+            url = url,
+            log = dir.join("xfstests-mount.log").display(),
+        ),
+    );
+    if !Path::new("/sbin/mount.fuse.crfs").exists() {
+        println!(
+            "xfstests: not run, install {} as /sbin/mount.fuse.crfs first",
+            helper.display()
+        );
+        return Vec::new();
+    }
+
+    let test_dir = dir.join("xfstests");
+    fs::create_dir_all(&test_dir).unwrap();
+    let config = dir.join("xfstests.config");
+    fs::write(
+        &config,
+        format!(
+            "export FSTYP=fuse\n\
+             export FUSE_SUBTYP=.crfs\n\
+             export TEST_DEV=crfs\n\
+             export TEST_DIR={}\n",
+            test_dir.display()
+        ),
+    )
+    .unwrap();
+    let tests: Vec<String> = match env::var("XFSTESTS_TESTS") {
+        Ok(tests) => tests.split_whitespace().map(String::from).collect(),
+        Err(_) => TESTS.iter().map(|t| t.to_string()).collect(),
+    };
+    let output = Command::new("./check")
+        .arg("-fuse")
+        .args(&tests)
+        .env("HOST_OPTIONS", &config)
+        .current_dir(suite)
+        .output()
+        .expect("running xfstests");
+    let _ = Command::new("fusermount").arg("-u").arg(&test_dir).status();
+
+    // check ends with "Failures: t1 t2 ..." when any test failed, and
+    // "Not run: ..." for those that do not apply.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let list = |prefix: &str| -> Vec<String> {
+        stdout
+            .lines()
+            .filter_map(|line| line.strip_prefix(prefix))
+            .flat_map(|tests| tests.split_whitespace().map(String::from))
+            .collect()
+    };
+    let failed = list("Failures:");
+    let not_run = list("Not run:");
+    let passed = tests.len().saturating_sub(failed.len() + not_run.len());
+    report("xfstests", passed, &failed);
+    if !not_run.is_empty() {
+        println!("xfstests: {} not run: {}", not_run.len(), not_run.join(" "));
+    }
+    failed
+}
+
+fn report(suite: &str, passed: usize, failed: &[String]) {
+    println!("{}: {} passed, {} failed", suite, passed, failed.len());
+    for test in failed {
+        println!("  {}", test);
+    }
+}
+
+fn write_executable(path: &Path, contents: &str) {
+    fs::write(path, contents).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
@@ -0,0 +1,361 @@
+//! Random sequences of operations, run against a mounted filesystem and a
+//! simple in-memory model of one, which must agree on the result of every
+//! operation, errnos included, and on what the tree holds at the end. Runs
+//! only with `cargo test --features model`, since it needs FUSE and a
+//! `cockroach` binary, configured by:
+//!
+//!     COCKROACH        cockroach binary to start a node with [cockroach]
+//!     PROPTEST_CASES   sequences to run [256]
+//!
+//! Each sequence starts in an empty directory of its own. Paths are drawn
+//! from a handful of names, two deep at most, so that operations often meet
+//! what earlier ones left behind. A sequence on which the two disagree is
+//! shrunk to a minimal one before it is reported.
+
+#![cfg(feature = "model")]
+
+mod common;
+
+use common::{run, Mount, Node};
+use libc::{c_int, EEXIST, EINVAL, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::sample::select;
+use proptest::test_runner::{Config, TestRunner};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The names paths are made of.
+const NAMES: &[&str] = &["a", "b", "c"];
+
+/// Bounds on where writes start and how long they are, chosen to cross a
+/// few blocks of the default size.
+const MAX_OFFSET: u64 = 20000;
+const MAX_WRITE: usize = 10000;
+
+/// Longest sequence of operations.
+const MAX_OPS: usize = 40;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Create(String),
+    Mkdir(String),
+    /// Write len bytes of a value at an offset.
+    Write(String, u64, usize, u8),
+    Truncate(String, u64),
+    Read(String),
+    ReadDir(String),
+    Rename(String, String),
+    Unlink(String),
+    Rmdir(String),
+}
+
+/// The contents of a file, as runs of a byte value and their lengths, which
+/// are short since every write is of a single value.
+type Runs = Vec<(u8, usize)>;
+
+/// The observable result of an operation.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Done,
+    Data(Runs),
+    Entries(Vec<String>),
+    Errno(c_int),
+}
+
+/// What a path in the tree holds.
+#[derive(Debug, PartialEq)]
+enum Contents {
+    Dir,
+    File(Runs),
+}
+
+#[test]
+fn model() {
+    let dir = env::temp_dir().join(format!("crfs-model-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let node = Node::start(&dir);
+    let crfs = Path::new(env!("CARGO_BIN_EXE_cockroach_fuse"));
+    run(Command::new(crfs).args(["--url", &node.url, "init"]));
+    let mountpoint = dir.join("mnt");
+    let mount = Mount::start(crfs, &node.url, &mountpoint, &dir.join("mount.log"), &[]);
+
+    let mut runner = TestRunner::new(Config {
+        failure_persistence: None,
+        ..Config::default()
+    });
+    let cases = AtomicUsize::new(0);
+    let result = runner.run(&vec(op(), 1..MAX_OPS), |ops| {
+        let root = mountpoint.join(format!("case-{}", cases.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir(&root).unwrap();
+        let mut model = Model::default();
+        for (i, op) in ops.iter().enumerate() {
+            let want = model.apply(op);
+            let got = apply(&root, op);
+            prop_assert_eq!(got, want, "operation {}, {:?}", i, op);
+        }
+        prop_assert_eq!(tree(&root), model.tree(), "the tree at the end");
+        Ok(())
+    });
+
+    drop(mount);
+    drop(node);
+    let _ = fs::remove_dir_all(&dir);
+    if let Err(err) = result {
+        panic!("{}", err);
+    }
+}
+
+fn path() -> impl Strategy<Value = String> {
+    vec(select(NAMES), 1..=2).prop_map(|names| names.join("/"))
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        3 => path().prop_map(Op::Create),
+        2 => path().prop_map(Op::Mkdir),
+        3 => (path(), 0..MAX_OFFSET, 1..MAX_WRITE, any::<u8>())
+            .prop_map(|(path, offset, len, byte)| Op::Write(path, offset, len, byte)),
+        1 => (path(), 0..MAX_OFFSET + MAX_WRITE as u64)
+            .prop_map(|(path, size)| Op::Truncate(path, size)),
+        2 => path().prop_map(Op::Read),
+        1 => prop_oneof![Just(String::new()), path()].prop_map(Op::ReadDir),
+        2 => (path(), path()).prop_map(|(from, to)| Op::Rename(from, to)),
+        1 => path().prop_map(Op::Unlink),
+        1 => path().prop_map(Op::Rmdir),
+    ]
+}
+
+/// Run op on the filesystem, with paths relative to root.
+fn apply(root: &Path, op: &Op) -> Outcome {
+    let write = |path: &str| OpenOptions::new().write(true).open(root.join(path));
+    let result = match op {
+        Op::Create(path) => OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(root.join(path))
+            .map(|_| Outcome::Done),
+        Op::Mkdir(path) => fs::create_dir(root.join(path)).map(|_| Outcome::Done),
+        Op::Write(path, offset, len, byte) => write(path)
+            .and_then(|file| file.write_all_at(&vec![*byte; *len], *offset))
+            .map(|_| Outcome::Done),
+        Op::Truncate(path, size) => write(path)
+            .and_then(|file| file.set_len(*size))
+            .map(|_| Outcome::Done),
+        Op::Read(path) => fs::read(root.join(path)).map(|data| Outcome::Data(runs(&data))),
+        Op::ReadDir(path) => entries(&root.join(path)).map(Outcome::Entries),
+        Op::Rename(from, to) => fs::rename(root.join(from), root.join(to)).map(|_| Outcome::Done),
+        Op::Unlink(path) => fs::remove_file(root.join(path)).map(|_| Outcome::Done),
+        Op::Rmdir(path) => fs::remove_dir(root.join(path)).map(|_| Outcome::Done),
+    };
+    result.unwrap_or_else(|err| Outcome::Errno(err.raw_os_error().expect("an errno")))
+}
+
+/// The names in the directory at path, in order.
+fn entries(path: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path)? {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Everything beneath root on the filesystem, by path relative to root.
+fn tree(root: &Path) -> BTreeMap<String, Contents> {
+    fn walk(dir: &Path, prefix: &str, tree: &mut BTreeMap<String, Contents>) {
+        for name in entries(dir).unwrap() {
+            let path = dir.join(&name);
+            let key = format!("{}{}", prefix, name);
+            if fs::symlink_metadata(&path).unwrap().is_dir() {
+                walk(&path, &format!("{}/", key), tree);
+                tree.insert(key, Contents::Dir);
+            } else {
+                tree.insert(key, Contents::File(runs(&fs::read(&path).unwrap())));
+            }
+        }
+    }
+    let mut tree = BTreeMap::new();
+    walk(root, "", &mut tree);
+    tree
+}
+
+fn runs(data: &[u8]) -> Runs {
+    let mut runs: Runs = Vec::new();
+    for &byte in data {
+        match runs.last_mut() {
+            Some((last, len)) if *last == byte => *len += 1,
+            _ => runs.push((byte, 1)),
+        }
+    }
+    runs
+}
+
+/// A filesystem as a map from paths, relative to an implicit root directory,
+/// to what they hold, with the errors POSIX, or rather Linux, gives.
+#[derive(Default)]
+struct Model {
+    entries: BTreeMap<String, Entry>,
+}
+
+enum Entry {
+    Dir,
+    File(Vec<u8>),
+}
+
+impl Model {
+    fn apply(&mut self, op: &Op) -> Outcome {
+        let result = match op {
+            Op::Create(path) => self.create(path, Entry::File(Vec::new())),
+            Op::Mkdir(path) => self.create(path, Entry::Dir),
+            Op::Write(path, offset, len, byte) => self.file(path).map(|data| {
+                let (start, end) = (*offset as usize, *offset as usize + len);
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[start..end].iter_mut().for_each(|b| *b = *byte);
+                Outcome::Done
+            }),
+            Op::Truncate(path, size) => self.file(path).map(|data| {
+                data.resize(*size as usize, 0);
+                Outcome::Done
+            }),
+            Op::Read(path) => self.file(path).map(|data| Outcome::Data(runs(data))),
+            Op::ReadDir(path) => self.read_dir(path).map(Outcome::Entries),
+            Op::Rename(from, to) => self.rename(from, to).map(|_| Outcome::Done),
+            Op::Unlink(path) => self.remove(path, false).map(|_| Outcome::Done),
+            Op::Rmdir(path) => self.remove(path, true).map(|_| Outcome::Done),
+        };
+        result.unwrap_or_else(Outcome::Errno)
+    }
+
+    /// Check that the directories above path exist.
+    fn parents(&self, path: &str) -> Result<(), c_int> {
+        for (i, _) in path.match_indices('/') {
+            match self.entries.get(&path[..i]) {
+                Some(Entry::Dir) => {}
+                Some(Entry::File(_)) => return Err(ENOTDIR),
+                None => return Err(ENOENT),
+            }
+        }
+        Ok(())
+    }
+
+    fn create(&mut self, path: &str, entry: Entry) -> Result<Outcome, c_int> {
+        self.parents(path)?;
+        if self.entries.contains_key(path) {
+            return Err(EEXIST);
+        }
+        self.entries.insert(path.to_string(), entry);
+        Ok(Outcome::Done)
+    }
+
+    fn file(&mut self, path: &str) -> Result<&mut Vec<u8>, c_int> {
+        self.parents(path)?;
+        match self.entries.get_mut(path) {
+            Some(Entry::File(data)) => Ok(data),
+            Some(Entry::Dir) => Err(EISDIR),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<String>, c_int> {
+        if !path.is_empty() {
+            self.parents(path)?;
+            match self.entries.get(path) {
+                Some(Entry::Dir) => {}
+                Some(Entry::File(_)) => return Err(ENOTDIR),
+                None => return Err(ENOENT),
+            }
+        }
+        Ok(self.children(path))
+    }
+
+    /// The names in the directory at path, in order.
+    fn children(&self, path: &str) -> Vec<String> {
+        let prefix = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", path)
+        };
+        self.entries
+            .keys()
+            .filter_map(|key| key.strip_prefix(&prefix))
+            .filter(|name| !name.contains('/'))
+            .map(String::from)
+            .collect()
+    }
+
+    fn remove(&mut self, path: &str, dir: bool) -> Result<(), c_int> {
+        self.parents(path)?;
+        match (self.entries.get(path), dir) {
+            (None, _) => return Err(ENOENT),
+            (Some(Entry::Dir), false) => return Err(EISDIR),
+            (Some(Entry::File(_)), true) => return Err(ENOTDIR),
+            (Some(Entry::Dir), true) if !self.children(path).is_empty() => return Err(ENOTEMPTY),
+            _ => {}
+        }
+        self.entries.remove(path);
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), c_int> {
+        self.parents(from)?;
+        self.parents(to)?;
+        let from_dir = match self.entries.get(from) {
+            Some(entry) => matches!(entry, Entry::Dir),
+            None => return Err(ENOENT),
+        };
+        // A directory cannot be moved beneath itself, nor over one of the
+        // directories it is in, which cannot be empty.
+        if to.starts_with(&format!("{}/", from)) {
+            return Err(EINVAL);
+        }
+        if from.starts_with(&format!("{}/", to)) {
+            return Err(ENOTEMPTY);
+        }
+        if from == to {
+            return Ok(());
+        }
+        match (from_dir, self.entries.get(to)) {
+            (true, Some(Entry::File(_))) => return Err(ENOTDIR),
+            (false, Some(Entry::Dir)) => return Err(EISDIR),
+            (true, Some(Entry::Dir)) if !self.children(to).is_empty() => return Err(ENOTEMPTY),
+            _ => {}
+        }
+        self.entries.remove(to);
+        let prefix = format!("{}/", from);
+        let moved: Vec<String> = self
+            .entries
+            .keys()
+            .filter(|key| *key == from || key.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for key in moved {
+            let entry = self.entries.remove(&key).unwrap();
+            self.entries
+                .insert(format!("{}{}", to, &key[from.len()..]), entry);
+        }
+        Ok(())
+    }
+
+    /// Everything in the model, by path.
+    fn tree(&self) -> BTreeMap<String, Contents> {
+        self.entries
+            .iter()
+            .map(|(path, entry)| {
+                let contents = match entry {
+                    Entry::Dir => Contents::Dir,
+                    Entry::File(data) => Contents::File(runs(data)),
+                };
+                (path.clone(), contents)
+            })
+            .collect()
+    }
+}